@@ -0,0 +1,431 @@
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use serde_json::{Value, json};
+
+use crate::error::{ConmonError, ConmonResult};
+
+/// Magic prefix of the hello packet an attach client may send as the very
+/// first packet on a new connection. The rest of the packet is a JSON object
+/// with the `version` and the requested `features`.
+pub const HELLO_MAGIC: &[u8] = b"\0conmon-attach-hello\0";
+
+/// Packet closing the container's stdin without disconnecting, sent by the
+/// clients which negotiated [`AttachFeature::StdinEof`].
+pub const STDIN_EOF_MAGIC: &[u8] = b"\0conmon-attach-stdin-eof\0";
+
+/// The attach protocol version implemented by this conmon.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// Prefix byte of the control packets sent to the attach clients. The data
+/// packets are prefixed with 2 (stdout) and 3 (stderr).
+pub const CONTROL_PIPE: u8 = 4;
+
+/// Optional features of the attach protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachFeature {
+    /// Data packets are prefixed with a byte identifying the stream.
+    Framing,
+
+    /// The client closes the container's stdin with the [`STDIN_EOF_MAGIC`]
+    /// packet and keeps receiving the output.
+    StdinEof,
+}
+
+impl AttachFeature {
+    /// Returns the name of the feature used in the hello and ack packets.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AttachFeature::Framing => "framing",
+            AttachFeature::StdinEof => "stdin-eof",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        SUPPORTED_FEATURES
+            .iter()
+            .copied()
+            .find(|f| f.name() == name)
+    }
+}
+
+/// The features supported by this conmon.
+pub const SUPPORTED_FEATURES: &[AttachFeature] = &[AttachFeature::Framing, AttachFeature::StdinEof];
+
+/// The result of the hello/ack exchange with an attach client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    /// The protocol version both sides speak.
+    pub version: u64,
+
+    /// The features requested by the client and supported by conmon.
+    pub features: Vec<AttachFeature>,
+}
+
+impl Negotiated {
+    /// Returns true if the feature `f` has been negotiated.
+    pub fn has(&self, f: AttachFeature) -> bool {
+        self.features.contains(&f)
+    }
+
+    /// Returns true if `packet` closes the container's stdin.
+    pub fn is_stdin_eof(&self, packet: &[u8]) -> bool {
+        self.has(AttachFeature::StdinEof) && packet == STDIN_EOF_MAGIC
+    }
+
+    /// Returns the ack packet to send back to the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `terminal` - True if the container runs with a terminal, so the
+    ///   output is not split into stdout and stderr.
+    pub fn ack_packet(&self, terminal: bool) -> Vec<u8> {
+        let names = |features: &[AttachFeature]| -> Vec<&str> {
+            features.iter().map(|f| f.name()).collect()
+        };
+        let ack = json!({
+            "version": self.version,
+            "features": names(&self.features),
+            "supported": names(SUPPORTED_FEATURES),
+            "terminal": terminal,
+        });
+        let mut packet = vec![CONTROL_PIPE];
+        packet.extend_from_slice(ack.to_string().as_bytes());
+        packet
+    }
+}
+
+/// Negotiates the protocol with the client if `packet` is a hello packet.
+///
+/// Unknown features are ignored, so newer clients can talk to older conmon.
+/// Malformed hello negotiates the base protocol without any features.
+///
+/// # Arguments
+///
+/// * `packet` - The first packet received from the attach client.
+///
+/// # Returns
+///
+/// * None if `packet` is not a hello packet and the client uses the legacy
+///   protocol without negotiation.
+pub fn negotiate(packet: &[u8]) -> Option<Negotiated> {
+    let body = packet.strip_prefix(HELLO_MAGIC)?;
+    let hello: Value = match serde_json::from_slice(body) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Malformed attach hello: {e}");
+            Value::Null
+        }
+    };
+    let version = hello["version"]
+        .as_u64()
+        .unwrap_or(PROTOCOL_VERSION)
+        .min(PROTOCOL_VERSION);
+    let mut features = Vec::new();
+    for name in hello["features"].as_array().into_iter().flatten() {
+        if let Some(f) = name.as_str().and_then(AttachFeature::from_name) {
+            if !features.contains(&f) {
+                features.push(f);
+            }
+        }
+    }
+    let negotiated = Negotiated { version, features };
+    info!("Negotiated attach protocol: {:?}", negotiated);
+    Some(negotiated)
+}
+
+/// Default size of the output queued for single slow attach client.
+pub const DEFAULT_ATTACH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// What to do with an attach client which does not read the output fast
+/// enough and fills its `--attach-buffer-size` queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowClientPolicy {
+    /// Disconnect the client.
+    #[default]
+    Disconnect,
+    /// Drop the oldest queued output to make room for the new one.
+    DropOldest,
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = ConmonError;
+
+    fn from_str(s: &str) -> ConmonResult<Self> {
+        match s {
+            "disconnect" => Ok(SlowClientPolicy::Disconnect),
+            "drop-oldest" => Ok(SlowClientPolicy::DropOldest),
+            _ => Err(ConmonError::new(
+                format!("Invalid attach client policy '{s}', expected disconnect or drop-oldest"),
+                1,
+            )),
+        }
+    }
+}
+
+/// The output state shared by the attach clients: the replay buffer and the
+/// limits of the per-client output queues.
+#[derive(Debug, Default)]
+pub struct AttachOutput {
+    pub replay: ReplayBuffer,
+
+    /// Maximum size of the output queued for single client in bytes.
+    pub buffer_size: usize,
+
+    pub policy: SlowClientPolicy,
+}
+
+impl AttachOutput {
+    pub fn new(replay_size: usize, buffer_size: usize, policy: SlowClientPolicy) -> Self {
+        Self {
+            replay: ReplayBuffer::new(replay_size),
+            buffer_size,
+            policy,
+        }
+    }
+}
+
+/// Path of the attach audit file, set by `--attach-audit`.
+static AUDIT_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Appends the audit records of the attach connections also to `path`.
+pub fn enable_audit(path: PathBuf) {
+    info!("Auditing attach connections into {}", path.display());
+    *AUDIT_PATH.lock().unwrap_or_else(|e| e.into_inner()) = Some(path);
+}
+
+/// Audit record of single attach connection.
+///
+/// It is written to the debug log and, with `--attach-audit`, appended as
+/// a JSON line to the audit file once the connection is closed.
+#[derive(Debug)]
+pub struct AttachAudit {
+    /// The uid, gid and pid of the peer from `SO_PEERCRED`, if known.
+    pub peer: Option<(u32, u32, i32)>,
+
+    connected: DateTime<Utc>,
+    start: Instant,
+
+    /// Bytes received from the client.
+    pub bytes_in: u64,
+
+    /// Bytes sent to the client, shared with its `AttachClient`.
+    pub bytes_out: Arc<AtomicU64>,
+}
+
+impl AttachAudit {
+    pub fn new(peer: Option<(u32, u32, i32)>) -> Self {
+        Self {
+            peer,
+            connected: Utc::now(),
+            start: Instant::now(),
+            bytes_in: 0,
+            bytes_out: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns the JSON audit record of the connection.
+    pub fn record(&self) -> Value {
+        let (uid, gid, pid) = match self.peer {
+            Some((uid, gid, pid)) => (json!(uid), json!(gid), json!(pid)),
+            None => (Value::Null, Value::Null, Value::Null),
+        };
+        json!({
+            "connected": self.connected.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "duration_ms": self.start.elapsed().as_millis() as u64,
+            "uid": uid,
+            "gid": gid,
+            "pid": pid,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Writes the record of the closed connection.
+    pub fn finish(&self) {
+        let record = self.record();
+        info!("Attach connection closed: {record}");
+        let path = AUDIT_PATH.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(path) = path.as_ref() else {
+            return;
+        };
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut f| writeln!(f, "{record}"));
+        if let Err(e) = written {
+            warn!("Failed to write attach audit {}: {e}", path.display());
+        }
+    }
+}
+
+/// Ring buffer of the recent output packets replayed to the newly connected
+/// attach clients (`--attach-replay-size`).
+///
+/// The packets are kept whole with their stream prefix, so the oldest packets
+/// are dropped once the buffered packets exceed the size.
+#[derive(Debug, Default)]
+pub struct ReplayBuffer {
+    packets: VecDeque<Vec<u8>>,
+
+    /// Total size of `packets` in bytes.
+    len: usize,
+
+    /// Maximum size of `packets` in bytes; 0 disables the replay.
+    size: usize,
+}
+
+impl ReplayBuffer {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            ..Default::default()
+        }
+    }
+
+    /// Appends the output packet made of `prefix` and `data`, dropping the
+    /// oldest packets which do not fit anymore.
+    pub fn push(&mut self, prefix: &[u8], data: &[u8]) {
+        let packet_len = prefix.len() + data.len();
+        if packet_len > self.size {
+            self.packets.clear();
+            self.len = 0;
+            return;
+        }
+        while self.len + packet_len > self.size {
+            if let Some(old) = self.packets.pop_front() {
+                self.len -= old.len();
+            }
+        }
+        let mut packet = Vec::with_capacity(packet_len);
+        packet.extend_from_slice(prefix);
+        packet.extend_from_slice(data);
+        self.packets.push_back(packet);
+        self.len += packet_len;
+    }
+
+    /// Returns the buffered packets, the oldest first.
+    pub fn packets(&self) -> impl Iterator<Item = &[u8]> {
+        self.packets.iter().map(Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(body: &str) -> Vec<u8> {
+        let mut packet = HELLO_MAGIC.to_vec();
+        packet.extend_from_slice(body.as_bytes());
+        packet
+    }
+
+    #[test]
+    fn negotiate_keeps_supported_features() {
+        let n = negotiate(&hello(
+            r#"{"version":7,"features":["replay","framing","framing"]}"#,
+        ))
+        .unwrap();
+        assert_eq!(n.version, PROTOCOL_VERSION);
+        assert_eq!(n.features, vec![AttachFeature::Framing]);
+
+        let ack = n.ack_packet(true);
+        assert_eq!(ack[0], CONTROL_PIPE);
+        let ack: Value = serde_json::from_slice(&ack[1..]).unwrap();
+        assert_eq!(ack["features"], json!(["framing"]));
+        assert_eq!(ack["supported"], json!(["framing", "stdin-eof"]));
+        assert_eq!(ack["terminal"], true);
+        assert!(!n.is_stdin_eof(STDIN_EOF_MAGIC));
+    }
+
+    #[test]
+    fn slow_client_policy_is_parsed() {
+        assert_eq!(
+            "disconnect".parse::<SlowClientPolicy>().unwrap(),
+            SlowClientPolicy::Disconnect
+        );
+        assert_eq!(
+            "drop-oldest".parse::<SlowClientPolicy>().unwrap(),
+            SlowClientPolicy::DropOldest
+        );
+        assert!("block".parse::<SlowClientPolicy>().is_err());
+    }
+
+    #[test]
+    fn replay_buffer_keeps_recent_packets() {
+        let mut replay = ReplayBuffer::new(8);
+        replay.push(&[2], b"abc");
+        replay.push(&[3], b"de");
+        assert_eq!(
+            replay.packets().collect::<Vec<_>>(),
+            [&b"\x02abc"[..], b"\x03de"]
+        );
+
+        // The oldest packet is dropped to make room.
+        replay.push(&[2], b"fgh");
+        assert_eq!(
+            replay.packets().collect::<Vec<_>>(),
+            [&b"\x03de"[..], b"\x02fgh"]
+        );
+
+        replay.push(&[2], b"too long");
+        assert_eq!(replay.packets().count(), 0);
+
+        let mut disabled = ReplayBuffer::default();
+        disabled.push(&[2], b"a");
+        assert_eq!(disabled.packets().count(), 0);
+    }
+
+    #[test]
+    fn audit_records_are_appended_to_file() -> ConmonResult<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("attach-audit.log");
+        enable_audit(path.clone());
+        let mut audit = AttachAudit::new(Some((1000, 1000, 4242)));
+        audit.bytes_in = 12;
+        audit.bytes_out.fetch_add(34, Ordering::Relaxed);
+        audit.finish();
+        *AUDIT_PATH.lock().unwrap() = None;
+
+        // Other tests may audit their connections meanwhile.
+        let contents = std::fs::read_to_string(&path)?;
+        let record: Value = contents
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .find(|r| r["pid"] == 4242)
+            .unwrap();
+        assert_eq!(record["uid"], 1000);
+        assert_eq!(record["bytes_in"], 12);
+        assert_eq!(record["bytes_out"], 34);
+        assert!(record["connected"].as_str().unwrap().ends_with('Z'));
+        assert!(record["duration_ms"].is_u64());
+        Ok(())
+    }
+
+    #[test]
+    fn stdin_eof_requires_negotiation() {
+        let n = negotiate(&hello(r#"{"features":["stdin-eof"]}"#)).unwrap();
+        assert_eq!(n.features, vec![AttachFeature::StdinEof]);
+        assert!(n.is_stdin_eof(STDIN_EOF_MAGIC));
+        assert!(!n.is_stdin_eof(b"data\n"));
+    }
+
+    #[test]
+    fn negotiate_ignores_legacy_and_malformed_packets() {
+        assert_eq!(negotiate(b"ls -l\n"), None);
+        let n = negotiate(&hello("not json")).unwrap();
+        assert_eq!(n.version, PROTOCOL_VERSION);
+        assert!(n.features.is_empty());
+    }
+}
@@ -1,18 +1,77 @@
+use crate::attach::{DEFAULT_ATTACH_BUFFER_SIZE, SlowClientPolicy};
 use crate::error::{ConmonError, ConmonResult};
-use crate::logging::plugin::LogPluginCfg;
+use crate::heartbeat::DEFAULT_HEARTBEAT_INTERVAL;
+use crate::log::warn_user;
+use crate::logging::plugin::{
+    DeliveryPolicy, LogPluginCfg, LogStreams, expand_log_tag, is_log_driver, parse_log_labels,
+};
+use crate::logging::redact::{Redactor, load_redact_rules};
+use crate::runtime::control::{DEFAULT_STOP_TIMEOUT, parse_signal};
+use crate::runtime::process::ForkMode;
+use crate::runtime::version::VersionCheck;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::path::PathBuf;
 
-use clap::{ArgAction, Parser};
-use log::warn;
+use clap::{ArgAction, Parser, Subcommand};
+use nix::sys::signal::Signal;
 
 /// Accept any string for --log-path (including empty) so we can reject empty with "log-path must not be empty" in determine_log_plugin.
 fn parse_log_path_any(s: &str) -> Result<PathBuf, String> {
     Ok(PathBuf::from(s))
 }
 
+/// Parses the `uid[:gid]` owner of --attach-socket-owner.
+fn parse_socket_owner(s: &str) -> Result<(u32, Option<u32>), String> {
+    let (uid, gid) = match s.split_once(':') {
+        Some((uid, gid)) => (uid, Some(gid)),
+        None => (s, None),
+    };
+    let uid = uid
+        .parse()
+        .map_err(|e| format!("Invalid socket owner uid '{uid}': {e}"))?;
+    let gid = gid
+        .map(|gid| {
+            gid.parse()
+                .map_err(|e| format!("Invalid socket owner gid '{gid}': {e}"))
+        })
+        .transpose()?;
+    Ok((uid, gid))
+}
+
+/// Parses the octal permissions of --attach-socket-mode.
+fn parse_socket_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!(
+            "Invalid socket mode '{s}', expected octal like 0660"
+        )),
+    }
+}
+
+/// Parses the signal name or number of --timeout-signal and --stop-signal.
+fn parse_signal_arg(s: &str) -> Result<Signal, String> {
+    parse_signal(s).map_err(|e| e.msg)
+}
+
+/// Parses the `cid:port` vsock address of --attach-vsock. The cid may be `any`.
+fn parse_vsock_addr(s: &str) -> Result<(u32, u32), String> {
+    let (cid, port) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid vsock address '{s}', expected cid:port"))?;
+    let cid = match cid {
+        "any" => nix::libc::VMADDR_CID_ANY,
+        cid => cid
+            .parse()
+            .map_err(|e| format!("Invalid vsock cid '{cid}': {e}"))?,
+    };
+    let port = port
+        .parse()
+        .map_err(|e| format!("Invalid vsock port '{port}': {e}"))?;
+    Ok((cid, port))
+}
+
 #[derive(Parser)]
 #[command(
     name = "conmon",
@@ -59,6 +118,14 @@ pub struct Opts {
     #[arg(long = "exec-process-spec")]
     pub exec_process_spec: Option<PathBuf>,
 
+    /// Feed the stdin of the exec session from this file
+    #[arg(long = "exec-stdin-path")]
+    pub exec_stdin_path: Option<PathBuf>,
+
+    /// Feed the stdin of the exec session from this inherited file descriptor
+    #[arg(long = "exec-stdin-fd", value_parser = clap::value_parser!(i32).range(0..))]
+    pub exec_stdin_fd: Option<i32>,
+
     /// Path to the program to execute when the container terminates
     #[arg(long = "exit-command")]
     pub exit_command: Option<PathBuf>,
@@ -75,10 +142,54 @@ pub struct Opts {
     #[arg(long = "exit-dir")]
     pub exit_dir: Option<PathBuf>,
 
+    /// Stay in the foreground of the calling terminal instead of daemonizing
+    #[arg(long = "foreground", action = ArgAction::SetTrue)]
+    pub foreground: bool,
+
     /// Leave stdin open when attached client disconnects
     #[arg(long = "leave-stdin-open", action = ArgAction::SetTrue)]
     pub leave_stdin_open: bool,
 
+    /// Do not forward SIGTERM, SIGINT and SIGQUIT to the container
+    #[arg(long = "no-forward-signals", action = ArgAction::SetTrue)]
+    pub no_forward_signals: bool,
+
+    /// Listen for attach connections on the AF_VSOCK cid:port instead of the attach socket
+    #[arg(long = "attach-vsock", value_parser = clap::builder::ValueParser::new(parse_vsock_addr))]
+    pub attach_vsock: Option<(u32, u32)>,
+
+    /// Also allow the attach connections of this UID besides conmon's own UID and root
+    #[arg(long = "attach-allowed-uid", action = ArgAction::Append)]
+    pub attach_allowed_uids: Vec<u32>,
+
+    /// Create the attach socket as SOCK_STREAM with length-prefixed packets
+    #[arg(long = "attach-stream", action = ArgAction::SetTrue)]
+    pub attach_stream: bool,
+
+    /// Chown the attach socket and its directory to UID[:GID]
+    #[arg(long = "attach-socket-owner", value_parser = clap::builder::ValueParser::new(parse_socket_owner))]
+    pub attach_socket_owner: Option<(u32, Option<u32>)>,
+
+    /// Octal permissions of the attach socket
+    #[arg(long = "attach-socket-mode", value_parser = clap::builder::ValueParser::new(parse_socket_mode))]
+    pub attach_socket_mode: Option<u32>,
+
+    /// Maximum size in bytes of the output queued for single slow attach client
+    #[arg(long = "attach-buffer-size", default_value_t = DEFAULT_ATTACH_BUFFER_SIZE)]
+    pub attach_buffer_size: usize,
+
+    /// What to do when an attach client's queue is full (disconnect or drop-oldest)
+    #[arg(long = "attach-slow-policy")]
+    pub attach_slow_policy: Option<String>,
+
+    /// Size in bytes of the recent container output replayed to new attach clients
+    #[arg(long = "attach-replay-size", default_value_t = 0)]
+    pub attach_replay_size: usize,
+
+    /// Audit the attach connections into --persist-dir
+    #[arg(long = "attach-audit", action = ArgAction::SetTrue)]
+    pub attach_audit: bool,
+
     /// Print debug logs based on log level
     #[arg(long = "log-level")]
     pub log_level: Option<String>,
@@ -95,6 +206,18 @@ pub struct Opts {
     #[arg(long = "log-global-size-max", value_parser = clap::value_parser!(i64))]
     pub log_global_size_max: Option<i64>,
 
+    /// Maximum length of a log line, longer lines are split into partial records
+    #[arg(long = "log-line-max")]
+    pub log_line_max: Option<usize>,
+
+    /// Maximum rate of the container output logged in bytes per second
+    #[arg(long = "log-rate-limit")]
+    pub log_rate_limit: Option<u64>,
+
+    /// Maximum burst of the container output over --log-rate-limit in bytes
+    #[arg(long = "log-rate-burst")]
+    pub log_rate_burst: Option<u64>,
+
     /// Additional tag to use for logging
     #[arg(long = "log-tag")]
     pub log_tag: Option<String>,
@@ -131,6 +254,14 @@ pub struct Opts {
     #[arg(long = "pidfile", hide = true)]
     pub deprecated_pidfile: Option<PathBuf>,
 
+    /// Also write a JSON exit record into --persist-dir
+    #[arg(long = "exit-json", action = ArgAction::SetTrue)]
+    pub exit_json: bool,
+
+    /// Record interactive sessions into --persist-dir (asciicast v2 format)
+    #[arg(long = "record-session", action = ArgAction::SetTrue)]
+    pub record_session: bool,
+
     /// Replace listen pid if set for oci-runtime pid
     #[arg(long = "replace-listen-pid", action = ArgAction::SetTrue)]
     pub replace_listen_pid: bool,
@@ -163,6 +294,10 @@ pub struct Opts {
     #[arg(long = "socket-dir-path")]
     pub socket_dir_path: Option<PathBuf>,
 
+    /// Start the container using `runtime start` once it has been created
+    #[arg(long = "start", action = ArgAction::SetTrue)]
+    pub start: bool,
+
     /// Open up a pipe to pass stdin to the container
     #[arg(long = "stdin", short = 'i', action = ArgAction::SetTrue)]
     pub stdin: bool,
@@ -187,6 +322,18 @@ pub struct Opts {
     #[arg(long = "timeout", short = 'T', value_parser = clap::value_parser!(i32))]
     pub timeout: Option<i32>,
 
+    /// Signal sent to the container when --timeout expires (default SIGKILL)
+    #[arg(long = "timeout-signal", value_parser = clap::builder::ValueParser::new(parse_signal_arg))]
+    pub timeout_signal: Option<Signal>,
+
+    /// Seconds to wait after --timeout-signal before sending SIGKILL
+    #[arg(long = "timeout-grace", default_value_t = DEFAULT_STOP_TIMEOUT)]
+    pub timeout_grace: u64,
+
+    /// Signal sent to the container by the stop control request (default SIGTERM)
+    #[arg(long = "stop-signal", value_parser = clap::builder::ValueParser::new(parse_signal_arg))]
+    pub stop_signal: Option<Signal>,
+
     /// Print the version and exit (matches C behavior; not clap's -V)
     #[arg(long = "version", action = ArgAction::SetTrue)]
     pub version_flag: bool,
@@ -214,6 +361,131 @@ pub struct Opts {
     /// Allowed log directory (can be specified multiple times)
     #[arg(long = "log-allowlist-dir")]
     pub log_allowlist_dir: Vec<PathBuf>,
+
+    /// Log file to spill the messages into while journald is rate limiting us (journald driver only)
+    #[arg(long = "log-journald-fallback")]
+    pub log_journald_fallback: Option<PathBuf>,
+
+    /// Log driver to switch to when the log keeps failing, as DRIVER:PATH or driver name
+    /// (can be specified multiple times to form a chain)
+    #[arg(long = "log-fallback")]
+    pub log_fallback: Vec<String>,
+
+    /// Number of consecutive log write failures after which the next --log-fallback is used
+    #[arg(long = "log-fallback-after")]
+    pub log_fallback_after: Option<u32>,
+
+    /// Key whose value is replaced with [REDACTED] in the container log (can be specified
+    /// multiple times)
+    #[arg(long = "log-redact-pattern")]
+    pub log_redact_pattern: Vec<String>,
+
+    /// File with the --log-redact-pattern keys, one per line
+    #[arg(long = "log-redact-rules")]
+    pub log_redact_rules: Option<PathBuf>,
+
+    /// Collapse the runs of identical lines of the container output into one line and a repeat count
+    #[arg(long = "log-dedup", action = ArgAction::SetTrue)]
+    pub log_dedup: bool,
+
+    /// Log only one of every N lines of the container output
+    #[arg(long = "log-sample", value_parser = clap::value_parser!(u64).range(1..))]
+    pub log_sample: Option<u64>,
+
+    /// Log driver option in KEY=VALUE format (can be specified multiple times)
+    #[arg(long = "log-opt")]
+    pub log_opt: Vec<String>,
+
+    /// Delivery policy (block, drop-newest or drop-oldest) used when a log target cannot keep up,
+    /// optionally prefixed with "DRIVER=" (can be specified multiple times)
+    #[arg(long = "log-delivery-policy")]
+    pub log_delivery_policy: Vec<String>,
+
+    /// Path to the file where the event-loop metrics are written on exit
+    #[arg(long = "metrics-file")]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Maximum number of open files for the conmon process
+    #[arg(long = "rlimit-nofile", value_parser = clap::value_parser!(u64))]
+    pub rlimit_nofile: Option<u64>,
+
+    /// Maximum size of the conmon process virtual memory in bytes
+    #[arg(long = "rlimit-as", value_parser = clap::value_parser!(u64))]
+    pub rlimit_as: Option<u64>,
+
+    /// Maximum size of memory the conmon process may lock in bytes
+    #[arg(long = "rlimit-memlock", value_parser = clap::value_parser!(u64))]
+    pub rlimit_memlock: Option<u64>,
+
+    /// OOM score adjustment for the conmon process
+    #[arg(long = "oom-score-adj", value_parser = clap::value_parser!(i32).range(-1000..=1000), allow_hyphen_values = true)]
+    pub oom_score_adj: Option<i32>,
+
+    /// Re-adopt a still-running container using the state file in --persist-dir
+    #[arg(long = "adopt", action = ArgAction::SetTrue)]
+    pub adopt: bool,
+
+    /// What to do when the runtime is too old for the flags passed to it (off, warn or fail)
+    #[arg(long = "runtime-version-check")]
+    pub runtime_version_check: Option<String>,
+
+    /// How to detach from the caller (daemon, child or none)
+    #[arg(long = "fork-mode")]
+    pub fork_mode: Option<String>,
+
+    /// Path to the file the event loop touches periodically to show conmon is alive
+    #[arg(long = "heartbeat-file")]
+    pub heartbeat_file: Option<PathBuf>,
+
+    /// Interval between two touches of the --heartbeat-file in seconds (default: 10)
+    #[arg(long = "heartbeat-interval", value_parser = clap::value_parser!(u64).range(1..))]
+    pub heartbeat_interval: Option<u64>,
+
+    /// Kill the runtime if the create, exec or restore does not finish in specified seconds
+    #[arg(long = "runtime-timeout", value_parser = clap::value_parser!(u64).range(1..))]
+    pub runtime_timeout: Option<u64>,
+
+    /// Kill the runtime if the create does not finish in specified seconds (overrides --runtime-timeout)
+    #[arg(long = "create-timeout", value_parser = clap::value_parser!(u64).range(1..))]
+    pub create_timeout: Option<u64>,
+
+    /// Do not print non-fatal warnings, only write them into the conmon log
+    #[arg(long = "quiet", action = ArgAction::SetTrue)]
+    pub quiet: bool,
+
+    /// Also log the input sent by attach clients to the log drivers logging both streams
+    #[arg(long = "log-stdin", action = ArgAction::SetTrue)]
+    pub log_stdin: bool,
+
+    /// Write the container output to the log drivers from a dedicated thread
+    #[arg(long = "log-writer-thread", action = ArgAction::SetTrue)]
+    pub log_writer_thread: bool,
+
+    /// What to do when the --log-writer-thread queue is full (block, drop-newest or drop-oldest)
+    #[arg(long = "log-writer-policy")]
+    pub log_writer_policy: Option<String>,
+
+    /// Maintenance command to run instead of supervising a container
+    #[command(subcommand)]
+    pub command: Option<MaintenanceCmd>,
+}
+
+/// Maintenance commands which do not supervise any container.
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum MaintenanceCmd {
+    /// Remove attach sockets and symlinks left behind by crashed conmon processes
+    Gc {
+        /// Location of container attach sockets
+        #[arg(long = "socket-dir")]
+        socket_dir: PathBuf,
+    },
+
+    /// List the containers supervised by conmon found in the persist dirs
+    Ps {
+        /// Directory containing the --persist-dir directories of the containers
+        #[arg(long = "persist-root")]
+        persist_root: PathBuf,
+    },
 }
 
 #[derive(Debug)]
@@ -222,9 +494,19 @@ pub enum Cmd {
     Create(CreateCfg),
     Exec(ExecCfg),
     Restore(RestoreCfg),
+    Adopt(AdoptCfg),
 }
 
-#[derive(Debug, Default)]
+/// Source of the exec session stdin given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecStdin {
+    /// --exec-stdin-path
+    Path(PathBuf),
+    /// --exec-stdin-fd
+    Fd(i32),
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct CommonCfg {
     pub api_version: i32,
     pub cid: String,
@@ -240,23 +522,49 @@ pub struct CommonCfg {
     pub full_attach: bool,
     pub socket_dir_path: PathBuf,
     pub stdin: bool,
+    pub exec_stdin: Option<ExecStdin>,
     pub leave_stdin_open: bool,
+    pub no_forward_signals: bool,
+    pub attach_vsock: Option<(u32, u32)>,
+    pub attach_allowed_uids: Vec<u32>,
+    pub attach_stream: bool,
+    pub attach_socket_owner: Option<(u32, Option<u32>)>,
+    pub attach_socket_mode: Option<u32>,
+    pub attach_replay_size: usize,
+    pub attach_buffer_size: usize,
+    pub attach_slow_policy: SlowClientPolicy,
+    pub attach_audit: bool,
     pub terminal: bool,
     pub timeout: Option<i32>,
+    pub timeout_signal: Option<Signal>,
+    pub timeout_grace: u64,
+    pub stop_signal: Option<Signal>,
+    pub runtime_timeout: Option<u64>,
     pub replace_listen_pid: bool,
     pub persist_dir: Option<PathBuf>,
+    pub record_session: bool,
     pub exit_dir: Option<PathBuf>,
     pub name: Option<String>,
     pub no_sync_log: bool,
     pub logging_passthrough: bool,
-    pub sync_flag: bool,
+    pub fork_mode: ForkMode,
+    pub foreground: bool,
     pub sdnotify_socket: Option<PathBuf>,
+    pub rlimit_nofile: Option<u64>,
+    pub rlimit_as: Option<u64>,
+    pub rlimit_memlock: Option<u64>,
+    pub oom_score_adj: Option<i32>,
+    pub log_paths: Vec<PathBuf>,
+    pub runtime_version_check: VersionCheck,
+    pub heartbeat_file: Option<PathBuf>,
+    pub heartbeat_interval: u64,
 }
 
 #[derive(Debug, Default)]
 pub struct CreateCfg {
     pub common: CommonCfg,
     pub systemd_cgroup: bool,
+    pub start: bool,
 }
 
 #[derive(Debug, Default)]
@@ -273,6 +581,11 @@ pub struct RestoreCfg {
     pub systemd_cgroup: bool,
 }
 
+#[derive(Debug, Default)]
+pub struct AdoptCfg {
+    pub common: CommonCfg,
+}
+
 /// Try to detect "executable" bit.
 fn is_executable(p: &Path) -> bool {
     if let Ok(md) = fs::metadata(p) {
@@ -306,6 +619,24 @@ pub fn determine_cmd(mut opts: Opts, logging_passthrough: bool) -> ConmonResult<
             1,
         ));
     }
+    if opts.adopt && (opts.exec || opts.restore.is_some()) {
+        return Err(ConmonError::new(
+            "Cannot use 'adopt' together with 'exec' or 'restore'",
+            1,
+        ));
+    }
+    if opts.start && (opts.exec || opts.restore.is_some() || opts.adopt) {
+        return Err(ConmonError::new(
+            "--start can only be used when creating a container",
+            1,
+        ));
+    }
+    if opts.create_timeout.is_some() && (opts.exec || opts.restore.is_some() || opts.adopt) {
+        return Err(ConmonError::new(
+            "--create-timeout can only be used when creating a container",
+            1,
+        ));
+    }
     if !opts.exec && opts.attach {
         return Err(ConmonError::new(
             "Attach can only be specified with exec",
@@ -319,6 +650,81 @@ pub fn determine_cmd(mut opts: Opts, logging_passthrough: bool) -> ConmonResult<
         ));
     }
 
+    let exec_stdin = match (opts.exec_stdin_path.take(), opts.exec_stdin_fd) {
+        (Some(_), Some(_)) => {
+            return Err(ConmonError::new(
+                "Cannot use --exec-stdin-path and --exec-stdin-fd at the same time",
+                1,
+            ));
+        }
+        (Some(path), None) => Some(ExecStdin::Path(path)),
+        (None, Some(fd)) => Some(ExecStdin::Fd(fd)),
+        (None, None) => None,
+    };
+    if exec_stdin.is_some() {
+        if !opts.exec {
+            return Err(ConmonError::new(
+                "--exec-stdin-path and --exec-stdin-fd can only be used with exec",
+                1,
+            ));
+        }
+        if opts.stdin || opts.terminal {
+            return Err(ConmonError::new(
+                "--exec-stdin-path and --exec-stdin-fd cannot be used with --stdin or --terminal",
+                1,
+            ));
+        }
+    }
+
+    if opts.heartbeat_interval.is_some() && opts.heartbeat_file.is_none() {
+        return Err(ConmonError::new(
+            "--heartbeat-interval requires --heartbeat-file",
+            1,
+        ));
+    }
+
+    if opts.record_session && opts.persist_dir.is_none() {
+        return Err(ConmonError::new(
+            "--record-session requires --persist-dir",
+            1,
+        ));
+    }
+
+    if opts.exit_json && opts.persist_dir.is_none() {
+        return Err(ConmonError::new("--exit-json requires --persist-dir", 1));
+    }
+
+    if opts.attach_audit && opts.persist_dir.is_none() {
+        return Err(ConmonError::new("--attach-audit requires --persist-dir", 1));
+    }
+
+    let runtime_version_check = match &opts.runtime_version_check {
+        Some(check) => check.parse()?,
+        None => VersionCheck::default(),
+    };
+
+    let fork_mode = match &opts.fork_mode {
+        Some(mode) => {
+            let mode: ForkMode = mode.parse()?;
+            if opts.sync_flag && mode == ForkMode::Daemon {
+                return Err(ConmonError::new(
+                    "Cannot use --sync with --fork-mode daemon",
+                    1,
+                ));
+            }
+            if opts.foreground && mode != ForkMode::None {
+                return Err(ConmonError::new(
+                    "--foreground requires --fork-mode none",
+                    1,
+                ));
+            }
+            mode
+        }
+        None if opts.foreground => ForkMode::None,
+        None if opts.sync_flag => ForkMode::Child,
+        None => ForkMode::Daemon,
+    };
+
     // cuuid rule: required unless legacy exec API (<1) with --exec
     if opts.cuuid.is_none() && (!opts.exec || api_version >= 1) {
         return Err(ConmonError::new(
@@ -347,6 +753,13 @@ pub fn determine_cmd(mut opts: Opts, logging_passthrough: bool) -> ConmonResult<
     // bundle defaults to "$cwd" if none provided
     let bundle = opts.bundle.take().unwrap_or_else(|| cwd.clone());
 
+    let attach_slow_policy = opts
+        .attach_slow_policy
+        .as_deref()
+        .map(|p| p.trim().parse::<SlowClientPolicy>())
+        .transpose()?
+        .unwrap_or_default();
+
     let socket_dir_path = opts
         .socket_dir_path
         .take()
@@ -367,21 +780,50 @@ pub fn determine_cmd(mut opts: Opts, logging_passthrough: bool) -> ConmonResult<
         full_attach: opts.full_attach,
         socket_dir_path,
         stdin: opts.stdin,
+        exec_stdin,
         leave_stdin_open: opts.leave_stdin_open,
+        no_forward_signals: opts.no_forward_signals,
+        attach_vsock: opts.attach_vsock,
+        attach_allowed_uids: opts.attach_allowed_uids,
+        attach_stream: opts.attach_stream,
+        attach_socket_owner: opts.attach_socket_owner,
+        attach_socket_mode: opts.attach_socket_mode,
+        attach_replay_size: opts.attach_replay_size,
+        attach_buffer_size: opts.attach_buffer_size,
+        attach_slow_policy,
+        attach_audit: opts.attach_audit,
         terminal: opts.terminal,
         timeout: opts.timeout,
+        timeout_signal: opts.timeout_signal,
+        timeout_grace: opts.timeout_grace,
+        stop_signal: opts.stop_signal,
+        runtime_timeout: opts.create_timeout.or(opts.runtime_timeout),
         replace_listen_pid: opts.replace_listen_pid,
         persist_dir: opts.persist_dir,
+        record_session: opts.record_session,
         exit_dir: opts.exit_dir,
         name: opts.name,
         no_sync_log: opts.no_sync_log,
         logging_passthrough,
-        sync_flag: opts.sync_flag,
+        fork_mode,
+        foreground: opts.foreground,
         sdnotify_socket: opts.sdnotify_socket,
+        rlimit_nofile: opts.rlimit_nofile,
+        rlimit_as: opts.rlimit_as,
+        rlimit_memlock: opts.rlimit_memlock,
+        oom_score_adj: opts.oom_score_adj,
+        log_paths: opts.log_path,
+        runtime_version_check,
+        heartbeat_file: opts.heartbeat_file,
+        heartbeat_interval: opts
+            .heartbeat_interval
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL),
     };
 
     // decide which subcommand this flag combination means
-    if let Some(restore_path) = opts.restore.take() {
+    if opts.adopt {
+        Ok(Cmd::Adopt(AdoptCfg { common }))
+    } else if let Some(restore_path) = opts.restore.take() {
         Ok(Cmd::Restore(RestoreCfg {
             common,
             restore_path,
@@ -403,10 +845,22 @@ pub fn determine_cmd(mut opts: Opts, logging_passthrough: bool) -> ConmonResult<
         Ok(Cmd::Create(CreateCfg {
             common,
             systemd_cgroup: opts.systemd_cgroup,
+            start: opts.start,
         }))
     }
 }
 
+/// Splits the optional "stdout=" or "stderr=" stream prefix from the --log-path value.
+fn split_log_streams(spec: &str) -> (LogStreams, &str) {
+    if let Some(rest) = spec.strip_prefix("stdout=") {
+        (LogStreams::Stdout, rest)
+    } else if let Some(rest) = spec.strip_prefix("stderr=") {
+        (LogStreams::Stderr, rest)
+    } else {
+        (LogStreams::All, spec)
+    }
+}
+
 // Handles the logging related options from `opts` and returns a list of (plugin name, LogPluginCfg)
 // so that multiple log plugins can be configured (one entry per --log-path).
 pub fn determine_log_plugin(opts: &Opts) -> ConmonResult<Vec<(String, LogPluginCfg)>> {
@@ -433,18 +887,103 @@ pub fn determine_log_plugin(opts: &Opts) -> ConmonResult<Vec<(String, LogPluginC
     }
     let max_files = raw_max_files as i32;
 
+    if opts.log_line_max == Some(0) {
+        return Err(ConmonError::new("log-line-max must be positive", 1));
+    }
+
+    if opts.log_rate_burst.is_some() && opts.log_rate_limit.is_none() {
+        return Err(ConmonError::new(
+            "--log-rate-burst requires --log-rate-limit",
+            1,
+        ));
+    }
+
+    if opts.log_writer_policy.is_some() && !opts.log_writer_thread {
+        return Err(ConmonError::new(
+            "--log-writer-policy requires --log-writer-thread",
+            1,
+        ));
+    }
+    let writer_policy = opts
+        .log_writer_policy
+        .as_deref()
+        .map(|p| p.trim().parse::<DeliveryPolicy>())
+        .transpose()?;
+
+    if opts.log_fallback_after.is_some() && opts.log_fallback.is_empty() {
+        return Err(ConmonError::new(
+            "--log-fallback-after requires --log-fallback",
+            1,
+        ));
+    }
+    if opts.log_fallback_after == Some(0) {
+        return Err(ConmonError::new("log-fallback-after must be positive", 1));
+    }
+
+    let mut redact_patterns = opts.log_redact_pattern.clone();
+    if let Some(rules) = &opts.log_redact_rules {
+        redact_patterns.extend(load_redact_rules(rules)?);
+    }
+    // Validate the patterns before forking.
+    Redactor::new(&redact_patterns)?;
+
+    let mut log_opts = Vec::with_capacity(opts.log_opt.len());
+    for opt in &opts.log_opt {
+        match opt.split_once('=') {
+            Some((key, value)) if !key.is_empty() => {
+                if !key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                {
+                    return Err(ConmonError::new(
+                        format!("Invalid log option key '{key}'"),
+                        1,
+                    ));
+                }
+                log_opts.push((key.to_string(), value.to_string()))
+            }
+            _ => {
+                return Err(ConmonError::new(
+                    format!("Log options must be in format KEY=VALUE (in '{opt}')"),
+                    1,
+                ));
+            }
+        }
+    }
+
+    // The delivery policies: the default one and the per-driver ones.
+    let mut default_policy: Option<DeliveryPolicy> = None;
+    let mut driver_policies: Vec<(String, DeliveryPolicy)> = Vec::new();
+    for spec in &opts.log_delivery_policy {
+        match spec.split_once('=') {
+            Some((driver, policy)) => {
+                driver_policies.push((driver.trim().replace("-", "_"), policy.trim().parse()?))
+            }
+            None => default_policy = Some(spec.trim().parse()?),
+        }
+    }
+
     // Base config from non-path options (shared by all plugin instances).
     let base_cfg = LogPluginCfg {
         path: PathBuf::new(),
         cid: opts.cid.clone(),
         cuuid: opts.cuuid.clone(),
-        log_tag: opts.log_tag.clone(),
-        log_labels: opts.log_labels.clone(),
+        log_tag: opts
+            .log_tag
+            .as_deref()
+            .map(|tag| expand_log_tag(tag, opts.cid.as_deref(), opts.name.as_deref()))
+            .transpose()?,
+        log_labels: parse_log_labels(&opts.log_labels)?,
         no_container_partial_message: opts.no_container_partial_message,
         name: opts.name.clone(),
         no_sync: opts.no_sync_log,
         max_size: opts.log_size_max.unwrap_or(0) as usize,
         global_max_size: opts.log_global_size_max.unwrap_or(0) as usize,
+        rate_limit: opts.log_rate_limit.unwrap_or(0),
+        rate_burst: opts.log_rate_burst.unwrap_or(0),
+        line_max: opts.log_line_max.unwrap_or(0),
+        writer_thread: opts.log_writer_thread,
+        writer_policy,
         max_files,
         allowlist_dirs: if opts.log_allowlist_dir.is_empty() {
             None
@@ -452,12 +991,22 @@ pub fn determine_log_plugin(opts: &Opts) -> ConmonResult<Vec<(String, LogPluginC
             Some(opts.log_allowlist_dir.clone())
         },
         rotate: opts.log_rotate,
+        journald_fallback: opts.log_journald_fallback.clone(),
+        fallbacks: Vec::new(),
+        fallback_after: opts.log_fallback_after.unwrap_or(0),
+        redact_patterns,
+        dedup: opts.log_dedup,
+        sample: opts.log_sample.unwrap_or(0),
+        streams: LogStreams::All,
+        log_opts,
+        delivery_policy: None,
     };
 
     let mut entries: Vec<(String, LogPluginCfg)> = Vec::with_capacity(opts.log_path.len());
 
     for p in &opts.log_path {
-        let s = p.to_string_lossy();
+        let spec = p.to_string_lossy();
+        let (streams, s) = split_log_streams(&spec);
         if s.is_empty() || s == ":" {
             return Err(ConmonError::new("log-path must not be empty", 1));
         }
@@ -465,38 +1014,50 @@ pub fn determine_log_plugin(opts: &Opts) -> ConmonResult<Vec<(String, LogPluginC
             return Err(ConmonError::new("k8s-file requires a filename", 1));
         }
 
-        let mut plugin: String = "file".into();
-        let mut path = PathBuf::new();
-
-        if let Some((plug, path_str)) = s.split_once(':') {
-            let path_str = path_str.trim();
-            if !path_str.is_empty() {
-                path = path_str.into();
-            }
-            let plug = plug.trim();
-            if !plug.is_empty() {
-                plugin = plug.replace("-", "_");
-            }
-        } else if s == "journald" {
-            plugin = "journald".to_string();
-        } else if s == "passthrough" {
-            plugin = "passthrough".to_string();
-        } else if s == "none" || s == "null" || s == "off" {
-            // Bare driver names (no ':') must not be treated as file paths.
-            // Matches conmon-v2: `--log-path none` disables logging.
-            plugin = s.to_string();
-        } else if !s.is_empty() {
-            path = s.to_string().into();
-        }
+        let (plugin, path) = parse_log_driver(s)?;
 
         let mut cfg = base_cfg.clone();
         cfg.path = path;
+        cfg.streams = match streams {
+            LogStreams::All if opts.log_stdin => LogStreams::AllWithStdin,
+            _ => streams,
+        };
+        cfg.delivery_policy = driver_policies
+            .iter()
+            .rev()
+            .find(|(driver, _)| *driver == plugin)
+            .map(|(_, policy)| *policy)
+            .or(default_policy);
         entries.push((plugin, cfg));
     }
 
+    let mut fallbacks = Vec::with_capacity(opts.log_fallback.len());
+    for spec in &opts.log_fallback {
+        let (plugin, path) = parse_log_driver(spec.trim())?;
+        if plugin == "passthrough" {
+            return Err(ConmonError::new(
+                "passthrough log driver cannot be used as --log-fallback",
+                1,
+            ));
+        }
+        if log_file_driver(&plugin) && path.as_os_str().is_empty() {
+            return Err(ConmonError::new(
+                format!("--log-fallback {spec} requires a filename"),
+                1,
+            ));
+        }
+        fallbacks.push((plugin, path));
+    }
+    for (_, cfg) in entries.iter_mut() {
+        cfg.fallbacks = fallbacks.clone();
+    }
+
     for (name, cfg) in &entries {
-        if name == "k8s_file" && cfg.path.as_os_str().is_empty() {
-            return Err(ConmonError::new("k8s-file requires a filename", 1));
+        if log_file_driver(name) && name != "file" && cfg.path.as_os_str().is_empty() {
+            return Err(ConmonError::new(
+                format!("{} requires a filename", name.replace('_', "-")),
+                1,
+            ));
         }
     }
 
@@ -511,6 +1072,18 @@ pub fn determine_log_plugin(opts: &Opts) -> ConmonResult<Vec<(String, LogPluginC
             1,
         ));
     }
+    if passthrough_count > 0 && !base_cfg.redact_patterns.is_empty() {
+        return Err(ConmonError::new(
+            "--log-redact-pattern cannot be used with the passthrough log driver",
+            1,
+        ));
+    }
+    if passthrough_count > 0 && (base_cfg.dedup || base_cfg.sample > 1) {
+        return Err(ConmonError::new(
+            "--log-dedup and --log-sample cannot be used with the passthrough log driver",
+            1,
+        ));
+    }
 
     let has_journald = entries.iter().any(|(name, _)| name == "journald");
     if has_journald {
@@ -524,17 +1097,63 @@ pub fn determine_log_plugin(opts: &Opts) -> ConmonResult<Vec<(String, LogPluginC
         }
     }
     if opts.no_container_partial_message && !has_journald {
-        let msg = "--no-container-partial-message has no effect without journald log driver";
-        warn!("{msg}");
-        eprintln!("{msg}");
+        warn_user("--no-container-partial-message has no effect without journald log driver");
     }
 
     Ok(entries)
 }
 
+/// Parses the log driver and its path from the `--log-path` or
+/// `--log-fallback` value without the stream prefix.
+fn parse_log_driver(s: &str) -> ConmonResult<(String, PathBuf)> {
+    let mut plugin: String = "file".into();
+    let mut path = PathBuf::new();
+
+    if let Some((plug, path_str)) = s.split_once(':') {
+        let path_str = path_str.trim();
+        if !path_str.is_empty() {
+            path = path_str.into();
+        }
+        let plug = plug.trim();
+        if !plug.is_empty() {
+            plugin = plug.replace("-", "_");
+        }
+        // Reject the unknown driver before anything is started, like conmon-v2.
+        if !is_log_driver(&plugin) {
+            return Err(ConmonError::new(format!("No such log driver {plug}"), 1));
+        }
+    } else if matches!(s, "journald" | "loki" | "gelf" | "splunk") {
+        plugin = s.to_string();
+    } else if s == "passthrough" {
+        plugin = "passthrough".to_string();
+    } else if s == "none" || s == "null" || s == "off" {
+        // Bare driver names (no ':') must not be treated as file paths.
+        // Matches conmon-v2: `--log-path none` disables logging.
+        plugin = s.to_string();
+    } else if !s.is_empty() {
+        path = s.to_string().into();
+    }
+    Ok((plugin, path))
+}
+
+/// Returns true if the log driver `name` writes into a log file.
+fn log_file_driver(name: &str) -> bool {
+    matches!(name, "file" | "k8s_file" | "json_file" | "json_lines")
+}
+
+/// Returns the paths of the log files written by the file-based log plugins.
+pub fn log_file_paths(entries: &[(String, LogPluginCfg)]) -> Vec<PathBuf> {
+    entries
+        .iter()
+        .filter(|(name, _)| log_file_driver(name))
+        .map(|(_, cfg)| cfg.path.clone())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
     use tempfile::NamedTempFile;
 
     /// Create a temp file with the given mode.
@@ -660,6 +1279,226 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn adopt_selects_adopt_cmd() -> ConmonResult<()> {
+        let runtime = make_temp_file_with_mode(0o700);
+        let o = Opts {
+            adopt: true,
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            persist_dir: Some(PathBuf::from("/run/persist")),
+            ..Default::default()
+        };
+        match determine_cmd(o, false)? {
+            Cmd::Adopt(cfg) => {
+                assert_eq!(cfg.common.persist_dir, Some(PathBuf::from("/run/persist")))
+            }
+            _ => panic!("expected Adopt"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn create_timeout_overrides_runtime_timeout() -> ConmonResult<()> {
+        let runtime = make_temp_file_with_mode(0o700);
+        let opts = |exec: bool| Opts {
+            exec,
+            runtime_timeout: Some(60),
+            create_timeout: Some(10),
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        match determine_cmd(opts(false), false)? {
+            Cmd::Create(cfg) => assert_eq!(cfg.common.runtime_timeout, Some(10)),
+            _ => panic!("expected Create"),
+        }
+        let err = determine_cmd(opts(true), false).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("--create-timeout can only be used")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn start_only_with_create() -> ConmonResult<()> {
+        let runtime = make_temp_file_with_mode(0o700);
+        let o = Opts {
+            start: true,
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        match determine_cmd(o, false)? {
+            Cmd::Create(cfg) => assert!(cfg.start),
+            _ => panic!("expected Create"),
+        }
+
+        let o = Opts {
+            start: true,
+            exec: true,
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        let err = determine_cmd(o, false).unwrap_err();
+        assert!(err.to_string().contains("--start can only be used"));
+        Ok(())
+    }
+
+    #[test]
+    fn exec_stdin_only_with_exec() -> ConmonResult<()> {
+        let runtime = make_temp_file_with_mode(0o700);
+        let o = Opts {
+            exec: true,
+            exec_process_spec: Some("/tmp/spec.json".into()),
+            exec_stdin_fd: Some(5),
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        match determine_cmd(o, false)? {
+            Cmd::Exec(cfg) => assert_eq!(cfg.common.exec_stdin, Some(ExecStdin::Fd(5))),
+            _ => panic!("expected Exec"),
+        }
+
+        let o = Opts {
+            exec_stdin_path: Some("/tmp/script.sh".into()),
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        let err = determine_cmd(o, false).unwrap_err();
+        assert!(err.to_string().contains("can only be used with exec"));
+
+        let o = Opts {
+            exec: true,
+            stdin: true,
+            exec_stdin_path: Some("/tmp/script.sh".into()),
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        let err = determine_cmd(o, false).unwrap_err();
+        assert!(err.to_string().contains("cannot be used with --stdin"));
+        Ok(())
+    }
+
+    #[test]
+    fn fork_mode_follows_sync_and_foreground() -> ConmonResult<()> {
+        let runtime = make_temp_file_with_mode(0o700);
+        let opts = |fork_mode: Option<&str>, sync_flag: bool, foreground: bool| Opts {
+            fork_mode: fork_mode.map(String::from),
+            sync_flag,
+            foreground,
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        let mode = |o| match determine_cmd(o, false) {
+            Ok(Cmd::Create(cfg)) => Ok(cfg.common.fork_mode),
+            Ok(_) => panic!("expected Create"),
+            Err(e) => Err(e),
+        };
+        assert_eq!(mode(opts(None, false, false))?, ForkMode::Daemon);
+        assert_eq!(mode(opts(None, true, false))?, ForkMode::Child);
+        assert_eq!(mode(opts(None, false, true))?, ForkMode::None);
+        assert_eq!(mode(opts(Some("none"), false, false))?, ForkMode::None);
+        assert!(mode(opts(Some("daemon"), true, false)).is_err());
+        assert!(mode(opts(Some("child"), false, true)).is_err());
+        assert!(mode(opts(Some("fork"), false, false)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn heartbeat_interval_requires_file() -> ConmonResult<()> {
+        let runtime = make_temp_file_with_mode(0o700);
+        let o = Opts {
+            heartbeat_interval: Some(3),
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        let err = determine_cmd(o, false).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("--heartbeat-interval requires --heartbeat-file")
+        );
+
+        let o = Opts {
+            heartbeat_file: Some("/run/hb".into()),
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        match determine_cmd(o, false)? {
+            Cmd::Create(cfg) => {
+                assert_eq!(cfg.common.heartbeat_interval, DEFAULT_HEARTBEAT_INTERVAL)
+            }
+            _ => panic!("expected Create"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn record_session_requires_persist_dir() {
+        let runtime = make_temp_file_with_mode(0o700);
+        let o = Opts {
+            record_session: true,
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        let err = determine_cmd(o, false).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("--record-session requires --persist-dir")
+        );
+    }
+
+    #[test]
+    fn attach_audit_requires_persist_dir() {
+        let runtime = make_temp_file_with_mode(0o700);
+        let o = Opts {
+            attach_audit: true,
+            cid: Some("abc".into()),
+            cuuid: Some("u1".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        let err = determine_cmd(o, false).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("--attach-audit requires --persist-dir")
+        );
+    }
+
+    #[test]
+    fn adopt_with_exec_errors() {
+        let runtime = make_temp_file_with_mode(0o700);
+        let o = Opts {
+            adopt: true,
+            exec: true,
+            cid: Some("abc".into()),
+            runtime: Some(runtime.path().to_path_buf()),
+            ..Default::default()
+        };
+        let err = determine_cmd(o, false).unwrap_err();
+        assert!(err.to_string().contains("Cannot use 'adopt'"));
+    }
+
     #[test]
     fn exec_success_with_spec_and_attach_new_api() -> ConmonResult<()> {
         let runtime = make_temp_file_with_mode(0o700);
@@ -845,6 +1684,45 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn conmon_v2_driver_prefixes_are_parsed() -> ConmonResult<()> {
+        let o = Opts {
+            cid: Some("0123456789abcdef".into()),
+            log_path: vec![
+                PathBuf::from("journald:"),
+                PathBuf::from("k8s-file:/var/log/k8s.log"),
+                PathBuf::from("none:"),
+            ],
+            ..Default::default()
+        };
+
+        let entries = determine_log_plugin(&o)?;
+        let parsed: Vec<(&str, &Path)> = entries
+            .iter()
+            .map(|(name, cfg)| (name.as_str(), cfg.path.as_path()))
+            .collect();
+        assert_eq!(
+            parsed,
+            vec![
+                ("journald", Path::new("")),
+                ("k8s_file", Path::new("/var/log/k8s.log")),
+                ("none", Path::new("")),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_driver_prefix_is_rejected() {
+        let o = Opts {
+            log_path: vec![PathBuf::from("fluentd:localhost:24224")],
+            ..Default::default()
+        };
+
+        let err = determine_log_plugin(&o).unwrap_err();
+        assert_eq!(err.msg, "No such log driver fluentd");
+    }
+
     #[test]
     fn empty_plugin_part_does_not_change_plugin_but_sets_path() -> ConmonResult<()> {
         // Starts with default "file" plugin, but entry has empty plugin name.
@@ -861,6 +1739,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn log_line_max_must_be_positive() {
+        let o = Opts {
+            log_path: vec![PathBuf::from("/var/log/my.log")],
+            log_line_max: Some(0),
+            ..Default::default()
+        };
+        let err = determine_log_plugin(&o).unwrap_err();
+        assert_eq!(err.msg, "log-line-max must be positive");
+    }
+
+    #[test]
+    fn log_rate_burst_requires_rate_limit() -> ConmonResult<()> {
+        let mut o = Opts {
+            log_path: vec![PathBuf::from("/var/log/my.log")],
+            log_rate_burst: Some(1024),
+            ..Default::default()
+        };
+        let err = determine_log_plugin(&o).unwrap_err();
+        assert_eq!(err.msg, "--log-rate-burst requires --log-rate-limit");
+
+        o.log_rate_limit = Some(512);
+        let entries = determine_log_plugin(&o)?;
+        assert_eq!(entries[0].1.rate_limit, 512);
+        assert_eq!(entries[0].1.rate_burst, 1024);
+        Ok(())
+    }
+
     #[test]
     fn log_max_files_negative_is_rejected() {
         let o = Opts {
@@ -920,6 +1826,342 @@ mod tests {
         assert_eq!(entries[0].1.path, PathBuf::from("/var/log/a.log"));
         assert_eq!(entries[1].0, "journald");
         assert_eq!(entries[1].1.path, PathBuf::new());
+        assert_eq!(
+            log_file_paths(&entries),
+            vec![PathBuf::from("/var/log/a.log")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stream_prefixes_route_log_paths() -> ConmonResult<()> {
+        let o = Opts {
+            log_path: vec![
+                PathBuf::from("stdout=k8s-file:/var/log/out.log"),
+                PathBuf::from("stderr=journald"),
+                PathBuf::from("/var/log/all.log"),
+            ],
+            cid: Some("cid1234567890".into()),
+            cuuid: Some("cuuid".into()),
+            ..Default::default()
+        };
+
+        let entries = determine_log_plugin(&o)?;
+        assert_eq!(entries[0].0, "k8s_file");
+        assert_eq!(entries[0].1.path, PathBuf::from("/var/log/out.log"));
+        assert_eq!(entries[0].1.streams, LogStreams::Stdout);
+        assert_eq!(entries[1].0, "journald");
+        assert_eq!(entries[1].1.streams, LogStreams::Stderr);
+        assert_eq!(entries[2].0, "file");
+        assert_eq!(entries[2].1.streams, LogStreams::All);
+        Ok(())
+    }
+
+    #[test]
+    fn log_stdin_extends_all_streams() -> ConmonResult<()> {
+        let o = Opts {
+            log_path: vec![
+                PathBuf::from("stdout=/var/log/out.log"),
+                PathBuf::from("/var/log/all.log"),
+            ],
+            log_stdin: true,
+            cid: Some("cid1234567890".into()),
+            cuuid: Some("cuuid".into()),
+            ..Default::default()
+        };
+
+        let entries = determine_log_plugin(&o)?;
+        assert_eq!(entries[0].1.streams, LogStreams::Stdout);
+        assert_eq!(entries[1].1.streams, LogStreams::AllWithStdin);
+        Ok(())
+    }
+
+    #[test]
+    fn log_fallbacks_are_parsed() -> ConmonResult<()> {
+        let o = Opts {
+            log_path: vec![PathBuf::from("journald:")],
+            log_fallback: vec!["k8s-file:/var/log/fallback.log".into(), "none".into()],
+            log_fallback_after: Some(3),
+            cid: Some("cid1234567890".into()),
+            ..Default::default()
+        };
+        let entries = determine_log_plugin(&o)?;
+        assert_eq!(
+            entries[0].1.fallbacks,
+            vec![
+                (
+                    "k8s_file".to_string(),
+                    PathBuf::from("/var/log/fallback.log")
+                ),
+                ("none".to_string(), PathBuf::new()),
+            ]
+        );
+        assert_eq!(entries[0].1.fallback_after, 3);
+
+        for (fallback, after, err) in [
+            ("bogus:/x", None, "No such log driver bogus"),
+            ("passthrough", None, "cannot be used as --log-fallback"),
+            ("json-file:", None, "requires a filename"),
+            ("none", Some(0), "log-fallback-after must be positive"),
+        ] {
+            let o = Opts {
+                log_path: vec![PathBuf::from("/var/log/a.log")],
+                log_fallback: vec![fallback.into()],
+                log_fallback_after: after,
+                ..Default::default()
+            };
+            let e = determine_log_plugin(&o).unwrap_err();
+            assert!(e.to_string().contains(err), "{fallback}: {e}");
+        }
+
+        let o = Opts {
+            log_path: vec![PathBuf::from("/var/log/a.log")],
+            log_fallback_after: Some(2),
+            ..Default::default()
+        };
+        assert!(determine_log_plugin(&o).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn log_writer_thread_is_passed_to_all_drivers() -> ConmonResult<()> {
+        let o = Opts::try_parse_from([
+            "conmon",
+            "--log-path",
+            "/var/log/a.log",
+            "--log-path",
+            "journald:",
+            "--log-writer-thread",
+        ])
+        .unwrap();
+        let entries = determine_log_plugin(&o)?;
+        assert!(entries.iter().all(|(_, cfg)| cfg.writer_thread));
+        assert_eq!(entries[0].1.writer_policy, None);
+
+        let o = Opts::try_parse_from([
+            "conmon",
+            "--log-path",
+            "/var/log/a.log",
+            "--log-writer-thread",
+            "--log-writer-policy",
+            "drop-oldest",
+        ])
+        .unwrap();
+        let entries = determine_log_plugin(&o)?;
+        assert_eq!(entries[0].1.writer_policy, Some(DeliveryPolicy::DropOldest));
+
+        for args in [
+            vec!["--log-writer-policy", "block"],
+            vec!["--log-writer-thread", "--log-writer-policy", "drop"],
+        ] {
+            let mut argv = vec!["conmon", "--log-path", "/var/log/a.log"];
+            argv.extend(args);
+            let o = Opts::try_parse_from(argv).unwrap();
+            assert!(determine_log_plugin(&o).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn timeout_and_stop_signals_are_parsed() {
+        let o = Opts::try_parse_from(["conmon"]).unwrap();
+        assert_eq!(
+            (o.timeout_signal, o.timeout_grace, o.stop_signal),
+            (None, DEFAULT_STOP_TIMEOUT, None)
+        );
+
+        let o = Opts::try_parse_from([
+            "conmon",
+            "--timeout-signal",
+            "TERM",
+            "--timeout-grace",
+            "30",
+            "--stop-signal",
+            "2",
+        ])
+        .unwrap();
+        assert_eq!(o.timeout_signal, Some(Signal::SIGTERM));
+        assert_eq!(o.timeout_grace, 30);
+        assert_eq!(o.stop_signal, Some(Signal::SIGINT));
+        assert!(Opts::try_parse_from(["conmon", "--timeout-signal", "NOPE"]).is_err());
+    }
+
+    #[test]
+    fn attach_socket_owner_and_mode_are_parsed() {
+        assert_eq!(parse_socket_owner("1000"), Ok((1000, None)));
+        assert_eq!(parse_socket_owner("1000:100"), Ok((1000, Some(100))));
+        assert!(parse_socket_owner("user").is_err());
+        assert!(parse_socket_owner("1000:").is_err());
+
+        assert_eq!(parse_socket_mode("0660"), Ok(0o660));
+        assert_eq!(parse_socket_mode("770"), Ok(0o770));
+        assert!(parse_socket_mode("0890").is_err());
+        assert!(parse_socket_mode("1777").is_err());
+    }
+
+    #[test]
+    fn attach_vsock_address_is_parsed() {
+        assert_eq!(parse_vsock_addr("3:1024"), Ok((3, 1024)));
+        assert_eq!(
+            parse_vsock_addr("any:5000"),
+            Ok((nix::libc::VMADDR_CID_ANY, 5000))
+        );
+        for bad in ["3", "x:1", "3:port", "-1:1"] {
+            assert!(parse_vsock_addr(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn log_redact_patterns_are_parsed() -> ConmonResult<()> {
+        let mut rules = NamedTempFile::new()?;
+        writeln!(rules, "# keys\napi_key")?;
+        let o = Opts::try_parse_from([
+            "conmon".as_ref(),
+            "--log-path".as_ref(),
+            "/var/log/a.log".as_ref(),
+            "--log-redact-pattern".as_ref(),
+            "password".as_ref(),
+            "--log-redact-rules".as_ref(),
+            rules.path().as_os_str(),
+        ])
+        .unwrap();
+        let entries = determine_log_plugin(&o)?;
+        assert_eq!(entries[0].1.redact_patterns, vec!["password", "api_key"]);
+
+        let o = Opts::try_parse_from([
+            "conmon",
+            "--log-path",
+            "passthrough",
+            "--log-redact-pattern",
+            "password",
+        ])
+        .unwrap();
+        assert!(determine_log_plugin(&o).is_err());
+
+        let o = Opts::try_parse_from([
+            "conmon",
+            "--log-path",
+            "/var/log/a.log",
+            "--log-redact-pattern",
+            "",
+        ])
+        .unwrap();
+        assert!(determine_log_plugin(&o).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn log_dedup_and_sample_are_parsed() -> ConmonResult<()> {
+        let o = Opts::try_parse_from([
+            "conmon",
+            "--log-path",
+            "/var/log/a.log",
+            "--log-dedup",
+            "--log-sample",
+            "10",
+        ])
+        .unwrap();
+        let entries = determine_log_plugin(&o)?;
+        assert!(entries[0].1.dedup);
+        assert_eq!(entries[0].1.sample, 10);
+
+        assert!(Opts::try_parse_from(["conmon", "--log-sample", "0"]).is_err());
+        let o =
+            Opts::try_parse_from(["conmon", "--log-path", "passthrough", "--log-dedup"]).unwrap();
+        assert!(determine_log_plugin(&o).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn maintenance_subcommands_are_parsed() {
+        let o = Opts::try_parse_from(["conmon", "gc", "--socket-dir", "/run/sockets"]).unwrap();
+        assert_eq!(
+            o.command,
+            Some(MaintenanceCmd::Gc {
+                socket_dir: PathBuf::from("/run/sockets")
+            })
+        );
+        assert!(Opts::try_parse_from(["conmon", "gc"]).is_err());
+
+        let o = Opts::try_parse_from(["conmon", "ps", "--persist-root", "/run/persist"]).unwrap();
+        assert_eq!(
+            o.command,
+            Some(MaintenanceCmd::Ps {
+                persist_root: PathBuf::from("/run/persist")
+            })
+        );
+    }
+
+    #[test]
+    fn log_opts_are_parsed() -> ConmonResult<()> {
+        let o = Opts {
+            log_path: vec![PathBuf::from("s3:http://minio:9000/logs")],
+            log_opt: vec!["s3-region=eu-west-1".into(), "empty=".into()],
+            ..Default::default()
+        };
+        let entries = determine_log_plugin(&o)?;
+        assert_eq!(entries[0].0, "s3");
+        assert_eq!(entries[0].1.path, PathBuf::from("http://minio:9000/logs"));
+        assert_eq!(entries[0].1.log_opt("s3-region"), Some("eu-west-1"));
+        assert_eq!(entries[0].1.log_opt("empty"), Some(""));
+
+        let o = Opts {
+            log_path: vec![PathBuf::from("loki")],
+            log_opt: vec!["url=http://loki:3100".into()],
+            ..Default::default()
+        };
+        let entries = determine_log_plugin(&o)?;
+        assert_eq!(entries[0].0, "loki");
+        assert_eq!(entries[0].1.path, PathBuf::new());
+
+        let o = Opts {
+            log_path: vec![PathBuf::from("/var/log/my.log")],
+            log_opt: vec!["novalue".into()],
+            ..Default::default()
+        };
+        let err = determine_log_plugin(&o).unwrap_err();
+        assert!(err.to_string().contains("KEY=VALUE"));
+
+        let o = Opts {
+            log_path: vec![PathBuf::from("/var/log/my.log")],
+            log_opt: vec!["bad key=1".into()],
+            ..Default::default()
+        };
+        let err = determine_log_plugin(&o).unwrap_err();
+        assert!(err.to_string().contains("Invalid log option key"));
+        Ok(())
+    }
+
+    #[test]
+    fn delivery_policies_per_driver() -> ConmonResult<()> {
+        let o = Opts {
+            log_path: vec![
+                PathBuf::from("journald"),
+                PathBuf::from("loki"),
+                PathBuf::from("/var/log/my.log"),
+            ],
+            log_delivery_policy: vec!["drop-newest".into(), "loki=block".into()],
+            cid: Some("cid1234567890".into()),
+            ..Default::default()
+        };
+        let entries = determine_log_plugin(&o)?;
+        assert_eq!(
+            entries[0].1.delivery_policy,
+            Some(DeliveryPolicy::DropNewest)
+        );
+        assert_eq!(entries[1].1.delivery_policy, Some(DeliveryPolicy::Block));
+        assert_eq!(
+            entries[2].1.delivery_policy,
+            Some(DeliveryPolicy::DropNewest)
+        );
+
+        let o = Opts {
+            log_path: vec![PathBuf::from("journald")],
+            log_delivery_policy: vec!["journald=sometimes".into()],
+            ..Default::default()
+        };
+        let err = determine_log_plugin(&o).unwrap_err();
+        assert!(err.to_string().contains("Invalid delivery policy"));
         Ok(())
     }
 
@@ -0,0 +1,214 @@
+use std::os::fd::{AsFd, OwnedFd};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+
+use crate::cli::AdoptCfg;
+use crate::error::{ConmonError, ConmonResult};
+use crate::exit::set_exit_status_unknown;
+use crate::runtime::process::{open_pidfd, process_alive, process_start_time};
+use crate::runtime::state::SessionState;
+
+/// How often we check whether the adopted container is still running, if
+/// the kernel does not support pidfds.
+const ADOPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Returns true if the process with the PID of the container is the one
+/// recorded in `state`, not another process which reused the PID.
+fn is_container(state: &SessionState) -> bool {
+    process_alive(state.container_pid)
+        && (state.container_start_time.is_none()
+            || process_start_time(state.container_pid) == state.container_start_time)
+}
+
+/// Opens the pidfd of the adopted container.
+///
+/// # Returns
+///
+/// * The pidfd, or None if the kernel does not support pidfds or the
+///   container has exited.
+fn open_container_pidfd(state: &SessionState) -> Option<OwnedFd> {
+    let pidfd = open_pidfd(state.container_pid)?;
+    // The pidfd refers to whatever process has the PID now, so check it is
+    // still the container once the pidfd pins the process.
+    is_container(state).then_some(pidfd)
+}
+
+/// Blocks until the process referred to by `pidfd` exits.
+fn wait_pidfd(pidfd: &OwnedFd) -> ConmonResult<()> {
+    loop {
+        match poll(
+            &mut [PollFd::new(pidfd.as_fd(), PollFlags::POLLIN)],
+            PollTimeout::NONE,
+        ) {
+            Ok(_) => return Ok(()),
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                return Err(ConmonError::new(
+                    format!("Failed to wait for the container: {e}"),
+                    1,
+                ));
+            }
+        }
+    }
+}
+
+/// Returns the exit status reported once the adopted container has exited.
+/// The real one is unknown, so -1 is written into the exit files.
+fn unknown_exit_status(state: &SessionState) -> i32 {
+    warn!(
+        "Exit status of the adopted container process {} is unknown",
+        state.container_pid
+    );
+    set_exit_status_unknown();
+    -1
+}
+
+/// Re-adopts a still-running container whose conmon has been killed.
+///
+/// The container's stdio pipes died together with the original conmon, so
+/// only the container lifetime is supervised. Its exit status cannot be
+/// collected, so the exit files are written with -1 once it exits.
+pub struct Adopt {
+    cfg: AdoptCfg,
+}
+
+impl Adopt {
+    pub fn new(cfg: AdoptCfg) -> Self {
+        Self { cfg }
+    }
+
+    pub fn exec(&self) -> ConmonResult<i32> {
+        let common = &self.cfg.common;
+        let persist_dir = common
+            .persist_dir
+            .as_ref()
+            .ok_or_else(|| ConmonError::new("--adopt requires --persist-dir", 1))?;
+
+        let mut state = SessionState::load(persist_dir)?;
+        if state.cid != common.cid {
+            return Err(ConmonError::new(
+                format!(
+                    "State file belongs to container {}, not {}",
+                    state.cid, common.cid
+                ),
+                1,
+            ));
+        }
+
+        let our_pid = std::process::id() as i32;
+        if state.conmon_pid != our_pid && process_alive(state.conmon_pid) {
+            return Err(ConmonError::new(
+                format!(
+                    "Container {} is still supervised by conmon {}",
+                    common.cid, state.conmon_pid
+                ),
+                1,
+            ));
+        }
+
+        let pidfd = open_container_pidfd(&state);
+        if pidfd.is_none() && !is_container(&state) {
+            info!(
+                "Container process {} has already exited",
+                state.container_pid
+            );
+            SessionState::remove(persist_dir);
+            return Ok(unknown_exit_status(&state));
+        }
+
+        // Take over the supervision.
+        info!(
+            "Adopting container {} with PID {}",
+            common.cid, state.container_pid
+        );
+        state.conmon_pid = our_pid;
+        state.save(persist_dir)?;
+        if let Some(pidfile) = &common.conmon_pidfile {
+            std::fs::write(pidfile, our_pid.to_string())?;
+        }
+
+        match &pidfd {
+            Some(pidfd) => wait_pidfd(pidfd)?,
+            None => {
+                while is_container(&state) {
+                    thread::sleep(ADOPT_POLL_INTERVAL);
+                }
+            }
+        }
+        info!(
+            "Adopted container process {} has exited",
+            state.container_pid
+        );
+        SessionState::remove(persist_dir);
+
+        // The container is not our child, so we cannot get real exit status.
+        Ok(unknown_exit_status(&state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::CommonCfg;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    fn mk_adopt_cfg(cid: &str, persist_dir: Option<PathBuf>) -> AdoptCfg {
+        AdoptCfg {
+            common: CommonCfg {
+                cid: cid.to_string(),
+                persist_dir,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn adopt_requires_persist_dir() {
+        let err = Adopt::new(mk_adopt_cfg("abc", None)).exec().unwrap_err();
+        assert!(err.to_string().contains("requires --persist-dir"));
+    }
+
+    #[test]
+    fn adopt_rejects_foreign_state() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        SessionState::new("other", 0, 0).save(tmp.path())?;
+        let err = Adopt::new(mk_adopt_cfg("abc", Some(tmp.path().into())))
+            .exec()
+            .unwrap_err();
+        assert!(err.to_string().contains("belongs to container other"));
+        Ok(())
+    }
+
+    #[test]
+    fn reused_pid_is_not_the_container() -> ConmonResult<()> {
+        let pid = std::process::id() as i32;
+        let mut state = SessionState::new("abc", 0, pid);
+        assert!(is_container(&state));
+        assert_eq!(
+            open_container_pidfd(&state).is_some(),
+            open_pidfd(pid).is_some()
+        );
+
+        state.container_start_time = state.container_start_time.map(|t| t + 1);
+        assert!(!is_container(&state));
+        assert!(open_container_pidfd(&state).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn adopt_rejects_live_conmon() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        // PID 1 always exists.
+        SessionState::new("abc", 1, 1).save(tmp.path())?;
+        let err = Adopt::new(mk_adopt_cfg("abc", Some(tmp.path().into())))
+            .exec()
+            .unwrap_err();
+        assert!(err.to_string().contains("still supervised"));
+        Ok(())
+    }
+}
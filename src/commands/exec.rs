@@ -19,23 +19,28 @@ impl Exec {
         open_files: &OpenFilesSnapshot,
     ) -> ConmonResult<i32> {
         let mut runtime_session = crate::runtime::session::RuntimeSession::new(open_files.clone());
-        runtime_session.launch(&self.cfg.common, self, self.cfg.attach)?;
-
-        // ===
-        // Now, after the `launch`, we are in the child process of our original process
-        // (See `RuntimeProcess::spawn` code and description for more information).
-        // ===
-
-        // In case of `--terminal`, wait until runtime creates the console socket.
-        if self.cfg.common.terminal {
-            runtime_session.wait_for_terminal_creation()?;
+        // After the live upgrade re-exec, the container is already running and
+        // the session is resumed from the handover instead.
+        if !runtime_session.resume(&self.cfg.common)? {
+            runtime_session.launch(&self.cfg.common, self, self.cfg.attach)?;
+
+            // ===
+            // Now, after the `launch()`, we are in the child process of our original process,
+            // unless `--sync` is used, because `launch()` forks into the background.
+            // (See `process::detach` code and description for more information).
+            // ===
+
+            // In case of `--terminal`, wait until runtime creates the console socket.
+            if self.cfg.common.terminal {
+                runtime_session.wait_for_terminal_creation()?;
+            }
+
+            // Wait until the `runtime create` finishes and return an error in case it fails.
+            runtime_session.wait_for_success(self.cfg.common.api_version, true)?;
+
+            runtime_session.write_container_pid_file(&self.cfg.common, false)?;
         }
 
-        // Wait until the `runtime create` finishes and return an error in case it fails.
-        runtime_session.wait_for_success(self.cfg.common.api_version, true)?;
-
-        runtime_session.write_container_pid_file(&self.cfg.common)?;
-
         // Run the eventloop to forward log messages to log plugin.
         runtime_session.run_event_loop(
             log_plugin,
@@ -0,0 +1,185 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::error::{ConmonError, ConmonResult};
+use crate::runtime::process::process_alive;
+
+/// Name of the conmon pidfile Podman stores in the bundle by default.
+const CONMON_PIDFILE_NAME: &str = "conmon.pid";
+
+/// Name of the attach socket created in the bundle.
+const ATTACH_SOCKET_NAME: &str = "attach";
+
+/// Removes the attach sockets and `--socket-dir-path` symlinks left behind by
+/// crashed conmon processes.
+///
+/// Every `CUUID` symlink in the socket directory points to the container
+/// bundle. The entry is stale when the bundle is gone or when the conmon PID
+/// stored in the bundle's `conmon.pid` no longer exists. Entries without
+/// a readable pidfile are kept, because their owner cannot be determined.
+pub struct Gc {
+    socket_dir: PathBuf,
+}
+
+/// Returns the PID stored in the conmon pidfile in `bundle`.
+fn owner_pid(bundle: &Path) -> Option<i32> {
+    fs::read_to_string(bundle.join(CONMON_PIDFILE_NAME))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Removes `path` unless it is already gone.
+fn remove(path: &Path) -> ConmonResult<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(ConmonError::new(
+            format!("Failed to remove {}: {e}", path.display()),
+            1,
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl Gc {
+    pub fn new(socket_dir: PathBuf) -> Self {
+        Self { socket_dir }
+    }
+
+    /// Removes the stale entry `link` pointing to `bundle` together with the
+    /// attach socket in the bundle.
+    fn remove_entry(&self, link: &Path, bundle: &Path) -> ConmonResult<()> {
+        let attach = bundle.join(ATTACH_SOCKET_NAME);
+        if fs::symlink_metadata(&attach).is_ok_and(|md| md.file_type().is_socket()) {
+            remove(&attach)?;
+            info!("Removed stale attach socket {}", attach.display());
+        }
+        remove(link)?;
+        info!("Removed stale socket symlink {}", link.display());
+        Ok(())
+    }
+
+    /// Collects the stale entries in the socket directory.
+    ///
+    /// # Returns
+    ///
+    /// * The removed symlinks.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the socket directory cannot be read. Failures to
+    ///   remove single entries are only logged.
+    pub fn collect(&self) -> ConmonResult<Vec<PathBuf>> {
+        let entries = fs::read_dir(&self.socket_dir).map_err(|e| {
+            ConmonError::new(
+                format!(
+                    "Failed to read socket directory {}: {e}",
+                    self.socket_dir.display()
+                ),
+                1,
+            )
+        })?;
+
+        let mut removed = Vec::new();
+        for entry in entries.flatten() {
+            let link = entry.path();
+            if !entry.file_type().is_ok_and(|t| t.is_symlink()) {
+                continue;
+            }
+            let Ok(target) = fs::read_link(&link) else {
+                continue;
+            };
+            let bundle = self.socket_dir.join(target);
+            if bundle.is_dir() {
+                match owner_pid(&bundle) {
+                    Some(pid) if process_alive(pid) => continue,
+                    Some(pid) => info!("Owner conmon {pid} of {} is gone", link.display()),
+                    None => {
+                        info!("Skipping {}: owner is unknown", link.display());
+                        continue;
+                    }
+                }
+            }
+            match self.remove_entry(&link, &bundle) {
+                Ok(()) => removed.push(link),
+                Err(e) => warn!("{}", e.msg),
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn exec(&self) -> ConmonResult<i32> {
+        for link in self.collect()? {
+            println!("{}", link.display());
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::net::UnixListener;
+    use std::process::Command;
+    use tempfile::tempdir;
+
+    /// Creates a bundle with attach socket owned by conmon `pid` and links it
+    /// from `socket_dir` as `cuuid`.
+    fn bundle(root: &Path, cuuid: &str, pid: Option<i32>) -> ConmonResult<PathBuf> {
+        let bundle = root.join(format!("bundle-{cuuid}"));
+        fs::create_dir(&bundle)?;
+        drop(UnixListener::bind(bundle.join(ATTACH_SOCKET_NAME))?);
+        if let Some(pid) = pid {
+            fs::write(bundle.join(CONMON_PIDFILE_NAME), pid.to_string())?;
+        }
+        symlink(&bundle, root.join("sockets").join(cuuid))?;
+        Ok(bundle)
+    }
+
+    #[test]
+    fn collect_removes_entries_of_dead_conmon() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let socket_dir = tmp.path().join("sockets");
+        fs::create_dir(&socket_dir)?;
+
+        let mut child = Command::new("true").spawn()?;
+        let dead_pid = child.id() as i32;
+        child.wait()?;
+
+        let alive = bundle(tmp.path(), "alive", Some(std::process::id() as i32))?;
+        let dead = bundle(tmp.path(), "dead", Some(dead_pid))?;
+        let unknown = bundle(tmp.path(), "unknown", None)?;
+        let gone = bundle(tmp.path(), "gone", None)?;
+        fs::remove_dir_all(&gone)?;
+        fs::write(socket_dir.join("regular"), "")?;
+
+        let mut removed = Gc::new(socket_dir.clone()).collect()?;
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![socket_dir.join("dead"), socket_dir.join("gone")]
+        );
+
+        assert!(!dead.join(ATTACH_SOCKET_NAME).exists());
+        assert!(dead.join(CONMON_PIDFILE_NAME).exists());
+        assert!(alive.join(ATTACH_SOCKET_NAME).exists());
+        assert!(unknown.join(ATTACH_SOCKET_NAME).exists());
+        assert!(socket_dir.join("alive").exists());
+        assert!(socket_dir.join("unknown").exists());
+        assert!(socket_dir.join("regular").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn collect_fails_on_missing_socket_dir() {
+        let err = Gc::new("/nonexistent/sockets".into())
+            .collect()
+            .unwrap_err();
+        assert!(err.msg.contains("/nonexistent/sockets"), "{}", err.msg);
+    }
+}
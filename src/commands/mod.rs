@@ -1,4 +1,7 @@
+pub mod adopt;
 pub mod create;
 pub mod exec;
+pub mod gc;
+pub mod ps;
 pub mod restore;
 pub mod version;
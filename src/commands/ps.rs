@@ -0,0 +1,190 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::error::{ConmonError, ConmonResult};
+use crate::runtime::process::process_alive;
+use crate::runtime::state::{STATE_FILE_NAME, SessionState};
+
+/// Supervision status of a container found in a persist dir.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupervisionStatus {
+    /// Both conmon and the container are running.
+    Running,
+    /// The container runs, but its conmon is gone. It can be re-adopted using
+    /// `--adopt`.
+    Orphaned,
+    /// The container has exited and its exit file has been written.
+    Exited,
+    /// The container has exited, but conmon still runs.
+    Exiting,
+    /// Both conmon and the container are gone without the exit file.
+    Lost,
+}
+
+impl SupervisionStatus {
+    /// Returns the status name shown in the listing.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SupervisionStatus::Running => "running",
+            SupervisionStatus::Orphaned => "orphaned",
+            SupervisionStatus::Exited => "exited",
+            SupervisionStatus::Exiting => "exiting",
+            SupervisionStatus::Lost => "lost",
+        }
+    }
+}
+
+/// Single container in the listing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SupervisedContainer {
+    /// The persist dir the container has been found in.
+    pub persist_dir: PathBuf,
+
+    /// The saved supervision state.
+    pub state: SessionState,
+
+    /// The status derived from the state.
+    pub status: SupervisionStatus,
+}
+
+impl SupervisedContainer {
+    /// Loads the container from `persist_dir` and checks its processes.
+    fn load(persist_dir: &Path) -> ConmonResult<Self> {
+        let state = SessionState::load(persist_dir)?;
+        let status = match (
+            process_alive(state.conmon_pid),
+            process_alive(state.container_pid),
+        ) {
+            (true, true) => SupervisionStatus::Running,
+            (false, true) => SupervisionStatus::Orphaned,
+            (true, false) => SupervisionStatus::Exiting,
+            (false, false) if persist_dir.join("exit").exists() => SupervisionStatus::Exited,
+            (false, false) => SupervisionStatus::Lost,
+        };
+        Ok(Self {
+            persist_dir: persist_dir.to_path_buf(),
+            state,
+            status,
+        })
+    }
+}
+
+/// Lists the containers supervised by conmon, so operators can reconcile the
+/// container engine state after crashes.
+///
+/// The containers are discovered from the state files in the persist dirs,
+/// which are the subdirectories of the persist root.
+pub struct Ps {
+    persist_root: PathBuf,
+}
+
+impl Ps {
+    pub fn new(persist_root: PathBuf) -> Self {
+        Self { persist_root }
+    }
+
+    /// Finds the containers in the persist root.
+    ///
+    /// # Returns
+    ///
+    /// * The containers sorted by the container ID.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the persist root cannot be read. Unreadable state
+    ///   files are skipped.
+    pub fn list(&self) -> ConmonResult<Vec<SupervisedContainer>> {
+        let entries = fs::read_dir(&self.persist_root).map_err(|e| {
+            ConmonError::new(
+                format!(
+                    "Failed to read persist root {}: {e}",
+                    self.persist_root.display()
+                ),
+                1,
+            )
+        })?;
+
+        let mut containers = Vec::new();
+        for entry in entries.flatten() {
+            let dir = entry.path();
+            if !dir.join(STATE_FILE_NAME).is_file() {
+                continue;
+            }
+            match SupervisedContainer::load(&dir) {
+                Ok(container) => containers.push(container),
+                Err(e) => debug!("Skipping {}: {}", dir.display(), e.msg),
+            }
+        }
+        containers.sort_by(|a, b| a.state.cid.cmp(&b.state.cid));
+        Ok(containers)
+    }
+
+    pub fn exec(&self) -> ConmonResult<i32> {
+        println!("CID\tCONMON PID\tCONTAINER PID\tSTATUS");
+        for c in self.list()? {
+            println!(
+                "{}\t{}\t{}\t{}",
+                c.state.cid,
+                c.state.conmon_pid,
+                c.state.container_pid,
+                c.status.name()
+            );
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::tempdir;
+
+    fn persist(root: &Path, cid: &str, conmon_pid: i32, container_pid: i32) -> PathBuf {
+        let dir = root.join(cid);
+        fs::create_dir(&dir).unwrap();
+        SessionState::new(cid, conmon_pid, container_pid)
+            .save(&dir)
+            .unwrap();
+        dir
+    }
+
+    #[test]
+    fn list_derives_status_from_processes() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let mut child = Command::new("true").spawn()?;
+        let dead = child.id() as i32;
+        child.wait()?;
+        let alive = std::process::id() as i32;
+
+        persist(tmp.path(), "running", alive, alive);
+        persist(tmp.path(), "orphaned", dead, alive);
+        persist(tmp.path(), "exiting", alive, dead);
+        let exited = persist(tmp.path(), "exited", dead, dead);
+        fs::write(exited.join("exit"), "0")?;
+        persist(tmp.path(), "lost", dead, dead);
+        fs::create_dir(tmp.path().join("no-state"))?;
+        let corrupt = tmp.path().join("corrupt");
+        fs::create_dir(&corrupt)?;
+        fs::write(corrupt.join(STATE_FILE_NAME), "{")?;
+
+        let listed: Vec<(String, SupervisionStatus)> = Ps::new(tmp.path().into())
+            .list()?
+            .into_iter()
+            .map(|c| (c.state.cid, c.status))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("exited".to_string(), SupervisionStatus::Exited),
+                ("exiting".to_string(), SupervisionStatus::Exiting),
+                ("lost".to_string(), SupervisionStatus::Lost),
+                ("orphaned".to_string(), SupervisionStatus::Orphaned),
+                ("running".to_string(), SupervisionStatus::Running),
+            ]
+        );
+        Ok(())
+    }
+}
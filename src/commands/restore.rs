@@ -3,6 +3,7 @@ use crate::error::ConmonResult;
 use crate::exit::OpenFilesSnapshot;
 use crate::logging::plugin::LogPlugin;
 use crate::runtime::args::RuntimeArgsGenerator;
+use crate::runtime::command::delete_container_on_error;
 
 pub struct Restore {
     cfg: RestoreCfg,
@@ -20,35 +21,42 @@ impl Restore {
     ) -> ConmonResult<i32> {
         // Start the `runtime create` session.
         let mut runtime_session = crate::runtime::session::RuntimeSession::new(open_files.clone());
-        runtime_session.launch(&self.cfg.common, self, false)?;
-
-        // ===
-        // Now, after the `launch()`, we are in the child process of our original process,
-        // because we double-fork in the RuntimeProcess::spawn.
-        // (See `RuntimeProcess::spawn` code and description for more information).
-        // ===
-
-        // In case of `--terminal`, wait until runtime creates the console socket.
-        if self.cfg.common.terminal {
-            runtime_session.wait_for_terminal_creation()?;
+        // After the live upgrade re-exec, the container is already running and
+        // the session is resumed from the handover instead.
+        if !runtime_session.resume(&self.cfg.common)? {
+            runtime_session.launch(&self.cfg.common, self, false)?;
+
+            // ===
+            // Now, after the `launch()`, we are in the child process of our original process,
+            // unless `--sync` is used, because `launch()` forks into the background.
+            // (See `process::detach` code and description for more information).
+            // ===
+
+            // In case of `--terminal`, wait until runtime creates the console socket.
+            if self.cfg.common.terminal {
+                runtime_session.wait_for_terminal_creation()?;
+            }
+
+            // Wait until the `runtime create` finishes and return an error in case it fails.
+            runtime_session.wait_for_success(self.cfg.common.api_version, false)?;
+
+            // Do not leak the restored container which is not supervised.
+            let result = runtime_session.write_container_pid_file(&self.cfg.common, true);
+            delete_container_on_error(&self.cfg.common, result)?;
         }
 
-        // Wait until the `runtime create` finishes and return an error in case it fails.
-        runtime_session.wait_for_success(self.cfg.common.api_version, false)?;
-
-        runtime_session.write_container_pid_file(&self.cfg.common)?;
-
         // ===
         // Now we wait for an external application like podman to really start the container.
         // and handle the containers stdio or its termination.
         // ===
 
         // Run the eventloop to forward log messages to log plugin.
-        runtime_session.run_event_loop(
+        let result = runtime_session.run_event_loop(
             log_plugin,
             self.cfg.common.leave_stdin_open,
             self.cfg.common.stdin,
-        )?;
+        );
+        delete_container_on_error(&self.cfg.common, result)?;
 
         // Wait for the `runtime exec` to finish and write its exit code.
         runtime_session.write_exit_code(self.cfg.common.api_version, false)?;
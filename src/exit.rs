@@ -1,15 +1,22 @@
 use crate::error::{ConmonError, ConmonResult};
+use crate::runtime::cgroup::oom_detected;
+use crate::runtime::limits::InheritedLimits;
 
+use chrono::{DateTime, SecondsFormat, Utc};
 use log::{error, info, warn};
 use nix::errno::Errno;
+use nix::sys::signal::Signal;
 use nix::sys::wait::waitpid;
 use nix::unistd::Pid;
 
+use serde_json::{Value, json};
 use std::os::fd::RawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
-use std::{fs, thread};
+use std::{fmt, fs, thread};
 
 use nix::libc::{PR_SET_CHILD_SUBREAPER, close, prctl};
 
@@ -92,9 +99,10 @@ pub fn run_exit_command(
             "Starting exit command: {:?} {:?}",
             program, exit_command_args
         );
-        let mut child = cmd
-            .spawn()
-            .map_err(|e| ConmonError::new(format!("Failed to spawn: {e}"), 1))?;
+        let inherited = InheritedLimits::restore();
+        let child = cmd.spawn();
+        drop(inherited);
+        let mut child = child.map_err(|e| ConmonError::new(format!("Failed to spawn: {e}"), 1))?;
 
         let exit_code = child.wait()?;
         info!("Exit command exited with: {exit_code}.");
@@ -102,19 +110,229 @@ pub fn run_exit_command(
     Ok(())
 }
 
-/// Writes exit files into persistent_path and exit_dir.
+/// Name of the file with the final container log size stored in persistent_path.
+pub const LOG_STATS_FILE_NAME: &str = "log-stats.json";
+
+/// Returns the final size of the container log files as JSON object.
+///
+/// The rotated backups ("<path>.1", "<path>.2", ...) are included, so the
+/// total size reflects the disk usage of the container logs.
+///
+/// # Arguments
+///
+/// * `log_files` - Paths of the log files written by the log plugins.
+pub fn log_stats(log_files: &[PathBuf]) -> Value {
+    let mut logs = Vec::new();
+    let mut total_size: u64 = 0;
+    for log_file in log_files {
+        let mut path = log_file.clone();
+        let mut backup = 0;
+        while let Ok(md) = fs::metadata(&path) {
+            total_size += md.len();
+            logs.push(json!({"path": path.to_string_lossy(), "size": md.len()}));
+            backup += 1;
+            path = PathBuf::from(format!("{}.{backup}", log_file.display()));
+        }
+    }
+    json!({"total_size": total_size, "logs": logs})
+}
+
+/// Writes the final size of the container log files into `persist_path`.
+fn write_log_stats(persist_path: &Path, log_files: &[PathBuf]) {
+    let path = persist_path.join(LOG_STATS_FILE_NAME);
+    if let Err(e) = write_file_atomic(&path, log_stats(log_files).to_string().as_bytes()) {
+        error!("Failed to write log stats file {}: {}", path.display(), e);
+    }
+}
+
+/// Name of the file describing the signal which killed the container,
+/// stored in persistent_path.
+pub const EXIT_SIGNAL_FILE_NAME: &str = "exit-signal.json";
+
+/// The signal which terminated the container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitSignal {
+    pub signal: Signal,
+    pub core_dumped: bool,
+}
+
+impl fmt::Display for ExitSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.signal)?;
+        if self.core_dumped {
+            write!(f, " (core dumped)")?;
+        }
+        Ok(())
+    }
+}
+
+impl ExitSignal {
+    /// Returns the signal description as JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "signal": self.signal.as_str(),
+            "signal_number": self.signal as i32,
+            "core_dumped": self.core_dumped,
+        })
+    }
+}
+
+/// The signal which terminated the container, if any.
+static EXIT_SIGNAL: Mutex<Option<ExitSignal>> = Mutex::new(None);
+
+/// Records the signal which terminated the container, so it is reported
+/// together with the exit status by `write_exit_files`.
+pub fn set_exit_signal(exit_signal: ExitSignal) {
+    if let Ok(mut s) = EXIT_SIGNAL.lock() {
+        *s = Some(exit_signal);
+    }
+}
+
+/// Returns the signal which terminated the container, if any.
+pub fn exit_signal() -> Option<ExitSignal> {
+    EXIT_SIGNAL.lock().ok().and_then(|s| *s)
+}
+
+/// Writes the description of the signal which killed the container into `persist_path`.
+fn write_exit_signal(persist_path: &Path, exit_signal: &ExitSignal) {
+    let path = persist_path.join(EXIT_SIGNAL_FILE_NAME);
+    if let Err(e) = write_file_atomic(&path, exit_signal.to_json().to_string().as_bytes()) {
+        error!("Failed to write exit signal file {}: {}", path.display(), e);
+    }
+}
+
+/// Name of the JSON exit record stored in persistent_path with `--exit-json`.
+pub const EXIT_JSON_FILE_NAME: &str = "exit.json";
+
+/// The time the container started, if known.
+static CONTAINER_STARTED: Mutex<Option<DateTime<Utc>>> = Mutex::new(None);
+
+/// True if the container has been killed by the `--timeout`.
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// True if the container exit status is not known.
+static EXIT_STATUS_UNKNOWN: AtomicBool = AtomicBool::new(false);
+
+/// Records that the container exit status is not known, for example because
+/// the adopted container is not a child of conmon. The exit files are written
+/// with -1 then, so the parent learns about the exit, but does not take a
+/// made-up status for the real one.
+pub fn set_exit_status_unknown() {
+    EXIT_STATUS_UNKNOWN.store(true, Ordering::Relaxed);
+}
+
+/// Records that the container has been killed by the `--timeout`, reported in
+/// the JSON exit record.
+pub fn set_timed_out() {
+    TIMED_OUT.store(true, Ordering::Relaxed);
+}
+
+/// Records the time the container started, reported in the JSON exit record.
+pub fn set_container_started(at: DateTime<Utc>) {
+    if let Ok(mut s) = CONTAINER_STARTED.lock() {
+        *s = Some(at);
+    }
+}
+
+/// Returns the JSON exit record of the container.
+///
+/// # Arguments
+///
+/// * `exit_status` - The exit code of the container.
+/// * `exit_signal` - The signal which terminated the container, if any.
+/// * `oom` - True if the container ran out of memory.
+/// * `timed_out` - True if the container has been killed by the `--timeout`.
+/// * `started` - The time the container started, if known.
+/// * `finished` - The time the container exited.
+fn exit_record(
+    exit_status: i32,
+    exit_signal: Option<&ExitSignal>,
+    oom: bool,
+    timed_out: bool,
+    started: Option<DateTime<Utc>>,
+    finished: DateTime<Utc>,
+) -> Value {
+    let rfc3339 = |t: DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::Nanos, true);
+    json!({
+        "exit_code": exit_status,
+        "signal": exit_signal.map(ExitSignal::to_json),
+        "oom_killed": oom,
+        "timed_out": timed_out,
+        "started_at": started.map(rfc3339),
+        "finished_at": rfc3339(finished),
+    })
+}
+
+/// Writes the JSON exit record into `persist_path`.
+fn write_exit_json(persist_path: &Path, exit_status: i32) {
+    let started = CONTAINER_STARTED.lock().ok().and_then(|s| *s);
+    let record = exit_record(
+        exit_status,
+        exit_signal().as_ref(),
+        oom_detected(),
+        TIMED_OUT.load(Ordering::Relaxed),
+        started,
+        Utc::now(),
+    );
+    let path = persist_path.join(EXIT_JSON_FILE_NAME);
+    if let Err(e) = write_file_atomic(&path, record.to_string().as_bytes()) {
+        error!("Failed to write exit record {}: {}", path.display(), e);
+    }
+}
+
+/// Writes `contents` into `path` atomically.
+///
+/// The data is written into a hidden temporary file in the same directory,
+/// which is then renamed to `path`, so the readers watching the directory
+/// never see a partially written file.
+pub fn write_file_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{name}.tmp"));
+    let written = fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    written
+}
+
+/// Writes exit files into persistent_path and exit_dir. When the exit status
+/// is unknown, -1 is written instead of `exit_status`.
+///
+/// When there are some `log_files`, their final size is written into
+/// persistent_path before the exit file, so it is available once the
+/// parent detects the exit. The same applies to the signal which killed
+/// the container and to the JSON exit record written with `exit_json`.
 pub fn write_exit_files(
     exit_status: i32,
     persist_path: Option<&PathBuf>,
     exit_dir: Option<&PathBuf>,
     cid: Option<&String>,
+    log_files: &[PathBuf],
+    exit_json: bool,
 ) {
+    let unknown = EXIT_STATUS_UNKNOWN.load(Ordering::Relaxed);
+    let exit_status = if unknown {
+        warn!("The container exit status is unknown, writing -1 into the exit files");
+        -1
+    } else {
+        exit_status
+    };
     let status_str: String = exit_status.to_string();
 
     // Write the exit file to container persistent directory if it is specified
     if let Some(persist_path) = persist_path {
+        if !log_files.is_empty() {
+            write_log_stats(persist_path, log_files);
+        }
+        if let Some(exit_signal) = exit_signal().filter(|_| !unknown) {
+            write_exit_signal(persist_path, &exit_signal);
+        }
+        if exit_json {
+            write_exit_json(persist_path, exit_status);
+        }
+
         let ctr_exit_file_path: PathBuf = persist_path.join("exit");
-        if let Err(e) = fs::write(&ctr_exit_file_path, &status_str) {
+        if let Err(e) = write_file_atomic(&ctr_exit_file_path, status_str.as_bytes()) {
             error!(
                 "Failed to write {} to container exit file {}: {}",
                 status_str,
@@ -129,7 +347,7 @@ pub fn write_exit_files(
     if let Some(exit_dir) = exit_dir {
         if let Some(cid) = cid {
             let exit_file_path: PathBuf = exit_dir.join(cid);
-            if let Err(e) = fs::write(&exit_file_path, &status_str) {
+            if let Err(e) = write_file_atomic(&exit_file_path, status_str.as_bytes()) {
                 error!(
                     "Failed to write {} to exit file {}: {}",
                     status_str,
@@ -179,6 +397,11 @@ impl OpenFilesSnapshot {
         self.open_fds.binary_search(&fd).is_ok()
     }
 
+    /// Returns the fds open at snapshot time, sorted.
+    pub fn fds(&self) -> &[RawFd] {
+        &self.open_fds
+    }
+
     pub fn remove(&mut self, fd: RawFd) {
         if fd < 0 {
             return;
@@ -229,3 +452,99 @@ pub fn close_all_except_stdio(snap: &OpenFilesSnapshot) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn log_stats_include_rotated_backups() -> std::io::Result<()> {
+        let tmp = tempdir()?;
+        let log = tmp.path().join("ctr.log");
+        fs::write(&log, b"12345")?;
+        fs::write(tmp.path().join("ctr.log.1"), b"123")?;
+        let missing = tmp.path().join("missing.log");
+
+        let stats = log_stats(&[log.clone(), missing]);
+        assert_eq!(stats["total_size"], 8);
+        assert_eq!(stats["logs"].as_array().unwrap().len(), 2);
+        assert_eq!(stats["logs"][0]["path"], log.to_string_lossy().as_ref());
+        assert_eq!(stats["logs"][0]["size"], 5);
+        Ok(())
+    }
+
+    #[test]
+    fn write_exit_files_writes_log_stats() -> std::io::Result<()> {
+        let tmp = tempdir()?;
+        let log = tmp.path().join("ctr.log");
+        fs::write(&log, b"abc")?;
+        let persist = tmp.path().to_path_buf();
+
+        write_exit_files(3, Some(&persist), None, None, &[log], false);
+        assert_eq!(fs::read_to_string(persist.join("exit"))?, "3");
+        let stats: Value =
+            serde_json::from_str(&fs::read_to_string(persist.join(LOG_STATS_FILE_NAME))?)?;
+        assert_eq!(stats["total_size"], 3);
+        Ok(())
+    }
+
+    #[test]
+    fn exit_files_are_written_atomically() -> std::io::Result<()> {
+        let tmp = tempdir()?;
+        let persist = tmp.path().join("persist");
+        let exit_dir = tmp.path().join("exits");
+        fs::create_dir(&persist)?;
+        fs::create_dir(&exit_dir)?;
+        // A previous exit file is replaced as a whole.
+        fs::write(persist.join("exit"), "12345")?;
+
+        let cid = "abc".to_string();
+        write_exit_files(137, Some(&persist), Some(&exit_dir), Some(&cid), &[], true);
+        assert_eq!(fs::read_to_string(persist.join("exit"))?, "137");
+        assert_eq!(fs::read_to_string(exit_dir.join("abc"))?, "137");
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(&persist)?.count(), 2);
+        assert_eq!(fs::read_dir(&exit_dir)?.count(), 1);
+
+        // The exit file is not written into a missing directory.
+        assert!(write_file_atomic(&tmp.path().join("missing/exit"), b"1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn exit_record_describes_exit() {
+        let started = DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let finished = started + chrono::Duration::seconds(10);
+        let exit_signal = ExitSignal {
+            signal: Signal::SIGKILL,
+            core_dumped: false,
+        };
+        let v = exit_record(137, Some(&exit_signal), true, true, Some(started), finished);
+        assert_eq!(v["exit_code"], 137);
+        assert_eq!(v["signal"]["signal"], "SIGKILL");
+        assert_eq!(v["oom_killed"], true);
+        assert_eq!(v["timed_out"], true);
+        assert_eq!(v["started_at"], "2024-01-02T03:04:05.000000000Z");
+        assert_eq!(v["finished_at"], "2024-01-02T03:04:15.000000000Z");
+
+        let v = exit_record(0, None, false, false, None, finished);
+        assert!(v["signal"].is_null());
+        assert!(v["started_at"].is_null());
+    }
+
+    #[test]
+    fn exit_signal_description() {
+        let exit_signal = ExitSignal {
+            signal: Signal::SIGABRT,
+            core_dumped: true,
+        };
+        assert_eq!(exit_signal.to_string(), "SIGABRT (core dumped)");
+        let v = exit_signal.to_json();
+        assert_eq!(v["signal"], "SIGABRT");
+        assert_eq!(v["signal_number"], 6);
+        assert_eq!(v["core_dumped"], true);
+    }
+}
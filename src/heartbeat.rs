@@ -0,0 +1,173 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use crate::error::{ConmonError, ConmonResult};
+
+/// Default interval between two heartbeats in seconds.
+pub const DEFAULT_HEARTBEAT_INTERVAL: u64 = 10;
+
+/// Touches the heartbeat file periodically from the event-loop.
+///
+/// External monitoring can compare the file modification time with the
+/// interval to detect a hung conmon. The file contains the Unix timestamp
+/// of the last heartbeat.
+pub struct Heartbeat {
+    path: PathBuf,
+    interval: Duration,
+    last: Option<Instant>,
+
+    /// True if the last write failed, so the failure is not logged again.
+    failing: bool,
+}
+
+impl Heartbeat {
+    /// Creates new Heartbeat writing into `path` every `interval`.
+    pub fn new(path: &Path, interval: Duration) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            interval,
+            last: None,
+            failing: false,
+        }
+    }
+
+    /// Writes the heartbeat file atomically.
+    fn touch(&self) -> ConmonResult<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, format!("{}\n", now.as_secs()))
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|e| {
+                ConmonError::new(
+                    format!(
+                        "Failed to write heartbeat file {}: {e}",
+                        self.path.display()
+                    ),
+                    1,
+                )
+            })
+    }
+
+    /// Touches the heartbeat file if the interval has elapsed since the last
+    /// heartbeat.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    ///
+    /// * True if the file has been touched.
+    pub fn beat(&mut self, now: Instant) -> bool {
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            return false;
+        }
+        // Retry on the next interval also when the write fails.
+        self.last = Some(now);
+        match self.touch() {
+            Ok(()) => {
+                self.failing = false;
+                true
+            }
+            Err(e) => {
+                if !self.failing {
+                    warn!("{}", e.msg);
+                }
+                self.failing = true;
+                false
+            }
+        }
+    }
+}
+
+static HEARTBEAT: Mutex<Option<Heartbeat>> = Mutex::new(None);
+
+fn heartbeat() -> MutexGuard<'static, Option<Heartbeat>> {
+    match HEARTBEAT.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Starts touching the heartbeat file `path` every `interval` seconds.
+pub fn start_heartbeat(path: &Path, interval: u64) {
+    info!(
+        "Writing heartbeat into {} every {interval} seconds",
+        path.display()
+    );
+    let mut hb = Heartbeat::new(path, Duration::from_secs(interval));
+    hb.beat(Instant::now());
+    *heartbeat() = Some(hb);
+}
+
+/// Touches the heartbeat file when it is due. Called on every event-loop
+/// iteration.
+pub fn beat() {
+    if let Some(hb) = heartbeat().as_mut() {
+        hb.beat(Instant::now());
+    }
+}
+
+/// Returns the heartbeat interval, or None if the heartbeat is not running.
+///
+/// The waits outside of the event-loop wake up at least this often to beat.
+pub fn interval() -> Option<Duration> {
+    heartbeat().as_ref().map(|hb| hb.interval)
+}
+
+/// Stops the heartbeat and removes the heartbeat file.
+pub fn stop_heartbeat() {
+    if let Some(hb) = heartbeat().take() {
+        if let Err(e) = fs::remove_file(&hb.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove heartbeat file {}: {e}", hb.path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn beats_once_per_interval() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("heartbeat");
+        let mut hb = Heartbeat::new(&path, Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(hb.beat(start));
+        let stamp: u64 = fs::read_to_string(&path)?.trim().parse().unwrap();
+        assert!(stamp > 0);
+
+        fs::remove_file(&path)?;
+        assert!(!hb.beat(start + Duration::from_secs(4)));
+        assert!(!path.exists());
+        assert!(hb.beat(start + Duration::from_secs(5)));
+        assert!(path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_beat_is_retried_next_interval() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("missing").join("heartbeat");
+        let mut hb = Heartbeat::new(&path, Duration::from_secs(1));
+        let start = Instant::now();
+        assert!(!hb.beat(start));
+
+        fs::create_dir(tmp.path().join("missing"))?;
+        assert!(!hb.beat(start + Duration::from_millis(500)));
+        assert!(hb.beat(start + Duration::from_secs(1)));
+        Ok(())
+    }
+}
@@ -1,10 +1,15 @@
 #![allow(clippy::collapsible_if)]
+pub mod attach;
 pub mod cli;
 pub mod commands;
 pub mod error;
 pub mod exit;
+pub mod heartbeat;
 pub mod log;
 pub mod logging;
+pub mod metrics;
 pub mod parent_pipe;
+pub mod recording;
 pub mod runtime;
+pub mod sync_socket;
 pub mod unix_socket;
@@ -2,10 +2,35 @@ use chrono::Utc;
 use log::{Level, LevelFilter, Log, Metadata, Record};
 use nix::unistd::getpid;
 use std::fs::OpenOptions;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{fs::File, io::Write, path::PathBuf, sync::Mutex};
 
 use crate::error::{ConmonError, ConmonResult};
 
+/// True if `--quiet` is used.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Stops printing the non-fatal warnings and errors on conmon's own stdio.
+/// They are still written into the conmon log.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Returns true if `--quiet` is used.
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Logs the warning and, unless `--quiet` is used, also prints it on stderr.
+/// Used for warnings about the command line the caller should see even
+/// without the conmon log.
+pub fn warn_user(msg: &str) {
+    log::warn!("{msg}");
+    if !is_quiet() {
+        eprintln!("{msg}");
+    }
+}
+
 pub struct FileLogger {
     level: LevelFilter,
     file: Mutex<File>,
@@ -47,7 +72,7 @@ impl Log for FileLogger {
         );
 
         // The conmon-v2 prints warnings and errors also on stdout.
-        if record.level() == Level::Warn || record.level() == Level::Error {
+        if !is_quiet() && (record.level() == Level::Warn || record.level() == Level::Error) {
             println!("{}", record.args());
         }
     }
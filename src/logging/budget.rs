@@ -0,0 +1,114 @@
+use std::sync::{Mutex, MutexGuard};
+
+use log::{info, warn};
+
+use crate::metrics::metrics;
+
+/// Byte budget shared by all the log targets, enforcing
+/// `--log-global-size-max`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalBudget {
+    /// The maximum number of bytes written into all the log targets.
+    max: u64,
+
+    /// The number of bytes written so far.
+    used: u64,
+
+    /// True once the budget has been exhausted and the drop was logged.
+    exhausted: bool,
+}
+
+impl GlobalBudget {
+    pub fn new(max: u64) -> Self {
+        Self {
+            max,
+            used: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Reserves `len` bytes for a write.
+    ///
+    /// # Returns
+    ///
+    /// * False if the write would exceed the budget, in which case nothing
+    ///   is reserved.
+    pub fn reserve(&mut self, len: u64) -> bool {
+        if self.used.saturating_add(len) >= self.max {
+            if !self.exhausted {
+                warn!(
+                    "Reached --log-global-size-max of {} bytes, dropping further output",
+                    self.max
+                );
+                self.exhausted = true;
+            }
+            return false;
+        }
+        self.used += len;
+        true
+    }
+}
+
+static GLOBAL_BUDGET: Mutex<Option<GlobalBudget>> = Mutex::new(None);
+
+fn global_budget() -> MutexGuard<'static, Option<GlobalBudget>> {
+    match GLOBAL_BUDGET.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Limits the bytes written into all the log targets to `max`. Zero disables
+/// the limit.
+pub fn set_global_max_size(max: u64) {
+    if max > 0 {
+        info!("Limiting the output of all log targets to {max} bytes");
+        *global_budget() = Some(GlobalBudget::new(max));
+    } else {
+        *global_budget() = None;
+    }
+}
+
+/// Reserves `len` bytes for a write into any log target.
+///
+/// # Returns
+///
+/// * False if `--log-global-size-max` would be exceeded and the data must be
+///   dropped.
+pub fn reserve(len: u64) -> bool {
+    match global_budget().as_mut() {
+        Some(budget) => budget.reserve(len),
+        None => true,
+    }
+}
+
+/// Accounts `len` bytes already stored in a log target, like the size of
+/// an existing log file, or written regardless of the limit.
+pub fn account(len: u64) {
+    if let Some(budget) = global_budget().as_mut() {
+        budget.used = budget.used.saturating_add(len);
+    }
+}
+
+/// Records `len` bytes of container output dropped because of
+/// `--log-global-size-max`.
+pub fn record_dropped(len: u64) {
+    metrics().record_global_size_drop(len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_stops_at_max() {
+        let mut budget = GlobalBudget::new(100);
+        assert!(budget.reserve(60));
+        assert!(!budget.reserve(40));
+        assert!(budget.exhausted);
+        // Smaller writes still fit, the sum never reaches the limit.
+        assert!(budget.reserve(39));
+        assert!(!budget.reserve(1));
+        assert_eq!(budget.used, 99);
+    }
+}
@@ -0,0 +1,302 @@
+use log::info;
+
+use crate::{
+    error::ConmonResult,
+    logging::plugin::{ContainerExit, ContainerMetadata, LogPlugin, LogStream},
+    metrics::metrics,
+};
+
+/// Dedup and sampling state of single stream.
+#[derive(Debug, Default)]
+struct StreamState {
+    /// The last complete line, if `--log-dedup` is enabled.
+    last: Option<Vec<u8>>,
+
+    /// Number of times the last line was repeated since it was written.
+    repeats: u64,
+
+    /// True if the last line was kept by the sampling.
+    last_kept: bool,
+
+    /// Number of lines seen, used for the sampling.
+    seen: u64,
+
+    /// True if the current part of a long line is kept by the sampling.
+    keep_part: bool,
+
+    /// True unless the last data ended in the middle of a line.
+    at_line_start: bool,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        Self {
+            at_line_start: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Log plugin collapsing the runs of identical lines (`--log-dedup`) and
+/// keeping only every N-th line (`--log-sample`) of the container output.
+///
+/// A run of identical lines is written once, followed by a `conmon: previous
+/// message repeated N times` record when the run ends. The numbers of the
+/// suppressed and sampled out lines are reported on shutdown.
+///
+/// It expects complete lines; the parts of the lines longer than
+/// `--log-line-max` are never collapsed and are kept or dropped together.
+pub struct DedupLogPlugin {
+    inner: Box<dyn LogPlugin>,
+    dedup: bool,
+
+    /// Keep one of `sample` lines; 0 or 1 keeps all of them.
+    sample: u64,
+
+    stdout: StreamState,
+    stderr: StreamState,
+    stdin: StreamState,
+
+    /// Total number of the repeated lines suppressed.
+    repeated: u64,
+
+    /// Total number of the lines dropped by the sampling.
+    sampled_out: u64,
+}
+
+impl DedupLogPlugin {
+    pub fn new(inner: Box<dyn LogPlugin>, dedup: bool, sample: u64) -> Self {
+        Self {
+            inner,
+            dedup,
+            sample,
+            stdout: StreamState::new(),
+            stderr: StreamState::new(),
+            stdin: StreamState::new(),
+            repeated: 0,
+            sampled_out: 0,
+        }
+    }
+
+    /// Returns the state of `stream`.
+    fn state(&mut self, stream: LogStream) -> &mut StreamState {
+        match stream {
+            LogStream::Stdout => &mut self.stdout,
+            LogStream::Stderr => &mut self.stderr,
+            LogStream::Stdin => &mut self.stdin,
+        }
+    }
+
+    /// Returns true if the next line of the stream is kept by the sampling.
+    fn sample_line(sample: u64, state: &mut StreamState) -> bool {
+        state.seen += 1;
+        sample <= 1 || (state.seen - 1).is_multiple_of(sample)
+    }
+
+    /// Ends the run of the identical lines, appending the record with the
+    /// number of repeats to `out`.
+    fn end_run(state: &mut StreamState, out: &mut Vec<u8>) {
+        if state.repeats > 0 && state.last_kept {
+            out.extend_from_slice(
+                format!(
+                    "conmon: previous message repeated {} times\n",
+                    state.repeats
+                )
+                .as_bytes(),
+            );
+        }
+        state.repeats = 0;
+        state.last = None;
+    }
+
+    /// Returns the output to write for `data`.
+    ///
+    /// # Returns
+    ///
+    /// * The output with the repeated and sampled out lines removed, the
+    ///   number of the repeated lines and the number of the sampled out lines.
+    fn filter(&mut self, stream: LogStream, data: &[u8]) -> (Vec<u8>, u64, u64) {
+        let (dedup, sample) = (self.dedup, self.sample);
+        let state = self.state(stream);
+        let mut out = Vec::with_capacity(data.len());
+        let (mut repeated, mut sampled_out) = (0, 0);
+        for piece in data.split_inclusive(|&c| c == b'\n') {
+            let complete = piece.ends_with(b"\n");
+            if !state.at_line_start {
+                // The rest of a long line follows its start.
+                if state.keep_part {
+                    out.extend_from_slice(piece);
+                }
+                state.at_line_start = complete;
+                continue;
+            }
+            if !complete {
+                // The start of a long line.
+                Self::end_run(state, &mut out);
+                state.keep_part = Self::sample_line(sample, state);
+                if state.keep_part {
+                    out.extend_from_slice(piece);
+                } else {
+                    sampled_out += 1;
+                }
+                state.at_line_start = false;
+                continue;
+            }
+            if dedup && state.last.as_deref() == Some(piece) {
+                state.repeats += 1;
+                repeated += 1;
+                continue;
+            }
+            Self::end_run(state, &mut out);
+            state.last_kept = Self::sample_line(sample, state);
+            if state.last_kept {
+                out.extend_from_slice(piece);
+            } else {
+                sampled_out += 1;
+            }
+            if dedup {
+                state.last = Some(piece.to_vec());
+            }
+        }
+        (out, repeated, sampled_out)
+    }
+
+    /// Writes the repeat records of the runs not ended yet.
+    fn end_runs(&mut self) -> ConmonResult<()> {
+        for stream in [LogStream::Stdout, LogStream::Stderr, LogStream::Stdin] {
+            self.end_run_of(stream)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the repeat record of the run of `stream` not ended yet.
+    fn end_run_of(&mut self, stream: LogStream) -> ConmonResult<()> {
+        let mut out = Vec::new();
+        Self::end_run(self.state(stream), &mut out);
+        if out.is_empty() {
+            return Ok(());
+        }
+        self.inner.write(stream, &out)
+    }
+}
+
+impl LogPlugin for DedupLogPlugin {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        if data.is_empty() {
+            // Do not keep the repeats of the last line unreported on flush.
+            self.end_run_of(stream)?;
+            return self.inner.write(stream, data);
+        }
+        let (out, repeated, sampled_out) = self.filter(stream, data);
+        if repeated > 0 || sampled_out > 0 {
+            let mut m = metrics();
+            m.record_log_repeated(repeated);
+            m.record_log_sampled_out(sampled_out);
+        }
+        self.repeated += repeated;
+        self.sampled_out += sampled_out;
+        if out.is_empty() {
+            return Ok(());
+        }
+        self.inner.write(stream, &out)
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        self.inner.reopen()
+    }
+
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        self.inner.reopen_files()
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.inner.stop_threads()
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        self.end_runs()?;
+        info!(
+            "Log dedup: suppressed {} repeated lines, sampled out {} lines",
+            self.repeated, self.sampled_out
+        );
+        self.inner.shutdown()
+    }
+
+    fn on_container_start(&mut self, pid: i32, metadata: &ContainerMetadata) -> ConmonResult<()> {
+        self.inner.on_container_start(pid, metadata)
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        self.inner.on_container_exit(exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Records = Arc<Mutex<Vec<(LogStream, String)>>>;
+
+    struct Recorder(Records);
+
+    impl LogPlugin for Recorder {
+        fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+            let data = String::from_utf8_lossy(data).into_owned();
+            self.0.lock().unwrap().push((stream, data));
+            Ok(())
+        }
+        fn reopen(&mut self) -> ConmonResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn repeated_lines_are_collapsed() -> ConmonResult<()> {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut plugin = DedupLogPlugin::new(Box::new(Recorder(written.clone())), true, 0);
+        plugin.write(LogStream::Stdout, b"a\na\na\n")?;
+        plugin.write(LogStream::Stderr, b"a\n")?;
+        plugin.write(LogStream::Stdout, b"a\nb\n")?;
+        plugin.write(LogStream::Stdout, b"b\n")?;
+        plugin.shutdown()?;
+
+        assert_eq!(
+            *written.lock().unwrap(),
+            vec![
+                (LogStream::Stdout, "a\n".to_string()),
+                (LogStream::Stderr, "a\n".to_string()),
+                (
+                    LogStream::Stdout,
+                    "conmon: previous message repeated 3 times\nb\n".to_string()
+                ),
+                (
+                    LogStream::Stdout,
+                    "conmon: previous message repeated 1 times\n".to_string()
+                ),
+            ]
+        );
+        assert_eq!((plugin.repeated, plugin.sampled_out), (4, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn lines_are_sampled_with_long_lines_kept_whole() -> ConmonResult<()> {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut plugin = DedupLogPlugin::new(Box::new(Recorder(written.clone())), false, 2);
+        plugin.write(LogStream::Stdout, b"1\n2\n3\n")?;
+        // The parts of the long line 4 are dropped together.
+        plugin.write(LogStream::Stdout, b"long")?;
+        plugin.write(LogStream::Stdout, b"er\n5\n")?;
+
+        let out: String = written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, d)| d.clone())
+            .collect();
+        assert_eq!(out, "1\n3\n5\n");
+        assert_eq!(plugin.sampled_out, 2);
+        Ok(())
+    }
+}
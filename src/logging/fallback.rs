@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+
+use log::warn;
+
+use crate::{
+    error::{ConmonError, ConmonResult},
+    logging::plugin::{
+        ContainerExit, ContainerMetadata, LogPlugin, LogPluginCfg, LogStream, create_log_target,
+        log_target_name,
+    },
+};
+
+/// Default number of consecutive write failures after which the log is
+/// switched to the next `--log-fallback`.
+pub const DEFAULT_FALLBACK_AFTER: u32 = 5;
+
+/// Log plugin switching to the next `--log-fallback` log driver once the
+/// active one fails `after` consecutive writes, for example because the disk
+/// is full or journald is down.
+///
+/// The fallback log drivers are created only when switching to them. A
+/// record describing the switch is written into the new log before the
+/// output which failed.
+pub struct FallbackLogPlugin {
+    active: Box<dyn LogPlugin>,
+
+    /// Name of the active log target used in the log messages.
+    name: String,
+
+    /// The (driver, cfg) of the fallbacks not used yet.
+    fallbacks: VecDeque<(String, LogPluginCfg)>,
+
+    /// Number of consecutive failures triggering the switch.
+    after: u32,
+
+    /// Number of consecutive failed writes of the active log target.
+    failures: u32,
+}
+
+impl FallbackLogPlugin {
+    pub fn new(
+        active: Box<dyn LogPlugin>,
+        name: String,
+        fallbacks: Vec<(String, LogPluginCfg)>,
+        after: u32,
+    ) -> Self {
+        Self {
+            active,
+            name,
+            fallbacks: fallbacks.into(),
+            after: if after > 0 {
+                after
+            } else {
+                DEFAULT_FALLBACK_AFTER
+            },
+            failures: 0,
+        }
+    }
+
+    /// Switches to the first fallback which can be created.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream the diagnostic record is written to.
+    /// * `err` - The last error of the active log target.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if no fallback can be created.
+    fn switch(&mut self, stream: LogStream, err: &ConmonError) -> ConmonResult<()> {
+        while let Some((driver, cfg)) = self.fallbacks.pop_front() {
+            let name = log_target_name(&driver, &cfg);
+            let mut plugin = match create_log_target(&driver, &cfg) {
+                Ok(plugin) => plugin,
+                Err(e) => {
+                    warn!("Failed to create the fallback log {name}: {}", e.msg);
+                    continue;
+                }
+            };
+            warn!(
+                "Log {} failed {} consecutive writes, switching to {name}: {}",
+                self.name, self.failures, err.msg
+            );
+            let record = format!(
+                "conmon: switched the container log from {} to {name} after {} consecutive write failures: {}\n",
+                self.name, self.failures, err.msg
+            );
+            if let Err(e) = plugin.write(stream, record.as_bytes()) {
+                warn!("Failed to write into the fallback log {name}: {}", e.msg);
+            }
+            self.active = plugin;
+            self.name = name;
+            self.failures = 0;
+            return Ok(());
+        }
+        Err(ConmonError::new("No usable --log-fallback left", 1))
+    }
+}
+
+impl LogPlugin for FallbackLogPlugin {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        let err = match self.active.write(stream, data) {
+            Ok(()) => {
+                self.failures = 0;
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        self.failures += 1;
+        if self.failures < self.after || self.fallbacks.is_empty() {
+            return Err(err);
+        }
+        if self.switch(stream, &err).is_err() {
+            return Err(err);
+        }
+        self.write(stream, data)
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        self.active.reopen()
+    }
+
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        self.active.reopen_files()
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.active.stop_threads()
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        self.active.shutdown()
+    }
+
+    fn on_container_start(&mut self, pid: i32, metadata: &ContainerMetadata) -> ConmonResult<()> {
+        self.active.on_container_start(pid, metadata)
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        self.active.on_container_exit(exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    struct Failing;
+
+    impl LogPlugin for Failing {
+        fn write(&mut self, _stream: LogStream, _data: &[u8]) -> ConmonResult<()> {
+            Err(ConmonError::new("No space left on device", 1))
+        }
+        fn reopen(&mut self) -> ConmonResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn switches_to_fallback_after_consecutive_failures() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("fallback.log");
+        let fallbacks = vec![
+            (
+                "file".to_string(),
+                LogPluginCfg {
+                    path: PathBuf::from("/nonexistent/dir/fallback.log"),
+                    ..Default::default()
+                },
+            ),
+            (
+                "file".to_string(),
+                LogPluginCfg {
+                    path: path.clone(),
+                    ..Default::default()
+                },
+            ),
+        ];
+        let mut plugin = FallbackLogPlugin::new(Box::new(Failing), "journald".into(), fallbacks, 2);
+
+        assert!(plugin.write(LogStream::Stdout, b"lost\n").is_err());
+        plugin.write(LogStream::Stdout, b"kept\n")?;
+        plugin.write(LogStream::Stderr, b"more\n")?;
+        assert_eq!(plugin.name, format!("file:{}", path.display()));
+
+        let log = fs::read_to_string(&path)?;
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 3, "{log}");
+        assert!(lines[0].ends_with(&format!(
+            "stdout F conmon: switched the container log from journald to file:{} after 2 consecutive write failures: No space left on device",
+            path.display()
+        )));
+        assert!(lines[1].ends_with("stdout F kept"));
+        assert!(lines[2].ends_with("stderr F more"));
+        Ok(())
+    }
+
+    #[test]
+    fn error_is_returned_without_fallback() {
+        let mut plugin = FallbackLogPlugin::new(Box::new(Failing), "journald".into(), vec![], 1);
+        for _ in 0..3 {
+            assert!(plugin.write(LogStream::Stdout, b"x\n").is_err());
+        }
+    }
+}
@@ -1,8 +1,9 @@
-use chrono::{Datelike, Local, Timelike};
+use chrono::{Datelike, Local, SecondsFormat, Timelike, Utc};
 
 use crate::{
     error::{ConmonError, ConmonResult},
-    logging::plugin::{LogPlugin, LogPluginCfg},
+    logging::budget,
+    logging::plugin::{LogPlugin, LogPluginCfg, LogStream},
 };
 
 use nix::errno::Errno;
@@ -22,21 +23,84 @@ use std::{
 };
 
 use log::warn;
+use serde_json::{Map, Value};
 
 const TSBUFLEN: usize = 44;
 
+/// Message of the line starting the log file truncated because of
+/// `--log-size-max`.
+const TRUNCATION_MARKER: &str = "conmon: log truncated after reaching --log-size-max";
+
+/// Format of the lines written by the FileLogger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `TIMESTAMP STREAM P|F MESSAGE` lines used by Kubernetes.
+    K8sFile,
+
+    /// Docker compatible `{"log":"...","stream":"...","time":"..."}` lines.
+    JsonFile,
+
+    /// JSON object per line with the container metadata, `--log-tag` and
+    /// `--log-label` values for the log shippers.
+    JsonLines,
+}
+
+impl LogFormat {
+    /// Returns the name of the log driver writing the format.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogFormat::K8sFile => "k8s-file",
+            LogFormat::JsonFile => "json-file",
+            LogFormat::JsonLines => "json-lines",
+        }
+    }
+
+    /// Returns true if the format records `--log-tag` and `--log-label`.
+    fn has_metadata(&self) -> bool {
+        *self == LogFormat::JsonLines
+    }
+}
+
+/// Returns the fields of the json-lines records which are the same in every
+/// record: the container ID and name, the `--log-tag` and the `--log-label`
+/// values.
+fn json_lines_fields(cfg: &LogPluginCfg) -> Map<String, Value> {
+    let mut fields = Map::new();
+    if let Some(cid) = &cfg.cid {
+        fields.insert("container_id".into(), Value::from(cid.as_str()));
+    }
+    if let Some(name) = &cfg.name {
+        fields.insert("container_name".into(), Value::from(name.as_str()));
+    }
+    if let Some(tag) = &cfg.log_tag {
+        fields.insert("tag".into(), Value::from(tag.as_str()));
+    }
+    if !cfg.log_labels.is_empty() {
+        let labels = cfg
+            .log_labels
+            .iter()
+            .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+            .collect();
+        fields.insert("labels".into(), Value::Object(labels));
+    }
+    fields
+}
+
 /// A simple file-based logging plugin.
 ///
 /// Writes all log data to the configured file path.
 pub struct FileLogger {
     file: File,
+    format: LogFormat,
+
+    /// The fields added to every json-lines record.
+    fields: Map<String, Value>,
     stdout_has_partial: bool,
     stderr_has_partial: bool,
+    stdin_has_partial: bool,
     no_sync: bool,
     max_size: u64,
-    global_max_size: u64,
     bytes_written: u64,
-    total_bytes_written: u64,
     path: PathBuf,
     max_files: i32,
     allowlist_dirs: Option<Vec<PathBuf>>,
@@ -45,12 +109,31 @@ pub struct FileLogger {
 
 impl FileLogger {
     pub fn new(cfg: &LogPluginCfg) -> ConmonResult<Self> {
-        if !cfg.log_labels.is_empty() {
-            return Err(ConmonError::new("k8s-file doesn't support --log-label", 1));
+        Self::with_format(cfg, LogFormat::K8sFile)
+    }
+
+    /// Creates new FileLogger writing the lines in `format`.
+    pub fn with_format(cfg: &LogPluginCfg, format: LogFormat) -> ConmonResult<Self> {
+        if !cfg.log_labels.is_empty() && !format.has_metadata() {
+            return Err(ConmonError::new(
+                format!("{} doesn't support --log-label", format.name()),
+                1,
+            ));
+        }
+        if cfg.log_tag.is_some() && !format.has_metadata() {
+            return Err(ConmonError::new(
+                format!("{} doesn't support --log-tag", format.name()),
+                1,
+            ));
         }
-        if cfg.log_tag.is_some() {
-            return Err(ConmonError::new("k8s-file doesn't support --log-tag", 1));
+        if let Some(dirs) = &cfg.allowlist_dirs {
+            Self::check_allowlist(&cfg.path, dirs)?;
         }
+        let fields = if format == LogFormat::JsonLines {
+            json_lines_fields(cfg)
+        } else {
+            Map::new()
+        };
 
         let file = OpenOptions::new()
             .create(true)
@@ -65,16 +148,18 @@ impl FileLogger {
             })?;
 
         let metadata = file.metadata()?;
+        budget::account(metadata.len());
 
         Ok(Self {
             file,
+            format,
+            fields,
             stdout_has_partial: false,
             stderr_has_partial: false,
+            stdin_has_partial: false,
             no_sync: cfg.no_sync,
             max_size: cfg.max_size as u64,
-            global_max_size: cfg.global_max_size as u64,
             bytes_written: metadata.len(),
-            total_bytes_written: metadata.len(),
             path: cfg.path.clone(),
             max_files: cfg.max_files,
             allowlist_dirs: cfg.allowlist_dirs.clone(),
@@ -138,6 +223,11 @@ impl FileLogger {
         let Some(dirs) = &self.allowlist_dirs else {
             return true; // no allowlist configured
         };
+        Self::is_path_in_dirs(dirs, canonical_path)
+    }
+
+    /// Returns true if `canonical_path` is under any of `dirs`.
+    fn is_path_in_dirs(dirs: &[PathBuf], canonical_path: &Path) -> bool {
         if dirs.is_empty() {
             return true; // treat empty allowlist like no allowlist
         }
@@ -166,6 +256,31 @@ impl FileLogger {
         false
     }
 
+    /// Checks that the canonicalized parent directory of the log file `path`
+    /// is under one of the `--log-allowlist-dir` directories.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the parent directory cannot be canonicalized or
+    ///   it is not in the allowlist.
+    fn check_allowlist(path: &Path, dirs: &[PathBuf]) -> ConmonResult<()> {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let canon_parent = Self::canonicalize(parent)?;
+        if !Self::is_path_in_dirs(dirs, &canon_parent) {
+            return Err(ConmonError::new(
+                format!(
+                    "Log path {} is not under any --log-allowlist-dir",
+                    path.display()
+                ),
+                1,
+            ));
+        }
+        Ok(())
+    }
+
     /// Atomic symlink validation using file descriptors to reduce race conditions.
     /// Returns true if any component is a symlink OR an unsafe error occurs.
     fn path_contains_symlinks_atomic(&self, canonical_path: Option<&Path>) -> bool {
@@ -427,15 +542,11 @@ impl FileLogger {
         // Validate path using secure validation
         self.secure_validate_log_path(&self.path)?;
 
-        // Shift: .N-1 -> .N, ...
-        let loop_start = if self.max_files > 1 {
-            self.max_files
-        } else {
-            2
-        };
+        // Shift: .N-1 -> .N, ... The oldest backup is overwritten, and the
+        // current log becomes .1 afterwards, so there are at most N backups.
         let mut had_errors = false;
 
-        for i in (2..=loop_start).rev() {
+        for i in (2..=self.max_files).rev() {
             let from = PathBuf::from(format!("{}.{}", self.path.display(), i - 1));
             let to = PathBuf::from(format!("{}.{}", self.path.display(), i));
 
@@ -633,12 +744,98 @@ impl FileLogger {
         Ok(())
     }
 
+    /// Returns the flag tracking whether a partial line of `stream` has been written.
+    fn has_partial_mut(&mut self, stream: LogStream) -> &mut bool {
+        match stream {
+            LogStream::Stdout => &mut self.stdout_has_partial,
+            LogStream::Stderr => &mut self.stderr_has_partial,
+            LogStream::Stdin => &mut self.stdin_has_partial,
+        }
+    }
+
+    /// Returns the log record of the `line` in the log format.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream the line comes from.
+    /// * `line` - The line including the newline, unless it is `partial`.
+    /// * `partial` - True if the rest of the line follows in the next record.
+    fn record(&self, stream: LogStream, line: &[u8], partial: bool) -> Vec<u8> {
+        match self.format {
+            LogFormat::K8sFile => {
+                let mut tsbuf = [0u8; TSBUFLEN];
+                Self::set_k8s_timestamp(&mut tsbuf, stream.name());
+                let ts_len = tsbuf.iter().position(|&b| b == 0).unwrap_or(tsbuf.len());
+
+                let mut record = Vec::with_capacity(ts_len + line.len() + 3);
+                record.extend_from_slice(&tsbuf[..ts_len]);
+                record.extend_from_slice(if partial { b"P " } else { b"F " });
+                record.extend_from_slice(line);
+                if partial {
+                    record.push(b'\n');
+                }
+                record
+            }
+            LogFormat::JsonFile => {
+                // Like Docker, the partial lines are the ones without newline.
+                let obj = serde_json::json!({
+                    "log": String::from_utf8_lossy(line),
+                    "stream": stream.name(),
+                    "time": Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+                });
+                let mut record = obj.to_string().into_bytes();
+                record.push(b'\n');
+                record
+            }
+            LogFormat::JsonLines => {
+                let line = line.strip_suffix(b"\n").unwrap_or(line);
+                let mut obj = self.fields.clone();
+                obj.insert(
+                    "timestamp".into(),
+                    Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true)),
+                );
+                obj.insert("stream".into(), Value::from(stream.name()));
+                obj.insert(
+                    "message".into(),
+                    Value::from(String::from_utf8_lossy(line).as_ref()),
+                );
+                obj.insert("partial".into(), Value::from(partial));
+                let mut record = Value::Object(obj).to_string().into_bytes();
+                record.push(b'\n');
+                record
+            }
+        }
+    }
+
+    /// Writes the line marking the start of the truncated log, so the readers
+    /// know the older output is gone.
+    fn write_truncation_marker(&mut self, stream: LogStream) -> ConmonResult<()> {
+        let marker = format!("{TRUNCATION_MARKER} of {} bytes\n", self.max_size);
+        let record = self.record(stream, marker.as_bytes(), false);
+        self.file
+            .write_all(&record)
+            .map_err(|e| ConmonError::new(format!("failed to write truncation marker: {e}"), 1))?;
+
+        let len = record.len() as u64;
+        self.bytes_written = self.bytes_written.saturating_add(len);
+        budget::account(len);
+        Ok(())
+    }
+
     /// Rotates a log when configured so and next record would push us over `self.max_size`.
-    fn rotate_if_needed(&mut self, bytes_to_be_written: u64) -> ConmonResult<()> {
+    /// Without `--log-rotate`, the log is truncated and starts with the truncation marker.
+    fn rotate_if_needed(
+        &mut self,
+        stream: LogStream,
+        bytes_to_be_written: u64,
+    ) -> ConmonResult<()> {
         if self.max_size > 0
             && self.bytes_written.saturating_add(bytes_to_be_written) >= self.max_size
         {
             self.rotate()?;
+            if !self.opt_rotate {
+                self.write_truncation_marker(stream)?;
+            }
         }
         Ok(())
     }
@@ -657,15 +854,61 @@ impl LogPlugin for FileLogger {
         self.rotate()
     }
 
-    fn write(&mut self, is_stdout: bool, data: &[u8]) -> ConmonResult<()> {
-        // Track if we previously wrote a partial line for each stream.
-        let has_partial = if is_stdout {
-            self.stdout_has_partial
-        } else {
-            self.stderr_has_partial
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        self.secure_validate_log_path(&self.path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o640)
+            .open(&self.path)
+            .map_err(|e| {
+                ConmonError::new(
+                    format!("Failed to reopen log file {}: {}", self.path.display(), e),
+                    1,
+                )
+            })?;
+        if !self.no_sync {
+            let _ = self.file.sync_all();
+        }
+        self.bytes_written = file.metadata()?.len();
+        self.file = file;
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        if self.no_sync {
+            return Ok(());
+        }
+        let sync_err = |path: &Path, e: std::io::Error| {
+            ConmonError::new(format!("Failed to sync {}: {e}", path.display()), 1)
         };
+        self.file.sync_all().map_err(|e| sync_err(&self.path, e))?;
 
-        let pipename = if is_stdout { "stdout" } else { "stderr" };
+        // The rotated backups may still be in the page cache too.
+        if self.opt_rotate {
+            for i in 1..=self.max_files {
+                let backup = PathBuf::from(format!("{}.{}", self.path.display(), i));
+                match File::open(&backup) {
+                    Ok(f) => f.sync_all().map_err(|e| sync_err(&backup, e))?,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                    Err(e) => return Err(sync_err(&backup, e)),
+                }
+            }
+        }
+
+        // Persist the directory entries of the created and renamed files.
+        let parent = match self.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        File::open(parent)
+            .and_then(|d| d.sync_all())
+            .map_err(|e| sync_err(parent, e))
+    }
+
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        // Track if we previously wrote a partial line for each stream.
+        let has_partial = *self.has_partial_mut(stream);
 
         let mut buf = data;
         let mut buflen = data.len() as isize;
@@ -674,104 +917,54 @@ impl LogPlugin for FileLogger {
         let map_err = |e: std::io::Error, msg: &str| ConmonError::new(format!("{msg}: {e}"), 1);
 
         // If we get an empty buffer and we had a partial line before, emit terminating "F\n".
+        // The JSON formats have no terminating record.
         if buflen == 0 && has_partial {
-            let mut tsbuf = [0u8; TSBUFLEN];
-            Self::set_k8s_timestamp(&mut tsbuf, pipename);
-            let ts_len = tsbuf.iter().position(|&b| b == 0).unwrap_or(tsbuf.len());
-
-            // bytes: timestamp + "F\n"
-            let bytes_to_be_written = ts_len as u64 + 2;
-            if self.global_max_size > 0
-                && self.total_bytes_written.saturating_add(bytes_to_be_written)
-                    >= self.global_max_size
-            {
+            *self.has_partial_mut(stream) = false;
+            if self.format != LogFormat::K8sFile {
                 return Ok(());
             }
-            self.rotate_if_needed(bytes_to_be_written)?;
+            let record = self.record(stream, b"", false);
+            let bytes_to_be_written = record.len() as u64;
+            if !budget::reserve(bytes_to_be_written) {
+                return Ok(());
+            }
+            self.rotate_if_needed(stream, bytes_to_be_written)?;
 
             self.file
-                .write_all(&tsbuf[..ts_len])
-                .map_err(|e| map_err(e, "failed to write timestamp"))?;
-            self.file
-                .write_all(b"F\n")
+                .write_all(&record)
                 .map_err(|e| map_err(e, "failed to write terminating F-sequence"))?;
             self.file
                 .flush()
                 .map_err(|e| map_err(e, "failed to flush log file"))?;
 
             self.bytes_written = self.bytes_written.saturating_add(bytes_to_be_written);
-            self.total_bytes_written = self.total_bytes_written.saturating_add(bytes_to_be_written);
-
-            if is_stdout {
-                self.stdout_has_partial = false;
-            } else {
-                self.stderr_has_partial = false;
-            };
             return Ok(());
         }
 
         while buflen > 0 {
             let mut line_len: isize = 0;
             let partial = Self::get_line_len(&mut line_len, buf, buflen);
+            let line_slice_len = line_len as usize;
 
-            let mut tsbuf = [0u8; TSBUFLEN];
-            Self::set_k8s_timestamp(&mut tsbuf, pipename);
-            let ts_len = tsbuf.iter().position(|&b| b == 0).unwrap_or(tsbuf.len());
-
-            // timestamp + ("P " or "F ") + line + maybe extra "\n"
-            let mut bytes_to_be_written: u64 = ts_len as u64 + 2 + (line_len as u64);
-            if partial {
-                bytes_to_be_written = bytes_to_be_written.saturating_add(1);
-            }
+            let record = self.record(stream, &buf[..line_slice_len], partial);
+            let bytes_to_be_written = record.len() as u64;
 
-            // Enforce global max before writing.
-            if self.global_max_size > 0
-                && self.total_bytes_written.saturating_add(bytes_to_be_written)
-                    >= self.global_max_size
-            {
+            // Enforce global max before writing, the rest of the data is dropped.
+            if !budget::reserve(bytes_to_be_written) {
+                budget::record_dropped(buflen as u64);
                 break;
             }
 
             // Rotate if needed before writing this record.
-            self.rotate_if_needed(bytes_to_be_written)?;
-
-            // timestamp + stream
-            self.file
-                .write_all(&tsbuf[..ts_len])
-                .map_err(|e| map_err(e, "failed to write timestamp"))?;
-
-            // partial ("P ") vs full ("F ") marker
-            if partial {
-                self.file
-                    .write_all(b"P ")
-                    .map_err(|e| map_err(e, "failed to write partial log tag"))?;
-            } else {
-                self.file
-                    .write_all(b"F ")
-                    .map_err(|e| map_err(e, "failed to write end log tag"))?;
-            }
+            self.rotate_if_needed(stream, bytes_to_be_written)?;
 
-            // actual log bytes
-            let line_slice_len = line_len as usize;
             self.file
-                .write_all(&buf[..line_slice_len])
+                .write_all(&record)
                 .map_err(|e| map_err(e, "failed to write log line"))?;
 
-            // If there was no newline in this chunk, add one
-            if partial {
-                self.file
-                    .write_all(b"\n")
-                    .map_err(|e| map_err(e, "failed to write newline for partial log"))?;
-            }
-
             self.bytes_written = self.bytes_written.saturating_add(bytes_to_be_written);
-            self.total_bytes_written = self.total_bytes_written.saturating_add(bytes_to_be_written);
 
-            if is_stdout {
-                self.stdout_has_partial = partial;
-            } else {
-                self.stderr_has_partial = partial;
-            };
+            *self.has_partial_mut(stream) = partial;
 
             // Advance buffer
             buf = &buf[line_slice_len..];
@@ -784,3 +977,277 @@ impl LogPlugin for FileLogger {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::plugin::parse_log_labels;
+    use tempfile::tempdir;
+
+    /// Returns the (stream, tag, message) of the k8s-file log lines in `path`.
+    fn read_k8s_lines(path: &Path) -> Vec<(String, String, String)> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let mut parts = line.splitn(4, ' ');
+                let ts = parts.next().unwrap();
+                assert!(
+                    chrono::DateTime::parse_from_rfc3339(ts).is_ok(),
+                    "invalid timestamp in {line:?}"
+                );
+                let stream = parts.next().unwrap().to_string();
+                let tag = parts.next().unwrap().to_string();
+                let msg = parts.next().unwrap_or_default().to_string();
+                (stream, tag, msg)
+            })
+            .collect()
+    }
+
+    fn line(stream: &str, tag: &str, msg: &str) -> (String, String, String) {
+        (stream.to_string(), tag.to_string(), msg.to_string())
+    }
+
+    #[test]
+    fn write_tags_partial_and_full_lines() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("ctr.log");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            ..Default::default()
+        };
+        let mut logger = FileLogger::new(&cfg)?;
+        logger.write(LogStream::Stdout, b"hello\nwor")?;
+        logger.write(LogStream::Stderr, b"oops\n")?;
+        logger.write(LogStream::Stdout, b"ld\n")?;
+        logger.write(LogStream::Stdout, b"tail")?;
+        logger.write(LogStream::Stdout, b"")?;
+        // Nothing is pending anymore, so the empty write is a no-op.
+        logger.write(LogStream::Stdout, b"")?;
+
+        assert_eq!(
+            read_k8s_lines(&path),
+            vec![
+                line("stdout", "F", "hello"),
+                line("stdout", "P", "wor"),
+                line("stderr", "F", "oops"),
+                line("stdout", "F", "ld"),
+                line("stdout", "P", "tail"),
+                line("stdout", "F", ""),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_json_file_records() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("ctr.log");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            ..Default::default()
+        };
+        let mut logger = FileLogger::with_format(&cfg, LogFormat::JsonFile)?;
+        logger.write(LogStream::Stdout, b"hello \"world\"\nwor")?;
+        logger.write(LogStream::Stdout, b"")?;
+        logger.write(LogStream::Stderr, b"oops\n")?;
+
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)?
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let logs: Vec<(&str, &str)> = records
+            .iter()
+            .map(|r| (r["stream"].as_str().unwrap(), r["log"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            logs,
+            vec![
+                ("stdout", "hello \"world\"\n"),
+                ("stdout", "wor"),
+                ("stderr", "oops\n"),
+            ]
+        );
+        let time = records[0]["time"].as_str().unwrap();
+        assert!(time.ends_with('Z'), "{time}");
+        assert!(chrono::DateTime::parse_from_rfc3339(time).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn write_json_lines_records_with_metadata() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("ctr.log");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            cid: Some("abc".into()),
+            name: Some("web".into()),
+            log_tag: Some("frontend".into()),
+            log_labels: parse_log_labels(&["env=prod".into(), "team=a".into()])?,
+            ..Default::default()
+        };
+        let mut logger = FileLogger::with_format(&cfg, LogFormat::JsonLines)?;
+        logger.write(LogStream::Stderr, b"oops\npart")?;
+
+        let records: Vec<Value> = std::fs::read_to_string(&path)?
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        let mut first = records[0].clone();
+        let timestamp = first.as_object_mut().unwrap().remove("timestamp").unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp.as_str().unwrap()).is_ok());
+        assert_eq!(
+            first,
+            serde_json::json!({
+                "container_id": "abc",
+                "container_name": "web",
+                "tag": "frontend",
+                "labels": {"env": "prod", "team": "a"},
+                "stream": "stderr",
+                "message": "oops",
+                "partial": false,
+            })
+        );
+        assert_eq!(records[1]["message"], "part");
+        assert_eq!(records[1]["partial"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn write_truncates_at_max_size_with_marker() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("ctr.log");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            max_size: 300,
+            ..Default::default()
+        };
+        let mut logger = FileLogger::new(&cfg)?;
+        for i in 0..8 {
+            logger.write(LogStream::Stdout, format!("line {i}\n").as_bytes())?;
+        }
+
+        let marker = format!("{TRUNCATION_MARKER} of 300 bytes");
+        let lines = read_k8s_lines(&path);
+        assert_eq!(lines[0], line("stdout", "F", &marker));
+        assert_eq!(lines.last().unwrap(), &line("stdout", "F", "line 7"));
+        let size = std::fs::metadata(&path)?.len();
+        assert!(size < 300, "log not truncated: {size} bytes");
+        assert_eq!(logger.bytes_written, size);
+        Ok(())
+    }
+
+    #[test]
+    fn new_rejects_path_outside_allowlist() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let allowed = tmp.path().join("allowed");
+        std::fs::create_dir(&allowed)?;
+        let cfg = |path: PathBuf| LogPluginCfg {
+            path,
+            allowlist_dirs: Some(vec![allowed.clone()]),
+            ..Default::default()
+        };
+
+        FileLogger::new(&cfg(allowed.join("ctr.log")))?;
+        let err = FileLogger::new(&cfg(tmp.path().join("ctr.log")))
+            .err()
+            .unwrap();
+        assert!(err.msg.contains("--log-allowlist-dir"), "{}", err.msg);
+        assert!(!tmp.path().join("ctr.log").exists());
+        // The parent directory is canonicalized before the check.
+        let escaped = allowed.join("..").join("ctr.log");
+        assert!(FileLogger::new(&cfg(escaped)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_files_follows_external_rotation() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("ctr.log");
+        let rotated = tmp.path().join("ctr.log.1");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            ..Default::default()
+        };
+        let mut logger = FileLogger::new(&cfg)?;
+        logger.write(LogStream::Stdout, b"before\n")?;
+        std::fs::rename(&path, &rotated)?;
+        logger.write(LogStream::Stdout, b"late\n")?;
+        logger.reopen_files()?;
+        logger.write(LogStream::Stdout, b"after\n")?;
+
+        assert_eq!(
+            read_k8s_lines(&rotated),
+            vec![line("stdout", "F", "before"), line("stdout", "F", "late")]
+        );
+        assert_eq!(read_k8s_lines(&path), vec![line("stdout", "F", "after")]);
+        assert_eq!(logger.bytes_written, std::fs::metadata(&path)?.len());
+        Ok(())
+    }
+
+    /// Writes 20 lines into the log rotated with `max_files` backups.
+    fn write_rotated(path: &Path, max_files: i32) -> ConmonResult<()> {
+        let cfg = LogPluginCfg {
+            path: path.to_path_buf(),
+            max_size: 200,
+            rotate: true,
+            max_files,
+            ..Default::default()
+        };
+        let mut logger = FileLogger::new(&cfg)?;
+        for i in 0..20 {
+            logger.write(LogStream::Stdout, format!("line {i}\n").as_bytes())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_syncs_log_and_backups() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("ctr.log");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            max_size: 200,
+            rotate: true,
+            max_files: 2,
+            ..Default::default()
+        };
+        let mut logger = FileLogger::new(&cfg)?;
+        for i in 0..20 {
+            logger.write(LogStream::Stdout, format!("line {i}\n").as_bytes())?;
+        }
+        logger.shutdown()?;
+
+        // A backup removed behind our back is not an error.
+        std::fs::remove_file(format!("{}.2", path.display()))?;
+        logger.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_keeps_max_files_backups() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let backup = |path: &Path, n: u32| PathBuf::from(format!("{}.{n}", path.display()));
+
+        let path = tmp.path().join("two.log");
+        write_rotated(&path, 2)?;
+        assert!(backup(&path, 1).exists());
+        assert!(backup(&path, 2).exists());
+        assert!(!backup(&path, 3).exists());
+        let lines = read_k8s_lines(&path);
+        assert_eq!(lines.last().unwrap(), &line("stdout", "F", "line 19"));
+        // The rotated log does not start with the truncation marker.
+        assert!(!lines[0].2.starts_with(TRUNCATION_MARKER));
+        assert_eq!(
+            read_k8s_lines(&backup(&path, 1)).last().unwrap().2,
+            format!("line {}", 19 - lines.len())
+        );
+
+        let path = tmp.path().join("one.log");
+        write_rotated(&path, 1)?;
+        assert!(backup(&path, 1).exists());
+        assert!(!backup(&path, 2).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{Map, Value};
+
+use crate::{
+    error::{ConmonError, ConmonResult},
+    logging::{
+        plugin::{DeliveryPolicy, LogPlugin, LogPluginCfg, LogStream},
+        remote::{
+            LineSplitter, LogRecord, RemoteCfg, RemoteSink, RemoteWorker, hostname, udp_connect,
+        },
+    },
+};
+
+/// Default GELF UDP port.
+const DEFAULT_PORT: u16 = 12201;
+
+/// Default maximum size of a datagram, fitting into the usual MTU.
+const DEFAULT_CHUNK_SIZE: usize = 1420;
+
+/// Default time the records wait for the batch to fill in milliseconds.
+const DEFAULT_BATCH_WAIT_MS: u64 = 100;
+
+/// Magic bytes starting a chunk.
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+
+/// Size of the chunk header: magic, message ID, sequence number and count.
+const CHUNK_HEADER_LEN: usize = 12;
+
+/// Maximum number of chunks of a message accepted by Graylog.
+const MAX_CHUNKS: usize = 128;
+
+/// Syslog severity of the stdout lines.
+const LEVEL_INFO: u8 = 6;
+
+/// Syslog severity of the stderr lines.
+const LEVEL_ERR: u8 = 3;
+
+/// Parses the `udp://host[:port]` GELF address.
+fn parse_address(s: &str) -> ConmonResult<String> {
+    let invalid = |why: &str| ConmonError::new(format!("Invalid GELF address {s}: {why}"), 1);
+    let Some(rest) = s.strip_prefix("udp://") else {
+        return Err(invalid("must start with udp://"));
+    };
+    let rest = rest.trim_end_matches('/');
+    match rest.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            port.parse::<u16>().map_err(|_| invalid("invalid port"))?;
+            if host.is_empty() {
+                return Err(invalid("missing host"));
+            }
+            Ok(rest.to_string())
+        }
+        _ if rest.is_empty() => Err(invalid("missing host")),
+        _ => Ok(format!("{rest}:{DEFAULT_PORT}")),
+    }
+}
+
+/// Returns the additional fields from the comma-separated "name=value"
+/// pairs in `fields`. The names get the "_" prefix required by GELF.
+fn parse_fields(fields: Option<&str>) -> ConmonResult<Map<String, Value>> {
+    let mut out = Map::new();
+    for field in fields.unwrap_or_default().split(',') {
+        let field = field.trim();
+        if field.is_empty() {
+            continue;
+        }
+        match field.split_once('=') {
+            Some((name, value))
+                if !name.trim().is_empty()
+                    && name
+                        .trim()
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+                    && name.trim() != "id" =>
+            {
+                out.insert(
+                    format!("_{}", name.trim()),
+                    Value::from(value.trim().to_string()),
+                );
+            }
+            _ => {
+                return Err(ConmonError::new(
+                    format!("GELF fields must be in format name=value (in '{field}')"),
+                    1,
+                ));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Returns the GELF message ID, unique for the messages sent by conmon.
+fn message_id() -> [u8; 8] {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    (nanos ^ (u64::from(process::id()) << 32) ^ seq.rotate_left(48)).to_be_bytes()
+}
+
+/// Splits the `message` into datagrams of at most `chunk_size` bytes.
+///
+/// # Returns
+///
+/// * The message itself if it fits into single datagram, the chunks
+///   otherwise, or nothing if the message needs more than 128 chunks.
+fn chunks(message: &[u8], chunk_size: usize, id: [u8; 8]) -> Vec<Vec<u8>> {
+    if message.len() <= chunk_size {
+        return vec![message.to_vec()];
+    }
+    let data_len = chunk_size.saturating_sub(CHUNK_HEADER_LEN).max(1);
+    let count = message.len().div_ceil(data_len);
+    if count > MAX_CHUNKS {
+        return Vec::new();
+    }
+    message
+        .chunks(data_len)
+        .enumerate()
+        .map(|(seq, data)| {
+            let mut chunk = Vec::with_capacity(CHUNK_HEADER_LEN + data.len());
+            chunk.extend_from_slice(&CHUNK_MAGIC);
+            chunk.extend_from_slice(&id);
+            chunk.push(seq as u8);
+            chunk.push(count as u8);
+            chunk.extend_from_slice(data);
+            chunk
+        })
+        .collect()
+}
+
+/// Sends the batches to the GELF UDP input.
+struct GelfSink {
+    addr: String,
+
+    /// Fields added to every message.
+    fields: Map<String, Value>,
+
+    /// Maximum size of a datagram.
+    chunk_size: usize,
+
+    /// The socket, dropped on error so the next retry resolves the address
+    /// again.
+    socket: Option<UdpSocket>,
+}
+
+impl GelfSink {
+    /// Returns the GELF message of the record.
+    fn message(&self, r: &LogRecord) -> Vec<u8> {
+        let mut msg = self.fields.clone();
+        msg.insert(
+            "short_message".into(),
+            Value::from(String::from_utf8_lossy(&r.line)),
+        );
+        let ts = r.timestamp.timestamp_micros() as f64 / 1_000_000.0;
+        msg.insert("timestamp".into(), Value::from(ts));
+        let level = match r.stream {
+            LogStream::Stderr => LEVEL_ERR,
+            LogStream::Stdout | LogStream::Stdin => LEVEL_INFO,
+        };
+        msg.insert("level".into(), Value::from(level));
+        msg.insert("_stream".into(), Value::from(r.stream()));
+        if r.partial {
+            msg.insert("_partial".into(), Value::from(true));
+        }
+        Value::Object(msg).to_string().into_bytes()
+    }
+
+    /// Creates the socket connected to the GELF input.
+    fn connect(&self) -> ConmonResult<UdpSocket> {
+        let err = |e: std::io::Error| ConmonError::new(format!("GELF {}: {e}", self.addr), 1);
+        let sock_addr: SocketAddr = self
+            .addr
+            .to_socket_addrs()
+            .map_err(err)?
+            .next()
+            .ok_or_else(|| ConmonError::new(format!("Cannot resolve {}", self.addr), 1))?;
+        udp_connect(sock_addr).map_err(err)
+    }
+}
+
+impl RemoteSink for GelfSink {
+    fn name(&self) -> &str {
+        "gelf"
+    }
+
+    fn send(&mut self, batch: &[LogRecord]) -> ConmonResult<()> {
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => self.connect()?,
+        };
+        for r in batch {
+            let datagrams = chunks(&self.message(r), self.chunk_size, message_id());
+            if datagrams.is_empty() {
+                warn!("Dropping GELF message needing more than {MAX_CHUNKS} chunks");
+            }
+            for datagram in datagrams {
+                socket.send(&datagram)?;
+            }
+        }
+        self.socket = Some(socket);
+        Ok(())
+    }
+}
+
+/// Logging plugin sending the container output to a Graylog GELF UDP input.
+///
+/// Each line is sent as a single uncompressed GELF 1.1 message, chunked when
+/// it does not fit into single datagram. The messages carry the container
+/// ID, name and tag, the stream and the configured static fields.
+pub struct GelfLogger {
+    splitter: LineSplitter,
+    worker: RemoteWorker,
+}
+
+impl GelfLogger {
+    pub fn new(cfg: &LogPluginCfg) -> ConmonResult<Self> {
+        let spec = cfg.path.to_string_lossy();
+        let addr = match cfg.log_opt("gelf-address") {
+            Some(addr) => addr,
+            None if !spec.is_empty() => &spec,
+            None => {
+                return Err(ConmonError::new(
+                    "gelf log driver requires --log-opt gelf-address",
+                    1,
+                ));
+            }
+        };
+        let chunk_size = cfg.log_opt_parse("gelf-chunk-size", DEFAULT_CHUNK_SIZE)?;
+        if chunk_size <= CHUNK_HEADER_LEN {
+            return Err(ConmonError::new(
+                format!("Invalid value '{chunk_size}' of log option gelf-chunk-size"),
+                1,
+            ));
+        }
+
+        let mut fields = Map::new();
+        fields.insert("version".into(), Value::from("1.1"));
+        fields.insert(
+            "host".into(),
+            Value::from(hostname().unwrap_or_else(|| "localhost".to_string())),
+        );
+        if let Some(cid) = &cfg.cid {
+            fields.insert("_container_id".into(), Value::from(cid.clone()));
+        }
+        if let Some(name) = &cfg.name {
+            fields.insert("_container_name".into(), Value::from(name.clone()));
+        }
+        if let Some(tag) = &cfg.log_tag {
+            fields.insert("_tag".into(), Value::from(tag.clone()));
+        }
+        fields.extend(parse_fields(cfg.log_opt("gelf-fields"))?);
+
+        let sink = GelfSink {
+            addr: parse_address(addr)?,
+            fields,
+            chunk_size,
+            socket: None,
+        };
+        let remote_cfg = RemoteCfg {
+            flush_interval: Duration::from_millis(
+                cfg.log_opt_parse("gelf-batch-wait", DEFAULT_BATCH_WAIT_MS)?,
+            ),
+            policy: cfg.delivery_policy.unwrap_or(DeliveryPolicy::DropOldest),
+            ..Default::default()
+        };
+        Ok(Self {
+            splitter: LineSplitter::new(cfg.line_max),
+            worker: RemoteWorker::new(remote_cfg, Box::new(sink)),
+        })
+    }
+}
+
+impl LogPlugin for GelfLogger {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        self.worker.push(self.splitter.split(stream, data))?;
+        if data.is_empty() {
+            self.worker.flush();
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.worker.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sink(addr: &str, chunk_size: usize) -> ConmonResult<GelfSink> {
+        let mut fields = Map::new();
+        fields.insert("version".into(), Value::from("1.1"));
+        fields.insert("host".into(), Value::from("node1"));
+        fields.extend(parse_fields(Some("env=prod, team = web"))?);
+        Ok(GelfSink {
+            addr: addr.to_string(),
+            fields,
+            chunk_size,
+            socket: None,
+        })
+    }
+
+    #[test]
+    fn address_and_fields_are_parsed() -> ConmonResult<()> {
+        assert_eq!(parse_address("udp://graylog")?, "graylog:12201");
+        assert_eq!(parse_address("udp://10.0.0.1:5555/")?, "10.0.0.1:5555");
+        assert!(parse_address("tcp://graylog:12201").is_err());
+        assert!(parse_address("udp://:12201").is_err());
+
+        let fields = parse_fields(Some("env=prod,dc=eu-1"))?;
+        assert_eq!(fields["_env"], "prod");
+        assert_eq!(fields["_dc"], "eu-1");
+        assert!(parse_fields(Some("id=1")).is_err());
+        assert!(parse_fields(Some("bad name=1")).is_err());
+        assert!(parse_fields(Some("novalue")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn message_carries_level_stream_and_fields() -> ConmonResult<()> {
+        let sink = sink("127.0.0.1:12201", DEFAULT_CHUNK_SIZE)?;
+        let mut splitter = LineSplitter::default();
+        let records = splitter.split(LogStream::Stderr, b"oops\n");
+        let msg: Value = serde_json::from_slice(&sink.message(&records[0]))?;
+        assert_eq!(msg["version"], "1.1");
+        assert_eq!(msg["host"], "node1");
+        assert_eq!(msg["short_message"], "oops");
+        assert_eq!(msg["level"], 3);
+        assert_eq!(msg["_stream"], "stderr");
+        assert_eq!(msg["_env"], "prod");
+        assert_eq!(msg["_team"], "web");
+        assert!(msg["timestamp"].as_f64().unwrap() > 0.0);
+        assert!(msg.get("_partial").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn large_messages_are_chunked() {
+        let id = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(chunks(b"short", 100, id), vec![b"short".to_vec()]);
+
+        let message: Vec<u8> = (0..50u8).collect();
+        let parts = chunks(&message, 32, id);
+        assert_eq!(parts.len(), 3);
+        for (seq, part) in parts.iter().enumerate() {
+            assert!(part.len() <= 32);
+            assert_eq!(&part[..2], &CHUNK_MAGIC);
+            assert_eq!(&part[2..10], &id);
+            assert_eq!(part[10], seq as u8);
+            assert_eq!(part[11], 3);
+        }
+        let joined: Vec<u8> = parts.iter().flat_map(|p| p[12..].to_vec()).collect();
+        assert_eq!(joined, message);
+
+        assert!(chunks(&vec![0u8; 129 * 20], 32, id).is_empty());
+    }
+
+    #[test]
+    fn messages_are_sent_over_udp() -> ConmonResult<()> {
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut sink = sink(&server.local_addr()?.to_string(), 64)?;
+        let mut splitter = LineSplitter::default();
+        sink.send(&splitter.split(LogStream::Stdout, b"hello\n"))?;
+
+        let mut message = Vec::new();
+        let mut buf = [0u8; 128];
+        loop {
+            let n = server.recv(&mut buf)?;
+            assert_eq!(&buf[..2], &CHUNK_MAGIC);
+            message.extend_from_slice(&buf[12..n]);
+            if buf[10] + 1 == buf[11] {
+                break;
+            }
+        }
+        let msg: Value = serde_json::from_slice(&message)?;
+        assert_eq!(msg["short_message"], "hello");
+        assert_eq!(msg["level"], 6);
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::error::{ConmonError, ConmonResult};
+
+/// Timeout for connecting to the server and for single read or write.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parsed `http://` or `https://` URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    /// True for `https://`.
+    pub tls: bool,
+
+    /// Host name or IP address.
+    pub host: String,
+
+    /// TCP port.
+    pub port: u16,
+
+    /// Path including the query string, always starting with '/'.
+    pub path: String,
+}
+
+impl Url {
+    /// Parses the `http[s]://host[:port][/path]` URL.
+    ///
+    /// The `https://` URLs are only supported when conmon is built with the
+    /// `tls` feature. Otherwise TLS has to be terminated by a local proxy or
+    /// sidecar.
+    pub fn parse(s: &str) -> ConmonResult<Self> {
+        let invalid = |why: &str| ConmonError::new(format!("Invalid URL {s}: {why}"), 1);
+        let (tls, rest) = if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            if !cfg!(feature = "tls") {
+                return Err(invalid(
+                    "https is not supported by this build, use a TLS-terminating proxy",
+                ));
+            }
+            (true, rest)
+        } else {
+            return Err(invalid("must start with http:// or https://"));
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse::<u16>().map_err(|_| invalid("invalid port"))?,
+            ),
+            _ => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Returns the value of the Host header.
+    pub fn host_header(&self) -> String {
+        if self.port == if self.tls { 443 } else { 80 } {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Response to the HTTP request.
+#[derive(Debug)]
+pub struct Response {
+    /// HTTP status code.
+    pub status: u16,
+
+    /// Raw response body.
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Returns an error if the status code does not indicate success.
+    pub fn check(self, what: &str) -> ConmonResult<Self> {
+        if (200..300).contains(&self.status) {
+            return Ok(self);
+        }
+        let body = String::from_utf8_lossy(&self.body);
+        Err(ConmonError::new(
+            format!(
+                "{what} failed with HTTP status {}: {}",
+                self.status,
+                body.trim()
+            ),
+            1,
+        ))
+    }
+}
+
+/// Sends single HTTP/1.1 request and returns the response.
+///
+/// # Arguments
+///
+/// * `method` - HTTP method.
+/// * `url` - The server and the path.
+/// * `headers` - Additional headers. Host, Content-Length and Connection are added automatically.
+/// * `body` - Request body.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the server cannot be reached or the response cannot be parsed.
+pub fn request(
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> ConmonResult<Response> {
+    let err = |e: std::io::Error| {
+        ConmonError::new(format!("HTTP request to {}: {e}", url.host_header()), 1)
+    };
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .map_err(err)?
+        .next()
+        .ok_or_else(|| ConmonError::new(format!("Cannot resolve {}", url.host), 1))?;
+    let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT).map_err(err)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(err)?;
+    stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(err)?;
+    let mut stream = connect_stream(url, stream)?;
+
+    let mut head = format!(
+        "{method} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host_header(),
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).map_err(err)?;
+    stream.write_all(body).map_err(err)?;
+
+    let mut raw = Vec::new();
+    match stream.read_to_end(&mut raw) {
+        // Some servers close the TLS connection without close_notify, the
+        // response is complete anyway with `Connection: close`.
+        Err(e) if url.tls && e.kind() == std::io::ErrorKind::UnexpectedEof => {}
+        r => {
+            r.map_err(err)?;
+        }
+    }
+    parse_response(&raw)
+}
+
+/// Byte stream to the HTTP server.
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// Returns the stream over the TCP connection `tcp`, with TLS for `https`.
+fn connect_stream(url: &Url, tcp: TcpStream) -> ConmonResult<Box<dyn Stream>> {
+    #[cfg(feature = "tls")]
+    if url.tls {
+        return Ok(Box::new(crate::logging::tls::connect(&url.host, tcp)?));
+    }
+    debug_assert!(!url.tls);
+    Ok(Box::new(tcp))
+}
+
+/// Parses the status code and the body from the raw HTTP response.
+fn parse_response(raw: &[u8]) -> ConmonResult<Response> {
+    let invalid = || ConmonError::new("Invalid HTTP response", 1);
+    let head_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&raw[..head_end]);
+    let status = head
+        .lines()
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+    Ok(Response {
+        status,
+        body: raw[head_end + 4..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn url_parse() -> ConmonResult<()> {
+        let url = Url::parse("http://minio:9000/bucket/prefix")?;
+        assert_eq!(url.host, "minio");
+        assert_eq!(url.port, 9000);
+        assert_eq!(url.path, "/bucket/prefix");
+        assert_eq!(url.host_header(), "minio:9000");
+
+        let url = Url::parse("http://loki")?;
+        assert_eq!(url.port, 80);
+        assert_eq!(url.path, "/");
+        assert_eq!(url.host_header(), "loki");
+
+        assert!(!url.tls);
+        assert!(Url::parse("loki:3100").is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn url_parse_https() -> ConmonResult<()> {
+        let url = Url::parse("https://splunk:8088/services/collector")?;
+        assert!(url.tls);
+        assert_eq!(url.port, 8088);
+        assert_eq!(url.host_header(), "splunk:8088");
+
+        let url = Url::parse("https://loki")?;
+        assert_eq!(url.port, 443);
+        assert_eq!(url.host_header(), "loki");
+        Ok(())
+    }
+
+    #[test]
+    fn request_roundtrip() -> ConmonResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let mut data = Vec::new();
+            while !data.ends_with(b"body") {
+                let n = conn.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                data.extend_from_slice(&buf[..n]);
+            }
+            conn.write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8_lossy(&data).into_owned()
+        });
+
+        let url = Url::parse(&format!("http://127.0.0.1:{port}/push"))?;
+        let resp = request(
+            "POST",
+            &url,
+            &[("X-Test".to_string(), "1".to_string())],
+            b"body",
+        )?
+        .check("push")?;
+        assert_eq!(resp.status, 204);
+
+        let sent = server.join().unwrap();
+        assert!(sent.starts_with("POST /push HTTP/1.1\r\n"));
+        assert!(sent.contains("X-Test: 1\r\n"));
+        assert!(sent.ends_with("\r\n\r\nbody"));
+        Ok(())
+    }
+}
@@ -1,11 +1,27 @@
 use crate::{
     error::{ConmonError, ConmonResult},
-    logging::plugin::{LogPlugin, LogPluginCfg},
+    logging::{
+        file_logger::FileLogger,
+        plugin::{
+            ContainerExit, ContainerMetadata, DeliveryPolicy, LogPlugin, LogPluginCfg, LogStream,
+        },
+    },
+    metrics::metrics,
 };
+use log::warn;
+use nix::libc;
+use std::collections::VecDeque;
+use std::time::Duration;
 use systemd::journal;
 
 const STDIO_BUF_SIZE: usize = 8192;
 
+/// Maximum number of messages kept for retry with the drop-oldest policy.
+const MAX_PENDING_MESSAGES: usize = 1024;
+
+/// Maximum delay between the retries with the block policy.
+const MAX_BLOCK_BACKOFF: Duration = Duration::from_secs(1);
+
 /// Logging plugin for journald.
 pub struct JournaldLogger {
     /// Buffer for partial (not ending with new-lline) stdout log messages.
@@ -22,69 +38,294 @@ pub struct JournaldLogger {
 
     // Log plugin configuration.
     cfg: LogPluginCfg,
+
+    /// Rate limiting detection state.
+    rate_limit: RateLimitState,
 }
 
-/// Helper function to return the number of occurence of `ch` in `str`.
-fn count_chars_in_string(s: &str, ch: char) -> usize {
-    s.chars().filter(|&c| c == ch).count()
+/// Message refused by journald and kept for retry.
+struct PendingMessage {
+    is_stdout: bool,
+    fields: Vec<String>,
 }
 
-/// Helper function to validate the label name.
-fn is_valid_label_name(s: &str) -> bool {
-    let chars = s.chars().peekable();
-    for c in chars {
-        if c == '=' {
-            return true;
+/// Tracks the messages journald refused because of rate limiting.
+///
+/// While journald is rate limiting us, the delivery policy decides what
+/// happens with the messages: with `drop-newest`, the refused messages are
+/// lost; with `drop-oldest`, they are kept for retry and the oldest ones are
+/// lost once too many are pending; with `block`, the send is retried until
+/// journald accepts the message. The lost messages are spilled to the
+/// fallback file if configured, otherwise dropped. Once journald accepts
+/// messages again, a marker record with the summary is emitted.
+#[derive(Default)]
+struct RateLimitState {
+    /// Delivery policy, drop-newest if not set.
+    policy: Option<DeliveryPolicy>,
+
+    /// Messages waiting for retry (drop-oldest only).
+    pending: VecDeque<PendingMessage>,
+
+    /// True if journald refused the last message.
+    limited: bool,
+
+    /// Number of messages dropped while rate limited.
+    dropped: u64,
+
+    /// Number of messages written to the `fallback` while rate limited.
+    spilled: u64,
+
+    /// Optional fallback file logger.
+    fallback: Option<FileLogger>,
+}
+
+impl RateLimitState {
+    /// Records that journald started rate limiting us.
+    fn set_limited(&mut self) {
+        if !self.limited {
+            warn!("journald is rate limiting container output");
+            self.limited = true;
+        }
+    }
+
+    /// Handles a message refused by journald.
+    fn refused(&mut self, is_stdout: bool, message: &[u8]) -> ConmonResult<()> {
+        self.set_limited();
+        self.lost(is_stdout, message)
+    }
+
+    /// Spills the message which cannot be delivered to the fallback file,
+    /// or drops it.
+    fn lost(&mut self, is_stdout: bool, message: &[u8]) -> ConmonResult<()> {
+        match self.fallback.as_mut() {
+            Some(fallback) => {
+                let stream = if is_stdout {
+                    LogStream::Stdout
+                } else {
+                    LogStream::Stderr
+                };
+                fallback.write(stream, message)?;
+                self.spilled += 1;
+            }
+            None => {
+                self.dropped += 1;
+                metrics().record_log_drop("journald", 1);
+            }
         }
-        if !c.is_ascii_uppercase() && !c.is_ascii_digit() && c != '_' {
-            return false;
+        Ok(())
+    }
+
+    /// Returns the marker message to emit once journald accepts messages again.
+    fn marker(&self) -> Option<String> {
+        if !self.limited {
+            return None;
         }
+        Some(format!(
+            "conmon: journald rate limited container output, {} messages dropped, {} messages written to fallback log",
+            self.dropped, self.spilled
+        ))
+    }
+
+    /// Resets the state after the marker has been emitted.
+    fn reset(&mut self) {
+        self.limited = false;
+        self.dropped = 0;
+        self.spilled = 0;
     }
-    true
+}
+
+/// Helper function to validate the label name.
+fn is_valid_label_name(s: &str) -> bool {
+    s.chars()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
 }
 
 impl JournaldLogger {
     pub fn new(cfg: &LogPluginCfg) -> ConmonResult<Self> {
-        // Validate the labels.
-        for l in &cfg.log_labels {
-            if l.starts_with('=') {
-                return Err(ConmonError::new(
-                    format!(
-                        "Container labels must be in format LABEL=VALUE (no LABEL present in '{}')",
-                        l
-                    ),
-                    1,
-                ));
-            }
-            if count_chars_in_string(l, '=') != 1 {
-                return Err(ConmonError::new(
-                    format!(
-                        "Container labels must be in format LABEL=VALUE (none or more than one '=' present in '{}')",
-                        l
-                    ),
-                    1,
-                ));
-            }
-            if !is_valid_label_name(l) {
+        // Validate the label names, they are used as journal fields.
+        for (name, value) in &cfg.log_labels {
+            if !is_valid_label_name(name) {
                 return Err(ConmonError::new(
                     format!(
-                        "Container label names must contain only uppercase letters, numbers and underscore (in '{}')",
-                        l
+                        "Container label names must contain only uppercase letters, numbers and underscore (in '{}={}')",
+                        name, value
                     ),
                     1,
                 ));
             }
         }
 
+        // The fallback file uses k8s-file format, which does not support
+        // tags and labels.
+        let fallback = match &cfg.journald_fallback {
+            Some(path) => {
+                let mut fallback_cfg = cfg.clone();
+                fallback_cfg.path = path.clone();
+                fallback_cfg.log_tag = None;
+                fallback_cfg.log_labels.clear();
+                Some(FileLogger::new(&fallback_cfg)?)
+            }
+            None => None,
+        };
+
         Ok(Self {
             stdout_buf: [0; STDIO_BUF_SIZE],
             stdout_buf_len: 0,
             stderr_buf: [0; STDIO_BUF_SIZE],
             stderr_buf_len: 0,
             cfg: cfg.clone(),
+            rate_limit: RateLimitState {
+                policy: cfg.delivery_policy,
+                fallback,
+                ..Default::default()
+            },
         })
     }
 
+    /// Returns the journald fields identifying the container.
+    fn container_fields(cfg: &LogPluginCfg) -> Vec<String> {
+        let mut fields: Vec<String> = Vec::new();
+        if let Some(cid) = &cfg.cid {
+            fields.push(format!("CONTAINER_ID={}", cid));
+        }
+
+        if let Some(cuuid) = &cfg.cuuid {
+            fields.push(format!("CONTAINER_ID_FULL={}", cuuid));
+        }
+
+        if let Some(tag) = &cfg.log_tag {
+            fields.push(format!("CONTAINER_TAG={}", tag));
+        }
+
+        if let Some(name) = &cfg.name {
+            fields.push(format!("CONTAINER_NAME={}", name));
+        }
+
+        if let Some(cuuid) = &cfg.cuuid {
+            fields.push(format!("SYSLOG_IDENTIFIER={}", Self::truncate_cuuid(cuuid)));
+        }
+
+        for (name, value) in &cfg.log_labels {
+            fields.push(format!("{}={}", name, value));
+        }
+        fields
+    }
+
+    /// Returns true if journald refused the message because it cannot keep up.
+    fn is_rate_limited(rc: i32) -> bool {
+        rc == -libc::EAGAIN || rc == -libc::ENOBUFS
+    }
+
+    /// Sends the fields to journald and returns the negative errno on failure.
+    fn send_fields(fields: &[String]) -> i32 {
+        // journal::send(&[&str]) wants &str slices, so we build a view
+        let field_slices: Vec<&str> = fields.iter().map(|s| s.as_str()).collect();
+        journal::send(&field_slices)
+    }
+
+    /// Returns an error if journald failed for other reason than rate limiting.
+    fn check_rc(rc: i32) -> ConmonResult<()> {
+        if rc < 0 && !Self::is_rate_limited(rc) {
+            return Err(ConmonError::new(
+                format!("Error calling journal::send: {}", rc),
+                1,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Emits the marker record if journald rate limited us before and
+    /// resets the rate limiting state if journald accepted it.
+    fn send_marker(rate_limit: &mut RateLimitState, cfg: &LogPluginCfg) {
+        let Some(marker) = rate_limit.marker() else {
+            return;
+        };
+        let mut marker_fields = vec![format!("MESSAGE={marker}"), "PRIORITY=4".to_string()];
+        marker_fields.extend(Self::container_fields(cfg));
+        // If we are still rate limited, keep counting.
+        if !Self::is_rate_limited(Self::send_fields(&marker_fields)) {
+            rate_limit.reset();
+        }
+    }
+
+    /// Sends the message to journald, applying the delivery policy when
+    /// journald cannot keep up.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate_limit` - The rate limiting state.
+    /// * `cfg` - Log plugin configuration.
+    /// * `is_stdout` - True if the message comes from stdout.
+    /// * `fields` - The journald fields, starting with "MESSAGE=".
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if journald fails for other reason than rate limiting.
+    fn deliver(
+        rate_limit: &mut RateLimitState,
+        cfg: &LogPluginCfg,
+        is_stdout: bool,
+        fields: Vec<String>,
+    ) -> ConmonResult<()> {
+        match rate_limit.policy.unwrap_or(DeliveryPolicy::DropNewest) {
+            DeliveryPolicy::Block => {
+                let mut backoff = Duration::from_millis(10);
+                loop {
+                    let rc = Self::send_fields(&fields);
+                    if !Self::is_rate_limited(rc) {
+                        rate_limit.reset();
+                        return Self::check_rc(rc);
+                    }
+                    rate_limit.set_limited();
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BLOCK_BACKOFF);
+                }
+            }
+            DeliveryPolicy::DropNewest => {
+                // If journald rate limited us before, emit the marker first. It
+                // also probes whether journald accepts messages again.
+                Self::send_marker(rate_limit, cfg);
+                let rc = if rate_limit.limited {
+                    -libc::EAGAIN
+                } else {
+                    Self::send_fields(&fields)
+                };
+                if Self::is_rate_limited(rc) {
+                    // The message without the "MESSAGE=" prefix.
+                    rate_limit.refused(is_stdout, &fields[0].as_bytes()[8..])?;
+                }
+                Self::check_rc(rc)
+            }
+            DeliveryPolicy::DropOldest => {
+                rate_limit
+                    .pending
+                    .push_back(PendingMessage { is_stdout, fields });
+                while let Some(msg) = rate_limit.pending.front() {
+                    let rc = Self::send_fields(&msg.fields);
+                    if Self::is_rate_limited(rc) {
+                        rate_limit.set_limited();
+                        break;
+                    }
+                    rate_limit.pending.pop_front();
+                    Self::check_rc(rc)?;
+                }
+                while rate_limit.pending.len() > MAX_PENDING_MESSAGES {
+                    if let Some(msg) = rate_limit.pending.pop_front() {
+                        rate_limit.lost(msg.is_stdout, &msg.fields[0].as_bytes()[8..])?;
+                    }
+                }
+                if rate_limit.pending.is_empty() {
+                    if rate_limit.dropped + rate_limit.spilled > 0 {
+                        Self::send_marker(rate_limit, cfg);
+                    } else {
+                        rate_limit.reset();
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
     /// Parses the journald log message priority from the log message.
     fn parse_priority_prefix(buf: &[u8], priority: &mut i32, message_start: &mut usize) -> i32 {
         if buf.len() < 3 {
@@ -129,6 +370,31 @@ impl JournaldLogger {
             None => s, // fewer than 12 chars
         }
     }
+
+    /// Sends the record of the container lifecycle `event` ("start" or
+    /// "exit") with `message` and the `extra` fields.
+    fn send_event(&self, event: &str, message: String, extra: Vec<String>) -> ConmonResult<()> {
+        let mut fields = vec![
+            format!("MESSAGE={message}"),
+            "PRIORITY=5".to_string(),
+            format!("CONTAINER_EVENT={event}"),
+        ];
+        fields.extend(extra);
+        fields.extend(Self::container_fields(&self.cfg));
+        Self::check_rc(Self::send_fields(&fields))
+    }
+}
+
+/// Returns the message of the container exit record.
+fn exit_message(exit: &ContainerExit) -> String {
+    let mut msg = match exit.signal {
+        Some(signal) => format!("Container killed by {signal} (exit code {})", exit.code),
+        None => format!("Container exited with code {}", exit.code),
+    };
+    if exit.oom {
+        msg.push_str(", out of memory");
+    }
+    msg
 }
 
 impl LogPlugin for JournaldLogger {
@@ -136,7 +402,46 @@ impl LogPlugin for JournaldLogger {
         Ok(())
     }
 
-    fn write(&mut self, is_stdout: bool, data: &[u8]) -> ConmonResult<()> {
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        match self.rate_limit.fallback.as_mut() {
+            Some(fallback) => fallback.reopen_files(),
+            None => Ok(()),
+        }
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        match self.rate_limit.fallback.as_mut() {
+            Some(fallback) => fallback.shutdown(),
+            None => Ok(()),
+        }
+    }
+
+    fn on_container_start(&mut self, pid: i32, _metadata: &ContainerMetadata) -> ConmonResult<()> {
+        self.send_event(
+            "start",
+            format!("Container started with PID {pid}"),
+            vec![format!("CONTAINER_PID={pid}")],
+        )
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        let mut extra = vec![format!("CONTAINER_EXIT_CODE={}", exit.code)];
+        if let Some(signal) = exit.signal {
+            extra.push(format!("CONTAINER_EXIT_SIGNAL={signal}"));
+        }
+        if exit.oom {
+            extra.push("CONTAINER_OOM=true".to_string());
+        }
+        self.send_event("exit", exit_message(exit), extra)
+    }
+
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        // The journal entries carry only the container output.
+        if stream == LogStream::Stdin {
+            return Ok(());
+        }
+        let is_stdout = stream == LogStream::Stdout;
+
         // Select the right partial buffer.
         let (partial_buf, partial_buf_len) = if is_stdout {
             (&mut self.stdout_buf[..], &mut self.stdout_buf_len)
@@ -206,45 +511,13 @@ impl LogPlugin for JournaldLogger {
             fields.push(priority_txt);
 
             // Other fields.
-            if let Some(cid) = &self.cfg.cid {
-                fields.push(format!("CONTAINER_ID={}", cid));
-            }
-
-            if let Some(cuuid) = &self.cfg.cuuid {
-                fields.push(format!("CONTAINER_ID_FULL={}", cuuid));
-            }
-
-            if let Some(tag) = &self.cfg.log_tag {
-                fields.push(format!("CONTAINER_TAG={}", tag));
-            }
-
-            if let Some(name) = &self.cfg.name {
-                fields.push(format!("CONTAINER_NAME={}", name));
-            }
-
-            if let Some(cuuid) = &self.cfg.cuuid {
-                fields.push(format!("SYSLOG_IDENTIFIER={}", Self::truncate_cuuid(cuuid)));
-            }
+            fields.extend(Self::container_fields(&self.cfg));
 
             if partial && !self.cfg.no_container_partial_message {
                 fields.push("CONTAINER_PARTIAL_MESSAGE=true".to_string());
             }
 
-            for label in &self.cfg.log_labels {
-                // label is something like "foo=bar"
-                fields.push(label.clone());
-            }
-
-            // journal::send(&[&str]) wants &str slices, so we build a view
-            let field_slices: Vec<&str> = fields.iter().map(|s| s.as_str()).collect();
-
-            let rc = journal::send(&field_slices);
-            if rc < 0 {
-                return Err(ConmonError::new(
-                    format!("Error calling journal::send: {}", rc),
-                    1,
-                ));
-            }
+            Self::deliver(&mut self.rate_limit, &self.cfg, is_stdout, fields)?;
 
             // Advance in the input buffer and reset partial buffer
             if buflen > 0 {
@@ -257,3 +530,65 @@ impl LogPlugin for JournaldLogger {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::Signal;
+    use tempfile::tempdir;
+
+    #[test]
+    fn exit_message_describes_exit() {
+        let mut exit = ContainerExit {
+            code: 3,
+            signal: None,
+            oom: false,
+        };
+        assert_eq!(exit_message(&exit), "Container exited with code 3");
+        exit.code = 137;
+        exit.signal = Some(Signal::SIGKILL);
+        exit.oom = true;
+        assert_eq!(
+            exit_message(&exit),
+            "Container killed by SIGKILL (exit code 137), out of memory"
+        );
+    }
+
+    #[test]
+    fn rate_limit_drops_without_fallback() -> ConmonResult<()> {
+        let mut state = RateLimitState::default();
+        assert_eq!(state.marker(), None);
+
+        state.refused(true, b"foo\n")?;
+        state.refused(false, b"bar\n")?;
+        let marker = state.marker().unwrap();
+        assert!(marker.contains("2 messages dropped"), "{marker}");
+        assert!(marker.contains("0 messages written"), "{marker}");
+
+        state.reset();
+        assert_eq!(state.marker(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limit_spills_to_fallback() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("fallback.log");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            ..Default::default()
+        };
+        let mut state = RateLimitState {
+            fallback: Some(FileLogger::new(&cfg)?),
+            ..Default::default()
+        };
+
+        state.refused(true, b"spilled line\n")?;
+        let contents = std::fs::read_to_string(&path)?;
+        assert!(contents.contains("stdout F spilled line"), "{contents}");
+
+        let marker = state.marker().unwrap();
+        assert!(marker.contains("1 messages written"), "{marker}");
+        Ok(())
+    }
+}
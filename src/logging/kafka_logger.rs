@@ -0,0 +1,683 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::{
+    error::{ConmonError, ConmonResult},
+    logging::{
+        plugin::{DeliveryPolicy, LogPlugin, LogPluginCfg, LogStream},
+        remote::{LineSplitter, LogRecord, RemoteCfg, RemoteSink, RemoteWorker},
+    },
+};
+
+/// Timeout for connecting to the broker and for single read or write.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default topic name template.
+const DEFAULT_TOPIC: &str = "conmon-{cid}";
+
+/// Default maximum size of the batch in bytes.
+const DEFAULT_BATCH_SIZE: usize = 1024 * 1024;
+
+/// Default time the records wait for the batch to fill in milliseconds.
+const DEFAULT_LINGER_MS: u64 = 100;
+
+/// Client ID sent to the brokers.
+const CLIENT_ID: &str = "conmon";
+
+/// Kafka API keys and versions used by the driver.
+const API_PRODUCE: i16 = 0;
+const API_PRODUCE_VERSION: i16 = 3;
+const API_METADATA: i16 = 3;
+const API_METADATA_VERSION: i16 = 4;
+
+/// Kafka error codes which are worth retrying.
+const RETRIABLE_ERRORS: &[i16] = &[3, 5, 6, 7, 13, 19, 20, 56];
+
+/// Returns CRC-32C (Castagnoli) checksum of `data`.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Returns 32-bit FNV-1a hash of `data`.
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5u32, |h, &b| {
+        (h ^ b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Writes the Kafka protocol primitives into a buffer.
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn i8(&mut self, v: i8) -> &mut Self {
+        self.buf.push(v as u8);
+        self
+    }
+
+    fn i16(&mut self, v: i16) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    fn i32(&mut self, v: i32) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    fn i64(&mut self, v: i64) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    /// Writes zigzag-encoded variable-length integer.
+    fn varint(&mut self, v: i64) -> &mut Self {
+        let mut z = ((v << 1) ^ (v >> 63)) as u64;
+        while z >= 0x80 {
+            self.buf.push((z as u8) | 0x80);
+            z >>= 7;
+        }
+        self.buf.push(z as u8);
+        self
+    }
+
+    /// Writes bytes prefixed with varint length.
+    fn varbytes(&mut self, v: &[u8]) -> &mut Self {
+        self.varint(v.len() as i64);
+        self.buf.extend_from_slice(v);
+        self
+    }
+
+    /// Writes string prefixed with int16 length.
+    fn string(&mut self, v: &str) -> &mut Self {
+        self.i16(v.len() as i16);
+        self.buf.extend_from_slice(v.as_bytes());
+        self
+    }
+
+    /// Writes bytes prefixed with int32 length.
+    fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.i32(v.len() as i32);
+        self.buf.extend_from_slice(v);
+        self
+    }
+}
+
+/// Reads the Kafka protocol primitives from a buffer.
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> ConmonResult<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(ConmonError::new("Truncated Kafka response", 1));
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn i16(&mut self) -> ConmonResult<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> ConmonResult<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> ConmonResult<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads nullable string prefixed with int16 length.
+    fn string(&mut self) -> ConmonResult<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        Ok(Some(
+            String::from_utf8_lossy(self.take(len as usize)?).into_owned(),
+        ))
+    }
+
+    /// Reads array length, treating null array as empty.
+    fn array_len(&mut self) -> ConmonResult<usize> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+}
+
+/// Encodes the records as a single uncompressed Kafka record batch (magic 2).
+fn encode_record_batch(key: &[u8], batch: &[LogRecord]) -> Vec<u8> {
+    let base_ts = batch
+        .first()
+        .map(|r| r.timestamp.timestamp_millis())
+        .unwrap_or(0);
+    let max_ts = batch
+        .iter()
+        .map(|r| r.timestamp.timestamp_millis())
+        .max()
+        .unwrap_or(base_ts);
+
+    let mut records = Encoder::default();
+    for (i, r) in batch.iter().enumerate() {
+        let mut rec = Encoder::default();
+        rec.i8(0)
+            .varint(r.timestamp.timestamp_millis() - base_ts)
+            .varint(i as i64)
+            .varbytes(key)
+            .varbytes(&r.line);
+        let mut headers: Vec<(&str, &[u8])> = vec![("stream", r.stream().as_bytes())];
+        if r.partial {
+            headers.push(("partial", b"true"));
+        }
+        rec.varint(headers.len() as i64);
+        for (name, value) in headers {
+            rec.varbytes(name.as_bytes()).varbytes(value);
+        }
+        records.varbytes(&rec.buf);
+    }
+
+    // The fields covered by the CRC.
+    let mut body = Encoder::default();
+    body.i16(0) // attributes: no compression, create time
+        .i32(batch.len() as i32 - 1)
+        .i64(base_ts)
+        .i64(max_ts)
+        .i64(-1) // producer ID
+        .i16(-1) // producer epoch
+        .i32(-1) // base sequence
+        .i32(batch.len() as i32);
+    body.buf.extend_from_slice(&records.buf);
+
+    let mut out = Encoder::default();
+    out.i64(0) // base offset
+        .i32((4 + 1 + 4 + body.buf.len()) as i32)
+        .i32(-1) // partition leader epoch
+        .i8(2) // magic
+        .i32(crc32c(&body.buf) as i32);
+    out.buf.extend_from_slice(&body.buf);
+    out.buf
+}
+
+/// Connection to single Kafka broker.
+struct BrokerConn {
+    stream: TcpStream,
+    correlation_id: i32,
+}
+
+impl BrokerConn {
+    fn connect(addr: &str) -> ConmonResult<Self> {
+        let err = |e: std::io::Error| ConmonError::new(format!("Kafka broker {addr}: {e}"), 1);
+        let sock_addr = addr
+            .to_socket_addrs()
+            .map_err(err)?
+            .next()
+            .ok_or_else(|| ConmonError::new(format!("Cannot resolve {addr}"), 1))?;
+        let stream = TcpStream::connect_timeout(&sock_addr, IO_TIMEOUT).map_err(err)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(err)?;
+        stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(err)?;
+        Ok(Self {
+            stream,
+            correlation_id: 0,
+        })
+    }
+
+    /// Sends the request and returns the response body, without the
+    /// correlation ID, if `expect_response` is set.
+    fn call(
+        &mut self,
+        api_key: i16,
+        api_version: i16,
+        body: &[u8],
+        expect_response: bool,
+    ) -> ConmonResult<Vec<u8>> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut req = Encoder::default();
+        req.i16(api_key)
+            .i16(api_version)
+            .i32(self.correlation_id)
+            .string(CLIENT_ID);
+        req.buf.extend_from_slice(body);
+        let mut framed = Encoder::default();
+        framed.bytes(&req.buf);
+        self.stream.write_all(&framed.buf)?;
+        if !expect_response {
+            return Ok(Vec::new());
+        }
+
+        let mut size = [0u8; 4];
+        self.stream.read_exact(&mut size)?;
+        let size = i32::from_be_bytes(size).max(0) as usize;
+        let mut resp = vec![0u8; size];
+        self.stream.read_exact(&mut resp)?;
+        let mut dec = Decoder { buf: &resp };
+        if dec.i32()? != self.correlation_id {
+            return Err(ConmonError::new("Kafka response correlation mismatch", 1));
+        }
+        Ok(resp[4..].to_vec())
+    }
+}
+
+/// Leader of the partition the records are produced to.
+struct PartitionLeader {
+    conn: BrokerConn,
+    partition: i32,
+}
+
+/// Produces the batches to a Kafka topic.
+struct KafkaSink {
+    /// Bootstrap brokers in "host:port" format.
+    brokers: Vec<String>,
+
+    /// Topic name.
+    topic: String,
+
+    /// Record key, also used to pick the partition.
+    key: Vec<u8>,
+
+    /// Required acknowledgements: 0, 1 or -1 (all).
+    acks: i16,
+
+    /// Connection to the partition leader, if known.
+    leader: Option<PartitionLeader>,
+}
+
+impl KafkaSink {
+    /// Finds the partition leader using the metadata from the bootstrap brokers.
+    fn find_leader(&self) -> ConmonResult<PartitionLeader> {
+        let mut last_err = ConmonError::new("No Kafka brokers configured", 1);
+        for broker in &self.brokers {
+            match self.find_leader_via(broker) {
+                Ok(leader) => return Ok(leader),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn find_leader_via(&self, broker: &str) -> ConmonResult<PartitionLeader> {
+        let mut conn = BrokerConn::connect(broker)?;
+        let mut req = Encoder::default();
+        req.i32(1).string(&self.topic).i8(1); // allow auto topic creation
+        let resp = conn.call(API_METADATA, API_METADATA_VERSION, &req.buf, true)?;
+
+        let mut dec = Decoder { buf: &resp };
+        dec.i32()?; // throttle time
+        let mut brokers = Vec::new();
+        for _ in 0..dec.array_len()? {
+            let node_id = dec.i32()?;
+            let host = dec.string()?.unwrap_or_default();
+            let port = dec.i32()?;
+            dec.string()?; // rack
+            brokers.push((node_id, format!("{host}:{port}")));
+        }
+        dec.string()?; // cluster ID
+        dec.i32()?; // controller ID
+
+        for _ in 0..dec.array_len()? {
+            let error_code = dec.i16()?;
+            let name = dec.string()?.unwrap_or_default();
+            dec.take(1)?; // is internal
+            let mut partitions = Vec::new();
+            for _ in 0..dec.array_len()? {
+                let p_error = dec.i16()?;
+                let index = dec.i32()?;
+                let leader_id = dec.i32()?;
+                for _ in 0..2 {
+                    // replica and ISR nodes
+                    let n = dec.array_len()?;
+                    dec.take(n * 4)?;
+                }
+                partitions.push((p_error, index, leader_id));
+            }
+            if name != self.topic {
+                continue;
+            }
+            if error_code != 0 || partitions.is_empty() {
+                return Err(ConmonError::new(
+                    format!(
+                        "Kafka topic {} is not available (error {error_code})",
+                        self.topic
+                    ),
+                    1,
+                ));
+            }
+            partitions.sort_by_key(|p| p.1);
+            let (p_error, index, leader_id) =
+                partitions[fnv1a(&self.key) as usize % partitions.len()];
+            if p_error != 0 || leader_id < 0 {
+                return Err(ConmonError::new(
+                    format!(
+                        "Kafka partition {}/{index} has no leader (error {p_error})",
+                        self.topic
+                    ),
+                    1,
+                ));
+            }
+            let addr = brokers
+                .iter()
+                .find(|(id, _)| *id == leader_id)
+                .map(|(_, addr)| addr.clone())
+                .ok_or_else(|| ConmonError::new("Kafka partition leader is unknown", 1))?;
+            info!(
+                "Producing container logs to {}/{index} at {addr}",
+                self.topic
+            );
+            return Ok(PartitionLeader {
+                conn: BrokerConn::connect(&addr)?,
+                partition: index,
+            });
+        }
+        Err(ConmonError::new(
+            format!("Kafka topic {} not found in metadata", self.topic),
+            1,
+        ))
+    }
+
+    /// Produces the batch to the partition leader and returns the Kafka error code.
+    fn produce(&mut self, leader: &mut PartitionLeader, batch: &[LogRecord]) -> ConmonResult<i16> {
+        let records = encode_record_batch(&self.key, batch);
+        let mut req = Encoder::default();
+        req.i16(-1) // transactional ID
+            .i16(self.acks)
+            .i32(IO_TIMEOUT.as_millis() as i32)
+            .i32(1)
+            .string(&self.topic)
+            .i32(1)
+            .i32(leader.partition)
+            .bytes(&records);
+        let resp = leader
+            .conn
+            .call(API_PRODUCE, API_PRODUCE_VERSION, &req.buf, self.acks != 0)?;
+        if self.acks == 0 {
+            return Ok(0);
+        }
+        let mut dec = Decoder { buf: &resp };
+        for _ in 0..dec.array_len()? {
+            dec.string()?;
+            for _ in 0..dec.array_len()? {
+                dec.i32()?; // partition index
+                let error_code = dec.i16()?;
+                dec.i64()?; // base offset
+                dec.i64()?; // log append time
+                if error_code != 0 {
+                    return Ok(error_code);
+                }
+            }
+        }
+        Ok(0)
+    }
+}
+
+impl RemoteSink for KafkaSink {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    fn send(&mut self, batch: &[LogRecord]) -> ConmonResult<()> {
+        let mut leader = match self.leader.take() {
+            Some(leader) => leader,
+            None => self.find_leader()?,
+        };
+        let error_code = self.produce(&mut leader, batch)?;
+        if error_code == 0 {
+            self.leader = Some(leader);
+            return Ok(());
+        }
+        if RETRIABLE_ERRORS.contains(&error_code) {
+            // Refresh the metadata with the next attempt.
+            return Err(ConmonError::new(
+                format!("Kafka produce failed with error {error_code}"),
+                1,
+            ));
+        }
+        warn!(
+            "Kafka rejected {} log records with error {error_code}, dropping them",
+            batch.len()
+        );
+        self.leader = Some(leader);
+        Ok(())
+    }
+}
+
+/// Returns the topic name for the `template`, replacing the "{cid}",
+/// "{cuuid}" and "{name}" placeholders.
+fn expand_topic(template: &str, cfg: &LogPluginCfg) -> ConmonResult<String> {
+    let topic = template
+        .replace("{cid}", cfg.cid.as_deref().unwrap_or_default())
+        .replace("{cuuid}", cfg.cuuid.as_deref().unwrap_or_default())
+        .replace("{name}", cfg.name.as_deref().unwrap_or_default());
+    let valid = !topic.is_empty()
+        && topic.len() <= 249
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+    if !valid {
+        return Err(ConmonError::new(
+            format!("Invalid Kafka topic name '{topic}'"),
+            1,
+        ));
+    }
+    Ok(topic)
+}
+
+/// Logging plugin producing the container output to a Kafka topic.
+///
+/// The `--log-path` is "kafka:broker:port[,broker:port...]". Each line is
+/// produced as a single record with the container ID as the key and with
+/// the "stream" header. All the records of a container go to the same
+/// partition, so their order is kept.
+pub struct KafkaLogger {
+    splitter: LineSplitter,
+    worker: RemoteWorker,
+}
+
+impl KafkaLogger {
+    pub fn new(cfg: &LogPluginCfg) -> ConmonResult<Self> {
+        let brokers: Vec<String> = cfg
+            .path
+            .to_string_lossy()
+            .split(',')
+            .map(|b| b.trim().to_string())
+            .filter(|b| !b.is_empty())
+            .collect();
+        if brokers.is_empty() {
+            return Err(ConmonError::new(
+                "kafka log driver requires the broker list",
+                1,
+            ));
+        }
+        let acks = match cfg.log_opt("kafka-acks").unwrap_or("1") {
+            "0" => 0,
+            "1" => 1,
+            "all" | "-1" => -1,
+            v => {
+                return Err(ConmonError::new(
+                    format!("Invalid value '{v}' of log option kafka-acks"),
+                    1,
+                ));
+            }
+        };
+        let sink = KafkaSink {
+            brokers,
+            topic: expand_topic(cfg.log_opt("kafka-topic").unwrap_or(DEFAULT_TOPIC), cfg)?,
+            key: cfg.cid.clone().unwrap_or_default().into_bytes(),
+            acks,
+            leader: None,
+        };
+        let remote_cfg = RemoteCfg {
+            batch_bytes: cfg.log_opt_parse("kafka-batch-size", DEFAULT_BATCH_SIZE)?,
+            flush_interval: Duration::from_millis(
+                cfg.log_opt_parse("kafka-linger-ms", DEFAULT_LINGER_MS)?,
+            ),
+            policy: cfg.delivery_policy.unwrap_or(DeliveryPolicy::DropOldest),
+            ..Default::default()
+        };
+        Ok(Self {
+            splitter: LineSplitter::new(cfg.line_max),
+            worker: RemoteWorker::new(remote_cfg, Box::new(sink)),
+        })
+    }
+}
+
+impl LogPlugin for KafkaLogger {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        self.worker.push(self.splitter.split(stream, data))?;
+        if data.is_empty() {
+            self.worker.flush();
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.worker.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn crc32c_and_varint_encoding() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+
+        let mut enc = Encoder::default();
+        enc.varint(0).varint(-1).varint(1).varint(300);
+        assert_eq!(enc.buf, vec![0x00, 0x01, 0x02, 0xd8, 0x04]);
+    }
+
+    #[test]
+    fn expand_topic_template() -> ConmonResult<()> {
+        let cfg = LogPluginCfg {
+            cid: Some("abc".into()),
+            name: Some("web".into()),
+            ..Default::default()
+        };
+        assert_eq!(expand_topic(DEFAULT_TOPIC, &cfg)?, "conmon-abc");
+        assert_eq!(expand_topic("logs.{name}", &cfg)?, "logs.web");
+        assert!(expand_topic("logs/{name}", &cfg).is_err());
+        Ok(())
+    }
+
+    /// Reads single request from the client and returns (api key, body after the header).
+    fn read_request(conn: &mut TcpStream) -> (i16, i32, Vec<u8>) {
+        let mut size = [0u8; 4];
+        conn.read_exact(&mut size).unwrap();
+        let mut req = vec![0u8; i32::from_be_bytes(size) as usize];
+        conn.read_exact(&mut req).unwrap();
+        let mut dec = Decoder { buf: &req };
+        let api_key = dec.i16().unwrap();
+        dec.i16().unwrap();
+        let correlation_id = dec.i32().unwrap();
+        dec.string().unwrap();
+        (api_key, correlation_id, dec.buf.to_vec())
+    }
+
+    fn respond(conn: &mut TcpStream, correlation_id: i32, body: &Encoder) {
+        let mut resp = Encoder::default();
+        resp.i32(correlation_id);
+        resp.buf.extend_from_slice(&body.buf);
+        let mut framed = Encoder::default();
+        framed.bytes(&resp.buf);
+        conn.write_all(&framed.buf).unwrap();
+    }
+
+    #[test]
+    fn produces_to_partition_leader() -> ConmonResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port() as i32;
+        let broker = std::thread::spawn(move || {
+            // Metadata on the bootstrap connection.
+            let (mut conn, _) = listener.accept().unwrap();
+            let (api_key, cid, _) = read_request(&mut conn);
+            assert_eq!(api_key, API_METADATA);
+            let mut md = Encoder::default();
+            md.i32(0) // throttle
+                .i32(1)
+                .i32(1)
+                .string("127.0.0.1")
+                .i32(port)
+                .i16(-1) // rack
+                .i16(-1) // cluster ID
+                .i32(1) // controller
+                .i32(1)
+                .i16(0)
+                .string("conmon-abc")
+                .i8(0)
+                .i32(1)
+                .i16(0)
+                .i32(0) // partition index
+                .i32(1) // leader
+                .i32(0)
+                .i32(0);
+            respond(&mut conn, cid, &md);
+
+            // Produce on the leader connection.
+            let (mut conn, _) = listener.accept().unwrap();
+            let (api_key, cid, body) = read_request(&mut conn);
+            assert_eq!(api_key, API_PRODUCE);
+            let mut resp = Encoder::default();
+            resp.i32(1)
+                .string("conmon-abc")
+                .i32(1)
+                .i32(0)
+                .i16(0)
+                .i64(0)
+                .i64(-1)
+                .i32(0);
+            respond(&mut conn, cid, &resp);
+            body
+        });
+
+        let mut sink = KafkaSink {
+            brokers: vec![format!("127.0.0.1:{port}")],
+            topic: "conmon-abc".to_string(),
+            key: b"abc".to_vec(),
+            acks: 1,
+            leader: None,
+        };
+        let records = LineSplitter::default().split(LogStream::Stdout, b"hello\n");
+        sink.send(&records)?;
+        assert!(sink.leader.is_some());
+
+        let body = broker.join().unwrap();
+        let mut dec = Decoder { buf: &body };
+        dec.i16()?; // transactional ID
+        assert_eq!(dec.i16()?, 1);
+        dec.i32()?;
+        dec.i32()?;
+        assert_eq!(dec.string()?.as_deref(), Some("conmon-abc"));
+        dec.i32()?;
+        assert_eq!(dec.i32()?, 0);
+        let batch_len = dec.i32()? as usize;
+        let batch = dec.take(batch_len)?;
+        assert_eq!(batch[16], 2); // magic
+        let crc = u32::from_be_bytes(batch[17..21].try_into().unwrap());
+        assert_eq!(crc, crc32c(&batch[21..]));
+        assert!(batch.windows(5).any(|w| w == b"hello"));
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{
+    error::ConmonResult,
+    logging::plugin::{ContainerExit, ContainerMetadata, LogPlugin, LogStream},
+};
+
+/// Default maximum length of the line kept in the reassembly buffer, used
+/// when `--log-line-max` is not set.
+pub const DEFAULT_LINE_MAX: usize = 16384;
+
+/// Reassembles the lines split across the reads of the container output.
+///
+/// The complete lines are emitted, the data after the last new-line is kept
+/// until the rest of the line arrives. The lines longer than `line_max`
+/// (`--log-line-max`) are emitted in parts of `line_max` bytes without the
+/// new-line, so at most `line_max` bytes are kept. Each emitted chunk is
+/// either a run of complete lines or a single part of a line.
+pub struct LineBuffer {
+    line_max: usize,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    stdin: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Creates new LineBuffer splitting the lines longer than `line_max`, or
+    /// `DEFAULT_LINE_MAX` bytes if zero.
+    pub fn new(line_max: usize) -> Self {
+        Self {
+            line_max: if line_max > 0 {
+                line_max
+            } else {
+                DEFAULT_LINE_MAX
+            },
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            stdin: Vec::new(),
+        }
+    }
+
+    /// Passes the lines completed by `data` to `emit`. Empty `data` emits
+    /// the pending part of the line.
+    ///
+    /// # Errors
+    ///
+    /// * The first error returned by `emit`.
+    pub fn push(
+        &mut self,
+        stream: LogStream,
+        data: &[u8],
+        mut emit: impl FnMut(&[u8]) -> ConmonResult<()>,
+    ) -> ConmonResult<()> {
+        let line_max = self.line_max;
+        let buf = match stream {
+            LogStream::Stdout => &mut self.stdout,
+            LogStream::Stderr => &mut self.stderr,
+            LogStream::Stdin => &mut self.stdin,
+        };
+
+        if data.is_empty() {
+            if !buf.is_empty() {
+                emit(&std::mem::take(buf))?;
+            }
+            return Ok(());
+        }
+
+        let mut data = data;
+        // Complete the pending line first.
+        if !buf.is_empty() {
+            let end = match data.iter().position(|&c| c == b'\n') {
+                Some(pos) => pos + 1,
+                None => data.len(),
+            };
+            // The new-line does not count into the line length.
+            let room = line_max - buf.len();
+            let take = if end == room + 1 && data[room] == b'\n' {
+                end
+            } else {
+                end.min(room)
+            };
+            buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if buf.ends_with(b"\n") || buf.len() >= line_max {
+                emit(&std::mem::take(buf))?;
+            } else {
+                return Ok(());
+            }
+        }
+
+        // Pass the complete lines at once and keep the tail.
+        let complete = match data.iter().rposition(|&c| c == b'\n') {
+            Some(pos) => pos + 1,
+            None => 0,
+        };
+        if complete > 0 {
+            emit_lines(&mut emit, &data[..complete], line_max)?;
+        }
+        let mut tail = &data[complete..];
+        while tail.len() >= line_max {
+            emit(&tail[..line_max])?;
+            tail = &tail[line_max..];
+        }
+        buf.extend_from_slice(tail);
+        Ok(())
+    }
+}
+
+/// Log plugin passing the lines reassembled by the `LineBuffer` to the inner
+/// plugin, which marks the parts of the long lines as partial. Empty write
+/// flushes the pending line.
+pub struct LineBufferedLogPlugin {
+    inner: Box<dyn LogPlugin>,
+    lines: LineBuffer,
+}
+
+impl LineBufferedLogPlugin {
+    pub fn new(inner: Box<dyn LogPlugin>, line_max: usize) -> Self {
+        Self {
+            inner,
+            lines: LineBuffer::new(line_max),
+        }
+    }
+}
+
+/// Passes the complete lines in `data` to `emit`, splitting the lines longer
+/// than `line_max`.
+fn emit_lines(
+    emit: &mut impl FnMut(&[u8]) -> ConmonResult<()>,
+    data: &[u8],
+    line_max: usize,
+) -> ConmonResult<()> {
+    // The run of the lines short enough to be passed at once.
+    let mut start = 0;
+    let mut pos = 0;
+    // The length of the line without the new-line.
+    let content_len = |line: &[u8]| line.len() - usize::from(line.ends_with(b"\n"));
+    for line in data.split_inclusive(|&c| c == b'\n') {
+        if content_len(line) > line_max {
+            if start < pos {
+                emit(&data[start..pos])?;
+            }
+            let mut rest = line;
+            while content_len(rest) > line_max {
+                emit(&rest[..line_max])?;
+                rest = &rest[line_max..];
+            }
+            emit(rest)?;
+            start = pos + line.len();
+        }
+        pos += line.len();
+    }
+    if start < pos {
+        emit(&data[start..pos])?;
+    }
+    Ok(())
+}
+
+impl LogPlugin for LineBufferedLogPlugin {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        let inner = &mut self.inner;
+        self.lines
+            .push(stream, data, |chunk| inner.write(stream, chunk))?;
+        if data.is_empty() {
+            return self.inner.write(stream, data);
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        self.inner.reopen()
+    }
+
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        self.inner.reopen_files()
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.inner.stop_threads()
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        self.inner.shutdown()
+    }
+
+    fn on_container_start(&mut self, pid: i32, metadata: &ContainerMetadata) -> ConmonResult<()> {
+        self.inner.on_container_start(pid, metadata)
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        self.inner.on_container_exit(exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Records = Arc<Mutex<Vec<(LogStream, String)>>>;
+
+    struct Recorder(Records);
+
+    impl LogPlugin for Recorder {
+        fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+            let data = String::from_utf8_lossy(data).into_owned();
+            self.0.lock().unwrap().push((stream, data));
+            Ok(())
+        }
+        fn reopen(&mut self) -> ConmonResult<()> {
+            Ok(())
+        }
+    }
+
+    fn written(records: &Records) -> Vec<(LogStream, String)> {
+        std::mem::take(&mut *records.lock().unwrap())
+    }
+
+    #[test]
+    fn lines_are_reassembled_per_stream() -> ConmonResult<()> {
+        let records = Records::default();
+        let mut plugin = LineBufferedLogPlugin::new(Box::new(Recorder(records.clone())), 16);
+        plugin.write(LogStream::Stdout, b"one\ntw")?;
+        plugin.write(LogStream::Stderr, b"err")?;
+        plugin.write(LogStream::Stdout, b"o\nthr")?;
+        plugin.write(LogStream::Stdout, b"ee")?;
+        plugin.write(LogStream::Stderr, b"\n")?;
+        assert_eq!(
+            written(&records),
+            vec![
+                (LogStream::Stdout, "one\n".into()),
+                (LogStream::Stdout, "two\n".into()),
+                (LogStream::Stderr, "err\n".into()),
+            ]
+        );
+
+        plugin.write(LogStream::Stdout, b"")?;
+        assert_eq!(
+            written(&records),
+            vec![
+                (LogStream::Stdout, "three".into()),
+                (LogStream::Stdout, "".into()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn long_pending_line_is_not_buffered() -> ConmonResult<()> {
+        let records = Records::default();
+        let mut plugin = LineBufferedLogPlugin::new(Box::new(Recorder(records.clone())), 4);
+        plugin.write(LogStream::Stdout, b"ab")?;
+        plugin.write(LogStream::Stdout, b"cdefghij\nk")?;
+        plugin.write(LogStream::Stdout, b"lmn\n")?;
+        assert_eq!(
+            written(&records),
+            vec![
+                (LogStream::Stdout, "abcd".into()),
+                (LogStream::Stdout, "efgh".into()),
+                (LogStream::Stdout, "ij\n".into()),
+                (LogStream::Stdout, "klmn\n".into()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn long_complete_lines_are_split() -> ConmonResult<()> {
+        let records = Records::default();
+        let mut plugin = LineBufferedLogPlugin::new(Box::new(Recorder(records.clone())), 4);
+        plugin.write(LogStream::Stdout, b"a\nb\n0123456789\nc\nd\nabcd\n")?;
+        assert_eq!(
+            written(&records),
+            vec![
+                (LogStream::Stdout, "a\nb\n".into()),
+                (LogStream::Stdout, "0123".into()),
+                (LogStream::Stdout, "4567".into()),
+                (LogStream::Stdout, "89\n".into()),
+                // The line of exactly `line_max` bytes is not split.
+                (LogStream::Stdout, "c\nd\nabcd\n".into()),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use log::warn;
+use serde_json::{Map, Value, json};
+
+use crate::{
+    error::{ConmonError, ConmonResult},
+    logging::{
+        http::{Url, request},
+        plugin::{DeliveryPolicy, LogPlugin, LogPluginCfg, LogStream},
+        remote::{LineSplitter, LogRecord, RemoteCfg, RemoteSink, RemoteWorker},
+    },
+};
+
+/// Path of the push API used when the `url` has no path.
+const DEFAULT_PUSH_PATH: &str = "/loki/api/v1/push";
+
+/// Default maximum size of the batch in bytes.
+const DEFAULT_BATCH_SIZE: usize = 1024 * 1024;
+
+/// Default time the records wait for the batch to fill in milliseconds.
+const DEFAULT_BATCH_WAIT_MS: u64 = 1000;
+
+/// Default maximum number of records buffered while Loki is unavailable.
+const DEFAULT_BUFFER_SIZE: usize = 100_000;
+
+/// Returns true if `name` is a valid Loki label name.
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Returns the labels attached to all the streams: the container ID and
+/// name and the comma-separated "name=value" pairs from `labels`.
+fn parse_labels(
+    labels: Option<&str>,
+    cfg: &LogPluginCfg,
+) -> ConmonResult<BTreeMap<String, String>> {
+    let mut out = BTreeMap::new();
+    if let Some(cid) = &cfg.cid {
+        out.insert("container_id".to_string(), cid.clone());
+    }
+    if let Some(name) = &cfg.name {
+        out.insert("container_name".to_string(), name.clone());
+    }
+    for label in labels.unwrap_or_default().split(',') {
+        let label = label.trim();
+        if label.is_empty() {
+            continue;
+        }
+        match label.split_once('=') {
+            Some((name, value)) if is_valid_label_name(name.trim()) => {
+                out.insert(name.trim().to_string(), value.trim().to_string());
+            }
+            _ => {
+                return Err(ConmonError::new(
+                    format!("Loki labels must be in format name=value (in '{label}')"),
+                    1,
+                ));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Pushes the batches to the Loki push API.
+struct LokiSink {
+    /// The push API endpoint.
+    url: Url,
+
+    /// Labels attached to all the streams.
+    labels: BTreeMap<String, String>,
+
+    /// Tenant sent in the X-Scope-OrgID header.
+    tenant: Option<String>,
+}
+
+impl LokiSink {
+    /// Returns the push request body with one stream per container stream.
+    fn push_body(&self, batch: &[LogRecord]) -> Value {
+        let mut streams: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+        for r in batch {
+            let ts = r.timestamp.timestamp_nanos_opt().unwrap_or(0);
+            streams
+                .entry(r.stream())
+                .or_default()
+                .push(json!([ts.to_string(), String::from_utf8_lossy(&r.line)]));
+        }
+        let streams: Vec<Value> = streams
+            .into_iter()
+            .map(|(stream, values)| {
+                let mut labels: Map<String, Value> = self
+                    .labels
+                    .iter()
+                    .map(|(k, v)| (k.clone(), Value::from(v.clone())))
+                    .collect();
+                labels.insert("stream".into(), Value::from(stream));
+                json!({"stream": labels, "values": values})
+            })
+            .collect();
+        json!({ "streams": streams })
+    }
+}
+
+impl RemoteSink for LokiSink {
+    fn name(&self) -> &str {
+        "loki"
+    }
+
+    fn send(&mut self, batch: &[LogRecord]) -> ConmonResult<()> {
+        let body = self.push_body(batch).to_string();
+        let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+        if let Some(tenant) = &self.tenant {
+            headers.push(("X-Scope-OrgID".to_string(), tenant.clone()));
+        }
+        let resp = request("POST", &self.url, &headers, body.as_bytes())?;
+        // Retry on rate limiting and server errors; other client errors,
+        // like entries out of order or too old, will not succeed on retry.
+        if resp.status == 429 || resp.status >= 500 {
+            resp.check("Loki push")?;
+        } else if let Err(e) = resp.check("Loki push") {
+            warn!("Dropping {} log records: {}", batch.len(), e.msg);
+        }
+        Ok(())
+    }
+}
+
+/// Logging plugin pushing the container output to a Loki-compatible push API.
+///
+/// The records are grouped into one stream per container stream, labeled by
+/// the container ID, container name, `stream` and the configured labels.
+pub struct LokiLogger {
+    splitter: LineSplitter,
+    worker: RemoteWorker,
+}
+
+impl LokiLogger {
+    pub fn new(cfg: &LogPluginCfg) -> ConmonResult<Self> {
+        let spec = cfg.path.to_string_lossy();
+        let url = match cfg.log_opt("url") {
+            Some(url) => url,
+            None if !spec.is_empty() => &spec,
+            None => {
+                return Err(ConmonError::new(
+                    "loki log driver requires --log-opt url",
+                    1,
+                ));
+            }
+        };
+        let mut url = Url::parse(url)?;
+        if url.path == "/" {
+            url.path = DEFAULT_PUSH_PATH.to_string();
+        }
+        let sink = LokiSink {
+            url,
+            labels: parse_labels(cfg.log_opt("labels"), cfg)?,
+            tenant: cfg.log_opt("tenant").map(|t| t.to_string()),
+        };
+        let remote_cfg = RemoteCfg {
+            batch_bytes: cfg.log_opt_parse("batch-size", DEFAULT_BATCH_SIZE)?,
+            flush_interval: Duration::from_millis(
+                cfg.log_opt_parse("batch-wait", DEFAULT_BATCH_WAIT_MS)?,
+            ),
+            max_buffered: cfg.log_opt_parse("buffer-size", DEFAULT_BUFFER_SIZE)?,
+            policy: cfg.delivery_policy.unwrap_or(DeliveryPolicy::DropOldest),
+            ..Default::default()
+        };
+        Ok(Self {
+            splitter: LineSplitter::new(cfg.line_max),
+            worker: RemoteWorker::new(remote_cfg, Box::new(sink)),
+        })
+    }
+}
+
+impl LogPlugin for LokiLogger {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        self.worker.push(self.splitter.split(stream, data))?;
+        if data.is_empty() {
+            self.worker.flush();
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.worker.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_labels_validates_names() -> ConmonResult<()> {
+        let cfg = LogPluginCfg {
+            cid: Some("abc".into()),
+            ..Default::default()
+        };
+        let labels = parse_labels(Some("env=prod, team = web"), &cfg)?;
+        assert_eq!(labels["container_id"], "abc");
+        assert_eq!(labels["env"], "prod");
+        assert_eq!(labels["team"], "web");
+
+        assert!(parse_labels(Some("1env=prod"), &cfg).is_err());
+        assert!(parse_labels(Some("env"), &cfg).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn push_body_groups_streams() -> ConmonResult<()> {
+        let sink = LokiSink {
+            url: Url::parse("http://loki:3100")?,
+            labels: BTreeMap::from([("env".to_string(), "prod".to_string())]),
+            tenant: None,
+        };
+        let mut splitter = LineSplitter::default();
+        let mut records = splitter.split(LogStream::Stdout, b"out1\nout2\n");
+        records.extend(splitter.split(LogStream::Stderr, b"err\n"));
+
+        let body = sink.push_body(&records);
+        let streams = body["streams"].as_array().unwrap();
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams[0]["stream"]["stream"], "stderr");
+        assert_eq!(streams[0]["stream"]["env"], "prod");
+        assert_eq!(streams[0]["values"][0][1], "err");
+        assert_eq!(streams[1]["values"].as_array().unwrap().len(), 2);
+        assert_eq!(streams[1]["values"][1][1], "out2");
+        Ok(())
+    }
+}
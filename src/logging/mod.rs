@@ -1,4 +1,23 @@
+pub mod budget;
+pub mod dedup;
+pub mod fallback;
 pub mod file_logger;
+pub mod gelf_logger;
+pub mod http;
 pub mod journald_logger;
+pub mod kafka_logger;
+pub mod line_buffer;
+pub mod loki_logger;
 pub mod none_logger;
 pub mod plugin;
+pub mod queue;
+pub mod rate_limit;
+pub mod redact;
+pub mod reload;
+pub mod remote;
+pub mod s3_logger;
+pub mod splunk_logger;
+pub mod syslog_logger;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod writer;
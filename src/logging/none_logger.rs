@@ -1,6 +1,6 @@
 use crate::{
     error::ConmonResult,
-    logging::plugin::{LogPlugin, LogPluginCfg},
+    logging::plugin::{LogPlugin, LogPluginCfg, LogStream},
 };
 
 /// A no-op logging plugin that discards all data.
@@ -16,7 +16,7 @@ impl NoneLogger {
 }
 
 impl LogPlugin for NoneLogger {
-    fn write(&mut self, _is_stdout: bool, _data: &[u8]) -> ConmonResult<()> {
+    fn write(&mut self, _stream: LogStream, _data: &[u8]) -> ConmonResult<()> {
         Ok(())
     }
 
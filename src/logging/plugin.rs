@@ -1,13 +1,317 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{info, warn};
+use nix::sys::signal::Signal;
 
 use crate::{
     error::{ConmonError, ConmonResult},
-    logging::{file_logger::FileLogger, journald_logger::JournaldLogger, none_logger::NoneLogger},
+    logging::{
+        budget,
+        dedup::DedupLogPlugin,
+        fallback::FallbackLogPlugin,
+        file_logger::{FileLogger, LogFormat},
+        gelf_logger::GelfLogger,
+        journald_logger::JournaldLogger,
+        kafka_logger::KafkaLogger,
+        line_buffer::LineBufferedLogPlugin,
+        loki_logger::LokiLogger,
+        none_logger::NoneLogger,
+        rate_limit::RateLimitedLogPlugin,
+        redact::{RedactingLogPlugin, Redactor},
+        s3_logger::S3Logger,
+        splunk_logger::SplunkLogger,
+        syslog_logger::SyslogLogger,
+        writer::{ThreadedLogPlugin, WRITER_QUEUE_LEN},
+    },
 };
 
-pub trait LogPlugin {
-    fn write(&mut self, is_stdout: bool, data: &[u8]) -> ConmonResult<()>;
+/// Container stream the data written to the log plugin comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStream {
+    /// Output written by the container to its stdout.
+    Stdout,
+    /// Output written by the container to its stderr.
+    Stderr,
+    /// Input sent to the container by the attach clients. Only written
+    /// with `--log-stdin`.
+    Stdin,
+}
+
+impl LogStream {
+    /// Returns the name of the stream used in the log records.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+            LogStream::Stdin => "stdin",
+        }
+    }
+}
+
+/// Metadata of the container passed to `LogPlugin::on_container_start`,
+/// like "cid", "name" and "bundle".
+pub type ContainerMetadata = BTreeMap<String, String>;
+
+/// How the container exited, passed to `LogPlugin::on_container_exit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContainerExit {
+    /// The exit code, 128 + the signal number if killed by a signal.
+    pub code: i32,
+
+    /// The signal which killed the container.
+    pub signal: Option<Signal>,
+
+    /// True if the container ran out of memory.
+    pub oom: bool,
+}
+
+pub trait LogPlugin: Send {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()>;
     fn reopen(&mut self) -> ConmonResult<()>;
+
+    /// Closes and reopens the log files at their paths without truncating
+    /// them, after they have been rotated by external tool like logrotate.
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    /// Stops the background threads of the plugin before the live upgrade
+    /// re-executes conmon, after the buffered data has been flushed. They
+    /// are started again by the next write if the re-exec fails.
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    /// Makes the logged data durable once the container has exited, unless
+    /// `--no-sync-log` is set. Called after the last write.
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    /// Called once the container process has been created, before any of
+    /// its output is written.
+    fn on_container_start(&mut self, _pid: i32, _metadata: &ContainerMetadata) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    /// Called once the container has exited, after its output has been
+    /// flushed and before `shutdown`.
+    fn on_container_exit(&mut self, _exit: &ContainerExit) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    /// Re-creates the log drivers from their configuration. Only supported
+    /// by the `ReloadableLogPlugin` wrapping the whole log pipeline.
+    fn reload(&mut self) -> ConmonResult<()> {
+        Err(ConmonError::new("The log plugin cannot be reloaded", 1))
+    }
+}
+
+/// True if SIGUSR1 asked us to reopen the log files.
+static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the event-loop to reopen the log files.
+pub fn request_reopen() {
+    REOPEN_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// True if SIGHUP or the `reload-log` control request asked us to reload
+/// the log drivers.
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the event-loop to reload the log drivers.
+pub fn request_reload() {
+    RELOAD_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Reloads the log drivers of `log_plugin` if requested by `request_reload`
+/// and reopens its log files if requested by `request_reopen`. Called on
+/// every event-loop iteration.
+pub fn reopen_if_requested(log_plugin: &mut dyn LogPlugin) {
+    if RELOAD_REQUESTED.swap(false, Ordering::Relaxed) {
+        info!("Reloading the log drivers");
+        if let Err(e) = log_plugin.reload() {
+            warn!(
+                "Failed to reload the log drivers, keeping the old ones: {}",
+                e.msg
+            );
+        }
+    }
+    if REOPEN_REQUESTED.swap(false, Ordering::Relaxed) {
+        info!("Reopening the log files");
+        if let Err(e) = log_plugin.reopen_files() {
+            warn!("Failed to reopen the log files: {}", e.msg);
+        }
+    }
+}
+
+/// Container streams routed to a log plugin.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStreams {
+    /// Both stdout and stderr.
+    #[default]
+    All,
+    /// Only stdout.
+    Stdout,
+    /// Only stderr.
+    Stderr,
+    /// Stdout, stderr and the attach clients input (`--log-stdin`).
+    AllWithStdin,
+}
+
+impl LogStreams {
+    /// Returns true if the data from `stream` should be logged.
+    pub fn accepts(&self, stream: LogStream) -> bool {
+        match self {
+            LogStreams::All => stream != LogStream::Stdin,
+            LogStreams::Stdout => stream == LogStream::Stdout,
+            LogStreams::Stderr => stream == LogStream::Stderr,
+            LogStreams::AllWithStdin => true,
+        }
+    }
+}
+
+/// What to do with the container output when a log target cannot keep up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryPolicy {
+    /// Wait until the target accepts the output, blocking the container.
+    Block,
+    /// Drop the output which does not fit into the buffer.
+    DropNewest,
+    /// Drop the oldest buffered output to make room for the new one.
+    DropOldest,
+}
+
+impl FromStr for DeliveryPolicy {
+    type Err = ConmonError;
+
+    fn from_str(s: &str) -> ConmonResult<Self> {
+        match s {
+            "block" => Ok(DeliveryPolicy::Block),
+            "drop-newest" => Ok(DeliveryPolicy::DropNewest),
+            "drop-oldest" => Ok(DeliveryPolicy::DropOldest),
+            _ => Err(ConmonError::new(
+                format!(
+                    "Invalid delivery policy '{s}', expected block, drop-newest or drop-oldest"
+                ),
+                1,
+            )),
+        }
+    }
+}
+
+/// Expands the placeholders in the `--log-tag` template.
+///
+/// The supported placeholders are `{{.ID}}` or `%i` for the first 12
+/// characters of the container ID, `{{.FullID}}` or `%I` for the full
+/// container ID, `{{.Name}}` or `%N` for the container name and `%%` for
+/// the `%` character.
+///
+/// # Arguments
+///
+/// * `template` - The `--log-tag` value.
+/// * `cid` - The container ID.
+/// * `name` - The container name.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the template contains an unknown placeholder.
+pub fn expand_log_tag(
+    template: &str,
+    cid: Option<&str>,
+    name: Option<&str>,
+) -> ConmonResult<String> {
+    let cid = cid.unwrap_or_default();
+    let short_id: String = cid.chars().take(12).collect();
+    let name = name.unwrap_or_default();
+    let unknown = |placeholder: &str| {
+        ConmonError::new(
+            format!("Unknown placeholder '{placeholder}' in --log-tag"),
+            1,
+        )
+    };
+
+    let mut tag = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find(['{', '%']) {
+        tag.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(placeholder) = rest.strip_prefix("{{") {
+            let Some(end) = placeholder.find("}}") else {
+                return Err(unknown(rest));
+            };
+            tag.push_str(match placeholder[..end].trim() {
+                ".ID" => &short_id,
+                ".FullID" => cid,
+                ".Name" => name,
+                _ => return Err(unknown(&rest[..end + 4])),
+            });
+            rest = &placeholder[end + 2..];
+        } else if rest.starts_with('%') {
+            let Some(c) = rest[1..].chars().next() else {
+                return Err(unknown(rest));
+            };
+            tag.push_str(match c {
+                'i' => &short_id,
+                'I' => cid,
+                'N' => name,
+                '%' => "%",
+                _ => return Err(unknown(&rest[..1 + c.len_utf8()])),
+            });
+            rest = &rest[1 + c.len_utf8()..];
+        } else {
+            tag.push('{');
+            rest = &rest[1..];
+        }
+    }
+    tag.push_str(rest);
+    Ok(tag)
+}
+
+/// Parses the `--log-label` values in the LABEL=VALUE format.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if a value is not in the LABEL=VALUE format or a label
+///   is set more than once.
+pub fn parse_log_labels(labels: &[String]) -> ConmonResult<BTreeMap<String, String>> {
+    let mut map = BTreeMap::new();
+    for l in labels {
+        let Some((name, value)) = l.split_once('=') else {
+            return Err(ConmonError::new(
+                format!(
+                    "Container labels must be in format LABEL=VALUE (none or more than one '=' present in '{l}')"
+                ),
+                1,
+            ));
+        };
+        if name.is_empty() {
+            return Err(ConmonError::new(
+                format!(
+                    "Container labels must be in format LABEL=VALUE (no LABEL present in '{l}')"
+                ),
+                1,
+            ));
+        }
+        if value.contains('=') {
+            return Err(ConmonError::new(
+                format!(
+                    "Container labels must be in format LABEL=VALUE (none or more than one '=' present in '{l}')"
+                ),
+                1,
+            ));
+        }
+        if map.insert(name.to_string(), value.to_string()).is_some() {
+            return Err(ConmonError::new(
+                format!("Container label {name} is set more than once"),
+                1,
+            ));
+        }
+    }
+    Ok(map)
 }
 
 #[derive(Default, Debug, Clone)]
@@ -16,88 +320,433 @@ pub struct LogPluginCfg {
     pub cid: Option<String>,
     pub cuuid: Option<String>,
     pub log_tag: Option<String>,
-    pub log_labels: Vec<String>,
+    pub log_labels: BTreeMap<String, String>,
     pub no_container_partial_message: bool,
     pub name: Option<String>,
     pub no_sync: bool,
     pub max_size: usize,
     pub global_max_size: usize,
+    pub rate_limit: u64,
+    pub rate_burst: u64,
+    pub line_max: usize,
+    pub writer_thread: bool,
+    pub writer_policy: Option<DeliveryPolicy>,
     pub max_files: i32,
     pub allowlist_dirs: Option<Vec<PathBuf>>,
     pub rotate: bool,
+    pub journald_fallback: Option<PathBuf>,
+    pub fallbacks: Vec<(String, PathBuf)>,
+    pub fallback_after: u32,
+    pub redact_patterns: Vec<String>,
+    pub dedup: bool,
+    pub sample: u64,
+    pub streams: LogStreams,
+    pub log_opts: Vec<(String, String)>,
+    pub delivery_policy: Option<DeliveryPolicy>,
+}
+
+impl LogPluginCfg {
+    /// Returns the value of the `--log-opt` option `key`.
+    pub fn log_opt(&self, key: &str) -> Option<&str> {
+        self.log_opts
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Parses the value of the `--log-opt` option `key`, returning `default`
+    /// if the option is not set.
+    pub fn log_opt_parse<T: FromStr>(&self, key: &str, default: T) -> ConmonResult<T> {
+        match self.log_opt(key) {
+            Some(v) => v.parse().map_err(|_| {
+                ConmonError::new(format!("Invalid value '{v}' of log option {key}"), 1)
+            }),
+            None => Ok(default),
+        }
+    }
+}
+
+/// Creates the log plugin of a log driver from its config.
+type LogDriverCtor = fn(&LogPluginCfg) -> ConmonResult<Box<dyn LogPlugin>>;
+
+/// The log drivers accepted in the `--log-path` driver prefix, by name.
+const LOG_DRIVERS: &[(&str, LogDriverCtor)] = &[
+    ("none", |cfg| Ok(Box::new(NoneLogger::new(cfg)?))),
+    ("passthrough", |cfg| Ok(Box::new(NoneLogger::new(cfg)?))),
+    ("null", |cfg| Ok(Box::new(NoneLogger::new(cfg)?))),
+    ("off", |cfg| Ok(Box::new(NoneLogger::new(cfg)?))),
+    ("file", |cfg| Ok(Box::new(FileLogger::new(cfg)?))),
+    ("k8s_file", |cfg| Ok(Box::new(FileLogger::new(cfg)?))),
+    ("json_file", |cfg| {
+        Ok(Box::new(FileLogger::with_format(cfg, LogFormat::JsonFile)?))
+    }),
+    ("json_lines", |cfg| {
+        Ok(Box::new(FileLogger::with_format(
+            cfg,
+            LogFormat::JsonLines,
+        )?))
+    }),
+    ("journald", |cfg| Ok(Box::new(JournaldLogger::new(cfg)?))),
+    ("s3", |cfg| Ok(Box::new(S3Logger::new(cfg)?))),
+    ("kafka", |cfg| Ok(Box::new(KafkaLogger::new(cfg)?))),
+    ("loki", |cfg| Ok(Box::new(LokiLogger::new(cfg)?))),
+    ("syslog", |cfg| Ok(Box::new(SyslogLogger::new(cfg)?))),
+    ("gelf", |cfg| Ok(Box::new(GelfLogger::new(cfg)?))),
+    ("splunk", |cfg| Ok(Box::new(SplunkLogger::new(cfg)?))),
+];
+
+/// Returns true if `name` is a known log driver.
+pub fn is_log_driver(name: &str) -> bool {
+    LOG_DRIVERS.iter().any(|(n, _)| *n == name)
 }
 
 /// Creates a single log plugin from name and config.
 fn create_log_plugin(name: &str, cfg: &LogPluginCfg) -> ConmonResult<Box<dyn LogPlugin>> {
-    match name {
-        "none" | "passthrough" | "null" | "off" => Ok(Box::new(NoneLogger::new(cfg)?)),
-        "file" | "k8s_file" => Ok(Box::new(FileLogger::new(cfg)?)),
-        "journald" => Ok(Box::new(JournaldLogger::new(cfg)?)),
-        _ => Err(ConmonError::new(format!("No such log driver {name}"), 1)),
+    match LOG_DRIVERS.iter().find(|(n, _)| *n == name) {
+        Some((_, ctor)) => ctor(cfg),
+        None => Err(ConmonError::new(format!("No such log driver {name}"), 1)),
+    }
+}
+
+/// Single destination of the MultiLogPlugin.
+struct LogTarget {
+    /// Name of the destination used in the log messages.
+    name: String,
+    streams: LogStreams,
+    plugin: Box<dyn LogPlugin>,
+
+    /// True if the last operation failed, so the failure is not logged again.
+    failing: bool,
+}
+
+impl LogTarget {
+    /// Logs the result of an operation on the destination when it starts or
+    /// stops failing.
+    fn track(&mut self, res: ConmonResult<()>) -> ConmonResult<()> {
+        match &res {
+            Err(e) if !self.failing => {
+                warn!("Log destination {} failed: {}", self.name, e.msg);
+                self.failing = true;
+            }
+            Ok(()) if self.failing => {
+                info!("Log destination {} recovered", self.name);
+                self.failing = false;
+            }
+            _ => {}
+        }
+        res
     }
 }
 
 /// Composite log plugin that fans out write() and reopen() to multiple plugins.
+///
+/// The write() is routed only to plugins accepting the stream. A failing
+/// destination does not affect the others, the error is returned only if
+/// all of them fail.
 pub struct MultiLogPlugin {
-    plugins: Vec<Box<dyn LogPlugin>>,
+    targets: Vec<LogTarget>,
+}
+
+/// Returns the first error if all the `results` are errors.
+fn all_failed(results: Vec<ConmonResult<()>>) -> ConmonResult<()> {
+    if results.iter().any(|r| r.is_ok()) {
+        return Ok(());
+    }
+    results.into_iter().next().unwrap_or(Ok(()))
 }
 
 impl MultiLogPlugin {
     pub fn new(plugins: Vec<Box<dyn LogPlugin>>) -> Self {
-        Self { plugins }
+        Self::with_streams(plugins.into_iter().map(|p| (LogStreams::All, p)).collect())
+    }
+
+    pub fn with_streams(plugins: Vec<(LogStreams, Box<dyn LogPlugin>)>) -> Self {
+        Self::with_names(
+            plugins
+                .into_iter()
+                .enumerate()
+                .map(|(i, (streams, p))| (format!("#{i}"), streams, p))
+                .collect(),
+        )
+    }
+
+    /// Creates the MultiLogPlugin from the (name, streams, plugin) entries.
+    pub fn with_names(plugins: Vec<(String, LogStreams, Box<dyn LogPlugin>)>) -> Self {
+        let targets = plugins
+            .into_iter()
+            .map(|(name, streams, plugin)| LogTarget {
+                name,
+                streams,
+                plugin,
+                failing: false,
+            })
+            .collect();
+        Self { targets }
     }
 }
 
 impl LogPlugin for MultiLogPlugin {
-    fn write(&mut self, is_stdout: bool, data: &[u8]) -> ConmonResult<()> {
-        let mut first_error: Option<ConmonError> = None;
-        for p in &mut self.plugins {
-            if let Err(e) = p.write(is_stdout, data) {
-                if first_error.is_none() {
-                    first_error = Some(e);
-                }
-            }
-        }
-        match first_error {
-            Some(e) => Err(e),
-            None => Ok(()),
-        }
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        let results = self
+            .targets
+            .iter_mut()
+            .filter(|t| t.streams.accepts(stream))
+            .map(|t| {
+                let res = t.plugin.write(stream, data);
+                t.track(res)
+            })
+            .collect();
+        all_failed(results)
     }
 
     fn reopen(&mut self) -> ConmonResult<()> {
-        let mut first_error: Option<ConmonError> = None;
-        for p in &mut self.plugins {
-            if let Err(e) = p.reopen() {
-                if first_error.is_none() {
-                    first_error = Some(e);
-                }
-            }
-        }
-        match first_error {
-            Some(e) => Err(e),
-            None => Ok(()),
+        let results = self
+            .targets
+            .iter_mut()
+            .map(|t| {
+                let res = t.plugin.reopen();
+                t.track(res)
+            })
+            .collect();
+        all_failed(results)
+    }
+
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        let results = self
+            .targets
+            .iter_mut()
+            .map(|t| {
+                let res = t.plugin.reopen_files();
+                t.track(res)
+            })
+            .collect();
+        all_failed(results)
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        // A thread left running must not be missed, so any error counts.
+        let results: Vec<_> = self
+            .targets
+            .iter_mut()
+            .map(|t| t.plugin.stop_threads())
+            .collect();
+        results.into_iter().collect()
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        let results = self
+            .targets
+            .iter_mut()
+            .map(|t| {
+                let res = t.plugin.shutdown();
+                t.track(res)
+            })
+            .collect();
+        all_failed(results)
+    }
+
+    fn on_container_start(&mut self, pid: i32, metadata: &ContainerMetadata) -> ConmonResult<()> {
+        let results = self
+            .targets
+            .iter_mut()
+            .map(|t| {
+                let res = t.plugin.on_container_start(pid, metadata);
+                t.track(res)
+            })
+            .collect();
+        all_failed(results)
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        let results = self
+            .targets
+            .iter_mut()
+            .map(|t| {
+                let res = t.plugin.on_container_exit(exit);
+                t.track(res)
+            })
+            .collect();
+        all_failed(results)
+    }
+}
+
+/// Log plugin dropping the output once `--log-global-size-max` is reached.
+///
+/// It wraps the drivers which do not account the written bytes themselves,
+/// so the limit is shared by all the log targets.
+pub struct SizeLimitedLogPlugin {
+    inner: Box<dyn LogPlugin>,
+}
+
+impl SizeLimitedLogPlugin {
+    pub fn new(inner: Box<dyn LogPlugin>) -> Self {
+        Self { inner }
+    }
+}
+
+impl LogPlugin for SizeLimitedLogPlugin {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        // The empty write only flushes the buffered data.
+        if !data.is_empty() && !budget::reserve(data.len() as u64) {
+            budget::record_dropped(data.len() as u64);
+            return Ok(());
         }
+        self.inner.write(stream, data)
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        self.inner.reopen()
+    }
+
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        self.inner.reopen_files()
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.inner.stop_threads()
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        self.inner.shutdown()
+    }
+
+    fn on_container_start(&mut self, pid: i32, metadata: &ContainerMetadata) -> ConmonResult<()> {
+        self.inner.on_container_start(pid, metadata)
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        self.inner.on_container_exit(exit)
+    }
+}
+
+/// Returns true if the log driver `name` writes the lines as they arrive, so
+/// the lines split across reads have to be reassembled before.
+fn needs_line_buffer(name: &str) -> bool {
+    matches!(name, "file" | "k8s_file" | "json_file" | "json_lines")
+}
+
+/// Returns true if the log driver `name` stores the output and does not
+/// account the written bytes in the global budget itself.
+fn needs_size_limit(name: &str) -> bool {
+    !matches!(
+        name,
+        "file" | "k8s_file" | "json_file" | "json_lines" | "none" | "passthrough" | "null" | "off"
+    )
+}
+
+/// Returns the name of the log target used in the log messages.
+pub(crate) fn log_target_name(name: &str, cfg: &LogPluginCfg) -> String {
+    if cfg.path.as_os_str().is_empty() {
+        name.to_string()
+    } else {
+        format!("{name}:{}", cfg.path.display())
     }
 }
 
+/// Creates the log plugin `name` for a single log target, with the line
+/// reassembly and the `--log-global-size-max` limit it needs.
+pub(crate) fn create_log_target(
+    name: &str,
+    cfg: &LogPluginCfg,
+) -> ConmonResult<Box<dyn LogPlugin>> {
+    let mut plugin = create_log_plugin(name, cfg)?;
+    if needs_line_buffer(name) {
+        plugin = Box::new(LineBufferedLogPlugin::new(plugin, cfg.line_max));
+    }
+    if cfg.global_max_size > 0 && needs_size_limit(name) {
+        plugin = Box::new(SizeLimitedLogPlugin::new(plugin));
+    }
+    Ok(plugin)
+}
+
 /// Initializes one or more log plugins from (name, cfg) entries.
-/// If there is exactly one entry, returns that plugin directly; otherwise
-/// returns a MultiLogPlugin that fans out to all of them.
+/// If there is exactly one entry logging all the streams, returns that plugin
+/// directly; otherwise returns a MultiLogPlugin that routes the streams to them.
 pub fn initialize_log_plugins(
     entries: &[(String, LogPluginCfg)],
 ) -> ConmonResult<Box<dyn LogPlugin>> {
     if entries.is_empty() {
         return Err(ConmonError::new("No log plugin entries provided", 1));
     }
-    let mut plugins: Vec<Box<dyn LogPlugin>> = Vec::with_capacity(entries.len());
+    let global_max_size = entries
+        .iter()
+        .map(|(_, cfg)| cfg.global_max_size)
+        .max()
+        .unwrap_or(0);
+    budget::set_global_max_size(global_max_size as u64);
+
+    let mut plugins: Vec<(String, LogStreams, Box<dyn LogPlugin>)> =
+        Vec::with_capacity(entries.len());
     for (name, cfg) in entries {
-        plugins.push(create_log_plugin(name, cfg)?);
+        let plugin = create_log_target(name, cfg)?;
+        plugins.push((log_target_name(name, cfg), cfg.streams, plugin));
     }
-    if plugins.len() == 1 {
-        Ok(plugins.into_iter().next().unwrap())
+    let plugin: Box<dyn LogPlugin> =
+        if plugins.len() == 1 && plugins[0].1 == LogStreams::AllWithStdin {
+            plugins.into_iter().next().unwrap().2
+        } else {
+            Box::new(MultiLogPlugin::with_names(plugins))
+        };
+    // The fallbacks, the rate limit, the dedup and the redaction apply to the
+    // container output, not per log target.
+    let cfg = &entries[0].1;
+    let plugin: Box<dyn LogPlugin> = if cfg.fallbacks.is_empty() {
+        plugin
     } else {
-        Ok(Box::new(MultiLogPlugin::new(plugins)))
+        let fallbacks = cfg
+            .fallbacks
+            .iter()
+            .map(|(name, path)| {
+                let mut fallback_cfg = cfg.clone();
+                fallback_cfg.path = path.clone();
+                (name.clone(), fallback_cfg)
+            })
+            .collect();
+        let names: Vec<String> = entries
+            .iter()
+            .map(|(name, cfg)| log_target_name(name, cfg))
+            .collect();
+        Box::new(FallbackLogPlugin::new(
+            plugin,
+            names.join(","),
+            fallbacks,
+            cfg.fallback_after,
+        ))
+    };
+    let mut plugin: Box<dyn LogPlugin> = if cfg.rate_limit > 0 {
+        Box::new(RateLimitedLogPlugin::new(
+            plugin,
+            cfg.rate_limit,
+            cfg.rate_burst,
+        ))
+    } else {
+        plugin
+    };
+    // The repeated lines are collapsed before they count into the rate limit.
+    let dedup = cfg.dedup || cfg.sample > 1;
+    if dedup {
+        plugin = Box::new(DedupLogPlugin::new(plugin, cfg.dedup, cfg.sample));
+    }
+    if !cfg.redact_patterns.is_empty() {
+        plugin = Box::new(RedactingLogPlugin::new(
+            plugin,
+            Redactor::new(&cfg.redact_patterns)?,
+        ));
+    }
+    if dedup || !cfg.redact_patterns.is_empty() {
+        // Both work on complete lines, so a secret split across two reads of
+        // the container output is redacted too.
+        plugin = Box::new(LineBufferedLogPlugin::new(plugin, cfg.line_max));
     }
+    if cfg.writer_thread {
+        return Ok(Box::new(ThreadedLogPlugin::new(
+            plugin,
+            WRITER_QUEUE_LEN,
+            cfg.writer_policy.unwrap_or(DeliveryPolicy::Block),
+        )));
+    }
+    Ok(plugin)
 }
 
 /// Thin wrapper for a single (name, cfg) pair; delegates to initialize_log_plugins.
@@ -118,18 +767,152 @@ mod tests {
             ("none".to_string(), cfg),
         ];
         let mut plugin = initialize_log_plugins(&entries)?;
-        plugin.write(true, b"hello")?;
-        plugin.write(false, b"world")?;
+        plugin.write(LogStream::Stdout, b"hello")?;
+        plugin.write(LogStream::Stderr, b"world")?;
         plugin.reopen()?;
         Ok(())
     }
 
+    #[test]
+    fn multi_log_plugin_routes_streams() -> ConmonResult<()> {
+        use std::sync::{Arc, Mutex};
+
+        struct Recorder(Arc<Mutex<Vec<LogStream>>>);
+        impl LogPlugin for Recorder {
+            fn write(&mut self, stream: LogStream, _data: &[u8]) -> ConmonResult<()> {
+                self.0.lock().unwrap().push(stream);
+                Ok(())
+            }
+            fn reopen(&mut self) -> ConmonResult<()> {
+                Ok(())
+            }
+        }
+
+        let stdout_rec = Arc::new(Mutex::new(Vec::new()));
+        let stderr_rec = Arc::new(Mutex::new(Vec::new()));
+        let all_rec = Arc::new(Mutex::new(Vec::new()));
+        let input_rec = Arc::new(Mutex::new(Vec::new()));
+        let mut plugin = MultiLogPlugin::with_streams(vec![
+            (LogStreams::Stdout, Box::new(Recorder(stdout_rec.clone()))),
+            (LogStreams::Stderr, Box::new(Recorder(stderr_rec.clone()))),
+            (LogStreams::All, Box::new(Recorder(all_rec.clone()))),
+            (
+                LogStreams::AllWithStdin,
+                Box::new(Recorder(input_rec.clone())),
+            ),
+        ]);
+        plugin.write(LogStream::Stdout, b"out")?;
+        plugin.write(LogStream::Stderr, b"err")?;
+        plugin.write(LogStream::Stdin, b"in")?;
+        plugin.write(LogStream::Stdout, b"out")?;
+
+        use LogStream::*;
+        assert_eq!(*stdout_rec.lock().unwrap(), vec![Stdout, Stdout]);
+        assert_eq!(*stderr_rec.lock().unwrap(), vec![Stderr]);
+        assert_eq!(*all_rec.lock().unwrap(), vec![Stdout, Stderr, Stdout]);
+        assert_eq!(
+            *input_rec.lock().unwrap(),
+            vec![Stdout, Stderr, Stdin, Stdout]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn multi_log_plugin_isolates_failing_destinations() -> ConmonResult<()> {
+        use std::sync::{Arc, Mutex};
+
+        struct Target(Option<Arc<Mutex<usize>>>);
+        impl LogPlugin for Target {
+            fn write(&mut self, _stream: LogStream, _data: &[u8]) -> ConmonResult<()> {
+                match &self.0 {
+                    Some(count) => {
+                        *count.lock().unwrap() += 1;
+                        Ok(())
+                    }
+                    None => Err(ConmonError::new("disk full", 1)),
+                }
+            }
+            fn reopen(&mut self) -> ConmonResult<()> {
+                Ok(())
+            }
+        }
+
+        let count = Arc::new(Mutex::new(0));
+        let mut plugin = MultiLogPlugin::with_names(vec![
+            ("broken".into(), LogStreams::All, Box::new(Target(None))),
+            (
+                "working".into(),
+                LogStreams::Stdout,
+                Box::new(Target(Some(count.clone()))),
+            ),
+        ]);
+        plugin.write(LogStream::Stdout, b"out")?;
+        plugin.write(LogStream::Stdout, b"out")?;
+        assert_eq!(*count.lock().unwrap(), 2);
+        assert!(plugin.targets[0].failing);
+
+        // Only the broken destination accepts stderr.
+        let err = plugin.write(LogStream::Stderr, b"err").unwrap_err();
+        assert_eq!(err.msg, "disk full");
+        // No destination accepts stdin.
+        plugin.write(LogStream::Stdin, b"in")?;
+        Ok(())
+    }
+
+    #[test]
+    fn log_tag_placeholders_are_expanded() -> ConmonResult<()> {
+        let cid = Some("0123456789abcdef");
+        let expand = |template| expand_log_tag(template, cid, Some("web"));
+        assert_eq!(expand("{{.Name}}/{{ .ID }}")?, "web/0123456789ab");
+        assert_eq!(
+            expand("%N-%i-%I 100%%")?,
+            "web-0123456789ab-0123456789abcdef 100%"
+        );
+        assert_eq!(expand("{{.FullID}}{x}")?, "0123456789abcdef{x}");
+        assert_eq!(expand_log_tag("%N", None, None)?, "");
+        for bad in ["{{.Image}}", "{{.Name", "%x", "50%"] {
+            assert!(expand(bad).is_err(), "{bad}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn log_labels_are_parsed_and_validated() -> ConmonResult<()> {
+        let labels = parse_log_labels(&["B=2".into(), "A=".into()])?;
+        assert_eq!(
+            labels.into_iter().collect::<Vec<_>>(),
+            vec![("A".into(), "".into()), ("B".into(), "2".into())]
+        );
+        for bad in [vec!["A"], vec!["=1"], vec!["A=1=2"], vec!["A=1", "A=2"]] {
+            let bad: Vec<String> = bad.into_iter().map(String::from).collect();
+            assert!(parse_log_labels(&bad).is_err(), "{bad:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn log_opt_lookup_and_parse() -> ConmonResult<()> {
+        let cfg = LogPluginCfg {
+            log_opts: vec![
+                ("size".to_string(), "1".to_string()),
+                ("size".to_string(), "2".to_string()),
+                ("bad".to_string(), "x".to_string()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(cfg.log_opt("size"), Some("2"));
+        assert_eq!(cfg.log_opt_parse("size", 0u64)?, 2);
+        assert_eq!(cfg.log_opt_parse("missing", 7u64)?, 7);
+        assert!(cfg.log_opt_parse("bad", 0u64).is_err());
+        Ok(())
+    }
+
     #[test]
     fn initialize_log_plugin_null_alias_works() -> ConmonResult<()> {
         let cfg = LogPluginCfg::default();
         let mut plugin = initialize_log_plugin("null", &cfg)?;
-        plugin.write(true, b"test")?;
-        plugin.write(false, b"data")?;
+        plugin.write(LogStream::Stdout, b"test")?;
+        plugin.write(LogStream::Stderr, b"data")?;
         plugin.reopen()?;
         Ok(())
     }
@@ -138,8 +921,8 @@ mod tests {
     fn initialize_log_plugin_off_alias_works() -> ConmonResult<()> {
         let cfg = LogPluginCfg::default();
         let mut plugin = initialize_log_plugin("off", &cfg)?;
-        plugin.write(true, b"test")?;
-        plugin.write(false, b"data")?;
+        plugin.write(LogStream::Stdout, b"test")?;
+        plugin.write(LogStream::Stderr, b"data")?;
         plugin.reopen()?;
         Ok(())
     }
@@ -0,0 +1,144 @@
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// State of the queue shared between a log plugin and its background thread.
+pub trait QueueState {
+    /// Returns the number of the queued entries.
+    fn queued(&self) -> usize;
+
+    /// Sets whether the thread should process the remaining entries and exit.
+    fn set_closed(&mut self, closed: bool);
+}
+
+/// Bounded queue shared between a log plugin and its background thread.
+///
+/// The state is guarded by a mutex and the condition variable is signalled
+/// whenever the state changes. A poisoned mutex is recovered, so a panic of
+/// one side does not bring the other one down.
+#[derive(Default)]
+pub struct SharedQueue<Q> {
+    state: Mutex<Q>,
+    cond: Condvar,
+}
+
+impl<Q: QueueState> SharedQueue<Q> {
+    /// Locks the state.
+    pub fn lock(&self) -> MutexGuard<'_, Q> {
+        match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Wakes up all the waiters after the state changed.
+    pub fn notify_all(&self) {
+        self.cond.notify_all();
+    }
+
+    /// Waits until the state changes.
+    pub fn wait<'a>(&self, guard: MutexGuard<'a, Q>) -> MutexGuard<'a, Q> {
+        match self.cond.wait(guard) {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Waits until the state changes or the `timeout` expires.
+    pub fn wait_timeout<'a>(
+        &self,
+        guard: MutexGuard<'a, Q>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, Q> {
+        match self.cond.wait_timeout(guard, timeout) {
+            Ok((guard, _)) => guard,
+            Err(poisoned) => poisoned.into_inner().0,
+        }
+    }
+
+    /// Waits while `condition` holds, at most until the `timeout` expires.
+    pub fn wait_timeout_while<'a>(
+        &self,
+        guard: MutexGuard<'a, Q>,
+        timeout: Duration,
+        condition: impl FnMut(&mut Q) -> bool,
+    ) -> MutexGuard<'a, Q> {
+        match self.cond.wait_timeout_while(guard, timeout, condition) {
+            Ok((guard, _)) => guard,
+            Err(poisoned) => poisoned.into_inner().0,
+        }
+    }
+
+    /// Waits until fewer than `max` entries are queued.
+    pub fn wait_for_room<'a>(&self, mut guard: MutexGuard<'a, Q>, max: usize) -> MutexGuard<'a, Q> {
+        while guard.queued() >= max {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Asks the thread to process the remaining entries and exit, and waits
+    /// for it. The queue is open again afterwards, so a new thread can be
+    /// started.
+    ///
+    /// # Returns
+    ///
+    /// * The result of the thread, or the error if it panicked.
+    pub fn close_and_join<R>(&self, handle: JoinHandle<R>) -> thread::Result<R> {
+        {
+            let mut state = self.lock();
+            state.set_closed(true);
+            self.notify_all();
+        }
+        let result = handle.join();
+        self.lock().set_closed(false);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct Counter {
+        queued: usize,
+        closed: bool,
+    }
+
+    impl QueueState for Counter {
+        fn queued(&self) -> usize {
+            self.queued
+        }
+
+        fn set_closed(&mut self, closed: bool) {
+            self.closed = closed;
+        }
+    }
+
+    #[test]
+    fn close_and_join_reopens_queue() {
+        let shared = Arc::new(SharedQueue::<Counter>::default());
+        shared.lock().queued = 2;
+        let thread_shared = shared.clone();
+        let handle = thread::spawn(move || {
+            let mut state = thread_shared.lock();
+            while !state.closed {
+                state = thread_shared.wait(state);
+            }
+            state.queued = 0;
+            thread_shared.notify_all();
+            7
+        });
+
+        // The thread empties the queue only once it is closed.
+        let state = shared.wait_for_room(shared.lock(), 3);
+        assert_eq!(state.queued, 2);
+        drop(state);
+        assert_eq!(shared.close_and_join(handle).unwrap(), 7);
+        let state = shared.lock();
+        assert_eq!(state.queued, 0);
+        assert!(!state.closed);
+    }
+}
@@ -0,0 +1,224 @@
+use std::time::Instant;
+
+use log::{info, warn};
+
+use crate::{
+    error::ConmonResult,
+    logging::plugin::{ContainerExit, ContainerMetadata, LogPlugin, LogStream},
+    metrics::metrics,
+};
+
+/// Token bucket limiting the container output to `rate` bytes per second
+/// with bursts of up to `burst` bytes.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+
+    /// Bytes which can be written right now.
+    tokens: f64,
+
+    /// Time of the last refill.
+    last: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// Creates new full TokenBucket. Zero `burst` defaults to `rate`.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        let burst = if burst == 0 { rate } else { burst };
+        Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            last: None,
+        }
+    }
+
+    /// Takes `len` bytes from the bucket.
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - The number of bytes to write.
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    ///
+    /// * False if the bucket does not hold `len` bytes, in which case nothing
+    ///   is taken.
+    pub fn take(&mut self, len: u64, now: Instant) -> bool {
+        if let Some(last) = self.last {
+            let refill = now.saturating_duration_since(last).as_secs_f64() * self.rate as f64;
+            self.tokens = (self.tokens + refill).min(self.burst as f64);
+        }
+        self.last = Some(now);
+        if self.tokens < len as f64 {
+            return false;
+        }
+        self.tokens -= len as f64;
+        true
+    }
+}
+
+/// Returns the number of lines in `data`, counting the unterminated tail.
+fn count_lines(data: &[u8]) -> u64 {
+    let newlines = data.iter().filter(|b| **b == b'\n').count() as u64;
+    if data.last().is_some_and(|b| *b != b'\n') {
+        newlines + 1
+    } else {
+        newlines
+    }
+}
+
+/// Log plugin dropping the container output exceeding `--log-rate-limit`.
+///
+/// Once the output fits the limit again, or on the final flush, a record
+/// with the number of dropped lines and bytes is written before the output.
+pub struct RateLimitedLogPlugin {
+    inner: Box<dyn LogPlugin>,
+    bucket: TokenBucket,
+
+    /// Lines dropped since the last accepted write.
+    dropped_lines: u64,
+
+    /// Bytes dropped since the last accepted write.
+    dropped_bytes: u64,
+
+    /// The stream the dropped output comes from.
+    dropped_stream: LogStream,
+}
+
+impl RateLimitedLogPlugin {
+    pub fn new(inner: Box<dyn LogPlugin>, rate: u64, burst: u64) -> Self {
+        info!("Limiting the container output to {rate} bytes per second");
+        Self {
+            inner,
+            bucket: TokenBucket::new(rate, burst),
+            dropped_lines: 0,
+            dropped_bytes: 0,
+            dropped_stream: LogStream::Stdout,
+        }
+    }
+
+    /// Writes `data` if it fits the limit at `now`.
+    fn write_at(&mut self, stream: LogStream, data: &[u8], now: Instant) -> ConmonResult<()> {
+        // The empty write only flushes the buffered data.
+        if !data.is_empty() && !self.bucket.take(data.len() as u64, now) {
+            if self.dropped_lines == 0 {
+                warn!("Container output exceeds --log-rate-limit, dropping it");
+            }
+            let lines = count_lines(data);
+            metrics().record_log_drop("rate-limit", lines);
+            self.dropped_lines += lines;
+            self.dropped_bytes += data.len() as u64;
+            self.dropped_stream = stream;
+            return Ok(());
+        }
+        self.write_dropped_record()?;
+        self.inner.write(stream, data)
+    }
+
+    /// Writes the record summarizing the dropped output, if any.
+    fn write_dropped_record(&mut self) -> ConmonResult<()> {
+        if self.dropped_lines == 0 {
+            return Ok(());
+        }
+        info!("Dropped {} lines over --log-rate-limit", self.dropped_lines);
+        let record = format!(
+            "conmon: {} messages dropped ({} bytes) by --log-rate-limit\n",
+            self.dropped_lines, self.dropped_bytes
+        );
+        self.dropped_lines = 0;
+        self.dropped_bytes = 0;
+        self.inner.write(self.dropped_stream, record.as_bytes())
+    }
+}
+
+impl LogPlugin for RateLimitedLogPlugin {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        self.write_at(stream, data, Instant::now())
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        self.inner.reopen()
+    }
+
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        self.inner.reopen_files()
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.inner.stop_threads()
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        self.inner.shutdown()
+    }
+
+    fn on_container_start(&mut self, pid: i32, metadata: &ContainerMetadata) -> ConmonResult<()> {
+        self.inner.on_container_start(pid, metadata)
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        self.inner.on_container_exit(exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    type Records = Arc<Mutex<Vec<(LogStream, String)>>>;
+
+    struct Recorder(Records);
+
+    impl LogPlugin for Recorder {
+        fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+            let data = String::from_utf8_lossy(data).into_owned();
+            self.0.lock().unwrap().push((stream, data));
+            Ok(())
+        }
+        fn reopen(&mut self) -> ConmonResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bucket_refills_at_rate_up_to_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, 200);
+        assert!(bucket.take(200, start));
+        assert!(!bucket.take(1, start));
+        assert!(bucket.take(50, start + Duration::from_millis(500)));
+        assert!(!bucket.take(1, start + Duration::from_millis(500)));
+        // The bucket never holds more than the burst.
+        assert!(!bucket.take(201, start + Duration::from_secs(60)));
+        assert!(bucket.take(200, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn dropped_output_is_summarized() -> ConmonResult<()> {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut plugin = RateLimitedLogPlugin::new(Box::new(Recorder(written.clone())), 10, 10);
+        let start = Instant::now();
+        plugin.write_at(LogStream::Stdout, b"0123456\n", start)?;
+        plugin.write_at(LogStream::Stderr, b"a\nb\nc", start)?;
+        plugin.write_at(LogStream::Stdout, b"ok\n", start + Duration::from_secs(1))?;
+        plugin.write_at(LogStream::Stdout, b"", start + Duration::from_secs(1))?;
+
+        assert_eq!(
+            *written.lock().unwrap(),
+            vec![
+                (LogStream::Stdout, "0123456\n".to_string()),
+                (
+                    LogStream::Stderr,
+                    "conmon: 3 messages dropped (5 bytes) by --log-rate-limit\n".to_string()
+                ),
+                (LogStream::Stdout, "ok\n".to_string()),
+                (LogStream::Stdout, "".to_string()),
+            ]
+        );
+        Ok(())
+    }
+}
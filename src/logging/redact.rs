@@ -0,0 +1,241 @@
+use std::fs;
+use std::path::Path;
+
+use crate::{
+    error::{ConmonError, ConmonResult},
+    logging::plugin::{ContainerExit, ContainerMetadata, LogPlugin, LogStream},
+};
+
+/// Replacement of the redacted values.
+pub const REDACTED: &[u8] = b"[REDACTED]";
+
+/// Returns true if `c` separates the key from its value, like in
+/// `key=value`, `key: value` or `"key": "value"`.
+fn is_separator(c: u8) -> bool {
+    c.is_ascii_whitespace() || matches!(c, b':' | b'=' | b'"' | b'\'')
+}
+
+/// Returns true if `c` ends the value.
+fn ends_value(c: u8) -> bool {
+    c.is_ascii_whitespace() || matches!(c, b'"' | b'\'' | b',' | b';' | b'&')
+}
+
+/// Reads the `--log-redact-rules` file with one key pattern per line.
+/// Empty lines and lines starting with '#' are ignored.
+pub fn load_redact_rules(path: &Path) -> ConmonResult<Vec<String>> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        ConmonError::new(
+            format!("Failed to read redaction rules {}: {e}", path.display()),
+            1,
+        )
+    })?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Replaces the values following the configured keys with `[REDACTED]`.
+///
+/// The keys are matched case-insensitively. The value starts after the
+/// separators following the key (white-space, ':', '=' and quotes) and ends
+/// at white-space, quote, ',', ';' or '&'. A key ending with alphanumeric
+/// character has to be followed by a separator, so `password` does not
+/// match in `passwords`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redactor {
+    /// The lower-cased keys.
+    keys: Vec<Vec<u8>>,
+}
+
+impl Redactor {
+    /// Creates new Redactor from the key `patterns`.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if a pattern is empty.
+    pub fn new(patterns: &[String]) -> ConmonResult<Self> {
+        let mut keys = Vec::with_capacity(patterns.len());
+        for p in patterns {
+            let p = p.trim();
+            if p.is_empty() {
+                return Err(ConmonError::new("Empty --log-redact-pattern", 1));
+            }
+            keys.push(p.to_ascii_lowercase().into_bytes());
+        }
+        Ok(Self { keys })
+    }
+
+    /// Returns the position of the value following the key matching at
+    /// `pos` in `line`, or None if no key matches there.
+    fn value_start(&self, line: &[u8], pos: usize) -> Option<usize> {
+        for key in &self.keys {
+            let end = pos + key.len();
+            if end > line.len() || !line[pos..end].eq_ignore_ascii_case(key) {
+                continue;
+            }
+            let mut start = end;
+            while start < line.len() && is_separator(line[start]) {
+                start += 1;
+            }
+            let key_ends_with_word = key.last().is_some_and(|c| c.is_ascii_alphanumeric());
+            if start == end && key_ends_with_word {
+                continue;
+            }
+            return Some(start);
+        }
+        None
+    }
+
+    /// Returns `line` with the values of the keys redacted.
+    pub fn redact(&self, line: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(line.len());
+        let mut pos = 0;
+        while pos < line.len() {
+            let Some(start) = self.value_start(line, pos) else {
+                out.push(line[pos]);
+                pos += 1;
+                continue;
+            };
+            let mut end = start;
+            while end < line.len() && !ends_value(line[end]) {
+                end += 1;
+            }
+            out.extend_from_slice(&line[pos..start]);
+            if end > start {
+                out.extend_from_slice(REDACTED);
+            }
+            pos = end;
+        }
+        out
+    }
+}
+
+/// Log plugin redacting the sensitive values in the container output before
+/// it reaches the log drivers (`--log-redact-pattern`).
+///
+/// It expects complete lines, so a value split across reads is still
+/// redacted; the caller wraps it in a `LineBufferedLogPlugin`.
+pub struct RedactingLogPlugin {
+    inner: Box<dyn LogPlugin>,
+    redactor: Redactor,
+}
+
+impl RedactingLogPlugin {
+    pub fn new(inner: Box<dyn LogPlugin>, redactor: Redactor) -> Self {
+        Self { inner, redactor }
+    }
+}
+
+impl LogPlugin for RedactingLogPlugin {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        if data.is_empty() {
+            return self.inner.write(stream, data);
+        }
+        self.inner.write(stream, &self.redactor.redact(data))
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        self.inner.reopen()
+    }
+
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        self.inner.reopen_files()
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.inner.stop_threads()
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        self.inner.shutdown()
+    }
+
+    fn on_container_start(&mut self, pid: i32, metadata: &ContainerMetadata) -> ConmonResult<()> {
+        self.inner.on_container_start(pid, metadata)
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        self.inner.on_container_exit(exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::plugin::{LogPluginCfg, initialize_log_plugin};
+    use std::io::Write;
+    use tempfile::{NamedTempFile, tempdir};
+
+    fn redact(patterns: &[&str], line: &str) -> String {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        let redactor = Redactor::new(&patterns).unwrap();
+        String::from_utf8(redactor.redact(line.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn values_after_keys_are_redacted() {
+        let keys = ["password", "Bearer", "AWS_SECRET_ACCESS_KEY", "token="];
+        for (line, expected) in [
+            (
+                "login password=hunter2 ok\n",
+                "login password=[REDACTED] ok\n",
+            ),
+            (
+                "Authorization: bearer eyJhbGciOi.x.y\n",
+                "Authorization: bearer [REDACTED]\n",
+            ),
+            (
+                r#"{"password": "s3cr3t", "user": "bob"}"#,
+                r#"{"password": "[REDACTED]", "user": "bob"}"#,
+            ),
+            (
+                "export AWS_SECRET_ACCESS_KEY='abc/def+ghi'\n",
+                "export AWS_SECRET_ACCESS_KEY='[REDACTED]'\n",
+            ),
+            (
+                "GET /?token=abc&page=2\n",
+                "GET /?token=[REDACTED]&page=2\n",
+            ),
+            // Keys ending with a word character need a separator.
+            ("passwords are hard\n", "passwords are hard\n"),
+            ("password:\n", "password:\n"),
+        ] {
+            assert_eq!(redact(&keys, line), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn values_split_across_writes_are_redacted() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("ctr.log");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            redact_patterns: vec!["password".into()],
+            ..Default::default()
+        };
+        let mut plugin = initialize_log_plugin("file", &cfg)?;
+        plugin.write(LogStream::Stdout, b"pass")?;
+        plugin.write(LogStream::Stdout, b"word=hun")?;
+        plugin.write(LogStream::Stdout, b"ter2\n")?;
+        plugin.write(LogStream::Stdout, b"")?;
+        let log = fs::read_to_string(&path)?;
+        assert!(log.ends_with("stdout F password=[REDACTED]\n"), "{log}");
+        Ok(())
+    }
+
+    #[test]
+    fn rules_file_and_empty_patterns() -> ConmonResult<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "# secrets\n\n  api_key \nsecret")?;
+        assert_eq!(
+            load_redact_rules(file.path())?,
+            vec!["api_key".to_string(), "secret".to_string()]
+        );
+        assert!(load_redact_rules(Path::new("/nonexistent/rules")).is_err());
+        assert!(Redactor::new(&[" ".to_string()]).is_err());
+        Ok(())
+    }
+}
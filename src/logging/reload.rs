@@ -0,0 +1,134 @@
+use log::{info, warn};
+
+use crate::{
+    error::ConmonResult,
+    logging::plugin::{
+        ContainerExit, ContainerMetadata, LogPlugin, LogPluginCfg, LogStream,
+        initialize_log_plugins,
+    },
+};
+
+/// Log plugin which can be re-created from its configuration while the
+/// container runs, on SIGHUP or the `reload-log` control request.
+///
+/// The new log drivers are initialized first, so a failing reload keeps the
+/// old ones. Once they are ready, the writes go to the new log drivers and
+/// the old ones are drained and closed.
+pub struct ReloadableLogPlugin {
+    active: Box<dyn LogPlugin>,
+
+    /// The (driver, cfg) entries the log drivers are created from.
+    entries: Vec<(String, LogPluginCfg)>,
+}
+
+impl ReloadableLogPlugin {
+    /// Creates new ReloadableLogPlugin.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the log drivers cannot be initialized.
+    pub fn new(entries: Vec<(String, LogPluginCfg)>) -> ConmonResult<Self> {
+        Ok(Self {
+            active: initialize_log_plugins(&entries)?,
+            entries,
+        })
+    }
+}
+
+impl LogPlugin for ReloadableLogPlugin {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        self.active.write(stream, data)
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        self.active.reopen()
+    }
+
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        self.active.reopen_files()
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.active.stop_threads()
+    }
+
+    fn reload(&mut self) -> ConmonResult<()> {
+        let new = initialize_log_plugins(&self.entries)?;
+        let mut old = std::mem::replace(&mut self.active, new);
+        // Write the partial lines and the queued records of the old log
+        // drivers before closing them.
+        for stream in [LogStream::Stdout, LogStream::Stderr, LogStream::Stdin] {
+            if let Err(e) = old.write(stream, &[]) {
+                warn!("Failed to drain the old {} log: {}", stream.name(), e.msg);
+            }
+        }
+        if let Err(e) = old.shutdown() {
+            warn!("Failed to close the old log drivers: {}", e.msg);
+        }
+        info!("Reloaded the log drivers");
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        self.active.shutdown()
+    }
+
+    fn on_container_start(&mut self, pid: i32, metadata: &ContainerMetadata) -> ConmonResult<()> {
+        self.active.on_container_start(pid, metadata)
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        self.active.on_container_exit(exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::plugin::{reopen_if_requested, request_reload};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn reload_switches_to_new_log_drivers() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("ctr.log");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            ..Default::default()
+        };
+        let mut plugin = ReloadableLogPlugin::new(vec![("k8s_file".into(), cfg)])?;
+        plugin.write(LogStream::Stdout, b"before ")?;
+
+        // The log file is recreated by the new log driver.
+        fs::rename(&path, tmp.path().join("old.log"))?;
+        request_reload();
+        reopen_if_requested(&mut plugin);
+        plugin.write(LogStream::Stdout, b"after\n")?;
+        plugin.write(LogStream::Stdout, b"")?;
+
+        let old = fs::read_to_string(tmp.path().join("old.log"))?;
+        assert!(old.contains("stdout P before \n"), "{old}");
+        let new = fs::read_to_string(&path)?;
+        assert!(new.ends_with("stdout F after\n"), "{new}");
+        Ok(())
+    }
+
+    #[test]
+    fn failed_reload_keeps_old_log_drivers() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let dir = tmp.path().join("logs");
+        fs::create_dir(&dir)?;
+        let path = dir.join("ctr.log");
+        let cfg = LogPluginCfg {
+            path: path.clone(),
+            ..Default::default()
+        };
+        let mut plugin = ReloadableLogPlugin::new(vec![("k8s_file".into(), cfg)])?;
+        fs::remove_file(&path)?;
+        fs::remove_dir(&dir)?;
+        assert!(plugin.reload().is_err());
+        plugin.write(LogStream::Stdout, b"still logged\n")?;
+        Ok(())
+    }
+}
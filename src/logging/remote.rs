@@ -0,0 +1,611 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use log::{info, warn};
+
+use crate::error::{ConmonError, ConmonResult};
+use crate::logging::line_buffer::LineBuffer;
+use crate::logging::plugin::{DeliveryPolicy, LogStream};
+use crate::logging::queue::{QueueState, SharedQueue};
+use crate::metrics::metrics;
+
+/// Maximum time to wait for the buffered records to be delivered on flush.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Single line of the container output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// Time the line was read from the container.
+    pub timestamp: DateTime<Local>,
+
+    /// The stream the line comes from.
+    pub stream: LogStream,
+
+    /// True if the line does not end with a new-line.
+    pub partial: bool,
+
+    /// The line without the trailing new-line.
+    pub line: Vec<u8>,
+}
+
+impl LogRecord {
+    /// Returns the name of the stream the line comes from.
+    pub fn stream(&self) -> &'static str {
+        self.stream.name()
+    }
+
+    /// Returns the record formatted the same way as in the k8s-file log,
+    /// including the trailing new-line.
+    pub fn to_k8s_line(&self) -> Vec<u8> {
+        let mut out = format!(
+            "{} {} {} ",
+            self.timestamp.to_rfc3339_opts(SecondsFormat::Nanos, false),
+            self.stream(),
+            if self.partial { "P" } else { "F" }
+        )
+        .into_bytes();
+        out.extend_from_slice(&self.line);
+        out.push(b'\n');
+        out
+    }
+}
+
+/// Splits the container output reassembled by the `LineBuffer` into
+/// `LogRecord`s.
+///
+/// The data not ending with a new-line is kept until the rest of the line
+/// arrives or until the buffer is flushed.
+pub struct LineSplitter {
+    lines: LineBuffer,
+}
+
+impl Default for LineSplitter {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl LineSplitter {
+    /// Creates new LineSplitter splitting the lines longer than `line_max`
+    /// (`--log-line-max`), or `DEFAULT_LINE_MAX` bytes if zero.
+    pub fn new(line_max: usize) -> Self {
+        Self {
+            lines: LineBuffer::new(line_max),
+        }
+    }
+
+    /// Splits `data` into records. Empty `data` flushes the pending partial line.
+    pub fn split(&mut self, stream: LogStream, data: &[u8]) -> Vec<LogRecord> {
+        let timestamp = Local::now();
+        let mut records = Vec::new();
+        // The chunks are runs of complete lines or a single part of a line.
+        // Collecting the records never fails.
+        let _ = self.lines.push(stream, data, |chunk| {
+            for line in chunk.split_inclusive(|&c| c == b'\n') {
+                let (line, partial) = match line.strip_suffix(b"\n") {
+                    Some(line) => (line, false),
+                    None => (line, true),
+                };
+                records.push(LogRecord {
+                    timestamp,
+                    stream,
+                    partial,
+                    line: line.to_vec(),
+                });
+            }
+            Ok(())
+        });
+        records
+    }
+}
+
+/// Returns the host name of the machine, if known.
+pub fn hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+}
+
+/// Returns UDP socket bound to any local address and connected to `addr`.
+pub fn udp_connect(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(addr)?;
+    Ok(socket)
+}
+
+/// Destination the `RemoteWorker` delivers the batches of records to.
+pub trait RemoteSink: Send + 'static {
+    /// Short name of the sink used in the log messages.
+    fn name(&self) -> &str;
+
+    /// Delivers the batch. On error, the batch is kept and retried later.
+    fn send(&mut self, batch: &[LogRecord]) -> ConmonResult<()>;
+}
+
+/// Batching and retry configuration of the `RemoteWorker`.
+#[derive(Debug, Clone)]
+pub struct RemoteCfg {
+    /// Maximum number of records in single batch.
+    pub batch_records: usize,
+
+    /// Maximum number of line bytes in single batch.
+    pub batch_bytes: usize,
+
+    /// Maximum time a record waits before its batch is sent.
+    pub flush_interval: Duration,
+
+    /// Maximum number of records buffered while the sink is unavailable.
+    pub max_buffered: usize,
+
+    /// What to do once `max_buffered` records are buffered.
+    pub policy: DeliveryPolicy,
+
+    /// Maximum delay between the retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RemoteCfg {
+    fn default() -> Self {
+        Self {
+            batch_records: 1000,
+            batch_bytes: 1024 * 1024,
+            flush_interval: Duration::from_secs(1),
+            max_buffered: 100_000,
+            policy: DeliveryPolicy::DropOldest,
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State shared between the `RemoteWorker` and its thread.
+#[derive(Default)]
+struct Queue {
+    /// Records not delivered yet.
+    records: VecDeque<LogRecord>,
+
+    /// Number of records in the batch currently being sent.
+    inflight: usize,
+
+    /// True if the records should be sent without waiting for the batch to fill.
+    flush: bool,
+
+    /// True if the worker should deliver the remaining records and exit.
+    closed: bool,
+
+    /// Number of records dropped because the buffer was full.
+    dropped: u64,
+}
+
+impl QueueState for Queue {
+    fn queued(&self) -> usize {
+        self.records.len()
+    }
+
+    fn set_closed(&mut self, closed: bool) {
+        self.closed = closed;
+    }
+}
+
+type Shared = SharedQueue<Queue>;
+
+/// Delivers the records to a `RemoteSink` from a background thread, so slow
+/// or unavailable network does not block the event-loop.
+///
+/// The thread is started with the first record. The log plugins are created
+/// before conmon forks and threads do not survive `fork()`.
+pub struct RemoteWorker {
+    name: String,
+    cfg: RemoteCfg,
+    sink: Option<Box<dyn RemoteSink>>,
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<Box<dyn RemoteSink>>>,
+}
+
+impl RemoteWorker {
+    pub fn new(cfg: RemoteCfg, sink: Box<dyn RemoteSink>) -> Self {
+        Self {
+            name: sink.name().to_string(),
+            cfg,
+            sink: Some(sink),
+            shared: Arc::new(Shared::default()),
+            handle: None,
+        }
+    }
+
+    /// Starts the worker thread if it is not running yet.
+    fn start(&mut self) -> ConmonResult<()> {
+        let Some(sink) = self.sink.take() else {
+            return Ok(());
+        };
+        let shared = self.shared.clone();
+        let cfg = self.cfg.clone();
+        let name = format!("log-{}", self.name);
+        self.handle = Some(
+            thread::Builder::new()
+                .name(name)
+                .spawn(move || run_worker(cfg, sink, shared))?,
+        );
+        Ok(())
+    }
+
+    /// Queues the records for delivery, applying the delivery policy when
+    /// the buffer is full.
+    pub fn push(&mut self, records: Vec<LogRecord>) -> ConmonResult<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.start()?;
+        let max = self.cfg.max_buffered.max(1);
+        let mut queue = self.shared.lock();
+        let dropped = match self.cfg.policy {
+            DeliveryPolicy::DropOldest => {
+                queue.records.extend(records);
+                drop_oldest(&mut queue, max)
+            }
+            DeliveryPolicy::DropNewest => {
+                let room = max.saturating_sub(queue.records.len());
+                let dropped = records.len().saturating_sub(room);
+                queue.records.extend(records.into_iter().take(room));
+                dropped
+            }
+            DeliveryPolicy::Block => {
+                for record in records {
+                    queue = self.shared.wait_for_room(queue, max);
+                    queue.records.push_back(record);
+                    self.shared.notify_all();
+                }
+                0
+            }
+        };
+        if dropped > 0 {
+            if queue.dropped == 0 {
+                warn!("Log buffer of {} is full, dropping log records", self.name);
+            }
+            queue.dropped += dropped as u64;
+            metrics().record_log_drop(&self.name, dropped as u64);
+        }
+        self.shared.notify_all();
+        Ok(())
+    }
+
+    /// Sends the queued records immediately and waits until they are
+    /// delivered or until the drain timeout expires.
+    pub fn flush(&mut self) {
+        if self.handle.is_none() {
+            return;
+        }
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        let mut queue = self.shared.lock();
+        queue.flush = true;
+        self.shared.notify_all();
+        while !queue.records.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!(
+                    "Timed out delivering {} buffered log records",
+                    queue.records.len()
+                );
+                break;
+            }
+            queue = self.shared.wait_timeout(queue, deadline - now);
+        }
+    }
+
+    /// Stops the worker thread once it has delivered the queued records, or
+    /// given up on them. The thread is started again by the next push.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the worker thread panicked.
+    pub fn stop(&mut self) -> ConmonResult<()> {
+        let Some(handle) = self.handle.take() else {
+            return Ok(());
+        };
+        let sink = self.shared.close_and_join(handle).map_err(|_| {
+            ConmonError::new(format!("Log worker thread of {} panicked", self.name), 1)
+        })?;
+        self.sink = Some(sink);
+        Ok(())
+    }
+}
+
+impl Drop for RemoteWorker {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.shared.close_and_join(handle);
+        }
+    }
+}
+
+/// Drops the oldest records not being sent right now, until at most `max`
+/// records are buffered. Returns the number of dropped records.
+fn drop_oldest(queue: &mut Queue, max: usize) -> usize {
+    let overflow = queue.records.len().saturating_sub(max);
+    let start = queue.inflight.min(queue.records.len());
+    let end = (start + overflow).min(queue.records.len());
+    queue.records.drain(start..end);
+    end - start
+}
+
+/// Returns the number of records from the front of `records` forming the next batch.
+fn batch_len(records: &VecDeque<LogRecord>, cfg: &RemoteCfg) -> usize {
+    let mut bytes = 0;
+    let mut n = 0;
+    for r in records.iter().take(cfg.batch_records.max(1)) {
+        if n > 0 && bytes + r.line.len() > cfg.batch_bytes {
+            break;
+        }
+        bytes += r.line.len();
+        n += 1;
+    }
+    n
+}
+
+/// Body of the worker thread. Returns the sink once the worker is closed.
+fn run_worker(
+    cfg: RemoteCfg,
+    mut sink: Box<dyn RemoteSink>,
+    shared: Arc<Shared>,
+) -> Box<dyn RemoteSink> {
+    let mut backoff = Duration::ZERO;
+    let mut failing = false;
+    let mut oldest: Option<Instant> = None;
+    loop {
+        let mut queue = shared.lock();
+
+        // Wait until there is a full batch, the oldest record waits for too
+        // long, flush is requested or the worker is closed.
+        loop {
+            if queue.records.is_empty() {
+                oldest = None;
+                queue.flush = false;
+                if queue.closed {
+                    if queue.dropped > 0 {
+                        warn!(
+                            "{} log records were dropped because {} could not keep up",
+                            queue.dropped,
+                            sink.name()
+                        );
+                    }
+                    return sink;
+                }
+                queue = shared.wait(queue);
+                continue;
+            }
+            let first = *oldest.get_or_insert_with(Instant::now);
+            let n = batch_len(&queue.records, &cfg);
+            let due = first + cfg.flush_interval;
+            let now = Instant::now();
+            if queue.closed
+                || queue.flush
+                || n < queue.records.len()
+                || n >= cfg.batch_records
+                || now >= due
+            {
+                break;
+            }
+            queue = shared.wait_timeout(queue, due - now);
+        }
+
+        let n = batch_len(&queue.records, &cfg);
+        let batch: Vec<LogRecord> = queue.records.iter().take(n).cloned().collect();
+        queue.inflight = n;
+        let closed = queue.closed;
+        drop(queue);
+
+        let result = sink.send(&batch);
+
+        let mut queue = shared.lock();
+        queue.inflight = 0;
+        match result {
+            Ok(()) => {
+                let delivered = n.min(queue.records.len());
+                queue.records.drain(..delivered);
+                oldest = None;
+                backoff = Duration::ZERO;
+                if failing {
+                    info!("Delivery of log records to {} recovered", sink.name());
+                    failing = false;
+                }
+                shared.notify_all();
+            }
+            Err(e) => {
+                if !failing {
+                    warn!(
+                        "Failed to deliver log records to {}: {}",
+                        sink.name(),
+                        e.msg
+                    );
+                    failing = true;
+                }
+                if closed {
+                    // Do not hold the conmon exit for an unavailable sink.
+                    warn!("Discarding {} undelivered log records", queue.records.len());
+                    queue.records.clear();
+                    shared.notify_all();
+                    continue;
+                }
+                backoff = (backoff * 2)
+                    .max(Duration::from_millis(100))
+                    .min(cfg.max_backoff);
+                drop(shared.wait_timeout_while(queue, backoff, |q| !q.closed));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Sink recording the delivered lines, failing the first `fail` sends.
+    struct TestSink {
+        fail: usize,
+        lines: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl RemoteSink for TestSink {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn send(&mut self, batch: &[LogRecord]) -> ConmonResult<()> {
+            if self.fail > 0 {
+                self.fail -= 1;
+                return Err(ConmonError::new("unavailable", 1));
+            }
+            let mut lines = self.lines.lock().unwrap();
+            lines.extend(batch.iter().map(|r| r.line.clone()));
+            Ok(())
+        }
+    }
+
+    /// Sink which never delivers anything.
+    struct DownSink;
+
+    impl RemoteSink for DownSink {
+        fn name(&self) -> &str {
+            "down"
+        }
+
+        fn send(&mut self, _batch: &[LogRecord]) -> ConmonResult<()> {
+            Err(ConmonError::new("unavailable", 1))
+        }
+    }
+
+    fn buffered_lines(worker: &RemoteWorker) -> Vec<Vec<u8>> {
+        let queue = worker.shared.lock();
+        queue.records.iter().map(|r| r.line.clone()).collect()
+    }
+
+    #[test]
+    fn drop_policies_keep_the_right_records() {
+        for (policy, expected) in [
+            (DeliveryPolicy::DropOldest, [b"c".to_vec(), b"d".to_vec()]),
+            (DeliveryPolicy::DropNewest, [b"a".to_vec(), b"b".to_vec()]),
+        ] {
+            let cfg = RemoteCfg {
+                max_buffered: 2,
+                policy,
+                flush_interval: Duration::from_secs(3600),
+                batch_records: 10,
+                ..Default::default()
+            };
+            let mut worker = RemoteWorker::new(cfg, Box::new(DownSink));
+            let mut splitter = LineSplitter::default();
+            worker
+                .push(splitter.split(LogStream::Stdout, b"a\nb\nc\nd\n"))
+                .unwrap();
+            assert_eq!(buffered_lines(&worker), expected, "{policy:?}");
+            assert_eq!(worker.shared.lock().dropped, 2);
+            // Do not wait for the undeliverable records on drop.
+            worker.shared.lock().records.clear();
+        }
+    }
+
+    #[test]
+    fn line_splitter_splits_lines_over_line_max() {
+        let mut splitter = LineSplitter::new(4);
+        let records: Vec<(Vec<u8>, bool)> = splitter
+            .split(LogStream::Stdout, b"0123456789\nab\n")
+            .into_iter()
+            .map(|r| (r.line, r.partial))
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (b"0123".to_vec(), true),
+                (b"4567".to_vec(), true),
+                (b"89".to_vec(), false),
+                (b"ab".to_vec(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn line_splitter_handles_partial_lines() {
+        let mut splitter = LineSplitter::default();
+        assert!(splitter.split(LogStream::Stdout, b"hel").is_empty());
+        let records = splitter.split(LogStream::Stdout, b"lo\nwor");
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].line, b"hello");
+        assert!(!records[0].partial);
+
+        let records = splitter.split(LogStream::Stderr, b"err\n");
+        assert_eq!(records[0].line, b"err");
+        assert_eq!(records[0].stream(), "stderr");
+
+        let records = splitter.split(LogStream::Stdout, b"");
+        assert_eq!(records[0].line, b"wor");
+        assert!(records[0].partial);
+        assert!(records[0].to_k8s_line().ends_with(b" stdout P wor\n"));
+    }
+
+    #[test]
+    fn stop_delivers_and_restarts_on_push() -> ConmonResult<()> {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let cfg = RemoteCfg {
+            flush_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let mut worker = RemoteWorker::new(
+            cfg,
+            Box::new(TestSink {
+                fail: 0,
+                lines: lines.clone(),
+            }),
+        );
+        let mut splitter = LineSplitter::default();
+        worker.push(splitter.split(LogStream::Stdout, b"one\n"))?;
+        worker.stop()?;
+        assert!(worker.handle.is_none());
+        assert_eq!(*lines.lock().unwrap(), vec![b"one".to_vec()]);
+
+        worker.push(splitter.split(LogStream::Stdout, b"two\n"))?;
+        worker.flush();
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec![b"one".to_vec(), b"two".to_vec()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn worker_retries_and_delivers_in_order() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let cfg = RemoteCfg {
+            batch_records: 2,
+            max_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut worker = RemoteWorker::new(
+            cfg,
+            Box::new(TestSink {
+                fail: 2,
+                lines: lines.clone(),
+            }),
+        );
+        let mut splitter = LineSplitter::default();
+        worker
+            .push(splitter.split(LogStream::Stdout, b"one\ntwo\nthree\n"))
+            .unwrap();
+        worker.flush();
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+        );
+    }
+}
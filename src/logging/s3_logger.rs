@@ -0,0 +1,452 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::Utc;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{ConmonError, ConmonResult},
+    logging::{
+        http::{Url, request},
+        plugin::{DeliveryPolicy, LogPlugin, LogPluginCfg, LogStream},
+        remote::{LineSplitter, LogRecord, RemoteCfg, RemoteSink, RemoteWorker},
+    },
+};
+
+/// Default size of the uncompressed chunk.
+const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Default maximum age of the chunk in seconds.
+const DEFAULT_FLUSH_INTERVAL: u64 = 60;
+
+/// Suffix of the chunk objects and spilled files.
+const CHUNK_SUFFIX: &str = ".log.gz";
+
+/// Credentials used to sign the S3 requests.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    /// Loads the credentials from the AWS-style credentials `file` or from
+    /// the AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN
+    /// environment variables.
+    fn load(file: Option<&Path>) -> ConmonResult<Self> {
+        let mut creds = Credentials::default();
+        match file {
+            Some(path) => {
+                let contents = fs::read_to_string(path).map_err(|e| {
+                    ConmonError::new(
+                        format!("Failed to read S3 credentials {}: {e}", path.display()),
+                        1,
+                    )
+                })?;
+                for line in contents.lines() {
+                    let Some((key, value)) = line.split_once('=') else {
+                        continue;
+                    };
+                    let value = value.trim().to_string();
+                    match key.trim() {
+                        "aws_access_key_id" => creds.access_key = value,
+                        "aws_secret_access_key" => creds.secret_key = value,
+                        "aws_session_token" => creds.session_token = Some(value),
+                        _ => {}
+                    }
+                }
+            }
+            None => {
+                creds.access_key = std::env::var("AWS_ACCESS_KEY_ID").unwrap_or_default();
+                creds.secret_key = std::env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default();
+                creds.session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+            }
+        }
+        if creds.access_key.is_empty() || creds.secret_key.is_empty() {
+            return Err(ConmonError::new(
+                "S3 log driver requires credentials: set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY or --log-opt s3-credentials-file",
+                1,
+            ));
+        }
+        Ok(creds)
+    }
+}
+
+/// Returns the lowercase hex representation of `data`.
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Returns HMAC-SHA256 of `data` using `key`.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Returns the AWS Signature Version 4 signing key.
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{secret_key}").as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// Percent-encodes the object path as required by the signature, keeping '/'.
+fn uri_encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// S3-compatible bucket the chunks are uploaded to.
+struct S3Target {
+    /// The endpoint.
+    endpoint: Url,
+
+    /// Bucket name.
+    bucket: String,
+
+    /// Region used to sign the requests.
+    region: String,
+
+    /// Credentials used to sign the requests.
+    credentials: Credentials,
+}
+
+impl S3Target {
+    /// Returns the headers of the signed `PUT` request uploading `body` to
+    /// `path`, with `amz_date` in the "YYYYMMDDTHHMMSSZ" format.
+    fn signed_headers(&self, path: &str, body: &[u8], amz_date: &str) -> Vec<(String, String)> {
+        let date = &amz_date[..8];
+        let payload_hash = hex(&Sha256::digest(body));
+        let host = self.endpoint.host_header();
+
+        let mut headers = vec![
+            ("host".to_string(), host),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), amz_date.to_string()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        let signed_names: Vec<&str> = headers.iter().map(|(n, _)| n.as_str()).collect();
+        let signed_names = signed_names.join(";");
+        let canonical_headers: String = headers.iter().map(|(n, v)| format!("{n}:{v}\n")).collect();
+        let canonical_request =
+            format!("PUT\n{path}\n\n{canonical_headers}\n{signed_names}\n{payload_hash}");
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.credentials.secret_key, date, &self.region, "s3");
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        // The Host header is added by the HTTP client.
+        headers.remove(0);
+        headers.push((
+            "Authorization".to_string(),
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_names}, Signature={signature}",
+                self.credentials.access_key
+            ),
+        ));
+        headers.push(("Content-Type".to_string(), "application/gzip".to_string()));
+        headers
+    }
+
+    /// Uploads `body` as object `key`.
+    fn put_object(&self, key: &str, body: &[u8]) -> ConmonResult<()> {
+        let path = uri_encode_path(&format!("/{}/{key}", self.bucket));
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let headers = self.signed_headers(&path, body, &amz_date);
+        let url = Url {
+            path,
+            ..self.endpoint.clone()
+        };
+        request("PUT", &url, &headers, body)?.check("S3 upload")?;
+        Ok(())
+    }
+}
+
+/// Uploads the batches as compressed chunks, spilling them to the local
+/// directory while the endpoint is unavailable.
+struct S3Sink {
+    target: S3Target,
+
+    /// Prefix of the object keys, ending with '/'.
+    key_prefix: String,
+
+    /// Directory for the chunks which could not be uploaded.
+    spill_dir: PathBuf,
+
+    /// True if there might be spilled chunks in the `spill_dir`.
+    has_spilled: bool,
+
+    /// Sequence number of the next chunk.
+    seq: u64,
+}
+
+impl S3Sink {
+    /// Returns the file name of the next chunk.
+    fn next_chunk_name(&mut self) -> String {
+        self.seq += 1;
+        format!(
+            "{}-{}-{:06}{CHUNK_SUFFIX}",
+            Utc::now().format("%Y%m%dT%H%M%SZ"),
+            std::process::id(),
+            self.seq
+        )
+    }
+
+    /// Uploads the chunks spilled while the endpoint was unavailable,
+    /// oldest first.
+    fn upload_spilled(&mut self) -> ConmonResult<()> {
+        let mut names: Vec<String> = fs::read_dir(&self.spill_dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|n| n.ends_with(CHUNK_SUFFIX))
+            .collect();
+        names.sort();
+        for name in &names {
+            let path = self.spill_dir.join(name);
+            let body = fs::read(&path)?;
+            self.target
+                .put_object(&format!("{}{name}", self.key_prefix), &body)?;
+            fs::remove_file(&path)?;
+        }
+        if !names.is_empty() {
+            info!("Uploaded {} spilled log chunks", names.len());
+        }
+        self.has_spilled = false;
+        Ok(())
+    }
+}
+
+impl RemoteSink for S3Sink {
+    fn name(&self) -> &str {
+        "s3"
+    }
+
+    fn send(&mut self, batch: &[LogRecord]) -> ConmonResult<()> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        for record in batch {
+            encoder.write_all(&record.to_k8s_line())?;
+        }
+        let body = encoder.finish()?;
+        let name = self.next_chunk_name();
+
+        let uploaded = if self.has_spilled {
+            self.upload_spilled()
+        } else {
+            Ok(())
+        }
+        .and_then(|_| {
+            self.target
+                .put_object(&format!("{}{name}", self.key_prefix), &body)
+        });
+        if let Err(e) = uploaded {
+            if !self.has_spilled {
+                warn!(
+                    "S3 upload failed, spilling log chunks to {}: {}",
+                    self.spill_dir.display(),
+                    e.msg
+                );
+            }
+            fs::write(self.spill_dir.join(&name), &body).map_err(|e| {
+                ConmonError::new(
+                    format!(
+                        "Failed to spill log chunk to {}: {e}",
+                        self.spill_dir.display()
+                    ),
+                    1,
+                )
+            })?;
+            self.has_spilled = true;
+        }
+        Ok(())
+    }
+}
+
+/// Logging plugin uploading the container output in compressed chunks to an
+/// S3-compatible object storage.
+///
+/// The `--log-path` is "s3:http://endpoint[:port]/bucket[/prefix]". The chunks
+/// are stored as "<prefix>/<cid>/<time>-<pid>-<seq>.log.gz" objects with lines
+/// in the k8s-file format.
+pub struct S3Logger {
+    splitter: LineSplitter,
+    worker: RemoteWorker,
+}
+
+impl S3Logger {
+    pub fn new(cfg: &LogPluginCfg) -> ConmonResult<Self> {
+        let spec = cfg.path.to_string_lossy();
+        let mut endpoint = Url::parse(&spec)?;
+        let location = std::mem::replace(&mut endpoint.path, "/".to_string());
+        let location = location.trim_matches('/');
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if bucket.is_empty() {
+            return Err(ConmonError::new(
+                format!("S3 log path {spec} does not contain the bucket name"),
+                1,
+            ));
+        }
+        let cid = cfg.cid.clone().unwrap_or_default();
+        let key_prefix = if prefix.is_empty() {
+            format!("{cid}/")
+        } else {
+            format!("{}/{cid}/", prefix.trim_matches('/'))
+        };
+
+        let credentials = Credentials::load(cfg.log_opt("s3-credentials-file").map(Path::new))?;
+        let spill_dir = match cfg.log_opt("s3-spill-dir") {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::temp_dir().join(format!("conmon-s3-{cid}")),
+        };
+        fs::create_dir_all(&spill_dir).map_err(|e| {
+            ConmonError::new(
+                format!(
+                    "Failed to create S3 spill directory {}: {e}",
+                    spill_dir.display()
+                ),
+                1,
+            )
+        })?;
+
+        let sink = S3Sink {
+            target: S3Target {
+                endpoint,
+                bucket: bucket.to_string(),
+                region: cfg.log_opt("s3-region").unwrap_or("us-east-1").to_string(),
+                credentials,
+            },
+            key_prefix,
+            spill_dir,
+            // Upload the chunks left by the previous conmon with the first batch.
+            has_spilled: true,
+            seq: 0,
+        };
+        let remote_cfg = RemoteCfg {
+            batch_records: usize::MAX,
+            batch_bytes: cfg.log_opt_parse("s3-chunk-size", DEFAULT_CHUNK_SIZE)?,
+            flush_interval: Duration::from_secs(
+                cfg.log_opt_parse("s3-flush-interval", DEFAULT_FLUSH_INTERVAL)?,
+            ),
+            policy: cfg.delivery_policy.unwrap_or(DeliveryPolicy::DropOldest),
+            ..Default::default()
+        };
+        Ok(Self {
+            splitter: LineSplitter::new(cfg.line_max),
+            worker: RemoteWorker::new(remote_cfg, Box::new(sink)),
+        })
+    }
+}
+
+impl LogPlugin for S3Logger {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        self.worker.push(self.splitter.split(stream, data))?;
+        if data.is_empty() {
+            self.worker.flush();
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.worker.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    #[test]
+    fn signing_key_matches_aws_example() {
+        // Example from the AWS Signature Version 4 documentation.
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+        assert_eq!(uri_encode_path("/b/a b+c"), "/b/a%20b%2Bc");
+    }
+
+    #[test]
+    fn credentials_from_file() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("credentials");
+        fs::write(
+            &path,
+            "[default]\naws_access_key_id = AKID\naws_secret_access_key=SECRET\n",
+        )?;
+        let creds = Credentials::load(Some(&path))?;
+        assert_eq!(creds.access_key, "AKID");
+        assert_eq!(creds.secret_key, "SECRET");
+        assert_eq!(creds.session_token, None);
+
+        fs::write(&path, "[default]\naws_access_key_id = AKID\n")?;
+        assert!(Credentials::load(Some(&path)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn spills_chunks_while_endpoint_is_down() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        // Nothing listens on the discard port.
+        let mut sink = S3Sink {
+            target: S3Target {
+                endpoint: Url::parse("http://127.0.0.1:9")?,
+                bucket: "logs".to_string(),
+                region: "us-east-1".to_string(),
+                credentials: Credentials {
+                    access_key: "AKID".to_string(),
+                    secret_key: "SECRET".to_string(),
+                    session_token: None,
+                },
+            },
+            key_prefix: "cid/".to_string(),
+            spill_dir: tmp.path().to_path_buf(),
+            has_spilled: false,
+            seq: 0,
+        };
+        let records = LineSplitter::default().split(LogStream::Stdout, b"hello\n");
+        sink.send(&records)?;
+        assert!(sink.has_spilled);
+
+        let spilled: Vec<_> = fs::read_dir(tmp.path())?.collect();
+        assert_eq!(spilled.len(), 1);
+        let mut text = String::new();
+        GzDecoder::new(fs::File::open(spilled[0].as_ref().unwrap().path())?)
+            .read_to_string(&mut text)?;
+        assert!(text.ends_with(" stdout F hello\n"));
+        Ok(())
+    }
+}
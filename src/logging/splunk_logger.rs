@@ -0,0 +1,340 @@
+use std::fs;
+use std::io::Write;
+use std::time::Duration;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::warn;
+use serde_json::{Map, Value};
+
+use crate::{
+    error::{ConmonError, ConmonResult},
+    logging::{
+        http::{Url, request},
+        plugin::{DeliveryPolicy, LogPlugin, LogPluginCfg, LogStream},
+        remote::{LineSplitter, LogRecord, RemoteCfg, RemoteSink, RemoteWorker, hostname},
+    },
+};
+
+/// Path of the event endpoint used when the `splunk-url` has no path.
+const DEFAULT_EVENT_PATH: &str = "/services/collector/event";
+
+/// Default maximum size of the batch in bytes.
+const DEFAULT_BATCH_SIZE: usize = 1024 * 1024;
+
+/// Default time the records wait for the batch to fill in milliseconds.
+const DEFAULT_BATCH_WAIT_MS: u64 = 1000;
+
+/// Default maximum number of records buffered while Splunk is unavailable.
+const DEFAULT_BUFFER_SIZE: usize = 100_000;
+
+/// Returns the HEC token from `splunk-token` or the first line of
+/// `splunk-token-file`.
+fn load_token(cfg: &LogPluginCfg) -> ConmonResult<String> {
+    let token = match (
+        cfg.log_opt("splunk-token"),
+        cfg.log_opt("splunk-token-file"),
+    ) {
+        (Some(token), None) => token.trim().to_string(),
+        (None, Some(path)) => fs::read_to_string(path)
+            .map_err(|e| ConmonError::new(format!("Failed to read Splunk token {path}: {e}"), 1))?
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+        (Some(_), Some(_)) => {
+            return Err(ConmonError::new(
+                "Only one of splunk-token and splunk-token-file can be set",
+                1,
+            ));
+        }
+        (None, None) => String::new(),
+    };
+    if token.is_empty() {
+        return Err(ConmonError::new(
+            "splunk log driver requires --log-opt splunk-token or splunk-token-file",
+            1,
+        ));
+    }
+    Ok(token)
+}
+
+/// Posts the batches to the Splunk HTTP Event Collector.
+struct SplunkSink {
+    /// The event endpoint.
+    url: Url,
+
+    /// The HEC token sent in the Authorization header.
+    token: String,
+
+    /// Metadata of every event: host, source, sourcetype and index.
+    metadata: Map<String, Value>,
+
+    /// Fields of the event object besides the line.
+    fields: Map<String, Value>,
+
+    /// True if the request body is compressed with gzip.
+    gzip: bool,
+}
+
+impl SplunkSink {
+    /// Returns the request body with one event object per record.
+    fn events_body(&self, batch: &[LogRecord]) -> ConmonResult<Vec<u8>> {
+        let mut body = Vec::new();
+        for r in batch {
+            let mut event = self.fields.clone();
+            event.insert("line".into(), Value::from(String::from_utf8_lossy(&r.line)));
+            event.insert("source".into(), Value::from(r.stream()));
+            if r.partial {
+                event.insert("partial".into(), Value::from(true));
+            }
+            let mut obj = self.metadata.clone();
+            let time = r.timestamp.timestamp_micros() as f64 / 1_000_000.0;
+            obj.insert("time".into(), Value::from(time));
+            obj.insert("event".into(), Value::Object(event));
+            serde_json::to_writer(&mut body, &Value::Object(obj))?;
+            body.push(b'\n');
+        }
+        if !self.gzip {
+            return Ok(body);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body)?;
+        Ok(encoder.finish()?)
+    }
+}
+
+impl RemoteSink for SplunkSink {
+    fn name(&self) -> &str {
+        "splunk"
+    }
+
+    fn send(&mut self, batch: &[LogRecord]) -> ConmonResult<()> {
+        let body = self.events_body(batch)?;
+        let mut headers = vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            (
+                "Authorization".to_string(),
+                format!("Splunk {}", self.token),
+            ),
+        ];
+        if self.gzip {
+            headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+        }
+        let resp = request("POST", &self.url, &headers, &body)?;
+        // Retry on rate limiting and server errors, including the "server is
+        // busy" 503; the rejected events will not succeed on retry.
+        if resp.status == 429 || resp.status >= 500 {
+            resp.check("Splunk HEC")?;
+        } else if let Err(e) = resp.check("Splunk HEC") {
+            warn!("Dropping {} log records: {}", batch.len(), e.msg);
+        }
+        Ok(())
+    }
+}
+
+/// Logging plugin sending the container output to the Splunk HTTP Event
+/// Collector.
+///
+/// Each line is sent as a single event with the line, the stream as the
+/// `source` and the container ID, name and tag. The events are batched and
+/// buffered in memory from a background thread, so a slow Splunk endpoint
+/// never blocks the container.
+pub struct SplunkLogger {
+    splitter: LineSplitter,
+    worker: RemoteWorker,
+}
+
+impl SplunkLogger {
+    pub fn new(cfg: &LogPluginCfg) -> ConmonResult<Self> {
+        let spec = cfg.path.to_string_lossy();
+        let url = match cfg.log_opt("splunk-url") {
+            Some(url) => url,
+            None if !spec.is_empty() => &spec,
+            None => {
+                return Err(ConmonError::new(
+                    "splunk log driver requires --log-opt splunk-url",
+                    1,
+                ));
+            }
+        };
+        let mut url = Url::parse(url)?;
+        if url.path == "/" {
+            url.path = DEFAULT_EVENT_PATH.to_string();
+        }
+        let gzip = match cfg.log_opt("splunk-gzip").unwrap_or("false") {
+            "true" => true,
+            "false" => false,
+            v => {
+                return Err(ConmonError::new(
+                    format!("Invalid value '{v}' of log option splunk-gzip"),
+                    1,
+                ));
+            }
+        };
+
+        let mut metadata = Map::new();
+        if let Some(host) = hostname() {
+            metadata.insert("host".into(), Value::from(host));
+        }
+        for (key, field) in [
+            ("splunk-source", "source"),
+            ("splunk-sourcetype", "sourcetype"),
+            ("splunk-index", "index"),
+        ] {
+            if let Some(value) = cfg.log_opt(key) {
+                metadata.insert(field.into(), Value::from(value));
+            }
+        }
+        let mut fields = Map::new();
+        if let Some(cid) = &cfg.cid {
+            fields.insert("container_id".into(), Value::from(cid.clone()));
+        }
+        if let Some(name) = &cfg.name {
+            fields.insert("container_name".into(), Value::from(name.clone()));
+        }
+        if let Some(tag) = &cfg.log_tag {
+            fields.insert("tag".into(), Value::from(tag.clone()));
+        }
+
+        let sink = SplunkSink {
+            url,
+            token: load_token(cfg)?,
+            metadata,
+            fields,
+            gzip,
+        };
+        let remote_cfg = RemoteCfg {
+            batch_bytes: cfg.log_opt_parse("splunk-batch-size", DEFAULT_BATCH_SIZE)?,
+            flush_interval: Duration::from_millis(
+                cfg.log_opt_parse("splunk-batch-wait", DEFAULT_BATCH_WAIT_MS)?,
+            ),
+            max_buffered: cfg.log_opt_parse("splunk-buffer-size", DEFAULT_BUFFER_SIZE)?,
+            policy: cfg.delivery_policy.unwrap_or(DeliveryPolicy::DropOldest),
+            ..Default::default()
+        };
+        Ok(Self {
+            splitter: LineSplitter::new(cfg.line_max),
+            worker: RemoteWorker::new(remote_cfg, Box::new(sink)),
+        })
+    }
+}
+
+impl LogPlugin for SplunkLogger {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        self.worker.push(self.splitter.split(stream, data))?;
+        if data.is_empty() {
+            self.worker.flush();
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.worker.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tempfile::NamedTempFile;
+
+    fn opts(opts: &[(&str, &str)]) -> LogPluginCfg {
+        LogPluginCfg {
+            log_opts: opts
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn token_is_loaded_from_option_or_file() -> ConmonResult<()> {
+        assert_eq!(load_token(&opts(&[("splunk-token", "abc")]))?, "abc");
+
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "secret\nignored")?;
+        let path = file.path().to_string_lossy().into_owned();
+        assert_eq!(
+            load_token(&opts(&[("splunk-token-file", &path)]))?,
+            "secret"
+        );
+
+        assert!(load_token(&opts(&[])).is_err());
+        assert!(
+            load_token(&opts(&[
+                ("splunk-token", "a"),
+                ("splunk-token-file", &path)
+            ]))
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn events_carry_metadata_and_fields() -> ConmonResult<()> {
+        for gzip in [false, true] {
+            let sink = SplunkSink {
+                url: Url::parse("http://splunk:8088")?,
+                token: "abc".into(),
+                metadata: Map::from_iter([("index".to_string(), Value::from("main"))]),
+                fields: Map::from_iter([("container_id".to_string(), Value::from("cid"))]),
+                gzip,
+            };
+            let mut splitter = LineSplitter::default();
+            let mut records = splitter.split(LogStream::Stdout, b"one\n");
+            records.extend(splitter.split(LogStream::Stderr, b"two"));
+            records.extend(splitter.split(LogStream::Stderr, b""));
+
+            let mut body = sink.events_body(&records)?;
+            if gzip {
+                let mut plain = Vec::new();
+                GzDecoder::new(&body[..]).read_to_end(&mut plain)?;
+                body = plain;
+            }
+            let events: Vec<Value> = body
+                .split(|b| *b == b'\n')
+                .filter(|l| !l.is_empty())
+                .map(serde_json::from_slice)
+                .collect::<Result<_, _>>()?;
+            assert_eq!(events.len(), 2);
+            assert_eq!(events[0]["index"], "main");
+            assert!(events[0]["time"].as_f64().unwrap() > 0.0);
+            assert_eq!(events[0]["event"]["line"], "one");
+            assert_eq!(events[0]["event"]["source"], "stdout");
+            assert_eq!(events[0]["event"]["container_id"], "cid");
+            assert_eq!(events[1]["event"]["source"], "stderr");
+            assert_eq!(events[1]["event"]["partial"], true);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn new_validates_options() {
+        assert!(SplunkLogger::new(&opts(&[("splunk-token", "abc")])).is_err());
+        assert_eq!(
+            SplunkLogger::new(&opts(&[
+                ("splunk-url", "https://splunk:8088"),
+                ("splunk-token", "abc")
+            ]))
+            .is_ok(),
+            cfg!(feature = "tls")
+        );
+        assert!(
+            SplunkLogger::new(&opts(&[
+                ("splunk-url", "http://splunk:8088"),
+                ("splunk-token", "abc"),
+                ("splunk-gzip", "yes")
+            ]))
+            .is_err()
+        );
+    }
+}
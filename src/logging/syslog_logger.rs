@@ -0,0 +1,471 @@
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use chrono::SecondsFormat;
+
+use crate::{
+    error::{ConmonError, ConmonResult},
+    logging::{
+        plugin::{DeliveryPolicy, LogPlugin, LogPluginCfg, LogStream},
+        remote::{
+            LineSplitter, LogRecord, RemoteCfg, RemoteSink, RemoteWorker, hostname, udp_connect,
+        },
+    },
+};
+
+/// Timeout for connecting to the server and for single write.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default syslog port.
+const DEFAULT_PORT: u16 = 514;
+
+/// Default syslog over TLS port (RFC 5425).
+const DEFAULT_TLS_PORT: u16 = 6514;
+
+/// Default time the records wait for the batch to fill in milliseconds.
+const DEFAULT_BATCH_WAIT_MS: u64 = 100;
+
+/// Maximum length of the APP-NAME field.
+const MAX_APP_NAME_LEN: usize = 48;
+
+/// Maximum length of the HOSTNAME field.
+const MAX_HOSTNAME_LEN: usize = 255;
+
+/// The syslog facilities in the order of their codes.
+const FACILITIES: &[&str] = &[
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+/// The syslog severities in the order of their codes.
+const SEVERITIES: &[&str] = &[
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+/// Transport used to reach the syslog server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    /// One message per datagram (RFC 5426).
+    Udp,
+    /// Octet-counted messages over a TCP stream (RFC 6587).
+    Tcp,
+    /// Octet-counted messages over a TLS stream (RFC 5425).
+    Tls,
+}
+
+/// Parses the `udp://host[:port]`, `tcp://host[:port]` or
+/// `tls://host[:port]` server address.
+fn parse_address(s: &str) -> ConmonResult<(Transport, String)> {
+    let invalid = |why: &str| ConmonError::new(format!("Invalid syslog address {s}: {why}"), 1);
+    let (transport, rest) = match s.split_once("://") {
+        Some(("udp", rest)) => (Transport::Udp, rest),
+        Some(("tcp", rest)) => (Transport::Tcp, rest),
+        Some(("tls", _)) if !cfg!(feature = "tls") => {
+            return Err(invalid(
+                "tls is not supported by this build, use a TLS-terminating proxy",
+            ));
+        }
+        Some(("tls", rest)) => (Transport::Tls, rest),
+        _ => return Err(invalid("must start with udp://, tcp:// or tls://")),
+    };
+    let default_port = match transport {
+        Transport::Tls => DEFAULT_TLS_PORT,
+        Transport::Udp | Transport::Tcp => DEFAULT_PORT,
+    };
+    let rest = rest.trim_end_matches('/');
+    let addr = match rest.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            port.parse::<u16>().map_err(|_| invalid("invalid port"))?;
+            if host.is_empty() {
+                return Err(invalid("missing host"));
+            }
+            rest.to_string()
+        }
+        _ if rest.is_empty() => return Err(invalid("missing host")),
+        _ => format!("{rest}:{default_port}"),
+    };
+    Ok((transport, addr))
+}
+
+/// Returns the code of `name` in `names`.
+fn parse_code(kind: &str, names: &[&str], name: &str) -> ConmonResult<u8> {
+    names
+        .iter()
+        .position(|n| *n == name)
+        .map(|code| code as u8)
+        .ok_or_else(|| ConmonError::new(format!("Invalid syslog {kind} '{name}'"), 1))
+}
+
+/// Returns `s` limited to `max` printable ASCII characters as required for
+/// the RFC 5424 header fields, or "-" if nothing is left.
+fn header_field(s: &str, max: usize) -> String {
+    let field: String = s
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
+/// Formats the records as RFC 5424 messages.
+#[derive(Debug, Clone)]
+struct Formatter {
+    facility: u8,
+    stdout_severity: u8,
+    stderr_severity: u8,
+    hostname: String,
+    app_name: String,
+    procid: String,
+}
+
+impl Formatter {
+    /// Returns the RFC 5424 message of the record, without framing.
+    fn message(&self, r: &LogRecord) -> Vec<u8> {
+        let severity = match r.stream {
+            LogStream::Stderr => self.stderr_severity,
+            LogStream::Stdout | LogStream::Stdin => self.stdout_severity,
+        };
+        let mut out = format!(
+            "<{}>1 {} {} {} {} {} - ",
+            u16::from(self.facility) * 8 + u16::from(severity),
+            r.timestamp.to_rfc3339_opts(SecondsFormat::Micros, false),
+            self.hostname,
+            self.app_name,
+            self.procid,
+            r.stream(),
+        )
+        .into_bytes();
+        out.extend_from_slice(&r.line);
+        out
+    }
+}
+
+/// Connection to the syslog server.
+enum Conn {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<crate::logging::tls::TlsStream>),
+}
+
+/// Sends the batches to the syslog server.
+struct SyslogSink {
+    transport: Transport,
+    addr: String,
+    formatter: Formatter,
+
+    /// The connection, dropped on error so the next retry reconnects.
+    conn: Option<Conn>,
+}
+
+impl SyslogSink {
+    /// Connects to the server.
+    fn connect(&self) -> ConmonResult<Conn> {
+        let err = |e: std::io::Error| ConmonError::new(format!("Syslog {}: {e}", self.addr), 1);
+        let sock_addr: SocketAddr = self
+            .addr
+            .to_socket_addrs()
+            .map_err(err)?
+            .next()
+            .ok_or_else(|| ConmonError::new(format!("Cannot resolve {}", self.addr), 1))?;
+        match self.transport {
+            Transport::Udp => Ok(Conn::Udp(udp_connect(sock_addr).map_err(err)?)),
+            Transport::Tcp | Transport::Tls => {
+                let stream = TcpStream::connect_timeout(&sock_addr, IO_TIMEOUT).map_err(err)?;
+                stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(err)?;
+                #[cfg(feature = "tls")]
+                if self.transport == Transport::Tls {
+                    // The port is always present in the parsed address.
+                    let host = self.addr.rsplit_once(':').map_or("", |(host, _)| host);
+                    let stream = crate::logging::tls::connect(host, stream)?;
+                    return Ok(Conn::Tls(Box::new(stream)));
+                }
+                Ok(Conn::Tcp(stream))
+            }
+        }
+    }
+
+    /// Sends the batch over the connection.
+    fn send_batch(conn: &mut Conn, formatter: &Formatter, batch: &[LogRecord]) -> ConmonResult<()> {
+        match conn {
+            Conn::Udp(socket) => {
+                for r in batch {
+                    socket.send(&formatter.message(r))?;
+                }
+            }
+            Conn::Tcp(stream) => stream.write_all(&Self::frames(formatter, batch))?,
+            #[cfg(feature = "tls")]
+            Conn::Tls(stream) => {
+                stream.write_all(&Self::frames(formatter, batch))?;
+                stream.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the octet-counted messages of the batch.
+    fn frames(formatter: &Formatter, batch: &[LogRecord]) -> Vec<u8> {
+        let mut frames = Vec::new();
+        for r in batch {
+            let msg = formatter.message(r);
+            frames.extend_from_slice(format!("{} ", msg.len()).as_bytes());
+            frames.extend_from_slice(&msg);
+        }
+        frames
+    }
+}
+
+impl RemoteSink for SyslogSink {
+    fn name(&self) -> &str {
+        "syslog"
+    }
+
+    fn send(&mut self, batch: &[LogRecord]) -> ConmonResult<()> {
+        let mut conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => self.connect()?,
+        };
+        Self::send_batch(&mut conn, &self.formatter, batch)?;
+        self.conn = Some(conn);
+        Ok(())
+    }
+}
+
+/// Logging plugin sending the container output to a remote syslog server.
+///
+/// The `--log-path` is "syslog:udp://host[:port]", "syslog:tcp://host[:port]"
+/// or "syslog:tls://host[:port]". Each line is sent as a single RFC 5424
+/// message with the stream name as the MSGID. Over TCP and TLS, the messages
+/// are framed using the octet counting (RFC 6587).
+pub struct SyslogLogger {
+    splitter: LineSplitter,
+    worker: RemoteWorker,
+}
+
+impl SyslogLogger {
+    pub fn new(cfg: &LogPluginCfg) -> ConmonResult<Self> {
+        let spec = cfg.path.to_string_lossy();
+        if spec.is_empty() {
+            return Err(ConmonError::new(
+                "syslog log driver requires the server address",
+                1,
+            ));
+        }
+        let (transport, addr) = parse_address(&spec)?;
+        let app_name = cfg
+            .log_tag
+            .as_deref()
+            .or(cfg.name.as_deref())
+            .unwrap_or("conmon");
+        let formatter = Formatter {
+            facility: parse_code(
+                "facility",
+                FACILITIES,
+                cfg.log_opt("syslog-facility").unwrap_or("daemon"),
+            )?,
+            stdout_severity: parse_code(
+                "severity",
+                SEVERITIES,
+                cfg.log_opt("syslog-stdout-severity").unwrap_or("info"),
+            )?,
+            stderr_severity: parse_code(
+                "severity",
+                SEVERITIES,
+                cfg.log_opt("syslog-stderr-severity").unwrap_or("err"),
+            )?,
+            hostname: header_field(&hostname().unwrap_or_default(), MAX_HOSTNAME_LEN),
+            app_name: header_field(app_name, MAX_APP_NAME_LEN),
+            procid: header_field(cfg.cid.as_deref().unwrap_or_default(), 12),
+        };
+        let sink = SyslogSink {
+            transport,
+            addr,
+            formatter,
+            conn: None,
+        };
+        let remote_cfg = RemoteCfg {
+            flush_interval: Duration::from_millis(
+                cfg.log_opt_parse("syslog-batch-wait", DEFAULT_BATCH_WAIT_MS)?,
+            ),
+            policy: cfg.delivery_policy.unwrap_or(DeliveryPolicy::DropOldest),
+            ..Default::default()
+        };
+        Ok(Self {
+            splitter: LineSplitter::new(cfg.line_max),
+            worker: RemoteWorker::new(remote_cfg, Box::new(sink)),
+        })
+    }
+}
+
+impl LogPlugin for SyslogLogger {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        self.worker.push(self.splitter.split(stream, data))?;
+        if data.is_empty() {
+            self.worker.flush();
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        self.worker.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    fn formatter() -> Formatter {
+        Formatter {
+            facility: 3,
+            stdout_severity: 6,
+            stderr_severity: 3,
+            hostname: "host".into(),
+            app_name: "web".into(),
+            procid: "0123456789ab".into(),
+        }
+    }
+
+    #[test]
+    fn address_is_parsed() -> ConmonResult<()> {
+        assert_eq!(
+            parse_address("tcp://logs.example.com")?,
+            (Transport::Tcp, "logs.example.com:514".to_string())
+        );
+        assert_eq!(
+            parse_address("udp://127.0.0.1:5514")?,
+            (Transport::Udp, "127.0.0.1:5514".to_string())
+        );
+        assert_eq!(
+            parse_address("udp://[::1]")?,
+            (Transport::Udp, "[::1]:514".to_string())
+        );
+        assert_eq!(
+            parse_address("tls://logs").ok(),
+            cfg!(feature = "tls").then(|| (Transport::Tls, "logs:6514".to_string()))
+        );
+        assert!(parse_address("logs:514").is_err());
+        assert!(parse_address("tcp://:514").is_err());
+        assert!(parse_address("tcp://logs:http").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn messages_use_rfc5424_format() {
+        let mut splitter = LineSplitter::default();
+        let mut records = splitter.split(LogStream::Stdout, b"hello\n");
+        records.extend(splitter.split(LogStream::Stderr, b"oops\n"));
+        let f = formatter();
+
+        let msg = String::from_utf8(f.message(&records[0])).unwrap();
+        assert!(msg.starts_with("<30>1 "), "{msg}");
+        assert!(
+            msg.ends_with(" host web 0123456789ab stdout - hello"),
+            "{msg}"
+        );
+        let msg = String::from_utf8(f.message(&records[1])).unwrap();
+        assert!(msg.starts_with("<27>1 "), "{msg}");
+        assert!(msg.ends_with(" stderr - oops"), "{msg}");
+    }
+
+    #[test]
+    fn invalid_facility_and_severity_are_rejected() {
+        for (key, value) in [
+            ("syslog-facility", "local8"),
+            ("syslog-stderr-severity", "error"),
+        ] {
+            let cfg = LogPluginCfg {
+                path: PathBuf::from("udp://127.0.0.1"),
+                log_opts: vec![(key.into(), value.into())],
+                ..Default::default()
+            };
+            assert!(SyslogLogger::new(&cfg).is_err(), "{key}={value}");
+        }
+        assert_eq!(parse_code("facility", FACILITIES, "local7").unwrap(), 23);
+        assert_eq!(header_field("my app", 48), "myapp");
+        assert_eq!(header_field("", 48), "-");
+    }
+
+    #[test]
+    fn tcp_messages_are_octet_counted() -> ConmonResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let mut sink = SyslogSink {
+            transport: Transport::Tcp,
+            addr: addr.to_string(),
+            formatter: formatter(),
+            conn: None,
+        };
+        let mut splitter = LineSplitter::default();
+        sink.send(&splitter.split(LogStream::Stdout, b"one\ntwo\n"))?;
+        drop(sink);
+
+        let (mut conn, _) = listener.accept()?;
+        let mut data = String::new();
+        conn.read_to_string(&mut data)?;
+        let (len, rest) = data.split_once(' ').unwrap();
+        let len: usize = len.parse().unwrap();
+        assert!(rest[..len].ends_with("stdout - one"));
+        let (len, rest) = rest[len..].split_once(' ').unwrap();
+        assert_eq!(rest.len(), len.parse::<usize>().unwrap());
+        assert!(rest.ends_with("stdout - two"));
+        Ok(())
+    }
+
+    #[test]
+    fn udp_messages_are_sent_as_datagrams() -> ConmonResult<()> {
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut sink = SyslogSink {
+            transport: Transport::Udp,
+            addr: server.local_addr()?.to_string(),
+            formatter: formatter(),
+            conn: None,
+        };
+        let mut splitter = LineSplitter::default();
+        sink.send(&splitter.split(LogStream::Stderr, b"a\nb\n"))?;
+
+        let mut buf = [0u8; 1024];
+        for line in ["a", "b"] {
+            let n = server.recv(&mut buf)?;
+            let msg = String::from_utf8_lossy(&buf[..n]);
+            assert!(msg.starts_with("<27>1 "), "{msg}");
+            assert!(msg.ends_with(&format!("stderr - {line}")), "{msg}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use std::net::TcpStream;
+use std::sync::{Arc, OnceLock};
+
+use log::warn;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use crate::error::{ConmonError, ConmonResult};
+
+/// TLS connection to a log server.
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Returns the client configuration trusting the system CA certificates.
+///
+/// The certificates are loaded once and shared by all the connections.
+fn client_config() -> ConmonResult<Arc<ClientConfig>> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    if let Some(config) = CONFIG.get() {
+        return Ok(config.clone());
+    }
+
+    let mut roots = RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    for e in &native.errors {
+        warn!("Failed to load the system CA certificates: {e}");
+    }
+    let (_added, ignored) = roots.add_parsable_certificates(native.certs);
+    if ignored > 0 {
+        warn!("Ignored {ignored} invalid system CA certificates");
+    }
+    if roots.is_empty() {
+        return Err(ConmonError::new("No system CA certificates found", 1));
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| ConmonError::new(format!("Failed to configure TLS: {e}"), 1))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(CONFIG.get_or_init(|| Arc::new(config)).clone())
+}
+
+/// Starts the TLS session over the connected `tcp` stream.
+///
+/// The server certificate is verified against the system CA certificates
+/// and the `host` name. The handshake itself runs on the first read or write.
+///
+/// # Arguments
+///
+/// * `host` - The server host name or IP address, as used for connecting.
+/// * `tcp` - The TCP connection to the server.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if `host` is not a valid server name or the TLS client
+///   cannot be configured.
+pub fn connect(host: &str, tcp: TcpStream) -> ConmonResult<TlsStream> {
+    // IPv6 addresses are written in brackets in the URLs.
+    let name = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(name.to_string())
+        .map_err(|e| ConmonError::new(format!("Invalid TLS server name {host}: {e}"), 1))?;
+    let conn = ClientConnection::new(client_config()?, name)
+        .map_err(|e| ConmonError::new(format!("Failed to start TLS with {host}: {e}"), 1))?;
+    Ok(StreamOwned::new(conn, tcp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn connect_rejects_plain_server() -> ConmonResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 512];
+            let _ = conn.read(&mut buf);
+            let _ = conn.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+        });
+
+        let Ok(mut stream) = connect("127.0.0.1", TcpStream::connect(addr)?) else {
+            // No system CA certificates in this environment.
+            return Ok(());
+        };
+        assert!(
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").is_err() || {
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).is_err()
+            }
+        );
+        server.join().unwrap();
+        Ok(())
+    }
+}
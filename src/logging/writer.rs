@@ -0,0 +1,477 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+use log::{info, warn};
+
+use crate::{
+    error::{ConmonError, ConmonResult},
+    logging::plugin::{ContainerExit, ContainerMetadata, DeliveryPolicy, LogPlugin, LogStream},
+    logging::queue::{QueueState, SharedQueue},
+    metrics::metrics,
+};
+
+/// Maximum number of writes queued for the writer thread. Once the queue is
+/// full, the `--log-writer-policy` applies.
+pub const WRITER_QUEUE_LEN: usize = 1024;
+
+/// Operation on the log plugin executed by the writer thread.
+enum Request {
+    Write(LogStream, Vec<u8>),
+    Flush(LogStream, Sender<ConmonResult<()>>),
+    Reopen(Sender<ConmonResult<()>>),
+    ReopenFiles(Sender<ConmonResult<()>>),
+    Shutdown(Sender<ConmonResult<()>>),
+    ContainerStart(i32, ContainerMetadata, Sender<ConmonResult<()>>),
+    ContainerExit(ContainerExit, Sender<ConmonResult<()>>),
+}
+
+/// State shared between the `ThreadedLogPlugin` and its thread.
+#[derive(Default)]
+struct Queue {
+    /// Requests not executed yet.
+    requests: VecDeque<Request>,
+
+    /// True if the thread should execute the remaining requests and exit.
+    closed: bool,
+}
+
+impl QueueState for Queue {
+    fn queued(&self) -> usize {
+        self.requests.len()
+    }
+
+    fn set_closed(&mut self, closed: bool) {
+        self.closed = closed;
+    }
+}
+
+type Shared = SharedQueue<Queue>;
+
+/// Log plugin serializing all the operations of the inner plugin on a
+/// dedicated writer thread (`--log-writer-thread`), so a slow log target
+/// does not stall the event-loop.
+///
+/// The writes are queued and their errors are only logged by the writer
+/// thread. When the queue is full, the `policy` decides whether the
+/// event-loop waits for the writer thread or a write is dropped; the other
+/// operations always wait. The empty write flushing the buffered data,
+/// reopen and shutdown wait for the queued writes and return the result of
+/// the inner plugin. The buffered data is flushed before the live upgrade,
+/// so nothing is lost on re-exec.
+///
+/// The thread is started with the first write. The log plugins are created
+/// before conmon forks and threads do not survive `fork()`.
+pub struct ThreadedLogPlugin {
+    /// The inner plugin until the thread is started.
+    inner: Option<Box<dyn LogPlugin>>,
+    max_queued: usize,
+    policy: DeliveryPolicy,
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<Box<dyn LogPlugin>>>,
+
+    /// True while the writes are dropped because the queue is full.
+    dropping: bool,
+}
+
+impl ThreadedLogPlugin {
+    pub fn new(inner: Box<dyn LogPlugin>, max_queued: usize, policy: DeliveryPolicy) -> Self {
+        Self {
+            inner: Some(inner),
+            max_queued: max_queued.max(1),
+            policy,
+            shared: Arc::new(Shared::default()),
+            handle: None,
+            dropping: false,
+        }
+    }
+
+    /// Starts the writer thread if it is not running yet.
+    fn start(&mut self) -> ConmonResult<()> {
+        let Some(plugin) = self.inner.take() else {
+            return Ok(());
+        };
+        let shared = self.shared.clone();
+        self.handle = Some(
+            thread::Builder::new()
+                .name("log-writer".into())
+                .spawn(move || run_writer(plugin, shared))?,
+        );
+        Ok(())
+    }
+
+    /// Queues the request. While the queue is full, the write requests are
+    /// handled according to the policy and the others wait.
+    fn push(&mut self, request: Request) -> ConmonResult<()> {
+        if self.handle.as_ref().is_some_and(|h| h.is_finished()) {
+            return Err(ConmonError::new("Log writer thread exited", 1));
+        }
+        let is_write = matches!(request, Request::Write(..));
+        let mut queue = self.shared.lock();
+        let mut dropped = false;
+        if is_write && queue.requests.len() >= self.max_queued {
+            match self.policy {
+                DeliveryPolicy::Block => {}
+                DeliveryPolicy::DropNewest => {
+                    drop(queue);
+                    self.record_drop();
+                    return Ok(());
+                }
+                DeliveryPolicy::DropOldest => {
+                    let oldest = queue
+                        .requests
+                        .iter()
+                        .position(|r| matches!(r, Request::Write(..)));
+                    if let Some(pos) = oldest {
+                        queue.requests.remove(pos);
+                        dropped = true;
+                    }
+                }
+            }
+        }
+        queue = self.shared.wait_for_room(queue, self.max_queued);
+        queue.requests.push_back(request);
+        let depth = queue.requests.len();
+        self.shared.notify_all();
+        drop(queue);
+
+        metrics().record_log_queue_depth(depth);
+        if dropped {
+            self.record_drop();
+        } else if is_write && self.dropping {
+            info!("Log writer thread caught up, no longer dropping the container output");
+            self.dropping = false;
+        }
+        Ok(())
+    }
+
+    /// Records single write dropped because the queue is full.
+    fn record_drop(&mut self) {
+        if !self.dropping {
+            warn!("Log writer thread cannot keep up, dropping the container output");
+            self.dropping = true;
+        }
+        metrics().record_log_drop("writer", 1);
+    }
+
+    /// Queues the request and waits for its result.
+    fn wait_for(
+        &mut self,
+        request: impl FnOnce(Sender<ConmonResult<()>>) -> Request,
+    ) -> ConmonResult<()> {
+        let (tx, rx) = mpsc::channel();
+        self.push(request(tx))?;
+        rx.recv()
+            .map_err(|_| ConmonError::new("Log writer thread exited", 1))?
+    }
+
+    /// Executes the operation on the inner plugin, on the writer thread if
+    /// it is running, and returns its result.
+    fn call(
+        &mut self,
+        request: fn(Sender<ConmonResult<()>>) -> Request,
+        op: fn(&mut dyn LogPlugin) -> ConmonResult<()>,
+    ) -> ConmonResult<()> {
+        if let Some(plugin) = self.inner.as_mut() {
+            return op(plugin.as_mut());
+        }
+        self.wait_for(request)
+    }
+}
+
+impl LogPlugin for ThreadedLogPlugin {
+    fn write(&mut self, stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+        if data.is_empty() {
+            if let Some(plugin) = self.inner.as_mut() {
+                return plugin.write(stream, data);
+            }
+            return self.wait_for(|tx| Request::Flush(stream, tx));
+        }
+        self.start()?;
+        self.push(Request::Write(stream, data.to_vec()))
+    }
+
+    fn reopen(&mut self) -> ConmonResult<()> {
+        self.call(Request::Reopen, |p| p.reopen())
+    }
+
+    fn reopen_files(&mut self) -> ConmonResult<()> {
+        self.call(Request::ReopenFiles, |p| p.reopen_files())
+    }
+
+    fn shutdown(&mut self) -> ConmonResult<()> {
+        self.call(Request::Shutdown, |p| p.shutdown())
+    }
+
+    fn stop_threads(&mut self) -> ConmonResult<()> {
+        if let Some(handle) = self.handle.take() {
+            // The writer thread executes the queued requests before exiting
+            // and hands the inner plugin back, the next write restarts it.
+            let plugin = self
+                .shared
+                .close_and_join(handle)
+                .map_err(|_| ConmonError::new("Log writer thread panicked", 1))?;
+            self.inner = Some(plugin);
+        }
+        match self.inner.as_mut() {
+            Some(plugin) => plugin.stop_threads(),
+            None => Ok(()),
+        }
+    }
+
+    fn on_container_start(&mut self, pid: i32, metadata: &ContainerMetadata) -> ConmonResult<()> {
+        if let Some(plugin) = self.inner.as_mut() {
+            return plugin.on_container_start(pid, metadata);
+        }
+        self.wait_for(|tx| Request::ContainerStart(pid, metadata.clone(), tx))
+    }
+
+    fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+        if let Some(plugin) = self.inner.as_mut() {
+            return plugin.on_container_exit(exit);
+        }
+        self.wait_for(|tx| Request::ContainerExit(*exit, tx))
+    }
+}
+
+impl Drop for ThreadedLogPlugin {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.shared.close_and_join(handle);
+        }
+    }
+}
+
+/// Body of the writer thread. Returns the plugin once the thread is closed.
+fn run_writer(mut plugin: Box<dyn LogPlugin>, shared: Arc<Shared>) -> Box<dyn LogPlugin> {
+    let mut failing = false;
+    loop {
+        let request = {
+            let mut queue = shared.lock();
+            loop {
+                if let Some(request) = queue.requests.pop_front() {
+                    shared.notify_all();
+                    break request;
+                }
+                if queue.closed {
+                    return plugin;
+                }
+                queue = shared.wait(queue);
+            }
+        };
+
+        match request {
+            Request::Write(stream, data) => match plugin.write(stream, &data) {
+                Err(e) if !failing => {
+                    warn!("Failed to write the container log: {}", e.msg);
+                    failing = true;
+                }
+                Ok(()) if failing => {
+                    info!("Writing the container log recovered");
+                    failing = false;
+                }
+                _ => {}
+            },
+            // The caller may be gone, ignore the send errors.
+            Request::Flush(stream, reply) => {
+                let _ = reply.send(plugin.write(stream, &[]));
+            }
+            Request::Reopen(reply) => {
+                let _ = reply.send(plugin.reopen());
+            }
+            Request::ReopenFiles(reply) => {
+                let _ = reply.send(plugin.reopen_files());
+            }
+            Request::Shutdown(reply) => {
+                let _ = reply.send(plugin.shutdown());
+            }
+            Request::ContainerStart(pid, metadata, reply) => {
+                let _ = reply.send(plugin.on_container_start(pid, &metadata));
+            }
+            Request::ContainerExit(exit, reply) => {
+                let _ = reply.send(plugin.on_container_exit(&exit));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread::ThreadId;
+
+    type Records = Arc<Mutex<Vec<(ThreadId, String)>>>;
+
+    /// Plugin recording the operations and the thread executing them.
+    struct Recorder(Records);
+
+    impl Recorder {
+        fn record(&self, op: String) {
+            self.0.lock().unwrap().push((thread::current().id(), op));
+        }
+    }
+
+    impl LogPlugin for Recorder {
+        fn write(&mut self, _stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+            self.record(String::from_utf8_lossy(data).into_owned());
+            Ok(())
+        }
+        fn reopen(&mut self) -> ConmonResult<()> {
+            self.record("reopen".into());
+            Ok(())
+        }
+        fn shutdown(&mut self) -> ConmonResult<()> {
+            self.record("shutdown".into());
+            Err(ConmonError::new("sync failed", 1))
+        }
+        fn on_container_start(
+            &mut self,
+            pid: i32,
+            metadata: &ContainerMetadata,
+        ) -> ConmonResult<()> {
+            self.record(format!("start {pid} {}", metadata["cid"]));
+            Ok(())
+        }
+        fn on_container_exit(&mut self, exit: &ContainerExit) -> ConmonResult<()> {
+            self.record(format!("exit {}", exit.code));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn operations_are_serialized_on_writer_thread() -> ConmonResult<()> {
+        let records = Records::default();
+        let mut plugin = ThreadedLogPlugin::new(
+            Box::new(Recorder(records.clone())),
+            2,
+            DeliveryPolicy::Block,
+        );
+        let main = thread::current().id();
+
+        // Before the first write, the inner plugin is called directly.
+        plugin.reopen()?;
+        let metadata = ContainerMetadata::from([("cid".to_string(), "abc".to_string())]);
+        plugin.on_container_start(42, &metadata)?;
+        for i in 0..10 {
+            plugin.write(LogStream::Stdout, format!("{i}\n").as_bytes())?;
+        }
+        plugin.write(LogStream::Stdout, b"")?;
+        plugin.on_container_exit(&ContainerExit {
+            code: 1,
+            signal: None,
+            oom: false,
+        })?;
+        // The shutdown waits for the queued writes and returns the error.
+        assert!(plugin.shutdown().is_err());
+
+        let records = records.lock().unwrap();
+        assert_eq!(records[0], (main, "reopen".to_string()));
+        assert_eq!(records[1], (main, "start 42 abc".to_string()));
+        let ops: Vec<&str> = records[2..].iter().map(|(_, op)| op.as_str()).collect();
+        assert_eq!(
+            ops,
+            vec![
+                "0\n", "1\n", "2\n", "3\n", "4\n", "5\n", "6\n", "7\n", "8\n", "9\n", "", "exit 1",
+                "shutdown"
+            ]
+        );
+        assert!(records[2..].iter().all(|(id, _)| *id != main));
+        Ok(())
+    }
+
+    #[test]
+    fn stop_threads_joins_writer_thread() -> ConmonResult<()> {
+        let records = Records::default();
+        let mut plugin = ThreadedLogPlugin::new(
+            Box::new(Recorder(records.clone())),
+            2,
+            DeliveryPolicy::Block,
+        );
+        let main = thread::current().id();
+        plugin.write(LogStream::Stdout, b"a")?;
+        plugin.stop_threads()?;
+        assert!(plugin.handle.is_none());
+
+        // The inner plugin is called directly until the next write.
+        plugin.reopen()?;
+        plugin.write(LogStream::Stdout, b"b")?;
+        plugin.write(LogStream::Stdout, b"")?;
+
+        let records = records.lock().unwrap();
+        assert_ne!(records[0], (main, "a".to_string()));
+        assert_eq!(records[1], (main, "reopen".to_string()));
+        assert_ne!(records[2], (main, "b".to_string()));
+        assert_eq!(records[2].1, "b");
+        Ok(())
+    }
+
+    /// Plugin recording the writes and blocking in them until `release` is
+    /// dropped.
+    struct Gate {
+        records: Arc<Mutex<Vec<String>>>,
+        entered: Sender<()>,
+        release: mpsc::Receiver<()>,
+    }
+
+    impl LogPlugin for Gate {
+        fn write(&mut self, _stream: LogStream, data: &[u8]) -> ConmonResult<()> {
+            if !data.is_empty() {
+                self.records
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(data).into_owned());
+                let _ = self.entered.send(());
+                let _ = self.release.recv();
+            }
+            Ok(())
+        }
+        fn reopen(&mut self) -> ConmonResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn full_queue_drops_writes_by_policy() -> ConmonResult<()> {
+        for (policy, expected) in [
+            (DeliveryPolicy::DropNewest, ["a", "b", "c"]),
+            (DeliveryPolicy::DropOldest, ["a", "c", "d"]),
+        ] {
+            let records = Arc::new(Mutex::new(Vec::new()));
+            let (entered_tx, entered_rx) = mpsc::channel();
+            let (release_tx, release_rx) = mpsc::channel();
+            let gate = Gate {
+                records: records.clone(),
+                entered: entered_tx,
+                release: release_rx,
+            };
+            let mut plugin = ThreadedLogPlugin::new(Box::new(gate), 2, policy);
+            plugin.write(LogStream::Stdout, b"a")?;
+            // The writer thread is blocked in the write of "a".
+            entered_rx.recv().unwrap();
+            for data in [b"b", b"c", b"d"] {
+                plugin.write(LogStream::Stdout, data)?;
+            }
+            drop(release_tx);
+            plugin.write(LogStream::Stdout, b"")?;
+            assert_eq!(*records.lock().unwrap(), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn drop_writes_queued_data() -> ConmonResult<()> {
+        let records = Records::default();
+        let mut plugin = ThreadedLogPlugin::new(
+            Box::new(Recorder(records.clone())),
+            8,
+            DeliveryPolicy::Block,
+        );
+        plugin.write(LogStream::Stdout, b"a\n")?;
+        plugin.write(LogStream::Stderr, b"b\n")?;
+        drop(plugin);
+        assert_eq!(records.lock().unwrap().len(), 2);
+        Ok(())
+    }
+}
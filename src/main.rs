@@ -3,18 +3,25 @@ use ::log::LevelFilter;
 use ::log::debug;
 use ::log::error;
 use ::log::info;
+use ::log::warn;
 use clap::Parser;
-use conmon::cli::{Cmd, Opts, determine_cmd, determine_log_plugin};
+use conmon::cli::{Cmd, MaintenanceCmd, Opts, determine_cmd, determine_log_plugin, log_file_paths};
+use conmon::commands::adopt::Adopt;
 use conmon::commands::create::Create;
 use conmon::commands::exec::Exec;
+use conmon::commands::gc::Gc;
+use conmon::commands::ps::Ps;
 use conmon::commands::restore::Restore;
 use conmon::commands::version::Version;
 use conmon::error::{ConmonError, ConmonResult};
 use conmon::exit::run_exit_command;
 use conmon::exit::snapshot_open_fds;
 use conmon::exit::write_exit_files;
+use conmon::heartbeat::stop_heartbeat;
 use conmon::log;
-use conmon::logging::plugin::initialize_log_plugins;
+use conmon::logging::plugin::{LogPlugin, LogStream};
+use conmon::logging::reload::ReloadableLogPlugin;
+use conmon::metrics::{metrics, write_metrics_file};
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
@@ -39,7 +46,7 @@ fn is_executable(p: &Path) -> bool {
 /// # Returns
 ///
 /// * Exit code the conmon executable should exit with.
-fn run_conmon(opts: Opts) -> ConmonResult<i32> {
+fn run_conmon(mut opts: Opts) -> ConmonResult<i32> {
     // Snapshot the open file descriptors.
     // Podman injects multiple fds into conmon when executing it. It uses these
     // fds to detect whether conmon still runs. We need to close these fds on
@@ -70,6 +77,15 @@ fn run_conmon(opts: Opts) -> ConmonResult<i32> {
         return Version {}.exec();
     }
 
+    // Maintenance commands do not supervise any container, so they need
+    // neither the container options nor the log plugin.
+    if let Some(cmd) = opts.command.take() {
+        return match cmd {
+            MaintenanceCmd::Gc { socket_dir } => Gc::new(socket_dir).exec(),
+            MaintenanceCmd::Ps { persist_root } => Ps::new(persist_root).exec(),
+        };
+    }
+
     // Pre-validate core arguments so errors match conmon v2 order (e.g. cid before log-path).
     opts.cid
         .as_ref()
@@ -97,7 +113,7 @@ fn run_conmon(opts: Opts) -> ConmonResult<i32> {
     let plugin_entries = determine_log_plugin(&opts)?;
     let plugin_names: Vec<&str> = plugin_entries.iter().map(|(n, _)| n.as_str()).collect();
     info!("Using log plugin(s): {:?}", plugin_names);
-    let mut log_plugin = initialize_log_plugins(&plugin_entries)?;
+    let mut log_plugin = ReloadableLogPlugin::new(plugin_entries.clone())?;
 
     // logging_passthrough: only true when the sole plugin is passthrough.
     let logging_passthrough = plugin_entries.len() == 1 && plugin_entries[0].0 == "passthrough";
@@ -105,9 +121,10 @@ fn run_conmon(opts: Opts) -> ConmonResult<i32> {
     // Determine the conmon subcommand to run and execute it.
     let result = match determine_cmd(opts, logging_passthrough) {
         Ok(cmd) => match cmd {
-            Cmd::Create(cfg) => Create::new(cfg).exec(log_plugin.as_mut(), &open_files),
-            Cmd::Exec(cfg) => Exec::new(cfg).exec(log_plugin.as_mut(), &open_files),
-            Cmd::Restore(cfg) => Restore::new(cfg).exec(log_plugin.as_mut(), &open_files),
+            Cmd::Create(cfg) => Create::new(cfg).exec(&mut log_plugin, &open_files),
+            Cmd::Exec(cfg) => Exec::new(cfg).exec(&mut log_plugin, &open_files),
+            Cmd::Restore(cfg) => Restore::new(cfg).exec(&mut log_plugin, &open_files),
+            Cmd::Adopt(cfg) => Adopt::new(cfg).exec(),
             Cmd::Version => Version {}.exec(),
         },
         Err(e) => Err(e),
@@ -116,11 +133,10 @@ fn run_conmon(opts: Opts) -> ConmonResult<i32> {
     // Always call write with empty buffer to trigger write of any cached
     // log lines into logs. Without that, we could loose some log messages.
     let no_data: &[u8] = &[];
-    if let Err(e) = log_plugin.write(true, no_data) {
-        error!("failed to drain stdout log: {e}");
-    }
-    if let Err(e) = log_plugin.write(false, no_data) {
-        error!("failed to drain stderr log: {e}");
+    for stream in [LogStream::Stdout, LogStream::Stderr, LogStream::Stdin] {
+        if let Err(e) = log_plugin.write(stream, no_data) {
+            error!("failed to drain {} log: {e}", stream.name());
+        }
     }
 
     // Return the exit code from subcommand execution.
@@ -131,12 +147,18 @@ fn main() -> ExitCode {
     // Parse the command line arguments and clone the ones we need
     // for the exit handling.
     let opts = Opts::parse();
+    log::set_quiet(opts.quiet);
     let exit_command = opts.exit_command.clone();
     let exit_command_args = opts.exit_args.clone();
     let exit_command_delay = opts.exit_delay;
     let exit_dir = opts.exit_dir.clone();
     let persist_dir = opts.persist_dir.clone();
+    let exit_json = opts.exit_json;
     let cid = opts.cid.clone();
+    let metrics_file = opts.metrics_file.clone();
+    let log_files = determine_log_plugin(&opts)
+        .map(|entries| log_file_paths(&entries))
+        .unwrap_or_default();
 
     // Run the conmon.
     let raw_code = match run_conmon(opts) {
@@ -149,6 +171,21 @@ fn main() -> ExitCode {
         }
     };
 
+    // The container is gone, so conmon is not expected to be alive anymore.
+    stop_heartbeat();
+
+    // Show the event-loop metrics and store them if requested.
+    metrics().log_summary();
+    let dropped = metrics().log_global_dropped_bytes;
+    if dropped > 0 {
+        warn!("Dropped {dropped} bytes of container output over --log-global-size-max");
+    }
+    if let Some(path) = metrics_file {
+        if let Err(e) = write_metrics_file(&path) {
+            error!("{}", e.msg);
+        }
+    }
+
     // Write the exit files into persistent path. The podman has inotify
     // set for that directory and uses it to detect the conmon exit.
     write_exit_files(
@@ -156,6 +193,8 @@ fn main() -> ExitCode {
         persist_dir.as_ref(),
         exit_dir.as_ref(),
         cid.as_ref(),
+        &log_files,
+        exit_json,
     );
 
     // Run the exit command if defined by podman. We do not care about the exit
@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::os::fd::RawFd;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::debug;
+use serde_json::{Map, Value, json};
+
+use crate::error::{ConmonError, ConmonResult};
+use crate::unix_socket::SocketType;
+
+/// Number of bytes moved through single fd.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct FdMetrics {
+    /// Bytes read from the fd.
+    pub bytes_read: u64,
+
+    /// Bytes written into the fd.
+    pub bytes_written: u64,
+}
+
+/// Self-metrics of the Conmon event-loop.
+///
+/// They are collected while the event-loop runs and logged at debug level
+/// on shutdown, so performance regressions in the hot path are visible.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Metrics {
+    /// Number of times `poll()` returned.
+    pub wakeups: u64,
+
+    /// Number of times `poll()` returned without any fd event.
+    pub idle_wakeups: u64,
+
+    /// The longest time spent handling fd events in single iteration.
+    pub max_loop_latency: Duration,
+
+    /// The maximum number of fds polled at once.
+    pub max_poll_fds: usize,
+
+    /// The maximum number of bytes buffered in single socket and not yet dispatched.
+    pub max_buffered_bytes: usize,
+
+    /// Bytes moved per fd. The key is "<socket type>:<fd>".
+    pub fds: BTreeMap<String, FdMetrics>,
+
+    /// Log records dropped because the log target could not keep up.
+    /// The key is the log driver name.
+    pub log_drops: BTreeMap<String, u64>,
+
+    /// Bytes of container output dropped because of `--log-global-size-max`.
+    pub log_global_dropped_bytes: u64,
+
+    /// The maximum number of requests queued for the `--log-writer-thread`.
+    pub max_log_queue_depth: usize,
+
+    /// Repeated lines collapsed by `--log-dedup`.
+    pub log_repeated_lines: u64,
+
+    /// Lines dropped by `--log-sample`.
+    pub log_sampled_out_lines: u64,
+
+    /// Output packets dropped for the slow attach clients.
+    pub attach_dropped_packets: u64,
+
+    /// Slow attach clients disconnected.
+    pub attach_slow_disconnects: u64,
+
+    /// The maximum number of bytes queued for single attach client.
+    pub max_attach_queued_bytes: usize,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            wakeups: 0,
+            idle_wakeups: 0,
+            max_loop_latency: Duration::ZERO,
+            max_poll_fds: 0,
+            max_buffered_bytes: 0,
+            fds: BTreeMap::new(),
+            log_drops: BTreeMap::new(),
+            log_global_dropped_bytes: 0,
+            max_log_queue_depth: 0,
+            log_repeated_lines: 0,
+            log_sampled_out_lines: 0,
+            attach_dropped_packets: 0,
+            attach_slow_disconnects: 0,
+            max_attach_queued_bytes: 0,
+        }
+    }
+
+    /// Returns the `fds` entry for given fd.
+    fn fd_entry(&mut self, socket_type: SocketType, fd: RawFd) -> &mut FdMetrics {
+        self.fds
+            .entry(format!("{:?}:{}", socket_type, fd))
+            .or_default()
+    }
+
+    /// Records single `poll()` wakeup.
+    pub fn record_wakeup(&mut self, idle: bool) {
+        self.wakeups += 1;
+        if idle {
+            self.idle_wakeups += 1;
+        }
+    }
+
+    /// Records time spent handling the fd events in single iteration.
+    pub fn record_loop_latency(&mut self, latency: Duration) {
+        self.max_loop_latency = self.max_loop_latency.max(latency);
+    }
+
+    /// Records the number of polled fds and bytes buffered in a socket.
+    pub fn record_queue_depth(&mut self, poll_fds: usize, buffered_bytes: usize) {
+        self.max_poll_fds = self.max_poll_fds.max(poll_fds);
+        self.max_buffered_bytes = self.max_buffered_bytes.max(buffered_bytes);
+    }
+
+    /// Records `n` bytes read from `fd`.
+    pub fn record_read(&mut self, socket_type: SocketType, fd: RawFd, n: usize) {
+        self.fd_entry(socket_type, fd).bytes_read += n as u64;
+    }
+
+    /// Records `n` bytes written to `fd`.
+    pub fn record_write(&mut self, socket_type: SocketType, fd: RawFd, n: usize) {
+        self.fd_entry(socket_type, fd).bytes_written += n as u64;
+    }
+
+    /// Records `n` log records dropped by the log driver `driver`.
+    pub fn record_log_drop(&mut self, driver: &str, n: u64) {
+        *self.log_drops.entry(driver.to_string()).or_default() += n;
+    }
+
+    /// Records `n` bytes dropped because of `--log-global-size-max`.
+    pub fn record_global_size_drop(&mut self, n: u64) {
+        self.log_global_dropped_bytes += n;
+    }
+
+    /// Records the number of requests queued for the log writer thread.
+    pub fn record_log_queue_depth(&mut self, depth: usize) {
+        self.max_log_queue_depth = self.max_log_queue_depth.max(depth);
+    }
+
+    /// Records `n` repeated lines collapsed by `--log-dedup`.
+    pub fn record_log_repeated(&mut self, n: u64) {
+        self.log_repeated_lines += n;
+    }
+
+    /// Records `n` lines dropped by `--log-sample`.
+    pub fn record_log_sampled_out(&mut self, n: u64) {
+        self.log_sampled_out_lines += n;
+    }
+
+    /// Records `n` output packets dropped for a slow attach client.
+    pub fn record_attach_drop(&mut self, n: u64) {
+        self.attach_dropped_packets += n;
+    }
+
+    /// Records a slow attach client disconnected.
+    pub fn record_attach_slow_disconnect(&mut self) {
+        self.attach_slow_disconnects += 1;
+    }
+
+    /// Records the number of bytes queued for an attach client.
+    pub fn record_attach_queued(&mut self, bytes: usize) {
+        self.max_attach_queued_bytes = self.max_attach_queued_bytes.max(bytes);
+    }
+
+    /// Returns the metrics as JSON object.
+    pub fn to_json(&self) -> Value {
+        let mut fds = Map::with_capacity(self.fds.len());
+        for (name, m) in &self.fds {
+            fds.insert(
+                name.clone(),
+                json!({"bytes_read": m.bytes_read, "bytes_written": m.bytes_written}),
+            );
+        }
+        json!({
+            "wakeups": self.wakeups,
+            "idle_wakeups": self.idle_wakeups,
+            "max_loop_latency_us": self.max_loop_latency.as_micros() as u64,
+            "max_poll_fds": self.max_poll_fds,
+            "max_buffered_bytes": self.max_buffered_bytes,
+            "fds": Value::Object(fds),
+            "log_drops": self.log_drops,
+            "log_global_dropped_bytes": self.log_global_dropped_bytes,
+            "max_log_queue_depth": self.max_log_queue_depth,
+            "log_repeated_lines": self.log_repeated_lines,
+            "log_sampled_out_lines": self.log_sampled_out_lines,
+            "attach_dropped_packets": self.attach_dropped_packets,
+            "attach_slow_disconnects": self.attach_slow_disconnects,
+            "max_attach_queued_bytes": self.max_attach_queued_bytes,
+        })
+    }
+
+    /// Logs the metrics summary at debug level.
+    pub fn log_summary(&self) {
+        debug!(
+            "Event-loop metrics: wakeups: {} (idle: {}), max loop latency: {:?}, max poll fds: {}, max buffered bytes: {}",
+            self.wakeups,
+            self.idle_wakeups,
+            self.max_loop_latency,
+            self.max_poll_fds,
+            self.max_buffered_bytes
+        );
+        for (name, m) in &self.fds {
+            debug!(
+                "Event-loop metrics: {name}: read {} bytes, written {} bytes",
+                m.bytes_read, m.bytes_written
+            );
+        }
+        if self.max_log_queue_depth > 0 {
+            debug!(
+                "Log metrics: max log writer queue depth: {}",
+                self.max_log_queue_depth
+            );
+        }
+        for (driver, n) in &self.log_drops {
+            debug!("Log metrics: {driver}: dropped {n} records");
+        }
+    }
+}
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+/// Returns the global Conmon metrics.
+pub fn metrics() -> MutexGuard<'static, Metrics> {
+    match METRICS.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Writes the global Conmon metrics as JSON into `path`.
+pub fn write_metrics_file(path: &Path) -> ConmonResult<()> {
+    let json = metrics().to_json().to_string();
+    std::fs::write(path, json).map_err(|e| {
+        ConmonError::new(
+            format!("Failed to write metrics file {}: {e}", path.display()),
+            1,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_wakeups_and_maximums() {
+        let mut m = Metrics::new();
+        m.record_wakeup(false);
+        m.record_wakeup(true);
+        m.record_loop_latency(Duration::from_millis(5));
+        m.record_loop_latency(Duration::from_millis(2));
+        m.record_queue_depth(4, 100);
+        m.record_queue_depth(2, 300);
+        m.record_log_queue_depth(7);
+        m.record_log_queue_depth(3);
+
+        assert_eq!(m.wakeups, 2);
+        assert_eq!(m.idle_wakeups, 1);
+        assert_eq!(m.max_loop_latency, Duration::from_millis(5));
+        assert_eq!(m.max_poll_fds, 4);
+        assert_eq!(m.max_buffered_bytes, 300);
+        assert_eq!(m.max_log_queue_depth, 7);
+    }
+
+    #[test]
+    fn records_bytes_per_fd() {
+        let mut m = Metrics::new();
+        m.record_read(SocketType::Stdout, 5, 10);
+        m.record_read(SocketType::Stdout, 5, 20);
+        m.record_write(SocketType::Console, 7, 3);
+        m.record_write(SocketType::Stdin, 8, 4);
+
+        let v = m.to_json();
+        assert_eq!(v["fds"]["Stdout:5"]["bytes_read"], 30);
+        assert_eq!(v["fds"]["Stdout:5"]["bytes_written"], 0);
+        assert_eq!(v["fds"]["Console:7"]["bytes_written"], 3);
+        assert_eq!(v["fds"]["Stdin:8"]["bytes_written"], 4);
+    }
+
+    #[test]
+    fn records_log_drops_per_driver() {
+        let mut m = Metrics::new();
+        m.record_log_drop("loki", 2);
+        m.record_log_drop("loki", 3);
+        m.record_log_drop("journald", 1);
+        m.record_global_size_drop(10);
+        m.record_global_size_drop(5);
+        m.record_log_repeated(4);
+        m.record_log_sampled_out(7);
+
+        let v = m.to_json();
+        assert_eq!(v["log_drops"]["loki"], 5);
+        assert_eq!(v["log_drops"]["journald"], 1);
+        assert_eq!(v["log_global_dropped_bytes"], 15);
+        assert_eq!(v["log_repeated_lines"], 4);
+        assert_eq!(v["log_sampled_out_lines"], 7);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::error::{ConmonError, ConmonResult};
+use crate::exit::ExitSignal;
 use nix::errno::Errno;
 use nix::fcntl::{FcntlArg, FdFlag, fcntl};
 use nix::unistd::write;
@@ -51,7 +52,7 @@ pub fn get_pipe_fd_from_env_with<E: Env>(e: &E, envname: &str) -> ConmonResult<O
 }
 
 /// Write all bytes to a RawFd using nix::unistd::write, retrying on EINTR/partial writes.
-fn write_all_fd(fd: &OwnedFd, mut buf: &[u8]) -> nix::Result<()> {
+pub(crate) fn write_all_fd(fd: &OwnedFd, mut buf: &[u8]) -> nix::Result<()> {
     while !buf.is_empty() {
         match write(fd, buf) {
             Ok(0) => {
@@ -77,6 +78,30 @@ pub fn write_or_close_sync_fd(
     str_data: Option<&str>,
     opt_api_version: i32,
     opt_exec: bool,
+) -> ConmonResult<Option<OwnedFd>> {
+    write_or_close_sync_fd_with_signal(
+        fd,
+        int_data,
+        str_data,
+        None,
+        false,
+        opt_api_version,
+        opt_exec,
+    )
+}
+
+/// Same as `write_or_close_sync_fd`, but also reports the signal which killed
+/// the process as the "signal" and "core_dumped" fields and the OOM as the
+/// "oom" field (API version 1 and newer).
+#[allow(clippy::too_many_arguments)]
+pub fn write_or_close_sync_fd_with_signal(
+    fd: OwnedFd,
+    int_data: i32,
+    str_data: Option<&str>,
+    exit_signal: Option<&ExitSignal>,
+    oom: bool,
+    opt_api_version: i32,
+    opt_exec: bool,
 ) -> ConmonResult<Option<OwnedFd>> {
     let data_key = if opt_api_version >= 1 {
         "data"
@@ -97,6 +122,21 @@ pub fn write_or_close_sync_fd(
             obj.insert("message".to_string(), Value::from(msg));
         }
     }
+    if let Some(exit_signal) = exit_signal {
+        if opt_api_version >= 1 {
+            obj.insert(
+                "signal".to_string(),
+                Value::from(exit_signal.signal.as_str()),
+            );
+            obj.insert(
+                "core_dumped".to_string(),
+                Value::from(exit_signal.core_dumped),
+            );
+        }
+    }
+    if oom && opt_api_version >= 1 {
+        obj.insert("oom".to_string(), Value::from(true));
+    }
     let mut json = Value::Object(obj).to_string();
     json.push('\n');
 
@@ -111,6 +151,27 @@ pub fn write_or_close_sync_fd(
     }
 }
 
+/// Signals the container exit on the `_OCI_EXITFD` eventfd.
+///
+/// The eventfd counter is incremented by the exit status plus one, so even
+/// the zero exit status wakes up the parent. The parent reads the counter
+/// and subtracts one to get the exit status.
+///
+/// # Arguments
+///
+/// * `fd` - The eventfd.
+/// * `exit_status` - The container exit status, 128 + signal if the container
+///   has been killed by a signal.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the eventfd cannot be written.
+pub fn write_exit_fd(fd: &OwnedFd, exit_status: i32) -> ConmonResult<()> {
+    let value = u64::from(exit_status as u8) + 1;
+    write_all_fd(fd, &value.to_ne_bytes())
+        .map_err(|e| ConmonError::new(format!("Unable to write the exit eventfd: {e}"), 1))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::runtime::stdio::{create_pipe, read_pipe};
@@ -219,6 +280,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn write_reports_exit_signal_for_api_v1() -> ConmonResult<()> {
+        use nix::sys::signal::Signal;
+        let exit_signal = ExitSignal {
+            signal: Signal::SIGSEGV,
+            core_dumped: true,
+        };
+        let (r, w) = create_pipe()?;
+        write_or_close_sync_fd_with_signal(w, 139, None, Some(&exit_signal), true, 1, true)?;
+        let mut buf = [0u8; 8192];
+        let n = read_pipe(&r, &mut buf)?;
+        drop(r);
+        let v: Value = serde_json::from_slice(&buf[..n])?;
+        assert_eq!(v.get("data").unwrap(), 139);
+        assert_eq!(v.get("signal").unwrap(), "SIGSEGV");
+        assert_eq!(v.get("core_dumped").unwrap(), true);
+        assert_eq!(v.get("oom").unwrap(), true);
+        assert!(v.get("message").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn write_exit_fd_encodes_exit_status() -> ConmonResult<()> {
+        let raw = unsafe { nix::libc::eventfd(0, nix::libc::EFD_CLOEXEC) };
+        assert!(raw >= 0);
+        let efd = unsafe { OwnedFd::from_raw_fd(raw) };
+        for status in [0, 137] {
+            write_exit_fd(&efd, status)?;
+            let mut buf = [0u8; 8];
+            assert_eq!(read_pipe(&efd, &mut buf)?, 8);
+            assert_eq!(u64::from_ne_bytes(buf) - 1, status as u64);
+        }
+        Ok(())
+    }
+
     #[test]
     fn write_ok_on_epipe() -> ConmonResult<()> {
         let (r, w) = create_pipe()?;
@@ -0,0 +1,238 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde_json::{Value, json};
+
+use crate::error::{ConmonError, ConmonResult};
+
+/// Default terminal size written into the recording header.
+const DEFAULT_WIDTH: u16 = 80;
+const DEFAULT_HEIGHT: u16 = 24;
+
+/// Returns the length of the longest prefix of `data` which does not end
+/// with an incomplete UTF-8 sequence.
+fn complete_utf8_len(data: &[u8]) -> usize {
+    let mut pos = 0;
+    loop {
+        match std::str::from_utf8(&data[pos..]) {
+            Ok(_) => return data.len(),
+            // Invalid bytes are written lossily, skip them.
+            Err(e) => match e.error_len() {
+                Some(n) => pos += e.valid_up_to() + n,
+                // The data ends in the middle of a valid sequence.
+                None => return pos + e.valid_up_to(),
+            },
+        }
+    }
+}
+
+/// Records the session in the asciicast v2 format.
+///
+/// The file starts with a JSON header, followed by one JSON array per event:
+/// `[elapsed seconds, "o" | "i" | "r", data]`, where "o" is the output of the
+/// container, "i" is the input sent by the attach clients and "r" is the
+/// terminal resize ("COLSxROWS").
+pub struct SessionRecorder {
+    file: File,
+    path: PathBuf,
+    start: Instant,
+
+    /// Incomplete UTF-8 sequences at the end of the last output and input.
+    pending_output: Vec<u8>,
+    pending_input: Vec<u8>,
+}
+
+impl SessionRecorder {
+    /// Creates the recording file in `dir` and writes the header.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory to create the recording in.
+    /// * `cid` - The container ID, used as the title.
+    /// * `terminal` - True if the container uses a terminal.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the file cannot be created.
+    pub fn create(dir: &Path, cid: &str, terminal: bool) -> ConmonResult<Self> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let path = dir.join(format!(
+            "session-{}-{}.cast",
+            now.as_secs(),
+            std::process::id()
+        ));
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|e| {
+                ConmonError::new(
+                    format!("Failed to create session recording {}: {e}", path.display()),
+                    1,
+                )
+            })?;
+        let header = json!({
+            "version": 2,
+            "width": DEFAULT_WIDTH,
+            "height": DEFAULT_HEIGHT,
+            "timestamp": now.as_secs(),
+            "title": cid,
+            "env": {"TERM": if terminal { "xterm" } else { "dumb" }},
+        });
+        writeln!(file, "{header}")?;
+        info!("Recording the session into {}", path.display());
+        Ok(Self {
+            file,
+            path,
+            start: Instant::now(),
+            pending_output: Vec::new(),
+            pending_input: Vec::new(),
+        })
+    }
+
+    /// Writes single event.
+    fn event(&mut self, code: &str, data: &str) -> ConmonResult<()> {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let line = Value::from(vec![
+            Value::from((elapsed * 1_000_000.0).round() / 1_000_000.0),
+            Value::from(code),
+            Value::from(data),
+        ]);
+        writeln!(self.file, "{line}")?;
+        Ok(())
+    }
+
+    /// Writes the data event, keeping incomplete UTF-8 sequence for later.
+    fn data_event(&mut self, input: bool, data: &[u8]) -> ConmonResult<()> {
+        let pending = if input {
+            &mut self.pending_input
+        } else {
+            &mut self.pending_output
+        };
+        let mut buf = std::mem::take(pending);
+        buf.extend_from_slice(data);
+        let n = complete_utf8_len(&buf);
+        *pending = buf.split_off(n);
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(&buf).into_owned();
+        self.event(if input { "i" } else { "o" }, &text)
+    }
+
+    /// Records output of the container.
+    pub fn output(&mut self, data: &[u8]) -> ConmonResult<()> {
+        self.data_event(false, data)
+    }
+
+    /// Records input sent to the container.
+    pub fn input(&mut self, data: &[u8]) -> ConmonResult<()> {
+        self.data_event(true, data)
+    }
+
+    /// Records the terminal resize.
+    pub fn resize(&mut self, width: u16, height: u16) -> ConmonResult<()> {
+        self.event("r", &format!("{width}x{height}"))
+    }
+
+    /// Returns the path of the recording.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+static RECORDER: Mutex<Option<SessionRecorder>> = Mutex::new(None);
+
+fn recorder() -> MutexGuard<'static, Option<SessionRecorder>> {
+    match RECORDER.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Starts recording the session into `dir`, unless it is recorded already.
+pub fn start_recording(dir: &Path, cid: &str, terminal: bool) -> ConmonResult<()> {
+    let mut rec = recorder();
+    if rec.is_none() {
+        *rec = Some(SessionRecorder::create(dir, cid, terminal)?);
+    }
+    Ok(())
+}
+
+/// Runs `f` with the active recorder, stopping the recording on error.
+fn with_recorder<F>(f: F)
+where
+    F: FnOnce(&mut SessionRecorder) -> ConmonResult<()>,
+{
+    let mut rec = recorder();
+    if let Some(r) = rec.as_mut() {
+        if let Err(e) = f(r) {
+            warn!(
+                "Failed to write session recording {}, stopping it: {}",
+                r.path().display(),
+                e.msg
+            );
+            *rec = None;
+        }
+    }
+}
+
+/// Records output of the container, if the session is recorded.
+pub fn record_output(data: &[u8]) {
+    with_recorder(|r| r.output(data));
+}
+
+/// Records input sent to the container, if the session is recorded.
+pub fn record_input(data: &[u8]) {
+    with_recorder(|r| r.input(data));
+}
+
+/// Records the terminal resize, if the session is recorded.
+pub fn record_resize(width: u16, height: u16) {
+    with_recorder(|r| r.resize(width, height));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn records_asciicast_events() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let mut rec = SessionRecorder::create(tmp.path(), "cid", true)?;
+        rec.input(b"ls\r")?;
+        // "é" split across two reads.
+        rec.output(b"caf\xc3")?;
+        rec.output(b"\xa9\r\n")?;
+        rec.resize(120, 40)?;
+
+        let contents = std::fs::read_to_string(rec.path())?;
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["title"], "cid");
+        assert_eq!(lines[1][1], "i");
+        assert_eq!(lines[1][2], "ls\r");
+        assert_eq!(lines[2][2], "caf");
+        assert_eq!(lines[3][2], "é\r\n");
+        assert_eq!(lines[4][1], "r");
+        assert_eq!(lines[4][2], "120x40");
+        Ok(())
+    }
+
+    #[test]
+    fn complete_utf8_len_keeps_incomplete_tail() {
+        assert_eq!(complete_utf8_len(b"abc"), 3);
+        assert_eq!(complete_utf8_len(b"ab\xe2\x82"), 2);
+        assert_eq!(complete_utf8_len(b"\xff\xfeab"), 4);
+    }
+}
@@ -1,7 +1,63 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
 use crate::cli::CommonCfg;
 use crate::error::ConmonResult;
 use crate::unix_socket::UnixSocket;
 
+/// Name of the runtime log file conmon asks runc and crun to write.
+pub const RUNTIME_LOG_FILE_NAME: &str = "runtime.log";
+
+/// Returns the value of the `--log` flag in `args`, if there is one.
+fn log_flag(args: &[String]) -> Option<&str> {
+    args.iter()
+        .enumerate()
+        .find_map(|(i, arg)| match arg.as_str() {
+            "--log" => args.get(i + 1).map(String::as_str),
+            _ => arg.strip_prefix("--log="),
+        })
+}
+
+/// Returns the JSON log file of the runtime: the `--log` passed in the
+/// `--runtime-arg` with `--log-format json`, or the file conmon passes to
+/// runc and crun in the `--persist-dir` or the bundle directory.
+///
+/// # Returns
+///
+/// * The path and true if conmon has to pass the `--log` flags itself, or
+///   None if the runtime log is not in JSON format.
+pub fn runtime_log_path(o: &CommonCfg) -> Option<(PathBuf, bool)> {
+    if let Some(path) = log_flag(&o.runtime_args) {
+        let args = &o.runtime_args;
+        let json = args.iter().enumerate().any(|(i, arg)| {
+            arg == "--log-format=json"
+                || (arg == "--log-format" && args.get(i + 1).is_some_and(|f| f == "json"))
+        });
+        return json.then(|| (PathBuf::from(path), false));
+    }
+    let name = o.runtime.file_name()?.to_str()?;
+    if !matches!(name, "runc" | "crun") {
+        return None;
+    }
+    let dir = o.persist_dir.as_ref().unwrap_or(&o.bundle);
+    Some((dir.join(RUNTIME_LOG_FILE_NAME), true))
+}
+
+/// Returns the message of the last error logged by the runtime in its JSON
+/// log `path`, like `{"level":"error","msg":"...","time":"..."}`.
+pub fn last_runtime_error(path: &Path) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    contents.lines().rev().find_map(|line| {
+        let entry: Value = serde_json::from_str(line).ok()?;
+        if entry["level"] != "error" {
+            return None;
+        }
+        entry["msg"].as_str().map(String::from)
+    })
+}
+
 /// Trait for constructing the runtime argv.
 /// Implementors append global/common flags and then subcommand-specificf,
 /// flags to `argv`, returning an error on failure.
@@ -28,6 +84,16 @@ pub fn generate_runtime_args(
     // Argument specific global args.
     args_gen.add_global_args(&mut argv)?;
 
+    // The runtime errors in JSON, parsed if the runtime fails.
+    if let Some((path, true)) = runtime_log_path(o) {
+        argv.extend([
+            "--log".to_string(),
+            path.to_string_lossy().into_owned(),
+            "--log-format".to_string(),
+            "json".to_string(),
+        ]);
+    }
+
     // Extra runtime args (appear right after the runtime path / global flags)
     argv.extend(o.runtime_args.iter().map(|s| s.to_string()));
 
@@ -135,6 +201,53 @@ mod tests {
         assert_eq!(argv, expected);
     }
 
+    #[test]
+    fn runtime_log_is_passed_to_runc_and_parsed() -> ConmonResult<()> {
+        let tmp = tempfile::tempdir()?;
+        let mut common = CommonCfg {
+            runtime: "/usr/bin/runc".into(),
+            cid: "abc".into(),
+            persist_dir: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        };
+        let log = tmp.path().join(RUNTIME_LOG_FILE_NAME);
+        let args_gen = OkGen {
+            globals: vec![],
+            subs: vec!["create".into()],
+        };
+        let argv = generate_runtime_args(&common, &args_gen, None)?;
+        assert_eq!(
+            argv[1..5],
+            ["--log", &log.to_string_lossy(), "--log-format", "json"]
+        );
+
+        fs::write(
+            &log,
+            concat!(
+                r#"{"level":"warning","msg":"deprecated","time":"t"}"#,
+                "\n",
+                r#"{"level":"error","msg":"container_linux.go: starting container process caused: exec: \"sh\": not found","time":"t"}"#,
+                "\n",
+                r#"{"level":"info","msg":"done","time":"t"}"#,
+                "\n"
+            ),
+        )?;
+        assert_eq!(
+            last_runtime_error(&log).as_deref(),
+            Some(r#"container_linux.go: starting container process caused: exec: "sh": not found"#)
+        );
+
+        // The log requested by the engine is used as it is.
+        common.runtime_args = vec!["--log=/run/oci-log".into(), "--log-format=json".into()];
+        assert_eq!(
+            runtime_log_path(&common),
+            Some((PathBuf::from("/run/oci-log"), false))
+        );
+        common.runtime_args = vec!["--log".into(), "/run/oci-log".into()];
+        assert_eq!(runtime_log_path(&common), None);
+        Ok(())
+    }
+
     #[test]
     fn propagates_error_from_add_global_args() {
         let common = CommonCfg {
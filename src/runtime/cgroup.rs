@@ -8,16 +8,25 @@ use nix::sys::stat::Mode;
 use nix::sys::statfs;
 use nix::unistd::close;
 use std::ffi::CString;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader, ErrorKind};
-use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::error::{ConmonError, ConmonResult};
 use crate::unix_socket::{RemoteSocket, SocketType};
 
 const CGROUP_ROOT: &str = "/sys/fs/cgroup";
 
+/// True once an OOM event of the container has been detected.
+static OOM_DETECTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if the container ran out of memory.
+pub fn oom_detected() -> bool {
+    OOM_DETECTED.load(Ordering::Relaxed)
+}
+
 /// Sets up OOM (out-of-memory) handling for `pid` .
 ///
 /// # Arguments
@@ -41,9 +50,115 @@ pub fn setup_oom_handling(
             let s = setup_oom_handling_cgroup_v2(pid, persist_dir, bundle)?;
             return Ok(s);
         }
+    }
+    // The rootless containers cannot register the cgroup v1 events, so the
+    // failure is not fatal.
+    setup_oom_handling_cgroup_v1(pid, persist_dir, bundle).or_else(|e| {
+        warn!("OOM of the container is not detected: {}", e.msg);
+        Ok(None)
+    })
+}
 
-        Err(ConmonError::new("Cgroups v1 is not supported.", 1))
+/// Returns the path of the cgroup v1 memory controller directory from the
+/// contents of /proc/[pid]/cgroup, or None if the memory controller is not
+/// mounted.
+///
+/// # Arguments
+///
+/// * `contents` - The contents of /proc/[pid]/cgroup.
+fn cgroup1_memory_path(contents: &str) -> Option<PathBuf> {
+    contents.lines().find_map(|line| {
+        // Format: hierarchy-ID:controllers:path
+        let mut parts = line.splitn(3, ':');
+        let (_, controllers, path) = (parts.next()?, parts.next()?, parts.next()?);
+        controllers.split(',').any(|c| c == "memory").then(|| {
+            Path::new(CGROUP_ROOT)
+                .join("memory")
+                .join(path.trim_start_matches('/'))
+        })
+    })
+}
+
+/// Sets up OOM (out-of-memory) handling for `pid` on cgroup v1.
+///
+/// The eventfd registered for `memory.oom_control` using `cgroup.event_control`
+/// is signalled on every OOM of the memory cgroup, and also when the cgroup
+/// is removed.
+///
+/// # Arguments
+///
+/// * `pid` - The pid to setup OOM for.
+/// * `persist_dir` - Optional path into which the OOM marker files will be created.
+/// * `bundle` - Path into which the OOM marker file is created.
+///
+/// # Returns
+///
+/// * RemoteSocket to pass to event-loop.
+fn setup_oom_handling_cgroup_v1(
+    pid: i32,
+    persist_dir: &Option<PathBuf>,
+    bundle: &Path,
+) -> ConmonResult<Option<RemoteSocket>> {
+    let contents = match fs::read_to_string(format!("/proc/{pid}/cgroup")) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(ConmonError::new(
+                format!("Failed to read cgroups file of {pid}: {e}"),
+                1,
+            ));
+        }
+    };
+    let Some(memory_path) = cgroup1_memory_path(&contents) else {
+        warn!("No cgroup v1 memory controller found for {pid}, OOM is not detected");
+        return Ok(None);
+    };
+
+    let oom_control_path = memory_path.join("memory.oom_control");
+    let oom_control = File::open(&oom_control_path).map_err(|e| {
+        ConmonError::new(
+            format!("Failed to open {}: {e}", oom_control_path.display()),
+            1,
+        )
+    })?;
+    let efd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+    if efd < 0 {
+        return Err(ConmonError::new(
+            format!("Failed to create eventfd: {}", Errno::last()),
+            1,
+        ));
     }
+    let efd_owned = unsafe { OwnedFd::from_raw_fd(efd) };
+    let event_control_path = memory_path.join("cgroup.event_control");
+    fs::write(
+        &event_control_path,
+        format!("{} {}", efd, oom_control.as_raw_fd()),
+    )
+    .map_err(|e| {
+        ConmonError::new(
+            format!("Failed to write {}: {e}", event_control_path.display()),
+            1,
+        )
+    })?;
+    info!("OOM eventfd registered for {}", oom_control_path.display());
+
+    let persist_dir = persist_dir.clone();
+    let bundle = PathBuf::from(bundle);
+    let mut socket = RemoteSocket::new(SocketType::Inotify, efd_owned);
+    socket.set_handler(move |_data| {
+        // The event is also sent when the cgroup is removed.
+        if !oom_control_path.exists() {
+            debug!(
+                "Cgroup appears to have been removed, stopping OOM monitoring: {}",
+                oom_control_path.display()
+            );
+            return false;
+        }
+        OOM_DETECTED.store(true, Ordering::Relaxed);
+        let _ = create_oom_files(&persist_dir, &bundle);
+        true
+    });
+    Ok(Some(socket))
 }
 
 /// Inspects /proc/[pid]/cgroup and returns the absolute
@@ -258,6 +373,7 @@ pub fn check_cgroup2_oom(
             };
 
             if counter != *last_counter {
+                OOM_DETECTED.store(true, Ordering::Relaxed);
                 if create_oom_files(persist_dir, bundle).is_ok() {
                     *last_counter = counter;
                     oom_detected = true;
@@ -325,3 +441,24 @@ fn create_oom_file(base_path: &Path) -> Result<(), ()> {
     let _ = close(fd);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroup1_memory_path_is_found() {
+        let contents = "12:pids:/machine/ctr\n\
+                        4:cpu,cpuacct:/machine/ctr\n\
+                        3:memory:/machine.slice/libpod-abc.scope\n\
+                        0::/\n";
+        assert_eq!(
+            cgroup1_memory_path(contents),
+            Some(PathBuf::from(
+                "/sys/fs/cgroup/memory/machine.slice/libpod-abc.scope"
+            ))
+        );
+        assert_eq!(cgroup1_memory_path("0::/user.slice\n"), None);
+        assert_eq!(cgroup1_memory_path("5:memory_x:/a\n"), None);
+    }
+}
@@ -0,0 +1,240 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use log::{debug, info, warn};
+use serde_json::Value;
+
+use crate::cli::CommonCfg;
+use crate::error::{ConmonError, ConmonResult};
+use crate::runtime::args::RuntimeArgsGenerator;
+use crate::runtime::limits::InheritedLimits;
+
+/// Generates the `runtime delete --force` arguments removing the container
+/// in any state.
+pub struct Delete;
+
+impl RuntimeArgsGenerator for Delete {
+    fn add_global_args(&self, _argv: &mut Vec<String>) -> ConmonResult<()> {
+        Ok(())
+    }
+
+    fn add_subcommand_args(&self, argv: &mut Vec<String>) -> ConmonResult<()> {
+        argv.extend(["delete".to_string(), "--force".to_string()]);
+        Ok(())
+    }
+}
+
+/// Returns the `Command` executing the runtime binary with the global
+/// `--runtime-arg` arguments followed by `args`.
+///
+/// # Arguments
+///
+/// * `runtime` - The runtime binary.
+/// * `runtime_args` - The global `--runtime-arg` arguments.
+/// * `args` - The runtime subcommand and its arguments.
+pub fn runtime_command(runtime: &Path, runtime_args: &[String], args: &[&str]) -> Command {
+    let mut cmd = Command::new(runtime);
+    cmd.args(runtime_args).args(args);
+    cmd
+}
+
+/// Runs the runtime subcommand and returns its standard output.
+///
+/// # Arguments
+///
+/// * `common` - The Conmon common configuration.
+/// * `args` - The runtime subcommand and its arguments.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the runtime cannot be executed or fails.
+pub fn run_runtime(common: &CommonCfg, args: &[&str]) -> ConmonResult<String> {
+    run_runtime_binary(&common.runtime, &common.runtime_args, args)
+}
+
+/// Runs the subcommand of the `runtime` binary with the global
+/// `runtime_args` and returns its standard output.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the runtime cannot be executed or fails.
+fn run_runtime_binary(
+    runtime: &Path,
+    runtime_args: &[String],
+    args: &[&str],
+) -> ConmonResult<String> {
+    debug!("Running runtime {} {:?}", runtime.display(), args);
+    let _inherited = InheritedLimits::restore();
+    let output = runtime_command(runtime, runtime_args, args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| {
+            ConmonError::new(
+                format!("Failed to execute runtime {}: {e}", runtime.display()),
+                1,
+            )
+        })?;
+    if !output.status.success() {
+        return Err(ConmonError::new(
+            format!(
+                "Runtime {} failed with {}: {}",
+                args.first().unwrap_or(&""),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            1,
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Removes the container with `runtime delete --force`, so the container
+/// created by the runtime is not leaked when conmon cannot supervise it.
+///
+/// The `--runtime-opt` options and the other create flags are not passed, as
+/// the runtime would reject them for `delete`.
+///
+/// # Arguments
+///
+/// * `common` - The Conmon common configuration.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the runtime cannot be executed or fails.
+pub fn delete_container(common: &CommonCfg) -> ConmonResult<()> {
+    delete_container_by_id(&common.runtime, &common.runtime_args, &common.cid)
+}
+
+/// Removes the container `cid` with `runtime delete --force`, like
+/// [`delete_container`].
+///
+/// # Arguments
+///
+/// * `runtime` - The runtime binary.
+/// * `runtime_args` - The global `--runtime-arg` arguments.
+/// * `cid` - The container ID.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the runtime cannot be executed or fails.
+pub fn delete_container_by_id(
+    runtime: &Path,
+    runtime_args: &[String],
+    cid: &str,
+) -> ConmonResult<()> {
+    let mut argv = Vec::new();
+    Delete.add_global_args(&mut argv)?;
+    Delete.add_subcommand_args(&mut argv)?;
+    argv.push(cid.to_string());
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    run_runtime_binary(runtime, runtime_args, &argv)?;
+    Ok(())
+}
+
+/// Removes the container if `result` is an error, then returns `result`.
+///
+/// # Arguments
+///
+/// * `common` - The Conmon common configuration.
+/// * `result` - The result of a step done after the container was created.
+///
+/// # Errors
+///
+/// * The error of `result`. Failing to remove the container is only logged.
+pub fn delete_container_on_error<T>(
+    common: &CommonCfg,
+    result: ConmonResult<T>,
+) -> ConmonResult<T> {
+    if let Err(e) = &result {
+        info!(
+            "Removing the container {} after error: {}",
+            common.cid, e.msg
+        );
+        if let Err(e) = delete_container(common) {
+            warn!("Failed to remove the container: {}", e.msg);
+        }
+    }
+    result
+}
+
+/// Returns the container PID reported by `runtime state <cid>`.
+///
+/// # Arguments
+///
+/// * `common` - The Conmon common configuration.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the runtime fails or does not report valid PID.
+pub fn runtime_state_pid(common: &CommonCfg) -> ConmonResult<i32> {
+    let state: Value = serde_json::from_str(&run_runtime(common, &["state", &common.cid])?)?;
+    match state["pid"].as_i64() {
+        Some(pid) if pid > 0 && pid <= i32::MAX as i64 => Ok(pid as i32),
+        _ => Err(ConmonError::new(
+            format!("Runtime state has no valid pid: {}", state["pid"]),
+            1,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn fake_runtime(dir: &Path, script: &str) -> CommonCfg {
+        let runtime = dir.join("runtime");
+        std::fs::write(&runtime, format!("#!/bin/sh\n{script}\n")).unwrap();
+        std::fs::set_permissions(&runtime, std::fs::Permissions::from_mode(0o700)).unwrap();
+        CommonCfg {
+            runtime,
+            runtime_args: vec!["--root".into(), "/run/test".into()],
+            cid: "abc".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn runtime_state_pid_parses_state() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let cfg = fake_runtime(
+            tmp.path(),
+            r#"[ "$1 $2 $3 $4" = "--root /run/test state abc" ] || exit 1
+echo '{"id":"abc","status":"created","pid":4242}'"#,
+        );
+        assert_eq!(runtime_state_pid(&cfg)?, 4242);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_step_deletes_container() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let log = tmp.path().join("calls");
+        let cfg = fake_runtime(tmp.path(), &format!("echo \"$@\" >> {}", log.display()));
+
+        delete_container_on_error(&cfg, Ok(()))?;
+        assert!(!log.exists());
+
+        let err = delete_container_on_error::<()>(&cfg, Err(ConmonError::new("broken", 1)));
+        assert_eq!(err.unwrap_err().msg, "broken");
+        assert_eq!(
+            std::fs::read_to_string(&log)?,
+            "--root /run/test delete --force abc\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_state_pid_errors() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let cfg = fake_runtime(tmp.path(), r#"echo '{"pid":0}'"#);
+        assert!(runtime_state_pid(&cfg).is_err());
+
+        let cfg = fake_runtime(tmp.path(), "echo 'container not found' >&2; exit 1");
+        let err = runtime_state_pid(&cfg).unwrap_err();
+        assert!(err.msg.contains("container not found"), "{}", err.msg);
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::{info, warn};
+use nix::sys::signal::{Signal, kill};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+use nix::unistd::Pid;
+
+use crate::cli::CommonCfg;
+use crate::error::{ConmonError, ConmonResult};
+use crate::logging::plugin::request_reload;
+use crate::runtime::command::run_runtime;
+use crate::unix_socket::{RemoteSocket, SocketType};
+
+/// Default grace period of the `stop` request in seconds.
+pub const DEFAULT_STOP_TIMEOUT: u64 = 10;
+
+/// Container control request received on the `ctl` fifo.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ControlRequest {
+    /// "kill SIGNAL [--all]": Sends the signal using `runtime kill`.
+    Kill { signal: Signal, all: bool },
+
+    /// "stop [SECONDS]": Sends the `--stop-signal` (SIGTERM) and SIGKILL to
+    /// all the container processes if the container does not exit in SECONDS.
+    Stop { timeout: u64 },
+
+    /// "reload-log": Re-creates the log drivers, like SIGHUP.
+    ReloadLog,
+}
+
+/// Parses the signal given by its name ("TERM", "SIGTERM") or number.
+pub fn parse_signal(s: &str) -> ConmonResult<Signal> {
+    let signal = match s.parse::<i32>() {
+        Ok(n) => Signal::try_from(n),
+        Err(_) => {
+            let name = s.to_ascii_uppercase();
+            if name.starts_with("SIG") {
+                Signal::from_str(&name)
+            } else {
+                Signal::from_str(&format!("SIG{name}"))
+            }
+        }
+    };
+    signal.map_err(|_| ConmonError::new(format!("Invalid signal: {s}"), 1))
+}
+
+impl ControlRequest {
+    /// Parses the control request in `line`.
+    ///
+    /// # Returns
+    ///
+    /// * None if `line` is not a container control request.
+    pub fn parse(line: &str) -> Option<ConmonResult<Self>> {
+        let mut parts = line.split_whitespace();
+        let req = match parts.next()? {
+            "kill" => {
+                let signal = match parts.next() {
+                    Some(s) => parse_signal(s),
+                    None => Err(ConmonError::new("Missing signal in kill request", 1)),
+                };
+                let all = parts.next() == Some("--all");
+                signal.map(|signal| ControlRequest::Kill { signal, all })
+            }
+            "stop" => match parts.next().map(|t| t.parse::<u64>()) {
+                None => Ok(ControlRequest::Stop {
+                    timeout: DEFAULT_STOP_TIMEOUT,
+                }),
+                Some(Ok(timeout)) => Ok(ControlRequest::Stop { timeout }),
+                Some(Err(e)) => Err(ConmonError::new(
+                    format!("Invalid timeout in stop request: {e}"),
+                    1,
+                )),
+            },
+            "reload-log" => Ok(ControlRequest::ReloadLog),
+            _ => return None,
+        };
+        Some(req)
+    }
+}
+
+/// The container the control requests apply to.
+struct ContainerControl {
+    common: CommonCfg,
+    container_pid: i32,
+}
+
+static CONTROL: Mutex<Option<ContainerControl>> = Mutex::new(None);
+
+fn control() -> MutexGuard<'static, Option<ContainerControl>> {
+    match CONTROL.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Enables the control requests once the container has been created.
+///
+/// # Arguments
+///
+/// * `common` - The Conmon common configuration.
+/// * `container_pid` - The PID of the container.
+pub fn enable(common: &CommonCfg, container_pid: i32) {
+    *control() = Some(ContainerControl {
+        common: common.clone(),
+        container_pid,
+    });
+}
+
+/// Sends `signal` to the container using `runtime kill`.
+fn kill_container(common: &CommonCfg, signal: Signal, all: bool) -> ConmonResult<()> {
+    info!("Sending {} to container {}", signal, common.cid);
+    let mut args = vec!["kill"];
+    if all {
+        args.push("--all");
+    }
+    args.extend([common.cid.as_str(), signal.as_str()]);
+    run_runtime(common, &args).map(|_| ())
+}
+
+/// Handles the container control request.
+///
+/// # Arguments
+///
+/// * `req` - The control request.
+///
+/// # Returns
+///
+/// * The timer socket the event-loop must poll to finish the `stop` request.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the container has not been created yet or the runtime
+///   fails.
+pub fn handle_request(req: ControlRequest) -> ConmonResult<Option<RemoteSocket>> {
+    let guard = control();
+    let Some(ctl) = guard.as_ref() else {
+        return Err(ConmonError::new(
+            "Cannot control the container before it is created",
+            1,
+        ));
+    };
+    let timeout = match req {
+        ControlRequest::Kill { signal, all } => {
+            kill_container(&ctl.common, signal, all)?;
+            return Ok(None);
+        }
+        ControlRequest::Stop { timeout: 0 } => {
+            kill_container(&ctl.common, Signal::SIGKILL, true)?;
+            return Ok(None);
+        }
+        ControlRequest::Stop { timeout } => timeout,
+        ControlRequest::ReloadLog => {
+            info!("Reloading the log drivers on control request");
+            request_reload();
+            return Ok(None);
+        }
+    };
+
+    let signal = ctl.common.stop_signal.unwrap_or(Signal::SIGTERM);
+    kill_container(&ctl.common, signal, false)?;
+    let timer = TimerFd::new(
+        ClockId::CLOCK_MONOTONIC,
+        TimerFlags::TFD_CLOEXEC | TimerFlags::TFD_NONBLOCK,
+    )?;
+    timer.set(
+        Expiration::OneShot(TimeSpec::from_duration(Duration::from_secs(timeout))),
+        TimerSetTimeFlags::empty(),
+    )?;
+
+    // Kill everything in the container once the grace period expires.
+    let common = ctl.common.clone();
+    let pid = Pid::from_raw(ctl.container_pid);
+    let mut socket = RemoteSocket::new(SocketType::Timer, timer.into());
+    socket.set_handler(move |_data| {
+        if kill(pid, None).is_ok() {
+            warn!("Container did not stop in {timeout} seconds, killing it");
+            if let Err(e) = kill_container(&common, Signal::SIGKILL, true) {
+                warn!("{}", e.msg);
+            }
+        }
+        false
+    });
+    Ok(Some(socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_control_requests() -> ConmonResult<()> {
+        assert_eq!(
+            ControlRequest::parse("kill TERM").unwrap()?,
+            ControlRequest::Kill {
+                signal: Signal::SIGTERM,
+                all: false
+            }
+        );
+        assert_eq!(
+            ControlRequest::parse("kill 9 --all\n").unwrap()?,
+            ControlRequest::Kill {
+                signal: Signal::SIGKILL,
+                all: true
+            }
+        );
+        assert_eq!(
+            ControlRequest::parse("kill sighup").unwrap()?,
+            ControlRequest::Kill {
+                signal: Signal::SIGHUP,
+                all: false
+            }
+        );
+        assert_eq!(
+            ControlRequest::parse("reload-log").unwrap()?,
+            ControlRequest::ReloadLog
+        );
+        assert_eq!(
+            ControlRequest::parse("stop").unwrap()?,
+            ControlRequest::Stop {
+                timeout: DEFAULT_STOP_TIMEOUT
+            }
+        );
+        assert_eq!(
+            ControlRequest::parse("stop 3").unwrap()?,
+            ControlRequest::Stop { timeout: 3 }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_rejects_invalid_requests() {
+        assert!(ControlRequest::parse("1 24 80").is_none());
+        assert!(ControlRequest::parse("kill").unwrap().is_err());
+        assert!(ControlRequest::parse("kill NOPE").unwrap().is_err());
+        assert!(ControlRequest::parse("stop soon").unwrap().is_err());
+    }
+}
@@ -11,6 +11,7 @@ use nix::unistd::{mkfifo, unlink};
 use crate::cli::CommonCfg;
 use crate::error::{ConmonError, ConmonResult};
 use crate::logging::plugin::LogPlugin;
+use crate::recording;
 use crate::unix_socket::{RemoteSocket, SocketType};
 
 /// Constants for `ctl` commands.
@@ -50,11 +51,39 @@ pub fn process_winsz_ctrl_line(stdout_fd: i32, line: &str) -> ConmonResult<()> {
         ));
     }
 
+    resize_terminal(stdout_fd, height as u16, width as u16);
+
+    Ok(())
+}
+
+/// Resizes the container pty defined by `stdout_fd` and records the resize.
+pub fn resize_terminal(stdout_fd: i32, height: u16, width: u16) {
     unsafe {
-        resize_winsz(stdout_fd, height as u16, width as u16);
+        resize_winsz(stdout_fd, height, width);
     }
+    recording::record_resize(width, height);
+}
 
-    Ok(())
+/// Returns the window size of the terminal `fd` as (height, width).
+///
+/// # Errors
+///
+/// * [`ConmonError`] if `fd` is not a terminal.
+pub fn terminal_size(fd: i32) -> ConmonResult<(u16, u16)> {
+    let mut ws = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let ret = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) };
+    if ret == -1 {
+        return Err(ConmonError::new(
+            format!("Failed to get terminal size: {}", Errno::last()),
+            1,
+        ));
+    }
+    Ok((ws.ws_row, ws.ws_col))
 }
 
 /// Parses "msg_type height width\n" in `line` and acts.
@@ -0,0 +1,188 @@
+use std::fs;
+use std::sync::Mutex;
+
+use log::{info, warn};
+use nix::sys::resource::{Resource, getrlimit, setrlimit};
+
+use crate::cli::CommonCfg;
+use crate::error::{ConmonError, ConmonResult};
+
+const OOM_SCORE_ADJ_PATH: &str = "/proc/self/oom_score_adj";
+
+/// A limit applied to conmon, with the (soft, hard) values it replaced.
+struct AppliedLimit {
+    resource: Resource,
+    original: (u64, u64),
+    applied: (u64, u64),
+}
+
+/// The limits and OOM score adjustment applied by `apply_self_limits`, with
+/// the values the children of conmon get instead.
+#[derive(Default)]
+struct AppliedLimits {
+    limits: Vec<AppliedLimit>,
+    oom_score_adj: Option<(String, i32)>,
+}
+
+static APPLIED_LIMITS: Mutex<Option<AppliedLimits>> = Mutex::new(None);
+
+/// Sets the soft limit of `resource` to `value`.
+///
+/// The hard limit is only raised when `value` exceeds it, so lowering the
+/// limit never requires any privileges.
+fn set_limit(resource: Resource, value: u64) -> ConmonResult<AppliedLimit> {
+    let original = getrlimit(resource)?;
+    let hard = if value > original.1 {
+        value
+    } else {
+        original.1
+    };
+    setrlimit(resource, value, hard)
+        .map_err(|e| ConmonError::new(format!("Failed to set {resource:?} to {value}: {e}"), 1))?;
+    info!("Set {resource:?} to {value}");
+    Ok(AppliedLimit {
+        resource,
+        original,
+        applied: (value, hard),
+    })
+}
+
+/// Applies the resource limits and OOM score adjustment to the conmon
+/// process itself.
+///
+/// This is called before the runtime process is spawned, so a failure does
+/// not leave a created container behind. The limits protect the node from
+/// runaway buffering in conmon; the processes conmon spawns get the
+/// original values back, see [`InheritedLimits`].
+///
+/// # Arguments
+///
+/// * `common` - The Conmon common configuration.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if any of the limits cannot be applied.
+pub fn apply_self_limits(common: &CommonCfg) -> ConmonResult<()> {
+    let mut applied = AppliedLimits::default();
+    let limits = [
+        (Resource::RLIMIT_NOFILE, common.rlimit_nofile),
+        (Resource::RLIMIT_AS, common.rlimit_as),
+        (Resource::RLIMIT_MEMLOCK, common.rlimit_memlock),
+    ];
+    for (resource, value) in limits {
+        if let Some(n) = value {
+            applied.limits.push(set_limit(resource, n)?);
+        }
+    }
+    if let Some(adj) = common.oom_score_adj {
+        let original = fs::read_to_string(OOM_SCORE_ADJ_PATH)?;
+        fs::write(OOM_SCORE_ADJ_PATH, adj.to_string()).map_err(|e| {
+            ConmonError::new(format!("Failed to set oom_score_adj to {adj}: {e}"), 1)
+        })?;
+        info!("Set oom_score_adj to {adj}");
+        applied.oom_score_adj = Some((original.trim().to_string(), adj));
+    }
+    *APPLIED_LIMITS.lock().unwrap() = Some(applied);
+    Ok(())
+}
+
+/// Restores the original limits and OOM score adjustment of conmon while
+/// a child process is spawned, so the child does not inherit the values
+/// applied by `apply_self_limits`. Dropping it applies them again.
+///
+/// Both steps are best-effort, the failures are only logged.
+pub struct InheritedLimits(());
+
+impl InheritedLimits {
+    /// Restores the original values, if `apply_self_limits` changed them.
+    pub fn restore() -> Self {
+        if let Some(applied) = APPLIED_LIMITS.lock().unwrap().as_ref() {
+            applied.set(|l| l.original, |(original, _)| original.clone());
+        }
+        Self(())
+    }
+}
+
+impl Drop for InheritedLimits {
+    fn drop(&mut self) {
+        if let Some(applied) = APPLIED_LIMITS.lock().unwrap().as_ref() {
+            applied.set(|l| l.applied, |(_, adj)| adj.to_string());
+        }
+    }
+}
+
+impl AppliedLimits {
+    /// Sets the limits and the OOM score adjustment to the values selected
+    /// by `limit` and `oom_score_adj`.
+    fn set(
+        &self,
+        limit: impl Fn(&AppliedLimit) -> (u64, u64),
+        oom_score_adj: impl Fn(&(String, i32)) -> String,
+    ) {
+        for l in &self.limits {
+            let (soft, hard) = limit(l);
+            if let Err(e) = setrlimit(l.resource, soft, hard) {
+                warn!("Failed to set {:?} to {soft}: {e}", l.resource);
+            }
+        }
+        if let Some(adj) = self.oom_score_adj.as_ref().map(oom_score_adj) {
+            if let Err(e) = fs::write(OOM_SCORE_ADJ_PATH, &adj) {
+                warn!("Failed to set oom_score_adj to {adj}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_self_limits_noop_without_options() -> ConmonResult<()> {
+        apply_self_limits(&CommonCfg::default())
+    }
+
+    /// Set in the test process running a single test on its own.
+    const OWN_PROCESS_ENV: &str = "_CONMON_TEST_OWN_PROCESS";
+
+    /// Runs the test `name` alone in a new test process, so the limits it
+    /// applies do not leak into the other tests spawning processes.
+    ///
+    /// # Returns
+    ///
+    /// * True in the new process, which should run the test body.
+    fn in_own_process(name: &str) -> bool {
+        if std::env::var_os(OWN_PROCESS_ENV).is_some() {
+            return true;
+        }
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([name, "--exact", "--test-threads=1"])
+            .env(OWN_PROCESS_ENV, "1")
+            .status()
+            .unwrap();
+        assert!(status.success(), "{name} failed in its own process");
+        false
+    }
+
+    #[test]
+    fn apply_self_limits_sets_nofile() -> ConmonResult<()> {
+        if !in_own_process("runtime::limits::tests::apply_self_limits_sets_nofile") {
+            return Ok(());
+        }
+        let (soft, _hard) = getrlimit(Resource::RLIMIT_NOFILE)?;
+        let cfg = CommonCfg {
+            rlimit_nofile: Some(soft - 1),
+            ..Default::default()
+        };
+        apply_self_limits(&cfg)?;
+        let (new_soft, _) = getrlimit(Resource::RLIMIT_NOFILE)?;
+        assert_eq!(new_soft, soft - 1);
+
+        // The spawned children get the original limit.
+        let inherited = InheritedLimits::restore();
+        assert_eq!(getrlimit(Resource::RLIMIT_NOFILE)?.0, soft);
+        drop(inherited);
+        assert_eq!(getrlimit(Resource::RLIMIT_NOFILE)?.0, soft - 1);
+        Ok(())
+    }
+}
@@ -1,6 +1,13 @@
 pub mod args;
 pub mod cgroup;
+pub mod command;
+pub mod control;
 pub mod ctl;
+pub mod limits;
+pub mod notify;
 pub mod process;
 pub mod session;
+pub mod state;
 pub mod stdio;
+pub mod upgrade;
+pub mod version;
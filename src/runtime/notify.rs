@@ -0,0 +1,194 @@
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use nix::cmsg_space;
+use nix::sys::socket::{
+    AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType,
+    SockaddrStorage, UnixAddr, recvmsg, sendmsg, socket,
+};
+
+/// The sd-notify variables relayed from the container to the host systemd.
+///
+/// The others, like `FDSTORE`, refer to the container's fds and would
+/// confuse the host systemd.
+pub const RELAYED_VARIABLES: &[&str] = &["READY", "STATUS", "ERRNO", "MAINPID", "BARRIER"];
+
+/// The host PID of the container init process, 0 if unknown.
+static MAIN_PID: AtomicI32 = AtomicI32::new(0);
+
+/// Sets the host PID of the container init process, read from the
+/// container pidfile. It replaces the `MAINPID` values sent by the container.
+pub fn set_main_pid(pid: i32) {
+    MAIN_PID.store(pid, Ordering::Relaxed);
+}
+
+/// Returns the host PID of the container init process, 0 if unknown.
+pub fn main_pid() -> i32 {
+    MAIN_PID.load(Ordering::Relaxed)
+}
+
+/// Returns the notification to relay to the host systemd for the `message`
+/// received from the container.
+///
+/// The message is a list of newline-separated `VARIABLE=value` assignments.
+/// Only the assignments of the [`RELAYED_VARIABLES`] are kept. The `MAINPID`
+/// value is in the container's PID namespace, so it is replaced with the host
+/// PID of the container init, or dropped if it is not known yet.
+///
+/// # Arguments
+///
+/// * `message` - The message received from the container.
+/// * `main_pid` - The host PID of the container init, 0 if unknown.
+///
+/// # Returns
+///
+/// * None if nothing is left to relay.
+pub fn filter_message(message: &[u8], main_pid: i32) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(message.len());
+    for line in message.split(|&c| c == b'\n') {
+        let Some(eq) = line.iter().position(|&c| c == b'=') else {
+            continue;
+        };
+        let variable = &line[..eq];
+        if !RELAYED_VARIABLES.iter().any(|v| v.as_bytes() == variable) {
+            continue;
+        }
+        if variable == b"MAINPID" {
+            if main_pid <= 0 {
+                continue;
+            }
+            out.extend_from_slice(format!("MAINPID={main_pid}").as_bytes());
+        } else {
+            out.extend_from_slice(line);
+        }
+        out.push(b'\n');
+    }
+    if out.is_empty() { None } else { Some(out) }
+}
+
+/// Returns true if the `message` is a `BARRIER=1` synchronization message,
+/// which comes with the fd to close once the previous messages are processed.
+pub fn is_barrier(message: &[u8]) -> bool {
+    message
+        .split(|&c| c == b'\n')
+        .any(|line| line == b"BARRIER=1")
+}
+
+/// Receives single notification and the fds passed with it.
+///
+/// # Arguments
+///
+/// * `fd` - The container-side notify socket.
+/// * `buf` - The buffer to receive the message into.
+///
+/// # Returns
+///
+/// * The number of bytes received and the fds.
+pub fn recv_message(fd: RawFd, buf: &mut [u8]) -> nix::Result<(usize, Vec<OwnedFd>)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsgspace = cmsg_space!([RawFd; 4]);
+    let msg = recvmsg::<SockaddrStorage>(
+        fd,
+        &mut iov,
+        Some(&mut cmsgspace),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(rights) = cmsg {
+            fds.extend(
+                rights
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    Ok((msg.bytes, fds))
+}
+
+/// Sends the `message` with the `fds` to the host systemd socket.
+///
+/// The `BARRIER=1` fd is passed on, so the host systemd closes it once it
+/// has processed the previous messages, as the container expects.
+///
+/// # Arguments
+///
+/// * `host_socket` - Path to the host's systemd notify socket.
+/// * `message` - The filtered message.
+/// * `fds` - The fds to pass with the message.
+pub fn relay(host_socket: &Path, message: &[u8], fds: &[OwnedFd]) -> nix::Result<()> {
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let addr = UnixAddr::new(host_socket)?;
+    let raw_fds: Vec<RawFd> = fds.iter().map(|f| f.as_raw_fd()).collect();
+    let rights = [ControlMessage::ScmRights(&raw_fds)];
+    let cmsgs: &[ControlMessage] = if raw_fds.is_empty() { &[] } else { &rights };
+    sendmsg(
+        fd.as_raw_fd(),
+        &[IoSlice::new(message)],
+        cmsgs,
+        MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
+        Some(&addr),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::bind;
+    use nix::unistd::{pipe, read};
+    use tempfile::tempdir;
+
+    #[test]
+    fn only_relayed_variables_are_kept() {
+        assert_eq!(
+            filter_message(b"READY=1\nMAINPID=1\nSTATUS=Serving\nFDSTORE=1\nERRNO=2", 0),
+            Some(b"READY=1\nSTATUS=Serving\nERRNO=2\n".to_vec())
+        );
+        assert_eq!(filter_message(b"WATCHDOG=1\n", 0), None);
+        assert_eq!(filter_message(b"READYX=1\nnot an assignment\n", 0), None);
+    }
+
+    #[test]
+    fn main_pid_is_rewritten_to_host_pid() {
+        assert_eq!(
+            filter_message(b"READY=1\nMAINPID=1\n", 4242),
+            Some(b"READY=1\nMAINPID=4242\n".to_vec())
+        );
+        assert_eq!(filter_message(b"MAINPID=7", 0), None);
+    }
+
+    #[test]
+    fn barrier_fd_is_passed_to_host() -> nix::Result<()> {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("notify.sock");
+        let host = socket(
+            AddressFamily::Unix,
+            SockType::Datagram,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        bind(host.as_raw_fd(), &UnixAddr::new(&path)?)?;
+
+        let (barrier_read, barrier_write) = pipe()?;
+        assert!(is_barrier(b"BARRIER=1\n"));
+        relay(&path, b"BARRIER=1\n", &[barrier_write])?;
+
+        let mut buf = [0u8; 64];
+        let (n, fds) = recv_message(host.as_raw_fd(), &mut buf)?;
+        assert_eq!(&buf[..n], b"BARRIER=1\n");
+        assert_eq!(fds.len(), 1);
+
+        // The barrier completes once the host closes the fd.
+        drop(fds);
+        assert_eq!(read(&barrier_read, &mut buf)?, 0);
+        Ok(())
+    }
+}
@@ -1,31 +1,63 @@
 use crate::error::{ConmonError, ConmonResult};
-use crate::exit::set_subreaper;
+use crate::exit::{set_subreaper, snapshot_open_fds};
+use crate::runtime::limits::InheritedLimits;
 use crate::runtime::stdio::read_pipe;
+use crate::sync_socket::SyncSocket;
 
-use log::{info, warn};
+use log::{debug, info, warn};
+use nix::errno::Errno;
 use nix::fcntl::{OFlag, open};
+use nix::libc;
 use nix::sys::signal::{SigSet, SigmaskHow, Signal, kill, pthread_sigmask};
 use nix::sys::stat::Mode;
-use nix::sys::wait::{WaitStatus, waitpid};
-use nix::unistd::{
-    ForkResult, Pid, dup2_stderr, dup2_stdin, dup2_stdout, fork, getpid, getppid, setsid,
-};
+use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
+use nix::unistd::{ForkResult, Pid, dup2_stderr, dup2_stdin, dup2_stdout, fork, getpid, setsid};
 
 use std::env;
-use std::io::{Error, Result as IoResult};
-use std::os::fd::{AsFd, OwnedFd};
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
-// for pre_exec
 use std::process::{Command, Stdio, exit};
+use std::str::FromStr;
+
+/// How conmon detaches from its caller before spawning the runtime.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForkMode {
+    /// Fork twice, the caller's child exits immediately. Conmon runs in a new
+    /// session with its stdio redirected to /dev/null.
+    #[default]
+    Daemon,
+    /// Do not fork, so the caller can wait for conmon (`--sync`). Conmon still
+    /// starts a new session and redirects its stdio to /dev/null.
+    Child,
+    /// Do not fork, do not start a new session and keep the stdio, so conmon
+    /// can be supervised directly, for example by a systemd unit.
+    None,
+}
+
+impl FromStr for ForkMode {
+    type Err = ConmonError;
 
-/// Convert a nix::Error into std::io::Error (for use inside pre_exec closure).
-fn io_err(e: nix::Error) -> Error {
-    Error::from_raw_os_error(e as i32)
+    fn from_str(s: &str) -> ConmonResult<Self> {
+        match s {
+            "daemon" => Ok(ForkMode::Daemon),
+            "child" => Ok(ForkMode::Child),
+            "none" => Ok(ForkMode::None),
+            _ => Err(ConmonError::new(
+                format!("Invalid fork mode '{s}', expected daemon, child or none"),
+                1,
+            )),
+        }
+    }
 }
 
 /// Block signals in the parent before we spawn.
-/// Returns the old mask, which the child will restore in `pre_exec`.
+/// Returns the old mask. The child does not need it, std starts the runtime
+/// with an empty signal mask.
 fn block_signals() -> ConmonResult<SigSet> {
     let mut mask = SigSet::empty();
     mask.add(Signal::SIGTERM);
@@ -53,26 +85,310 @@ fn redirect_self_to_devnull() -> ConmonResult<()> {
     Ok(())
 }
 
-/// Helper function to replace LISTEN_PID with the proper afterk-fork PID.
-fn update_listen_pid(replace_listen_pid: bool) {
-    // If LISTEN_PID env is set, we may need to update it to the new child process
-    if let Ok(listenpid) = env::var("LISTEN_PID") {
-        // Try to parse LISTEN_PID as an integer
-        let lpid: i32 = match listenpid.parse() {
-            Ok(v) if v > 0 => v,
-            _ => {
-                warn!("Invalid LISTEN_PID {}", listenpid);
-                return;
+/// Detaches conmon from its caller as selected by `fork_mode`, before the
+/// runtime is spawned.
+///
+/// In the `daemon` mode conmon forks and the caller's child exits right away,
+/// after writing the PID of the forked conmon to `pidfile`. With `--sync`
+/// (the `child` mode) conmon stays the process the caller waits for and
+/// writes its own PID to `pidfile`.
+///
+/// # Arguments
+///
+/// * `fork_mode` - How to detach from the caller.
+/// * `logging_is_passthrough` - True if the runtime inherits conmon's stdio,
+///   so it must not be redirected to /dev/null yet.
+/// * `pidfile` - The `--conmon-pidfile`, written by the exiting parent.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the fork, the new session or the stdio redirection
+///   fails.
+pub fn detach(
+    fork_mode: ForkMode,
+    logging_is_passthrough: bool,
+    pidfile: &Option<PathBuf>,
+) -> ConmonResult<()> {
+    if !logging_is_passthrough && fork_mode != ForkMode::None {
+        redirect_self_to_devnull()?;
+    }
+
+    if fork_mode == ForkMode::Daemon {
+        match unsafe { fork() } {
+            // In the parent: exit immediately so the child won't be a process group leader.
+            Ok(ForkResult::Parent { child }) => {
+                if let Some(pidfile) = &pidfile {
+                    std::fs::write(pidfile, child.to_string())?;
+                }
+                exit(0);
             }
+            // In the child: continue execution.
+            Ok(ForkResult::Child) => {}
+            Err(e) => {
+                return Err(ConmonError::new(format!("Failed to fork: {e}"), 1));
+            }
+        }
+    }
+
+    // Detach from controlling terminal: new session. Without the fork we
+    // may be the process group leader already, so setsid() fails with EPERM.
+    // In the "none" mode we keep the terminal, so we can follow its window size.
+    match fork_mode {
+        ForkMode::Daemon => {
+            setsid()?;
+        }
+        ForkMode::Child => match setsid() {
+            Ok(_) | Err(nix::Error::EPERM) => {}
+            Err(e) => return Err(e.into()),
+        },
+        ForkMode::None => {}
+    }
+
+    // Without the fork, the caller's child is conmon itself.
+    if fork_mode != ForkMode::Daemon
+        && let Some(pidfile) = &pidfile
+    {
+        std::fs::write(pidfile, getpid().to_string())?;
+    }
+    Ok(())
+}
+
+/// Returns the number of socket activation fds in `$LISTEN_FDS`.
+fn listen_fds() -> RawFd {
+    env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Returns the first fd not passed to the runtime on purpose.
+///
+/// The fds below it are stdio, the socket activation fds (`listen_fds`) and
+/// the fds the runtime passes to the container (`--preserve-fds N` in
+/// `args`). The runtime hands them in this order, starting at fd 3.
+fn first_fd_to_close(listen_fds: RawFd, args: &[String]) -> RawFd {
+    let mut preserve_fds: RawFd = 0;
+    for (i, arg) in args.iter().enumerate() {
+        let n = match arg.strip_prefix("--preserve-fds") {
+            Some("") => args.get(i + 1).map(String::as_str),
+            Some(v) => v.strip_prefix('='),
+            None => None,
         };
+        if let Some(n) = n.and_then(|n| n.parse().ok()) {
+            preserve_fds = n;
+        }
+    }
+    3 + listen_fds.max(0) + preserve_fds.max(0)
+}
+
+/// The fds inherited from the engine, marked close-on-exec while the runtime
+/// is spawned, so they do not leak into the runtime and the container.
+///
+/// Only the fds without FD_CLOEXEC are marked, and dropping it clears the
+/// flag on them again, so conmon's own fds are left as they were.
+struct HiddenFds(Vec<RawFd>);
+
+impl HiddenFds {
+    /// Marks the fds in `open_fds` from `first` up close-on-exec.
+    fn hide(first: RawFd, open_fds: &[RawFd]) -> Self {
+        let mut hidden = Vec::new();
+        for &fd in open_fds.iter().filter(|fd| **fd >= first) {
+            // Best-effort: the fd may be closed already.
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+            if flags < 0 || flags & libc::FD_CLOEXEC != 0 {
+                continue;
+            }
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == 0 {
+                hidden.push(fd);
+            }
+        }
+        Self(hidden)
+    }
+}
+
+impl Drop for HiddenFds {
+    fn drop(&mut self) {
+        for &fd in &self.0 {
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+            if flags >= 0 {
+                let _ = unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) };
+            }
+        }
+    }
+}
+
+/// Returns true if LISTEN_PID has to be replaced with the runtime PID: when
+/// `--replace-listen-pid` is set or LISTEN_PID is conmon's PID.
+fn listen_pid_needs_update(replace_listen_pid: bool) -> bool {
+    let Ok(listenpid) = env::var("LISTEN_PID") else {
+        return false;
+    };
+    match listenpid.parse::<i32>() {
+        Ok(lpid) if lpid > 0 => replace_listen_pid || lpid == getpid().as_raw(),
+        _ => {
+            warn!("Invalid LISTEN_PID {}", listenpid);
+            false
+        }
+    }
+}
+
+/// Returns the path of the `program` executable, searched in PATH unless it
+/// contains a slash, as `execvp` does.
+fn find_program(program: &str) -> ConmonResult<PathBuf> {
+    if program.contains('/') {
+        return Ok(PathBuf::from(program));
+    }
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|p| {
+            p.metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+        .ok_or_else(|| ConmonError::new(format!("Failed to spawn: {program} not found"), 1))
+}
+
+const LISTEN_PID_PREFIX: &[u8] = b"LISTEN_PID=";
+
+/// The runtime exec with LISTEN_PID replaced by the runtime PID.
+///
+/// The program path, argv and environment are prepared before the fork, so
+/// the child runs only async-signal-safe code: it writes its PID into the
+/// preallocated LISTEN_PID entry and calls `execve`.
+struct ListenPidExec {
+    program: CString,
+    _argv: Vec<CString>,
+    _env: Vec<CString>,
+    listen_pid: Vec<u8>,
+    argv_ptrs: Vec<*const libc::c_char>,
+    envp_ptrs: Vec<*const libc::c_char>,
+}
+
+// The pointers refer to the heap buffers owned by the struct itself.
+unsafe impl Send for ListenPidExec {}
+unsafe impl Sync for ListenPidExec {}
+
+impl ListenPidExec {
+    fn new(args: &[String]) -> ConmonResult<Self> {
+        let program = CString::new(find_program(&args[0])?.as_os_str().as_bytes())?;
+        let argv = args
+            .iter()
+            .map(|a| CString::new(a.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut env = Vec::new();
+        for (key, value) in env::vars_os().filter(|(k, _)| k != "LISTEN_PID") {
+            let mut entry = key.as_bytes().to_vec();
+            entry.push(b'=');
+            entry.extend_from_slice(value.as_bytes());
+            env.push(CString::new(entry)?);
+        }
+        // Room for any PID and the terminating NUL, filled in the child.
+        let mut listen_pid = LISTEN_PID_PREFIX.to_vec();
+        listen_pid.resize(LISTEN_PID_PREFIX.len() + 12, 0);
+
+        let mut argv_ptrs: Vec<_> = argv.iter().map(|a| a.as_ptr()).collect();
+        argv_ptrs.push(std::ptr::null());
+        let mut envp_ptrs: Vec<_> = env.iter().map(|e| e.as_ptr()).collect();
+        envp_ptrs.push(listen_pid.as_ptr() as *const libc::c_char);
+        envp_ptrs.push(std::ptr::null());
+
+        Ok(Self {
+            program,
+            _argv: argv,
+            _env: env,
+            listen_pid,
+            argv_ptrs,
+            envp_ptrs,
+        })
+    }
+
+    /// Executes the runtime in the forked child. Returns only on failure.
+    fn exec(&mut self) -> io::Error {
+        let mut pid = unsafe { libc::getpid() } as u32;
+        let mut digits = [0u8; 10];
+        let mut n = 0;
+        loop {
+            digits[n] = b'0' + (pid % 10) as u8;
+            pid /= 10;
+            n += 1;
+            if pid == 0 {
+                break;
+            }
+        }
+        let value = &mut self.listen_pid[LISTEN_PID_PREFIX.len()..];
+        for i in 0..n {
+            value[i] = digits[n - 1 - i];
+        }
+        value[n] = 0;
+
+        unsafe {
+            libc::execve(
+                self.program.as_ptr(),
+                self.argv_ptrs.as_ptr(),
+                self.envp_ptrs.as_ptr(),
+            )
+        };
+        io::Error::last_os_error()
+    }
+}
 
-        let parent_pid = getppid().as_raw();
+/// Opens a pidfd of the process `pid`.
+///
+/// # Returns
+///
+/// * The pidfd, or None if the kernel does not support pidfds or the
+///   process is already gone.
+pub fn open_pidfd(pid: i32) -> Option<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        debug!("Cannot open pidfd of the process {pid}: {}", Errno::last());
+        return None;
+    }
+    Some(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Returns true if the process with `pid` exists.
+pub fn process_alive(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    // Nix kill function does not support 0 signal, so we have to use libc one.
+    let rc = unsafe { nix::libc::kill(pid, 0) };
+    rc == 0 || nix::errno::Errno::last() == nix::errno::Errno::EPERM
+}
+
+/// Returns the start time of the process `pid` in clock ticks since boot.
+///
+/// Together with the PID, it identifies the process, so a PID reused by
+/// another process is not mistaken for it.
+pub fn process_start_time(pid: i32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name may contain spaces, the fields follow its last ')'.
+    // The start time is the 22nd field, the state being the 3rd one.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
 
-        // If we should replace it, or it matches the parent PID, set it to our PID
-        if replace_listen_pid || lpid == parent_pid {
-            let pid = getpid().as_raw();
-            let pidstr = pid.to_string();
-            unsafe { env::set_var("LISTEN_PID", &pidstr) };
+/// Channel the parent uses to tell conmon to execute the runtime.
+pub enum StartGate {
+    /// The `_OCI_STARTPIPE` pipe, any data written to it start the runtime.
+    Pipe(OwnedFd),
+    /// The `_OCI_SYNCSOCK` socket, the `start` message starts the runtime.
+    SyncSocket(SyncSocket),
+}
+
+impl StartGate {
+    /// Blocks until the parent allows to execute the runtime.
+    fn wait(self) -> ConmonResult<()> {
+        match self {
+            StartGate::Pipe(fd) => {
+                // It is OK to just once from the pipe here. The pipe is used as a sync
+                // mechanism. We do not care about the read data at all.
+                let mut buf = [0u8; 8192];
+                read_pipe(&fd, &mut buf)?;
+                Ok(())
+            }
+            StartGate::SyncSocket(mut sock) => sock.wait_for_start(),
         }
     }
 }
@@ -83,16 +399,41 @@ fn update_listen_pid(replace_listen_pid: bool) {
 #[derive(Default)]
 pub struct RuntimeProcess {
     pid: i32,
+
+    /// The pidfd of the runtime process, readable once it exits.
+    pidfd: Option<OwnedFd>,
 }
 
 impl RuntimeProcess {
     pub fn new() -> Self {
-        Self { pid: -1 }
+        Self {
+            pid: -1,
+            pidfd: None,
+        }
+    }
+
+    /// Creates RuntimeProcess for an already running runtime `pid`.
+    pub fn with_pid(pid: i32) -> Self {
+        Self {
+            pid,
+            pidfd: open_pidfd(pid),
+        }
+    }
+
+    /// Creates RuntimeProcess for an already running runtime `pid` with its
+    /// `pidfd` opened before, if any.
+    pub fn with_pidfd(pid: i32, pidfd: Option<OwnedFd>) -> Self {
+        Self { pid, pidfd }
     }
 
     /// Spawn the runtime binary defined by `args`.
     /// The stdio is redirected to `workerfd_stdin`, `workerfd_stdout` and `workerfd_stderr`.
     /// Returns the PID.
+    ///
+    /// The child is prepared by the parent, so the runtime is started with
+    /// `posix_spawn` and no code of conmon runs between fork and exec. Only
+    /// replacing LISTEN_PID needs the child PID, and so a `pre_exec` hook,
+    /// which runs async-signal-safe code only (see `ListenPidExec`).
     #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         &mut self,
@@ -100,11 +441,9 @@ impl RuntimeProcess {
         workerfd_stdin: Stdio,
         workerfd_stdout: Stdio,
         workerfd_stderr: Stdio,
-        mut start_pipe_fd: Option<OwnedFd>,
+        start_gate: Option<StartGate>,
         replace_listen_pid: bool,
         logging_is_passthrough: bool,
-        double_fork: bool,
-        pidfile: &Option<PathBuf>,
     ) -> ConmonResult<i32> {
         if args.is_empty() {
             return Err(ConmonError::new(
@@ -113,60 +452,24 @@ impl RuntimeProcess {
             ));
         }
 
-        if !logging_is_passthrough {
-            redirect_self_to_devnull()?;
-        }
-
-        if double_fork {
-            unsafe {
-                match fork() {
-                    // In the parent: exit immediately so the child won't be a process group leader.
-                    Ok(ForkResult::Parent { child }) => {
-                        self.pid = child.as_raw();
-                        // Store the RuntimeProcess::pid in the `conmon_pidfile`.
-                        if let Some(pidfile) = &pidfile {
-                            std::fs::write(pidfile, self.pid.to_string())?;
-                        }
-                        exit(0);
-                    }
-                    // In the child: continue execution.
-                    Ok(ForkResult::Child) => {}
-                    Err(e) => {
-                        return Err(ConmonError::new(format!("Failed to fork: {e}"), 1));
-                    }
-                }
-            }
-        }
-
-        // Detach from controlling terminal: new session.
-        setsid()?;
-
         // Enable subreaper, so we can wait for container process exit code.
         set_subreaper(true)?;
 
         // Wait with the `spawn()` until parent tells us to start the runtime
-        // using the start_pipe_fd (if defined).
-        if let Some(fd) = start_pipe_fd.take() {
-            // It is OK to just once from the pipe here. The pipe is used as a sync
-            // mechanism. We do not care about the read data at all.
-            let mut buf = [0u8; 8192];
-            read_pipe(&fd, &mut buf)?;
+        // using the start_gate (if defined).
+        if let Some(gate) = start_gate {
+            gate.wait()?;
         }
 
-        // Block signals in the parent so none are delivered between fork and exec.
-        let oldmask = block_signals()?;
-
-        // Child setup performed between fork and exec.
-        fn child_setup(oldmask: &SigSet, replace_listen_pid: bool) -> IoResult<()> {
-            // Restore (unblock) the parent's original signal mask.
-            pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(oldmask), None).map_err(io_err)?;
+        // Do not leak the inherited fds into the runtime.
+        let _hidden = HiddenFds::hide(
+            first_fd_to_close(listen_fds(), args),
+            snapshot_open_fds().fds(),
+        );
 
-            // Set conservative umask.
-            nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(0o022));
-
-            update_listen_pid(replace_listen_pid);
-            Ok(())
-        }
+        // Block signals in the parent so none are delivered between fork and
+        // exec. The child starts with an empty signal mask.
+        block_signals()?;
 
         info!("Executing {:?}", args);
 
@@ -180,19 +483,29 @@ impl RuntimeProcess {
                 .stdout(workerfd_stdout)
                 .stderr(workerfd_stderr);
         }
-        unsafe {
-            cmd.pre_exec(move || child_setup(&oldmask, replace_listen_pid));
+        if listen_pid_needs_update(replace_listen_pid) {
+            let mut exec = ListenPidExec::new(args)?;
+            unsafe {
+                cmd.pre_exec(move || Err(exec.exec()));
+            }
         }
 
-        let child = cmd
-            .spawn()
-            .map_err(|e| ConmonError::new(format!("Failed to spawn: {e}"), 1))?;
+        // Set conservative umask for the runtime.
+        let umask = nix::sys::stat::umask(Mode::from_bits_truncate(0o022));
+        let inherited = InheritedLimits::restore();
+        let child = cmd.spawn();
+        drop(inherited);
+        nix::sys::stat::umask(umask);
+        let child = child.map_err(|e| ConmonError::new(format!("Failed to spawn: {e}"), 1))?;
 
         if logging_is_passthrough {
             redirect_self_to_devnull()?;
         }
 
         self.pid = child.id() as i32;
+        // The unreaped child cannot be replaced by another process with the
+        // same PID, so the pidfd refers to the runtime.
+        self.pidfd = open_pidfd(self.pid);
         info!("Conmon PID: {}", self.pid);
         Ok(self.pid)
     }
@@ -202,25 +515,33 @@ impl RuntimeProcess {
         self.pid
     }
 
-    /// Block until the runtime process exits. Returns the exit code.
-    pub fn wait(&self) -> ConmonResult<i32> {
+    /// Returns the pidfd of the runtime process, if the kernel supports them.
+    pub fn pidfd(&self) -> Option<BorrowedFd<'_>> {
+        self.pidfd.as_ref().map(|fd| fd.as_fd())
+    }
+
+    /// Reaps the runtime process if it has exited, without blocking. The
+    /// caller learns about the exit from the pidfd or SIGCHLD.
+    /// Returns the exit code or None if the process still runs.
+    pub fn try_wait(&self) -> ConmonResult<Option<i32>> {
         let pid = Pid::from_raw(self.pid);
 
         loop {
-            match waitpid(pid, None) {
-                Ok(WaitStatus::Exited(_, code)) => return Ok(code),
+            match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(_, code)) => return Ok(Some(code)),
                 Ok(WaitStatus::Signaled(_, sig, _core_dumped)) => {
                     return Err(ConmonError::new(
                         format!("Runtime process exited due to signal: {sig:?}"),
                         1,
                     ));
                 }
-                // These shouldn’t occur with no flags, but if they do, keep waiting.
-                Ok(WaitStatus::StillAlive)
-                | Ok(WaitStatus::Stopped(_, _))
+                // The process still runs.
+                Ok(WaitStatus::StillAlive) => return Ok(None),
+                // These shouldn’t occur without WUNTRACED, the process still runs.
+                Ok(WaitStatus::Stopped(_, _))
                 | Ok(WaitStatus::Continued(_))
                 | Ok(WaitStatus::PtraceEvent(_, _, _))
-                | Ok(WaitStatus::PtraceSyscall(_)) => continue,
+                | Ok(WaitStatus::PtraceSyscall(_)) => return Ok(None),
 
                 // Interrupted - continue.
                 Err(nix::Error::EINTR) => continue,
@@ -237,3 +558,128 @@ impl RuntimeProcess {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+    use std::os::fd::AsRawFd;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    /// Blocks until `process` exits. Returns the exit code.
+    fn wait(process: &RuntimeProcess) -> ConmonResult<i32> {
+        loop {
+            if let Some(code) = process.try_wait()? {
+                return Ok(code);
+            }
+            if let Some(pidfd) = process.pidfd() {
+                poll(
+                    &mut [PollFd::new(pidfd, PollFlags::POLLIN)],
+                    PollTimeout::NONE,
+                )?;
+            } else {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    #[test]
+    fn detach_without_fork_writes_own_pid() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let pidfile = Some(tmp.path().join("conmon.pid"));
+        detach(ForkMode::None, false, &pidfile)?;
+        let pid = std::fs::read_to_string(pidfile.as_ref().unwrap())?;
+        assert_eq!(pid, getpid().to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn preserved_fds_are_not_closed() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(first_fd_to_close(0, &args(&["runc", "exec"])), 3);
+        assert_eq!(
+            first_fd_to_close(0, &args(&["runc", "exec", "--preserve-fds", "2"])),
+            5
+        );
+        assert_eq!(
+            first_fd_to_close(0, &args(&["runc", "exec", "--preserve-fds=1"])),
+            4
+        );
+        assert_eq!(first_fd_to_close(2, &args(&["runc", "exec"])), 5);
+        // The preserved fds follow the socket activation fds.
+        assert_eq!(
+            first_fd_to_close(2, &args(&["runc", "exec", "--preserve-fds", "3"])),
+            8
+        );
+    }
+
+    #[test]
+    fn inherited_fds_do_not_leak_into_runtime() -> ConmonResult<()> {
+        // dup() does not set FD_CLOEXEC, like the fds passed by the engine.
+        let leaked = nix::unistd::dup(std::io::stderr())?;
+        let check = format!("test ! -e /proc/self/fd/{}", leaked.as_raw_fd());
+        let mut process = RuntimeProcess::new();
+        process.spawn(
+            &["/bin/sh".to_string(), "-c".to_string(), check],
+            Stdio::null(),
+            Stdio::null(),
+            Stdio::null(),
+            None,
+            false,
+            false,
+        )?;
+        assert_eq!(wait(&process)?, 0);
+
+        // The fd is hidden from the runtime only, conmon can still pass it on.
+        let flags = nix::fcntl::fcntl(&leaked, nix::fcntl::FcntlArg::F_GETFD)?;
+        assert_eq!(flags & libc::FD_CLOEXEC, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn listen_pid_is_the_runtime_pid() -> ConmonResult<()> {
+        let args = ["sh", "-c", r#"test "$LISTEN_PID" = "$$""#].map(String::from);
+        let mut exec = ListenPidExec::new(&args)?;
+        let mut cmd = Command::new(&args[0]);
+        unsafe {
+            cmd.pre_exec(move || Err(exec.exec()));
+        }
+        assert!(cmd.status()?.success());
+        Ok(())
+    }
+
+    #[test]
+    fn try_wait_does_not_block() -> ConmonResult<()> {
+        let mut process = RuntimeProcess::new();
+        process.spawn(
+            &["sleep".to_string(), "0.2".to_string()],
+            Stdio::null(),
+            Stdio::null(),
+            Stdio::null(),
+            None,
+            false,
+            false,
+        )?;
+        assert_eq!(process.try_wait()?, None);
+        assert_eq!(wait(&process)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn spawn_does_not_fork_conmon() -> ConmonResult<()> {
+        let mut process = RuntimeProcess::new();
+        let pid = process.spawn(
+            &["/bin/true".to_string()],
+            Stdio::null(),
+            Stdio::null(),
+            Stdio::null(),
+            None,
+            false,
+            false,
+        )?;
+        assert_eq!(process.pid(), pid);
+        assert_eq!(wait(&process)?, 0);
+        Ok(())
+    }
+}
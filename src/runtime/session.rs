@@ -1,14 +1,21 @@
-use std::os::fd::{AsFd, AsRawFd};
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{fs, os::fd::OwnedFd, path::PathBuf, process::Stdio};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    env, fs,
+    os::fd::OwnedFd,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use nix::sys::signal::{SigSet, SigmaskHow, Signal, kill, pthread_sigmask};
 use nix::sys::signalfd::{SfdFlags, SignalFd};
-use nix::unistd::{Pid, getpgid};
+use nix::unistd::{Pid, getpgid, getuid};
 use nix::{
     errno::Errno,
+    fcntl::{FcntlArg, OFlag, fcntl, open},
     libc,
+    poll::{PollFd, PollFlags, PollTimeout, poll},
     sys::{
         socket::{SockFlag, SockType},
         stat::Mode,
@@ -16,22 +23,166 @@ use nix::{
     },
 };
 
-use crate::exit::{OpenFilesSnapshot, close_all_except_stdio};
-use crate::runtime::cgroup::setup_oom_handling;
+use crate::exit::{
+    ExitSignal, OpenFilesSnapshot, close_all_except_stdio, set_container_started, set_exit_signal,
+    set_exit_status_unknown, set_timed_out,
+};
+use crate::runtime::cgroup::{oom_detected, setup_oom_handling};
+use crate::runtime::limits::apply_self_limits;
+use crate::runtime::state::SessionState;
+use crate::runtime::upgrade::Handover;
 use crate::{
-    cli::CommonCfg,
+    attach::{self, AttachOutput},
+    cli::{CommonCfg, ExecStdin},
     error::{ConmonError, ConmonResult},
-    logging::plugin::LogPlugin,
-    parent_pipe::{get_pipe_fd_from_env, write_or_close_sync_fd},
+    heartbeat,
+    logging::plugin::{
+        ContainerExit, ContainerMetadata, LogPlugin, LogStream, request_reload, request_reopen,
+    },
+    parent_pipe::{
+        get_pipe_fd_from_env, write_exit_fd, write_or_close_sync_fd,
+        write_or_close_sync_fd_with_signal,
+    },
+    recording,
     runtime::{
-        args::{RuntimeArgsGenerator, generate_runtime_args},
-        ctl::{setup_console_fifo, setup_terminal_control_fifo},
-        process::RuntimeProcess,
-        stdio::{create_pipe, handle_stdio, read_pipe, receive_console_fd},
+        args::{RuntimeArgsGenerator, generate_runtime_args, last_runtime_error, runtime_log_path},
+        command::{delete_container_by_id, run_runtime, runtime_state_pid},
+        control,
+        ctl::{resize_terminal, setup_console_fifo, setup_terminal_control_fifo, terminal_size},
+        notify,
+        process::{RuntimeProcess, StartGate, detach, open_pidfd},
+        stdio::{StdioRemains, create_pipe, handle_stdio, read_pipe, receive_console_fd},
+        version::check_runtime_version,
     },
-    unix_socket::{RemoteSocket, SocketType, UnixSocket},
+    sync_socket::{SYNC_SOCKET_ENV, SyncMessage, SyncSocket},
+    unix_socket::{RemoteSocket, Socket, SocketType, UnixSocket},
 };
 
+/// Reads and parses the PID stored in the `pidfile`.
+fn read_pidfile(pidfile: &Path) -> ConmonResult<i32> {
+    let contents = fs::read_to_string(pidfile).map_err(|e| {
+        ConmonError::new(
+            format!("Failed to read PID file {}: {e}", pidfile.display()),
+            1,
+        )
+    })?;
+    let pid = contents.trim().parse::<i32>().map_err(|e| {
+        ConmonError::new(
+            format!(
+                "Invalid PID contents in {}: {} ({})",
+                pidfile.display(),
+                contents.trim(),
+                e
+            ),
+            1,
+        )
+    })?;
+    if pid <= 0 {
+        return Err(ConmonError::new(
+            format!("Invalid PID {pid} in {}", pidfile.display()),
+            1,
+        ));
+    }
+    Ok(pid)
+}
+
+/// Returns the attach output state configured by the attach options.
+fn attach_output(common: &CommonCfg) -> AttachOutput {
+    AttachOutput::new(
+        common.attach_replay_size,
+        common.attach_buffer_size,
+        common.attach_slow_policy,
+    )
+}
+
+/// Enables the audit file of the attach connections if `--attach-audit` is set.
+fn setup_attach_audit(common: &CommonCfg) {
+    if let (true, Some(persist_dir)) = (common.attach_audit, &common.persist_dir) {
+        attach::enable_audit(persist_dir.join("attach-audit.log"));
+    }
+}
+
+/// Returns the UIDs allowed to connect to the attach socket: conmon's own UID,
+/// root, the `--attach-socket-owner` and the `--attach-allowed-uid` ones.
+fn attach_allowed_uids(common: &CommonCfg) -> Vec<u32> {
+    let mut uids = vec![getuid().as_raw(), 0];
+    uids.extend(common.attach_socket_owner.map(|(uid, _)| uid));
+    uids.extend(&common.attach_allowed_uids);
+    uids
+}
+
+/// Opens the controlling terminal of conmon, if there is one.
+fn open_host_tty() -> Option<OwnedFd> {
+    match open(
+        "/dev/tty",
+        OFlag::O_RDONLY | OFlag::O_NOCTTY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(fd) => Some(fd),
+        Err(e) => {
+            warn!("Not following the terminal window size, cannot open /dev/tty: {e}");
+            None
+        }
+    }
+}
+
+/// Returns true if the process referred to by `pidfd` has exited.
+fn pidfd_exited(pidfd: &OwnedFd) -> bool {
+    let mut fds = [PollFd::new(pidfd.as_fd(), PollFlags::POLLIN)];
+    matches!(poll(&mut fds, PollTimeout::ZERO), Ok(n) if n > 0)
+}
+
+/// Exit status of a process reaped through its pidfd.
+#[derive(Debug, PartialEq)]
+enum PidfdExit {
+    /// The process exited with the exit code.
+    Exited(i32),
+    /// The process was killed by the signal number, maybe dumping core.
+    /// Realtime signals are kept as numbers, [`Signal`] does not cover them.
+    Signaled(i32, bool),
+}
+
+/// Reaps the exited process referred to by `pidfd` with `waitid(P_PIDFD)`.
+///
+/// # Returns
+///
+/// * The exit status, or None if the process is not a child of conmon, as
+///   the parent or the subreaper, or has not exited.
+fn pidfd_wait(pidfd: &OwnedFd) -> Option<PidfdExit> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let rc = unsafe {
+        libc::waitid(
+            libc::P_PIDFD,
+            pidfd.as_raw_fd() as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG,
+        )
+    };
+    let pid = unsafe { info.si_pid() };
+    if rc != 0 || pid == 0 {
+        return None;
+    }
+    let status = unsafe { info.si_status() };
+    match info.si_code {
+        libc::CLD_EXITED => Some(PidfdExit::Exited(status)),
+        libc::CLD_KILLED => Some(PidfdExit::Signaled(status, false)),
+        libc::CLD_DUMPED => Some(PidfdExit::Signaled(status, true)),
+        _ => None,
+    }
+}
+
+/// Deadline of the runtime create, exec or restore set by `--runtime-timeout`.
+///
+/// It keeps what `runtime delete` needs to remove the half-created container
+/// when the runtime is killed.
+struct RuntimeDeadline {
+    at: Instant,
+    secs: u64,
+    runtime: PathBuf,
+    runtime_args: Vec<String>,
+    cid: String,
+}
+
 /// Represents Runtime session.
 /// Handles spawning of runtime process, reading its stdio, writing its
 /// pid and error code as well as the event loop to forward its log messages
@@ -46,6 +197,12 @@ pub struct RuntimeSession {
     /// or error message in case the runtime cannot be executed.
     sync_pipe_fd: Option<OwnedFd>,
 
+    /// Structured sync channel replacing the sync, attach and start pipes.
+    sync_socket: Option<SyncSocket>,
+
+    /// The eventfd from `_OCI_EXITFD` signalled as soon as the container exits.
+    exit_fd: Option<OwnedFd>,
+
     /// Represents container's stdin.
     /// Any data written here are sent to container's input.
     workerfd_stdin: Option<OwnedFd>,
@@ -61,6 +218,15 @@ pub struct RuntimeSession {
     /// Exit code of `process`.
     exit_code: i32,
 
+    /// The time the runtime must finish by, if `--runtime-timeout` is used.
+    runtime_deadline: Option<RuntimeDeadline>,
+
+    /// True if the runtime has been killed for not finishing in time.
+    runtime_timed_out: bool,
+
+    /// The JSON log of the runtime, its last error is reported if it fails.
+    runtime_log: Option<PathBuf>,
+
     /// UnixSocket for `attach`.
     /// The process executing conmon uses it to attach to container. It opens new
     /// connection to socket and any data read from it are forwarded to
@@ -69,6 +235,10 @@ pub struct RuntimeSession {
     /// the process executing conmon can handle them.
     attach_socket: Option<UnixSocket>,
 
+    /// The recent container output replayed to the new `attach` connections
+    /// and the limits of the output queued for them.
+    attach_output: AttachOutput,
+
     /// UnixSocket for runtime `--console-socket`. The runtime connects to it
     /// and sends a `terminal_socket` of terminal to that connection. This is used if
     /// `--terminal` is used.
@@ -91,15 +261,32 @@ pub struct RuntimeSession {
     /// The PID of container created by the runtime.
     container_pid: i32,
 
+    /// The pidfd of the container process, readable once it exits, so its
+    /// exit wakes up the event loop even if it is not a direct child.
+    container_pidfd: Option<OwnedFd>,
+
     /// The exit status of container.
     container_status: i32,
 
+    /// The signal which killed the container.
+    container_signal: Option<ExitSignal>,
+
+    /// Metadata passed to `LogPlugin::on_container_start` once the event-loop
+    /// starts, None after a live upgrade.
+    log_start: Option<ContainerMetadata>,
+
     // Time (unix timestamp) after which the session should terminate
     timeout: u64,
 
     // True if timeout occured.
     timed_out: bool,
 
+    /// Signal sent when the `timeout` expires, SIGKILL if None.
+    timeout_signal: Option<Signal>,
+
+    /// Seconds to wait after `timeout_signal` before sending SIGKILL.
+    timeout_grace: u64,
+
     /// RemoteSocket for OOM handling.
     oom_socket: Option<RemoteSocket>,
 
@@ -114,6 +301,19 @@ pub struct RuntimeSession {
 
     // Open file descriptor snapshot.
     open_files: OpenFilesSnapshot,
+
+    /// Directory with the crash-recovery state file, if it has been written.
+    state_dir: Option<PathBuf>,
+
+    /// True if SIGUSR2 asked us to re-exec the conmon binary.
+    upgrade_requested: bool,
+
+    /// The calling terminal in `--foreground` mode. Its window size is
+    /// propagated to the container terminal on SIGWINCH.
+    host_tty: Option<OwnedFd>,
+
+    /// Raw fd of the container terminal while the event-loop owns it.
+    container_tty_fd: Option<i32>,
 }
 
 impl RuntimeSession {
@@ -122,6 +322,7 @@ impl RuntimeSession {
             process: RuntimeProcess::new(),
             exit_code: -1,
             container_pid: -1,
+            container_pidfd: None,
             container_status: -1,
             timed_out: false,
             container_started: false,
@@ -157,6 +358,8 @@ impl RuntimeSession {
     /// # Arguments
     ///
     /// * `common` - The Conmon common configuration.
+    /// * `query_runtime` - When `true`, the PID reported by `runtime state` is
+    ///   used if the PID file cannot be read.
     ///
     /// # Returns
     ///
@@ -164,21 +367,24 @@ impl RuntimeSession {
     ///
     /// # Errors
     ///
-    /// * [`ConmonError`] if the PID file does not exit or cannot be parsed.
-    fn read_container_pid(&self, common: &CommonCfg) -> ConmonResult<i32> {
-        let contents = fs::read_to_string(common.container_pidfile.as_path())?;
-        let pid = contents.trim().parse::<i32>().map_err(|e| {
-            ConmonError::new(
-                format!(
-                    "Invalid PID contents in {}: {} ({})",
-                    common.container_pidfile.display(),
-                    contents.trim(),
-                    e
-                ),
-                1,
-            )
-        })?;
-        Ok(pid)
+    /// * [`ConmonError`] if the PID file does not exit or cannot be parsed
+    ///   and the runtime does not report the PID either.
+    fn read_container_pid(&self, common: &CommonCfg, query_runtime: bool) -> ConmonResult<i32> {
+        let err = match read_pidfile(&common.container_pidfile) {
+            Ok(pid) => return Ok(pid),
+            Err(e) if query_runtime => e,
+            Err(e) => return Err(e),
+        };
+
+        // Some runtimes write the state before the pidfile, so ask the runtime.
+        warn!("{}, querying the runtime state", err.msg);
+        match runtime_state_pid(common) {
+            Ok(pid) => Ok(pid),
+            Err(e) => {
+                warn!("{}", e.msg);
+                Err(err)
+            }
+        }
     }
 
     /// Launches the "runtime" binary.
@@ -202,6 +408,20 @@ impl RuntimeSession {
         args_gen: &impl RuntimeArgsGenerator,
         attach: bool,
     ) -> ConmonResult<()> {
+        // Get the sync socket. When used, it carries all the messages exchanged with
+        // the parent, so the one-shot pipes must not be passed too.
+        self.sync_socket = SyncSocket::from_env()?;
+        if let Some(sock) = &self.sync_socket {
+            self.open_files.remove(sock.raw_fd());
+            let pipes = ["_OCI_SYNCPIPE", "_OCI_ATTACHPIPE", "_OCI_STARTPIPE"];
+            if let Some(pipe) = pipes.iter().find(|p| env::var_os(p).is_some()) {
+                return Err(ConmonError::new(
+                    format!("{SYNC_SOCKET_ENV} cannot be combined with {pipe}"),
+                    1,
+                ));
+            }
+        }
+
         // Get the sync_pipe FD. It is used by the Conmon caller to obtain the container_pid
         // or the runtime error message later.
         self.sync_pipe_fd = get_pipe_fd_from_env("_OCI_SYNCPIPE")?;
@@ -212,12 +432,19 @@ impl RuntimeSession {
             self.open_files.remove(fd.as_raw_fd());
         }
 
+        // Get the exit eventfd. It gives the parent a fast wakeup on the container
+        // exit, without waiting for the exit files.
+        self.exit_fd = get_pipe_fd_from_env("_OCI_EXITFD")?;
+        if let Some(fd) = &self.exit_fd {
+            self.open_files.remove(fd.as_raw_fd());
+        }
+
         // Get the attach pipe FD. We use it later to inform parent that attach
         // socket is ready.
         let mut attach_pipe_fd: Option<OwnedFd> = None;
         if attach {
             attach_pipe_fd = get_pipe_fd_from_env("_OCI_ATTACHPIPE")?;
-            if attach_pipe_fd.is_none() {
+            if attach_pipe_fd.is_none() && self.sync_socket.is_none() {
                 return Err(ConmonError::new(
                     "--attach specified but _OCI_ATTACHPIPE was not set",
                     1,
@@ -235,21 +462,38 @@ impl RuntimeSession {
         // sockets the control the terminal.
         if !common.logging_passthrough {
             // Create the `attach` socket which is used to send data to container's stdin.
-            let mut attach_socket = UnixSocket::new(
-                SocketType::Console,
-                common.full_attach,
-                common.bundle.clone(),
-                Some(common.socket_dir_path.clone()),
-                common.cuuid.clone(),
-            );
-            attach_socket.bind(
-                Some(PathBuf::from("attach")),
-                SockType::SeqPacket,
-                SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
-                Mode::from_bits_truncate(0o700),
-            )?;
-            attach_socket.listen()?;
-            self.attach_socket = Some(attach_socket);
+            // The VM-based runtimes expose the guest console over vsock instead.
+            if let Some((cid, port)) = common.attach_vsock {
+                self.attach_socket =
+                    Some(UnixSocket::listen_vsock(SocketType::Console, cid, port)?);
+            } else {
+                let mut attach_socket = UnixSocket::new(
+                    SocketType::Console,
+                    common.full_attach,
+                    common.bundle.clone(),
+                    Some(common.socket_dir_path.clone()),
+                    common.cuuid.clone(),
+                );
+                // Some clients cannot use SEQPACKET; the stream socket keeps the
+                // packet boundaries using the length prefix.
+                let sock_type = if common.attach_stream {
+                    SockType::Stream
+                } else {
+                    SockType::SeqPacket
+                };
+                attach_socket.bind(
+                    Some(PathBuf::from("attach")),
+                    sock_type,
+                    SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+                    Mode::from_bits_truncate(0o700),
+                )?;
+                attach_socket.set_access(common.attach_socket_owner, common.attach_socket_mode);
+                attach_socket.listen()?;
+                attach_socket.set_allowed_uids(attach_allowed_uids(common));
+                self.attach_socket = Some(attach_socket);
+            }
+            self.attach_output = attach_output(common);
+            setup_attach_audit(common);
 
             // Create `ctl` fifo - this is used to control Conmon using simple commands
             // sent to it.
@@ -262,6 +506,8 @@ impl RuntimeSession {
             // Inform the parent that the attach socket is ready.
             if let Some(fd) = attach_pipe_fd.take() {
                 write_or_close_sync_fd(fd, 0, None, common.api_version, true)?;
+            } else if let Some(sock) = self.sync_socket.as_mut().filter(|_| attach) {
+                sock.send(&SyncMessage::AttachReady)?;
             }
         }
 
@@ -288,6 +534,18 @@ impl RuntimeSession {
             }
             info!("exec with attach got start message from parent");
         }
+        let mut start_gate = start_pipe_fd.map(StartGate::Pipe);
+
+        // With the sync socket, the parent always sends the start message. With
+        // attach, it sends the second one once it is attached to the container.
+        if let Some(sock) = &mut self.sync_socket {
+            info!("Waiting for start message from parent");
+            sock.wait_for_start()?;
+            if attach {
+                start_gate = Some(StartGate::SyncSocket(sock.try_clone()?));
+            }
+            info!("Got start message from parent");
+        }
 
         // Create console socket if the --terminal option is used.
         // We later pass the path to the socket to runtime and it sends a fd
@@ -335,9 +593,24 @@ impl RuntimeSession {
             let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
             self.timeout = now.as_secs() + t as u64;
         }
+        self.timeout_signal = common.timeout_signal;
+        self.timeout_grace = common.timeout_grace;
+
+        // Start the liveness heartbeat if --heartbeat-file is used.
+        if let Some(path) = &common.heartbeat_file {
+            heartbeat::start_heartbeat(path, common.heartbeat_interval);
+        }
 
         // Generate the list of arguments for runtime.
         let runtime_args = generate_runtime_args(common, args_gen, self.console_socket.as_ref())?;
+        if let Some((path, owned)) = runtime_log_path(common) {
+            // Do not report the errors of the previous runtime execution.
+            if owned {
+                let _ = fs::remove_file(&path);
+            }
+            self.runtime_log = Some(path);
+        }
+        check_runtime_version(common, &runtime_args)?;
 
         // Generate the stdin and stdout.
         let mainfd_stdin_stdio: Stdio;
@@ -351,7 +624,9 @@ impl RuntimeSession {
             mainfd_stdout_stdio = Stdio::null();
         } else {
             // Create the pipe to handle stdin in case the --stdin is used.
-            if common.stdin {
+            if let Some(src) = &common.exec_stdin {
+                mainfd_stdin_stdio = self.open_exec_stdin(src)?;
+            } else if common.stdin {
                 let (fd_out, fd_in) = create_pipe()?;
                 info!("Created pipe for stdin: {:?} {:?}", fd_out, fd_in);
                 // We store the "in" part of the pipe to `self.workerfd_stdin`, so anything
@@ -385,31 +660,176 @@ impl RuntimeSession {
         );
         self.mainfd_stderr = Some(mainfd_stderr);
 
-        // Run the `runtime create` and store our PID after first fork to `conmon_pidfile`.
+        // Apply the resource limits to ourselves before the runtime creates the
+        // container, so a failure does not leave it behind. The runtime gets
+        // the original limits back when it is spawned.
+        apply_self_limits(common)?;
+
+        // Fork into the background unless the caller waits for us, the parent
+        // stores our PID to `conmon_pidfile`.
+        detach(
+            common.fork_mode,
+            common.logging_passthrough,
+            &common.conmon_pidfile,
+        )?;
+
+        // Run the `runtime create`.
         self.process.spawn(
             &runtime_args,
             mainfd_stdin_stdio,
             mainfd_stdout_stdio,
             Stdio::from(workerfd_stderr),
-            start_pipe_fd,
+            start_gate,
             common.replace_listen_pid,
             common.logging_passthrough,
-            !common.sync_flag,
-            &common.conmon_pidfile,
         )?;
 
-        // Setup the signal-fd for the signals we want to handle.
+        if let Some(secs) = common.runtime_timeout {
+            self.runtime_deadline = Some(RuntimeDeadline {
+                at: Instant::now() + Duration::from_secs(secs),
+                secs,
+                runtime: common.runtime.clone(),
+                runtime_args: common.runtime_args.clone(),
+                cid: common.cid.clone(),
+            });
+        }
+
+        if common.foreground && common.terminal {
+            self.host_tty = open_host_tty();
+        }
+        self.setup_signals(common)?;
+
+        Ok(())
+    }
+
+    /// Sets up the signal-fd for the signals we want to handle.
+    ///
+    /// SIGTERM, SIGQUIT and SIGINT are forwarded to the container, unless
+    /// `--no-forward-signals` is used; conmon then keeps their default
+    /// action.
+    ///
+    /// # Arguments
+    ///
+    /// * `common` - The Conmon common configuration.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] on any error.
+    fn setup_signals(&mut self, common: &CommonCfg) -> ConmonResult<()> {
         let mut mask = SigSet::empty();
-        mask.add(Signal::SIGTERM);
-        mask.add(Signal::SIGQUIT);
-        mask.add(Signal::SIGINT);
+        if !common.no_forward_signals {
+            mask.add(Signal::SIGTERM);
+            mask.add(Signal::SIGQUIT);
+            mask.add(Signal::SIGINT);
+        }
+        mask.add(Signal::SIGUSR1);
+        mask.add(Signal::SIGUSR2);
+        mask.add(Signal::SIGHUP);
+        // Reap the children as soon as they exit.
+        mask.add(Signal::SIGCHLD);
+        if self.host_tty.is_some() {
+            mask.add(Signal::SIGWINCH);
+        }
         pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&mask), None)?;
         let signals = SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC | SfdFlags::SFD_NONBLOCK)?;
         self.signals = Some(signals);
-
         Ok(())
     }
 
+    /// Resumes the session handed over by the previous conmon binary.
+    ///
+    /// When conmon is re-executed for live upgrade, the container is already
+    /// running and its stdio fds are inherited, so instead of `launch`, we
+    /// only restore the session from the handover and recreate the fifos,
+    /// OOM handling and signal-fd.
+    ///
+    /// # Arguments
+    ///
+    /// * `common` - The Conmon common configuration.
+    ///
+    /// # Returns
+    ///
+    /// * False if this conmon has not been re-executed for live upgrade.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] on any error.
+    pub fn resume(&mut self, common: &CommonCfg) -> ConmonResult<bool> {
+        let Some(handover) = Handover::from_env()? else {
+            return Ok(false);
+        };
+        info!("Resuming session after live upgrade: {:?}", handover);
+
+        // The handed over fds are owned by the session now, so they must not
+        // be closed as inherited fds on exit.
+        for fd in handover.fds() {
+            self.open_files.remove(fd);
+        }
+
+        let own = |fd: Option<i32>| fd.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+        // The pidfds are handed over, as opening them again by PID could
+        // refer to another process if the PID has been reused.
+        self.process =
+            RuntimeProcess::with_pidfd(handover.runtime_pid, own(handover.runtime_pidfd));
+        self.exit_code = handover.exit_code;
+        self.container_pid = handover.container_pid;
+        self.container_pidfd = own(handover.container_pidfd);
+        self.container_started = handover.container_started;
+        self.timeout = handover.timeout;
+        self.timeout_signal = common.timeout_signal;
+        self.timeout_grace = common.timeout_grace;
+        self.sync_pipe_fd = own(handover.sync_pipe_fd);
+        self.exit_fd = own(handover.exit_fd);
+        self.sync_socket = own(handover.sync_socket_fd).map(SyncSocket::new);
+        self.workerfd_stdin = own(handover.stdin_fd);
+        self.mainfd_stdout = own(handover.stdout_fd);
+        self.mainfd_stderr = own(handover.stderr_fd);
+        self.terminal_socket =
+            own(handover.terminal_fd).map(|fd| RemoteSocket::new(SocketType::Terminal, fd));
+        if let Some(fd) = own(handover.notify_fd) {
+            self.notify_socket = Some(RemoteSocket::new(SocketType::Notify, fd));
+            self.sdnotify_socket_path = common.sdnotify_socket.clone();
+        }
+        self.attach_socket = own(handover.attach_fd).map(|fd| {
+            let vsock = handover.attach_path.is_none();
+            let mut socket =
+                UnixSocket::from_listening_fd(SocketType::Console, fd, handover.attach_path);
+            if !vsock {
+                socket.set_allowed_uids(attach_allowed_uids(common));
+            }
+            socket
+        });
+        self.attach_output = attach_output(common);
+        setup_attach_audit(common);
+
+        // The heartbeat does not survive the re-exec, start it again.
+        if let Some(path) = &common.heartbeat_file {
+            heartbeat::start_heartbeat(path, common.heartbeat_interval);
+        }
+
+        if !common.logging_passthrough {
+            self.ctl_fifo = Some(setup_terminal_control_fifo(common)?);
+            self.winsz_fifo = Some(setup_console_fifo(common)?);
+        }
+        if self.container_pid > 0 {
+            self.oom_socket =
+                setup_oom_handling(self.container_pid, &common.persist_dir, &common.bundle)?;
+            self.save_state(common);
+            self.start_recording(common);
+            control::enable(common, self.container_pid);
+            notify::set_main_pid(self.container_pid);
+        }
+
+        // The calling terminal is not handed over, open it again so the
+        // window size changes keep reaching the container.
+        if common.foreground && common.terminal {
+            self.host_tty = open_host_tty();
+        }
+        self.setup_signals(common)?;
+
+        Ok(true)
+    }
+
     /// Writes the container pid file to all the configured locations.
     ///
     /// This funtion is called after the `launch`. It writes the container PID
@@ -418,16 +838,32 @@ impl RuntimeSession {
     /// # Arguments
     ///
     /// * `common` - The Conmon common configuration.
+    /// * `query_runtime` - When `true`, the container PID is queried using
+    ///   `runtime state` if the PID file cannot be read. This is not usable
+    ///   for exec sessions, because the runtime reports the container init PID.
     ///
     /// # Errors
     ///
     /// * [`ConmonError`] on any error.
-    pub fn write_container_pid_file(&mut self, common: &CommonCfg) -> ConmonResult<()> {
+    pub fn write_container_pid_file(
+        &mut self,
+        common: &CommonCfg,
+        query_runtime: bool,
+    ) -> ConmonResult<()> {
         // Read the container PID and store it.
-        self.container_pid = self.read_container_pid(common)?;
+        self.container_pid = self.read_container_pid(common, query_runtime)?;
+        self.container_pidfd = open_pidfd(self.container_pid);
 
         // We know the container started, so note it.
         self.container_started = true;
+        set_container_started(chrono::Utc::now());
+        let mut metadata = ContainerMetadata::new();
+        metadata.insert("cid".into(), common.cid.clone());
+        if let Some(name) = &common.name {
+            metadata.insert("name".into(), name.clone());
+        }
+        metadata.insert("bundle".into(), common.bundle.display().to_string());
+        self.log_start = Some(metadata);
 
         // Setup the out-of-mana (eh, *-memory) handler, so we can detect OOM event
         // and pass it to parent.
@@ -439,10 +875,116 @@ impl RuntimeSession {
             self.sync_pipe_fd =
                 write_or_close_sync_fd(fd, self.container_pid, None, common.api_version, false)?;
         }
+        if let Some(sock) = &mut self.sync_socket {
+            sock.send(&SyncMessage::Pid {
+                pid: self.container_pid,
+            })?;
+        }
+
+        self.save_state(common);
+        self.start_recording(common);
+        control::enable(common, self.container_pid);
+        notify::set_main_pid(self.container_pid);
+
+        Ok(())
+    }
 
+    /// Starts the created container using `runtime start`.
+    ///
+    /// The container is killed if it cannot be started, so it does not stay
+    /// in the created state without any conmon supervising it.
+    ///
+    /// # Arguments
+    ///
+    /// * `common` - The Conmon common configuration.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the runtime fails to start the container.
+    pub fn start_container(&mut self, common: &CommonCfg) -> ConmonResult<()> {
+        info!("Starting container {}", common.cid);
+        if let Err(e) = run_runtime(common, &["start", &common.cid]) {
+            if self.container_pid > 0 {
+                let _ = kill(Pid::from_raw(self.container_pid), Signal::SIGKILL);
+            }
+            return Err(e);
+        }
         Ok(())
     }
 
+    /// Opens the file or inherited fd the exec session stdin is read from.
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - The `--exec-stdin-path` or `--exec-stdin-fd` value.
+    ///
+    /// # Returns
+    ///
+    /// * The stdin to pass to the runtime binary.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the file cannot be opened or the fd is not open.
+    fn open_exec_stdin(&mut self, src: &ExecStdin) -> ConmonResult<Stdio> {
+        match src {
+            ExecStdin::Path(path) => {
+                info!("Passing {} as stdin to runtime binary", path.display());
+                let file = fs::File::open(path).map_err(|e| {
+                    ConmonError::new(
+                        format!("Failed to open exec stdin {}: {e}", path.display()),
+                        1,
+                    )
+                })?;
+                Ok(Stdio::from(file))
+            }
+            ExecStdin::Fd(fd) => {
+                info!("Passing inherited fd {fd} as stdin to runtime binary");
+                // SAFETY: The fd is only borrowed to check that it is open.
+                let borrowed = unsafe { BorrowedFd::borrow_raw(*fd) };
+                fcntl(borrowed, FcntlArg::F_GETFD).map_err(|e| {
+                    ConmonError::new(format!("Invalid --exec-stdin-fd {fd}: {e}"), 1)
+                })?;
+                // The fd now belongs to the runtime's stdin, do not close it at exit.
+                self.open_files.remove(*fd);
+                Ok(Stdio::from(unsafe { OwnedFd::from_raw_fd(*fd) }))
+            }
+        }
+    }
+
+    /// Persists the supervision state into `--persist-dir`.
+    ///
+    /// A restarted conmon uses the state to re-adopt the still-running container
+    /// in case this conmon gets killed. Failing to save the state is not fatal.
+    ///
+    /// # Arguments
+    ///
+    /// * `common` - The Conmon common configuration.
+    fn save_state(&mut self, common: &CommonCfg) {
+        let Some(persist_dir) = &common.persist_dir else {
+            return;
+        };
+        let state = SessionState::new(&common.cid, std::process::id() as i32, self.container_pid);
+        match state.save(persist_dir) {
+            Ok(()) => self.state_dir = Some(persist_dir.clone()),
+            Err(e) => warn!("{}", e.msg),
+        }
+    }
+
+    /// Starts recording the session into `--persist-dir` when `--record-session`
+    /// is set. Failing to start the recording is not fatal.
+    ///
+    /// # Arguments
+    ///
+    /// * `common` - The Conmon common configuration.
+    fn start_recording(&self, common: &CommonCfg) {
+        let (true, Some(persist_dir)) = (common.record_session, &common.persist_dir) else {
+            return;
+        };
+        if let Err(e) = recording::start_recording(persist_dir, &common.cid, common.terminal) {
+            warn!("{}", e.msg);
+        }
+    }
+
     /// Writes the "runtime" exit code to all the configured locations.
     ///
     /// This funtion is called at the end of Conmon Session execution and ensures
@@ -463,54 +1005,102 @@ impl RuntimeSession {
         // be exiting soon.
         close_all_except_stdio(&self.open_files);
 
+        // The container is not supervised anymore, so there is nothing to re-adopt.
+        if let Some(dir) = self.state_dir.take() {
+            SessionState::remove(&dir);
+        }
+
+        // Once create has reported the container PID, it must not write another
+        // response to the sync pipe, even if the runtime later exits or times out.
+        // Exec (write_exit_code=true) writes the final exit status. The sync socket
+        // messages are typed, so the container exit is always reported there.
+        let report_to_pipe =
+            self.sync_pipe_fd.is_some() && (write_exit_code || !self.container_started);
+        if !report_to_pipe && self.sync_socket.is_none() {
+            return Ok(());
+        }
+
+        // Prepare the exit code according to the `write_exit_code`.
+        let mut to_report = -1;
+
+        if self.container_started {
+            to_report = self.container_status
+        } else if write_exit_code && self.exit_code > 0 {
+            to_report = -self.exit_code;
+        }
+
+        let err_msg = if self.timed_out {
+            Some("command timed out".to_string())
+        } else if let Some(msg) = self.runtime_timeout_msg() {
+            Some(msg)
+        } else if let Some(msg) = self
+            .runtime_log
+            .as_deref()
+            .filter(|_| !self.container_started)
+            .and_then(last_runtime_error)
+        {
+            // The runtime failed, report the error it logged rather than its
+            // stderr, which may not be the error or be truncated.
+            error!("Runtime exited with error: {msg}");
+            Some(msg)
+        } else if let Some(mainfd_stderr) = &self.mainfd_stderr {
+            // If we have stderr from runtime, read it and pass the error message to parent.
+            // TODO: We are reading just once here and if container prints more than
+            // a buffer sizeto stderr, we ignore whatever does not fid into the buffer.
+            // This might be a problem, but the original conmon-v2 code behaves the same way.
+            let mut err_bytes = [0u8; 8192];
+            let n = read_pipe(mainfd_stderr, &mut err_bytes)?;
+            let err_str = String::from_utf8_lossy(&err_bytes[..n]);
+            error!("Runtime exited with error: {err_str}");
+            Some(err_str.into_owned())
+        } else {
+            None
+        };
+
+        let exit_signal = self
+            .container_signal
+            .as_ref()
+            .filter(|_| self.container_started);
+        let oom = self.container_started && oom_detected();
+
         // Send exit code toe sync_pipe.
         if let Some(fd) = self.sync_pipe_fd.take() {
-            // Once create has reported the container PID, it must not write another
-            // response to the sync pipe, even if the runtime later exits or times out.
-            // Exec (write_exit_code=true) writes the final exit status.
-            if !write_exit_code && self.container_started {
+            if !report_to_pipe {
                 self.sync_pipe_fd = Some(fd);
-                return Ok(());
-            }
-
-            // Prepare the exit code according to the `write_exit_code`.
-            let mut to_report = -1;
-
-            if self.container_started {
-                to_report = self.container_status
-            } else if write_exit_code && self.exit_code > 0 {
-                to_report = -self.exit_code;
+            } else {
+                self.sync_pipe_fd = write_or_close_sync_fd_with_signal(
+                    fd,
+                    to_report,
+                    err_msg.as_deref(),
+                    exit_signal,
+                    oom,
+                    api_version,
+                    write_exit_code,
+                )?;
             }
+        }
 
-            let err_msg = if self.timed_out {
-                Some("command timed out".to_string())
-            } else if let Some(mainfd_stderr) = &self.mainfd_stderr {
-                // If we have stderr from runtime, read it and pass the error message to parent.
-                // TODO: We are reading just once here and if container prints more than
-                // a buffer sizeto stderr, we ignore whatever does not fid into the buffer.
-                // This might be a problem, but the original conmon-v2 code behaves the same way.
-                let mut err_bytes = [0u8; 8192];
-                let n = read_pipe(mainfd_stderr, &mut err_bytes)?;
-                let err_str = String::from_utf8_lossy(&err_bytes[..n]);
-                error!("Runtime exited with error: {err_str}");
-                Some(err_str.into_owned())
+        // The exit or error is the last message sent over the sync socket.
+        if let Some(mut sock) = self.sync_socket.take() {
+            let msg = if self.container_started {
+                SyncMessage::exit(to_report, exit_signal, oom, err_msg)
             } else {
-                None
+                SyncMessage::Error {
+                    exit_code: self.exit_code,
+                    message: err_msg.unwrap_or_default(),
+                }
             };
-
-            self.sync_pipe_fd = write_or_close_sync_fd(
-                fd,
-                to_report,
-                err_msg.as_deref(),
-                api_version,
-                write_exit_code,
-            )?;
+            sock.send(&msg)?;
         }
         Ok(())
     }
 
     /// Waits for the Runtime process to exit. Returns the exit code.
     ///
+    /// The runtime exit is noticed from its pidfd or SIGCHLD, polled together
+    /// with the signalfd, so the signals received meanwhile are handled like
+    /// in the event-loop.
+    ///
     /// # Returns
     ///
     /// * The "runtime" process exit code.
@@ -519,10 +1109,115 @@ impl RuntimeSession {
     ///
     /// * [`ConmonError`] on any error.
     pub fn wait(&mut self) -> ConmonResult<i32> {
-        self.exit_code = self.process.wait()?;
+        loop {
+            match self.process.try_wait() {
+                Ok(Some(code)) if !self.runtime_timed_out => {
+                    self.exit_code = code;
+                    return Ok(code);
+                }
+                // The runtime has been killed.
+                Ok(Some(_)) => break,
+                Err(_) if self.runtime_timed_out => break,
+                Err(e) => return Err(e),
+                Ok(None) => {}
+            }
+
+            let deadline = self
+                .runtime_deadline
+                .as_ref()
+                .filter(|_| !self.runtime_timed_out)
+                .map(|d| d.at);
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                self.kill_hung_runtime();
+                continue;
+            }
+            self.poll_runtime_exit(deadline)?;
+        }
+        self.exit_code = -1;
         Ok(self.exit_code)
     }
 
+    /// Polls the runtime pidfd and the signalfd until one of them is ready
+    /// or the `deadline` passes, and handles the received signal.
+    ///
+    /// Without the pidfd and the signalfd, it sleeps for 10 milliseconds at
+    /// most.
+    fn poll_runtime_exit(&mut self, deadline: Option<Instant>) -> ConmonResult<()> {
+        let signal_fd = self.signals.as_ref().map(|s| s.as_fd());
+        let mut fds: Vec<PollFd> = signal_fd
+            .iter()
+            .chain(self.process.pidfd().iter())
+            .map(|fd| PollFd::new(*fd, PollFlags::POLLIN))
+            .collect();
+        let mut timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        // Keep the heartbeat going while the runtime runs.
+        if let Some(interval) = heartbeat::interval() {
+            timeout = Some(timeout.map_or(interval, |t| t.min(interval)));
+        }
+        if fds.is_empty() {
+            timeout = Some(timeout.map_or(Duration::from_millis(10), |t| {
+                t.min(Duration::from_millis(10))
+            }));
+        }
+        let timeout = timeout.map_or(PollTimeout::NONE, |t| {
+            PollTimeout::try_from(t).unwrap_or(PollTimeout::MAX)
+        });
+        let signal_received = match poll(&mut fds, timeout) {
+            Ok(_) => {
+                signal_fd.is_some()
+                    && fds[0]
+                        .revents()
+                        .is_some_and(|r| r.contains(PollFlags::POLLIN))
+            }
+            Err(Errno::EINTR) => false,
+            Err(e) => {
+                return Err(ConmonError::new(
+                    format!("Failed to wait for runtime process to exit: {e}"),
+                    1,
+                ));
+            }
+        };
+        drop(fds);
+        heartbeat::beat();
+        if !signal_received {
+            return Ok(());
+        }
+
+        let Some(Ok(Some(info))) = self.signals.as_ref().map(|s| s.read_signal()) else {
+            return Ok(());
+        };
+        match Signal::try_from(info.ssi_signo as i32) {
+            // The runtime is reaped by the caller. The other children are
+            // left for the event-loop, the container may be one of them.
+            Ok(Signal::SIGCHLD) => {}
+            Ok(Signal::SIGUSR2) => {
+                warn!("Ignoring the live upgrade request, the runtime is still running");
+            }
+            Ok(sig) => {
+                self.handle_signal(sig)?;
+            }
+            Err(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Kills the runtime which did not finish before the `--runtime-timeout`.
+    fn kill_hung_runtime(&mut self) {
+        if let Some(d) = &self.runtime_deadline {
+            warn!("Runtime did not finish in {} seconds, killing it", d.secs);
+        }
+        let _ = kill(Pid::from_raw(self.process.pid()), Signal::SIGKILL);
+        self.runtime_timed_out = true;
+    }
+
+    /// Returns the error message reported when the runtime timed out.
+    fn runtime_timeout_msg(&self) -> Option<String> {
+        self.runtime_deadline
+            .as_ref()
+            .filter(|_| self.runtime_timed_out)
+            .map(|d| format!("runtime timed out after {} seconds", d.secs))
+    }
+
     /// Waits for the Runtime process to exit. Returns the exit code.
     ///
     /// In case of non-zero exit code, calls `write_exit_code` and returns `ConmonError`.
@@ -547,16 +1242,52 @@ impl RuntimeSession {
     ) -> ConmonResult<()> {
         // Wait until the `runtime create` finishes.
         self.wait()?;
+
+        // The killed runtime may leave half-created container behind, so remove
+        // it. The exec session (`write_exit_code`) does not own the container.
+        if let (true, false, Some(d)) = (
+            self.runtime_timed_out,
+            write_exit_code,
+            &self.runtime_deadline,
+        ) {
+            if let Err(e) = delete_container_by_id(&d.runtime, &d.runtime_args, &d.cid) {
+                warn!(
+                    "Failed to remove the container after the runtime timeout: {}",
+                    e.msg
+                );
+            }
+        }
+        if self.runtime_timed_out {
+            // Nobody can attach to the container which was not created.
+            self.attach_socket = None;
+        }
+
         if self.exit_code != 0 {
             self.write_exit_code(api_version, write_exit_code)?;
-            return Err(ConmonError::new(
-                format!("Runtime exited with status: {}", self.exit_code),
-                1,
-            ));
+            let msg = self
+                .runtime_timeout_msg()
+                .unwrap_or_else(|| format!("Runtime exited with status: {}", self.exit_code));
+            return Err(ConmonError::new(msg, 1));
         }
+        self.runtime_deadline = None;
         Ok(())
     }
 
+    /// Sends `signal` to the process group of the container, or only to the
+    /// container process if it has no group of its own.
+    fn kill_container_group(&self, signal: Signal) -> nix::Result<()> {
+        let pid = Pid::from_raw(self.container_pid);
+        let pgid = getpgid(Some(pid))?;
+
+        // NOTE:
+        // If pgid is 1, calling kill(-1, SIGKILL) would kill everything we have permission for.
+        if pgid.as_raw() > 1 {
+            kill(Pid::from_raw(-pgid.as_raw()), signal)
+        } else {
+            kill(pid, signal)
+        }
+    }
+
     /// Function executed periodically during the event-loop execuction.
     ///
     /// This function monitors the signal-fd, all the children processes and
@@ -581,12 +1312,7 @@ impl RuntimeSession {
                 match signals.read_signal() {
                     Ok(Some(info)) => {
                         if let Ok(sig) = Signal::try_from(info.ssi_signo as i32) {
-                            // Forward the signal the container if it's running.
-                            info!("Received signal: {:?}", sig);
-                            if self.container_pid > 0 {
-                                let pid = Pid::from_raw(self.container_pid);
-                                kill(pid, sig)?;
-                            }
+                            return self.handle_signal(sig);
                         }
                     }
                     Ok(None) => return Ok(true),
@@ -596,122 +1322,287 @@ impl RuntimeSession {
             return Ok(true);
         }
 
-        // Stop the event-loop if we reach a timeout.
+        // Kill the container if we reach a timeout.
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
         if self.timeout > 0 && now.as_secs() > self.timeout {
-            info!("Timed out - exiting event-loop.");
-            // Kill the container in case it exists.
-            if self.container_pid > 0 {
-                let pid = Pid::from_raw(self.container_pid);
-                // Get the process group ID of the container
-                let pgid = getpgid(Some(pid))?;
-
-                // NOTE:
-                // If pgid is 1, calling kill(-1, SIGKILL) would kill everything we have permission for.
-                if pgid.as_raw() > 1 {
-                    // kill entire process group
-                    kill(Pid::from_raw(-pgid.as_raw()), Signal::SIGKILL)?;
-                } else {
-                    // kill only the container process
-                    kill(pid, Signal::SIGKILL)?;
-                }
-            }
+            // The container did not exit in the grace period after the
+            // `--timeout-signal`, so it is killed.
+            let signal = match self.timeout_signal {
+                Some(signal) if !self.timed_out => signal,
+                _ => Signal::SIGKILL,
+            };
+            self.timeout = if signal == Signal::SIGKILL {
+                0
+            } else {
+                now.as_secs() + self.timeout_grace
+            };
             self.timed_out = true;
-            // Quite the event-loop.
+            set_timed_out();
+            if self.container_pid <= 0 {
+                info!("Timed out - exiting event-loop.");
+                return Ok(false);
+            }
+            info!("Timed out - sending {signal} to the container.");
+            if let Err(e) = self.kill_container_group(signal) {
+                warn!("Failed to signal the container after the timeout: {e}");
+            }
+            // Keep running until the container is reaped, so its exit status
+            // is reported as usual.
+            return Ok(true);
+        }
+
+        self.reap_children()
+    }
+
+    /// Handles the `sig` read from the signal-fd.
+    ///
+    /// # Returns
+    ///
+    /// * True if the event-loop should still continue.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] on any error.
+    fn handle_signal(&mut self, sig: Signal) -> ConmonResult<bool> {
+        // SIGUSR2 asks us to re-exec ourselves, so stop the event-loop
+        // and let the `run_event_loop` hand the session over.
+        if sig == Signal::SIGUSR2 {
+            info!("Received SIGUSR2, live upgrade requested");
+            self.upgrade_requested = true;
             return Ok(false);
         }
 
-        // Wait for any child to finish (non-blocking).
-        let res = waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG));
-
-        match res {
-            // Interrupted by signal - retry.
-            Err(Errno::EINTR) => Ok(true),
-
-            // no more child processes
-            Err(Errno::ECHILD) => {
-                // Before quitting, probe the container_pid.
-                // It might not be a direct child.
-                if self.container_pid > 0 {
-                    // Nix kill function does not support 0 signal, so we have to use libc one.
-                    let rc = unsafe { libc::kill(self.container_pid, 0) };
-                    if rc == 0 {
-                        info!(
-                            "Container process {} is still alive but not a direct child",
-                            self.container_pid
-                        );
-                        // Do not quit main loop yet...
-                        return Ok(true);
-                    } else if Errno::last() == Errno::ESRCH {
-                        // Process exited.
-                        info!(
-                            "Container process {} has exited (detected via kill probe)",
-                            self.container_pid
-                        );
-                        // We cannot get real exit status.
-                        self.container_status = 0;
-                        self.container_pid = -1;
-                        return Ok(false);
-                    } else {
-                        info!("No more child processes.");
-                        return Ok(false);
+        // SIGUSR1 asks us to reopen the log files rotated externally.
+        if sig == Signal::SIGUSR1 {
+            info!("Received SIGUSR1, reopening log files");
+            request_reopen();
+            return Ok(true);
+        }
+
+        // SIGHUP asks us to re-create the log drivers.
+        if sig == Signal::SIGHUP {
+            info!("Received SIGHUP, reloading the log drivers");
+            request_reload();
+            return Ok(true);
+        }
+
+        // A child exited. The idle callback reaps the
+        // children too, in case the signals are coalesced.
+        if sig == Signal::SIGCHLD {
+            return self.reap_children();
+        }
+
+        // The calling terminal has been resized.
+        if sig == Signal::SIGWINCH {
+            self.propagate_window_size();
+            return Ok(true);
+        }
+
+        // Forward the signal the container if it's running.
+        info!("Received signal: {:?}", sig);
+        if self.container_pid > 0 {
+            let pid = Pid::from_raw(self.container_pid);
+            // The container may be exiting already.
+            if let Err(e) = kill(pid, sig) {
+                warn!("Failed to forward {sig} to the container: {e}");
+            }
+        } else {
+            warn!("Ignoring {sig}, the container has not started yet");
+        }
+        Ok(true)
+    }
+
+    /// Reaps the exited children: the container, the runtime and any
+    /// descendant reparented to conmon as the subreaper, like the processes
+    /// double-forked by the runtime.
+    ///
+    /// # Returns
+    ///
+    /// * True if the event-loop should still continue.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] on any error.
+    fn reap_children(&mut self) -> ConmonResult<bool> {
+        // Reap the container through its pidfd first. This only works if the
+        // container is a child of conmon, the other children are reaped below.
+        if self.container_pid > 0 {
+            let exit = self
+                .container_pidfd
+                .as_ref()
+                .filter(|pidfd| pidfd_exited(pidfd))
+                .and_then(pidfd_wait);
+            if let Some(exit) = exit {
+                match exit {
+                    PidfdExit::Exited(code) => {
+                        self.container_status = code;
+                        info!("Container exited: {}", self.container_status);
+                    }
+                    PidfdExit::Signaled(signo, core_dumped) => {
+                        self.container_killed_by(signo, core_dumped);
                     }
                 }
-
-                // If container has not started yet, keep running.
-                Ok(!self.container_started)
+                self.container_pid = -1;
+                self.signal_exit_fd();
+                return Ok(false);
             }
+        }
 
-            // some other waitpid error
-            Err(e) => Err(ConmonError::new(
-                format!("Failed to read child process status: {e}"),
-                1,
-            )),
+        loop {
+            // Wait for any child to finish (non-blocking).
+            let res = waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG));
+
+            match res {
+                // Interrupted by signal - retry.
+                Err(Errno::EINTR) => return Ok(true),
+
+                // no more child processes
+                Err(Errno::ECHILD) => {
+                    // Before quitting, probe the container_pid.
+                    // It might not be a direct child.
+                    if self.container_pid > 0 {
+                        if let Some(pidfd) = &self.container_pidfd {
+                            if !pidfd_exited(pidfd) {
+                                return Ok(true);
+                            }
+                            info!(
+                                "Container process {} has exited (detected via pidfd)",
+                                self.container_pid
+                            );
+                            self.container_exit_unknown();
+                            return Ok(false);
+                        }
+                        // Nix kill function does not support 0 signal, so we have to use libc one.
+                        let rc = unsafe { libc::kill(self.container_pid, 0) };
+                        if rc == 0 {
+                            info!(
+                                "Container process {} is still alive but not a direct child",
+                                self.container_pid
+                            );
+                            // Do not quit main loop yet...
+                            return Ok(true);
+                        } else if Errno::last() == Errno::ESRCH {
+                            // Process exited.
+                            info!(
+                                "Container process {} has exited (detected via kill probe)",
+                                self.container_pid
+                            );
+                            self.container_exit_unknown();
+                            return Ok(false);
+                        } else {
+                            info!("No more child processes.");
+                            return Ok(false);
+                        }
+                    }
 
-            // No child has changed state.
-            Ok(WaitStatus::StillAlive) => Ok(true),
+                    // If container has not started yet, keep running.
+                    return Ok(!self.container_started);
+                }
 
-            // Child exited, store the exit code.
-            Ok(WaitStatus::Exited(p, code)) => {
-                if p == Pid::from_raw(self.container_pid) {
-                    self.container_status = code;
-                    info!("Container exited: {}", self.container_status);
-                    return Ok(false);
-                } else if p == Pid::from_raw(self.process.pid()) {
-                    self.exit_code = code;
-                    info!("Runtime exited: {}", self.exit_code);
-                    return Ok(false);
-                } else {
-                    info!("Uknown child {} exited", p);
+                // some other waitpid error
+                Err(e) => {
+                    return Err(ConmonError::new(
+                        format!("Failed to read child process status: {e}"),
+                        1,
+                    ));
                 }
-                Ok(true)
-            }
-
-            // Child killed with a signal, store it as exit code.
-            Ok(WaitStatus::Signaled(p, s, _)) => {
-                let code: i32 = s as i32;
-                if p == Pid::from_raw(self.container_pid) {
-                    self.container_status = 128 + code;
-                    info!("Container killed with signal: {}", self.container_status);
-                    return Ok(false);
-                } else if p == Pid::from_raw(self.process.pid()) {
-                    self.exit_code = 128 + code;
-                    info!("Runtime killed with signal: {}", self.exit_code);
-                    return Ok(false);
-                } else {
-                    info!("Uknown child {} exited", p);
+
+                // No child has changed state.
+                Ok(WaitStatus::StillAlive) => return Ok(true),
+
+                // Child exited, store the exit code.
+                Ok(WaitStatus::Exited(p, code)) => {
+                    if p == Pid::from_raw(self.container_pid) {
+                        self.container_status = code;
+                        info!("Container exited: {}", self.container_status);
+                        self.signal_exit_fd();
+                        return Ok(false);
+                    } else if p == Pid::from_raw(self.process.pid()) {
+                        self.exit_code = code;
+                        info!("Runtime exited: {}", self.exit_code);
+                        return Ok(false);
+                    }
+                    // Reap the other exited descendants right away.
+                    debug!("Reaped unknown child {p}, exited with status {code}");
+                }
+
+                // Child killed with a signal, store it as exit code.
+                Ok(WaitStatus::Signaled(p, s, core_dumped)) => {
+                    let code: i32 = s as i32;
+                    if p == Pid::from_raw(self.container_pid) {
+                        self.container_killed(s, core_dumped);
+                        self.signal_exit_fd();
+                        return Ok(false);
+                    } else if p == Pid::from_raw(self.process.pid()) {
+                        self.exit_code = 128 + code;
+                        info!("Runtime killed with signal: {}", self.exit_code);
+                        return Ok(false);
+                    }
+                    debug!("Reaped unknown child {p}, killed with signal {s}");
+                }
+
+                Ok(
+                    WaitStatus::Stopped(_, _)
+                    | WaitStatus::Continued(_)
+                    | WaitStatus::PtraceEvent(_, _, _)
+                    | WaitStatus::PtraceSyscall(_),
+                ) => {
+                    // Just continue with the event-loop.
+                    return Ok(true);
                 }
-                Ok(true)
             }
+        }
+    }
 
-            Ok(
-                WaitStatus::Stopped(_, _)
-                | WaitStatus::Continued(_)
-                | WaitStatus::PtraceEvent(_, _, _)
-                | WaitStatus::PtraceSyscall(_),
-            ) => {
-                // Just continue with the event-loop.
-                Ok(true)
+    /// Records the container killed by `signal` as its exit status.
+    fn container_killed(&mut self, signal: Signal, core_dumped: bool) {
+        self.container_status = 128 + signal as i32;
+        let exit_signal = ExitSignal {
+            signal,
+            core_dumped,
+        };
+        info!(
+            "Container killed with signal {}: {}",
+            exit_signal, self.container_status
+        );
+        set_exit_signal(exit_signal);
+        self.container_signal = Some(exit_signal);
+    }
+
+    /// Records that the container has been killed by the signal number
+    /// `signo`, which may be a realtime signal not covered by [`Signal`].
+    fn container_killed_by(&mut self, signo: i32, core_dumped: bool) {
+        match Signal::try_from(signo) {
+            Ok(signal) => self.container_killed(signal, core_dumped),
+            Err(_) => {
+                self.container_status = 128 + signo;
+                info!(
+                    "Container killed with signal {signo}: {}",
+                    self.container_status
+                );
+            }
+        }
+    }
+
+    /// Records that the container exited, but its exit status cannot be
+    /// obtained because it is not a child of conmon.
+    ///
+    /// The status is reported and written into the exit files as -1, so the
+    /// exit is never taken for a success.
+    fn container_exit_unknown(&mut self) {
+        info!("The container exit status is unknown");
+        self.container_status = -1;
+        set_exit_status_unknown();
+        self.container_pid = -1;
+        self.signal_exit_fd();
+    }
+
+    /// Writes the container exit status to the `_OCI_EXITFD` eventfd, before
+    /// the slower cleanup like writing the exit files starts.
+    fn signal_exit_fd(&mut self) {
+        if let Some(fd) = self.exit_fd.take() {
+            if let Err(e) = write_exit_fd(&fd, self.container_status) {
+                warn!("{}", e.msg);
             }
         }
     }
@@ -719,6 +1610,9 @@ impl RuntimeSession {
     /// Runs the main event-loop.
     ///
     /// The event-loop polls all the file descriptors which drives the Conmon's logic.
+    /// When live upgrade is requested using SIGUSR2, the session is handed over to
+    /// the re-executed conmon binary and this function does not return. If the
+    /// re-exec fails, the event-loop continues.
     ///
     /// # Arguments
     ///
@@ -735,16 +1629,35 @@ impl RuntimeSession {
         leave_stdin_open: bool,
         stdin_attached: bool,
     ) -> ConmonResult<()> {
-        #[allow(clippy::collapsible_if)]
-        if let Some(mainfd_err) = self.mainfd_stderr.take() {
+        if self.mainfd_stderr.is_none() {
+            return Err(ConmonError::new("RuntimeSession called without stdio", 1));
+        }
+
+        // The terminal is owned by the event-loop, keep its fd for SIGWINCH.
+        self.container_tty_fd = self.terminal_socket.as_ref().map(|s| s.fd.as_raw_fd());
+        self.propagate_window_size();
+
+        if let Some(metadata) = self.log_start.take() {
+            if let Err(e) = log_plugin.on_container_start(self.container_pid, &metadata) {
+                warn!("Failed to log the container start: {}", e.msg);
+            }
+        }
+
+        // The closure below borrows the whole session.
+        let mut attach_output = std::mem::take(&mut self.attach_output);
+        loop {
             let mut signal_fd: i32 = -1;
             if let Some(signals) = &self.signals {
                 signal_fd = signals.as_fd().as_raw_fd();
             }
-            handle_stdio(
+            let pidfd = self
+                .container_pidfd
+                .as_ref()
+                .map_or(-1, |fd| fd.as_raw_fd());
+            let remains = handle_stdio(
                 log_plugin,
                 self.mainfd_stdout.take(),
-                mainfd_err,
+                self.mainfd_stderr.take(),
                 self.workerfd_stdin.take(),
                 self.attach_socket.take(),
                 self.terminal_socket.take(),
@@ -755,13 +1668,133 @@ impl RuntimeSession {
                 self.sdnotify_socket_path.take(),
                 stdin_attached,
                 leave_stdin_open,
+                &mut attach_output,
                 signal_fd,
+                pidfd,
                 |signal_received| self.idle_callback(signal_received),
             )?;
-            return Ok(());
+
+            if !self.upgrade_requested {
+                // The container exited, make its output durable.
+                if let Err(e) = Self::flush_logs(log_plugin) {
+                    warn!("Failed to sync the container logs: {}", e.msg);
+                }
+                if self.container_started && self.container_status >= 0 {
+                    let exit = ContainerExit {
+                        code: self.container_status,
+                        signal: self.container_signal.map(|s| s.signal),
+                        oom: oom_detected(),
+                    };
+                    if let Err(e) = log_plugin.on_container_exit(&exit) {
+                        warn!("Failed to log the container exit: {}", e.msg);
+                    }
+                }
+                if let Err(e) = log_plugin.shutdown() {
+                    warn!("Failed to sync the container logs: {}", e.msg);
+                }
+                return Ok(());
+            }
+            self.upgrade_requested = false;
+            self.restore_stdio(remains)?;
+
+            // Flush the partial log lines, the new binary starts with empty
+            // buffers, and stop the log threads, they must not run during
+            // the re-exec.
+            Self::flush_logs(log_plugin)?;
+            log_plugin.stop_threads()?;
+
+            let Err(e) = self.handover().exec();
+            error!("Live upgrade failed, continuing: {}", e.msg);
+        }
+    }
+
+    /// Writes the buffered partial log lines of all the streams.
+    fn flush_logs(log_plugin: &mut dyn LogPlugin) -> ConmonResult<()> {
+        let no_data: &[u8] = &[];
+        log_plugin.write(LogStream::Stdout, no_data)?;
+        log_plugin.write(LogStream::Stderr, no_data)?;
+        log_plugin.write(LogStream::Stdin, no_data)
+    }
+
+    /// Moves the sockets still open after the event-loop stopped back into
+    /// the session.
+    ///
+    /// The attach client connections cannot be handed over, so they are closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `remains` - The sockets returned by `handle_stdio`.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] on any error.
+    fn restore_stdio(&mut self, remains: StdioRemains) -> ConmonResult<()> {
+        self.workerfd_stdin = remains.workerfd_stdin;
+        for socket in remains.sockets {
+            let r = match socket {
+                Socket::Unix(u) => {
+                    self.attach_socket = Some(u);
+                    continue;
+                }
+                Socket::Remote(r) => r,
+                Socket::Invalid() => continue,
+            };
+            match r.socket_type {
+                SocketType::Stdout => self.mainfd_stdout = Some(r.fd.try_clone()?),
+                SocketType::Stderr => self.mainfd_stderr = Some(r.fd.try_clone()?),
+                SocketType::Terminal => self.terminal_socket = Some(r),
+                SocketType::TerminalFifo => self.ctl_fifo = Some(r),
+                SocketType::ConsoleFifo => self.winsz_fifo = Some(r),
+                SocketType::Inotify => self.oom_socket = Some(r),
+                SocketType::Notify => self.notify_socket = Some(r),
+                SocketType::Timer => warn!("Dropping the pending stop timer for live upgrade"),
+                _ => info!("Closing attach client {:?} for live upgrade", r),
+            }
         }
+        Ok(())
+    }
 
-        Err(ConmonError::new("RuntimeSession called without stdio", 1))
+    /// Sets the window size of the container terminal to the size of the
+    /// calling terminal in `--foreground` mode.
+    fn propagate_window_size(&self) {
+        let (Some(host_tty), Some(container_tty_fd)) = (&self.host_tty, self.container_tty_fd)
+        else {
+            return;
+        };
+        match terminal_size(host_tty.as_raw_fd()) {
+            Ok((height, width)) => {
+                debug!("Propagating window size {width}x{height}");
+                resize_terminal(container_tty_fd, height, width);
+            }
+            Err(e) => warn!("{}", e.msg),
+        }
+    }
+
+    /// Returns the Handover describing this session.
+    fn handover(&self) -> Handover {
+        Handover {
+            runtime_pid: self.process.pid(),
+            runtime_pidfd: self.process.pidfd().map(|fd| fd.as_raw_fd()),
+            exit_code: self.exit_code,
+            container_pid: self.container_pid,
+            container_pidfd: self.container_pidfd.as_ref().map(|fd| fd.as_raw_fd()),
+            container_started: self.container_started,
+            timeout: self.timeout,
+            sync_pipe_fd: self.sync_pipe_fd.as_ref().map(|fd| fd.as_raw_fd()),
+            exit_fd: self.exit_fd.as_ref().map(|fd| fd.as_raw_fd()),
+            sync_socket_fd: self.sync_socket.as_ref().map(|s| s.raw_fd()),
+            stdin_fd: self.workerfd_stdin.as_ref().map(|fd| fd.as_raw_fd()),
+            stdout_fd: self.mainfd_stdout.as_ref().map(|fd| fd.as_raw_fd()),
+            stderr_fd: self.mainfd_stderr.as_ref().map(|fd| fd.as_raw_fd()),
+            terminal_fd: self.terminal_socket.as_ref().map(|s| s.fd.as_raw_fd()),
+            notify_fd: self.notify_socket.as_ref().map(|s| s.fd.as_raw_fd()),
+            attach_fd: self
+                .attach_socket
+                .as_ref()
+                .and_then(|s| s.fd())
+                .map(|fd| fd.as_raw_fd()),
+            attach_path: self.attach_socket.as_ref().and_then(|s| s.path().cloned()),
+        }
     }
 
     /// Waits for the runtime to send the terminal fd to conmon.
@@ -775,6 +1808,17 @@ impl RuntimeSession {
     pub fn wait_for_terminal_creation(&mut self) -> ConmonResult<()> {
         debug!("Waiting for terminal creation.");
         if let Some(cs) = self.console_socket.take() {
+            // Do not block forever on a hung runtime, `wait_for_success`
+            // reports the timeout.
+            if let (Some(d), Some(fd)) = (&self.runtime_deadline, cs.fd()) {
+                let remaining = d.at.saturating_duration_since(Instant::now());
+                let timeout = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+                let mut pfds = [PollFd::new(fd.as_fd(), PollFlags::POLLIN)];
+                if poll(&mut pfds, timeout)? == 0 {
+                    self.kill_hung_runtime();
+                    return Ok(());
+                }
+            }
             self.terminal_socket = Some(receive_console_fd(cs)?);
         }
         debug!("Terminal created.");
@@ -810,7 +1854,7 @@ mod tests {
 
         let open_files = OpenFilesSnapshot::default();
         let sess = RuntimeSession::new(open_files);
-        let pid = sess.read_container_pid(&cfg)?;
+        let pid = sess.read_container_pid(&cfg, true)?;
         assert_eq!(pid, 12345);
         Ok(())
     }
@@ -830,7 +1874,7 @@ mod tests {
 
         let open_files = OpenFilesSnapshot::default();
         let sess = RuntimeSession::new(open_files);
-        let err = sess.read_container_pid(&cfg).unwrap_err();
+        let err = sess.read_container_pid(&cfg, true).unwrap_err();
         let msg = format!("{err}");
         assert!(
             msg.contains("Invalid PID contents"),
@@ -843,7 +1887,7 @@ mod tests {
     fn run_event_loop_errors_without_stdio() -> ConmonResult<()> {
         struct NoopLog;
         impl crate::logging::plugin::LogPlugin for NoopLog {
-            fn write(&mut self, _is_stdout: bool, _data: &[u8]) -> ConmonResult<()> {
+            fn write(&mut self, _stream: LogStream, _data: &[u8]) -> ConmonResult<()> {
                 Ok(())
             }
             fn reopen(&mut self) -> ConmonResult<()> {
@@ -896,6 +1940,32 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn write_exit_code_reports_exit_on_sync_socket() -> ConmonResult<()> {
+        use nix::sys::socket::{AddressFamily, SockFlag, SockType, socketpair};
+
+        let (conmon, parent) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?;
+        let mut sess = RuntimeSession {
+            sync_socket: Some(SyncSocket::new(conmon)),
+            container_started: true,
+            container_status: 3,
+            ..Default::default()
+        };
+
+        sess.write_exit_code(0, false)?;
+        assert!(sess.sync_socket.is_none());
+        assert_eq!(
+            SyncSocket::new(parent).recv()?,
+            SyncMessage::exit(3, None, false, None)
+        );
+        Ok(())
+    }
+
     #[test]
     fn write_exit_code_reports_negative_pid_on_create_failure() -> ConmonResult<()> {
         use crate::runtime::stdio::{create_pipe, read_pipe};
@@ -929,6 +1999,105 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn wait_for_success_kills_hung_runtime() -> ConmonResult<()> {
+        use crate::runtime::stdio::{create_pipe, read_pipe};
+        use serde_json::Value;
+
+        let hung = std::process::Command::new("sleep").arg("30").spawn()?;
+        let (sync_r, sync_w) = create_pipe()?;
+        let mut sess = RuntimeSession::new(OpenFilesSnapshot::default());
+        sess.process = RuntimeProcess::with_pid(hung.id() as i32);
+        sess.sync_pipe_fd = Some(sync_w);
+        sess.runtime_deadline = Some(RuntimeDeadline {
+            at: Instant::now() + Duration::from_millis(50),
+            secs: 1,
+            runtime: PathBuf::from("/bin/true"),
+            runtime_args: Vec::new(),
+            cid: "abc".into(),
+        });
+
+        let err = sess.wait_for_success(1, false).unwrap_err();
+        assert_eq!(err.msg, "runtime timed out after 1 seconds");
+        assert!(!crate::runtime::process::process_alive(hung.id() as i32));
+
+        let mut buf = [0u8; 8192];
+        let n = read_pipe(&sync_r, &mut buf)?;
+        let v: Value = serde_json::from_slice(&buf[..n])?;
+        assert_eq!(v["data"], -1);
+        assert_eq!(v["message"], "runtime timed out after 1 seconds");
+        Ok(())
+    }
+
+    #[test]
+    fn wait_returns_runtime_exit_code() -> ConmonResult<()> {
+        let runtime = std::process::Command::new("sh")
+            .args(["-c", "sleep 0.05; exit 3"])
+            .spawn()?;
+        let mut sess = RuntimeSession::new(OpenFilesSnapshot::default());
+        sess.process = RuntimeProcess::with_pid(runtime.id() as i32);
+        assert_eq!(sess.wait()?, 3);
+        assert_eq!(sess.exit_code(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn pidfd_reports_container_exit() -> ConmonResult<()> {
+        let mut child = std::process::Command::new("sleep").arg("30").spawn()?;
+        let Some(pidfd) = open_pidfd(child.id() as i32) else {
+            // The kernel does not support pidfds.
+            child.kill()?;
+            child.wait()?;
+            return Ok(());
+        };
+        assert!(!pidfd_exited(&pidfd));
+        child.kill()?;
+        child.wait()?;
+        assert!(pidfd_exited(&pidfd));
+        // The child has been reaped already, so its status is unknown.
+        assert_eq!(pidfd_wait(&pidfd), None);
+        Ok(())
+    }
+
+    #[test]
+    fn pidfd_wait_reaps_child() -> ConmonResult<()> {
+        let child = std::process::Command::new("sh")
+            .args(["-c", "exit 7"])
+            .spawn()?;
+        let Some(pidfd) = open_pidfd(child.id() as i32) else {
+            // The kernel does not support pidfds.
+            return Ok(());
+        };
+        poll(
+            &mut [PollFd::new(pidfd.as_fd(), PollFlags::POLLIN)],
+            PollTimeout::NONE,
+        )?;
+        assert_eq!(pidfd_wait(&pidfd), Some(PidfdExit::Exited(7)));
+        Ok(())
+    }
+
+    #[test]
+    fn pidfd_wait_reports_realtime_signal() -> ConmonResult<()> {
+        let child = std::process::Command::new("sleep").arg("30").spawn()?;
+        let Some(pidfd) = open_pidfd(child.id() as i32) else {
+            // The kernel does not support pidfds.
+            unsafe { libc::kill(child.id() as i32, libc::SIGKILL) };
+            return Ok(());
+        };
+        let signo = libc::SIGRTMIN() + 1;
+        assert_eq!(unsafe { libc::kill(child.id() as i32, signo) }, 0);
+        poll(
+            &mut [PollFd::new(pidfd.as_fd(), PollFlags::POLLIN)],
+            PollTimeout::NONE,
+        )?;
+        assert_eq!(pidfd_wait(&pidfd), Some(PidfdExit::Signaled(signo, false)));
+
+        let mut sess = RuntimeSession::new(OpenFilesSnapshot::default());
+        sess.container_killed_by(signo, false);
+        assert_eq!(sess.container_status, 128 + signo);
+        Ok(())
+    }
+
     #[test]
     fn write_exit_code_is_noop_without_sync_fd() -> ConmonResult<()> {
         // If no syncpipe FD was captured in launch(), write_exit_code should simply succeed
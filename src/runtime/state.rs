@@ -0,0 +1,152 @@
+use std::fs;
+use std::path::Path;
+
+use log::{info, warn};
+use serde_json::{Map, Value};
+
+use crate::error::{ConmonError, ConmonResult};
+use crate::runtime::process::process_start_time;
+
+/// Name of the state file stored in `--persist-dir`.
+pub const STATE_FILE_NAME: &str = "conmon-state.json";
+
+/// Supervision state of a running container.
+///
+/// The state is persisted into `--persist-dir`, so a restarted conmon can
+/// re-adopt a still-running container instead of orphaning it. The stdio of
+/// the container dies with its conmon, so the log targets and the attach
+/// socket cannot be taken over and are not part of the state.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SessionState {
+    /// The container ID.
+    pub cid: String,
+
+    /// PID of the conmon process supervising the container.
+    pub conmon_pid: i32,
+
+    /// PID of the container init process.
+    pub container_pid: i32,
+
+    /// Start time of the container init process in clock ticks since boot.
+    /// A pidfd cannot outlive conmon, so the adopting conmon compares it to
+    /// the start time of the process it opens the pidfd for.
+    pub container_start_time: Option<u64>,
+}
+
+impl SessionState {
+    /// Creates new SessionState and records the start time of the container.
+    pub fn new(cid: &str, conmon_pid: i32, container_pid: i32) -> Self {
+        Self {
+            cid: cid.to_string(),
+            conmon_pid,
+            container_pid,
+            container_start_time: process_start_time(container_pid),
+        }
+    }
+
+    /// Returns the state as JSON object.
+    pub fn to_json(&self) -> Value {
+        let mut obj = Map::new();
+        obj.insert("cid".into(), Value::from(self.cid.clone()));
+        obj.insert("conmon_pid".into(), Value::from(self.conmon_pid));
+        obj.insert("container_pid".into(), Value::from(self.container_pid));
+        obj.insert(
+            "container_start_time".into(),
+            Value::from(self.container_start_time),
+        );
+        Value::Object(obj)
+    }
+
+    /// Parses the state from JSON object.
+    pub fn from_json(v: &Value) -> ConmonResult<Self> {
+        let invalid = |field: &str| ConmonError::new(format!("Invalid state file: {field}"), 1);
+        let cid = v["cid"].as_str().ok_or_else(|| invalid("cid"))?;
+        let conmon_pid = v["conmon_pid"]
+            .as_i64()
+            .ok_or_else(|| invalid("conmon_pid"))?;
+        let container_pid = v["container_pid"]
+            .as_i64()
+            .ok_or_else(|| invalid("container_pid"))?;
+        Ok(Self {
+            cid: cid.to_string(),
+            conmon_pid: conmon_pid as i32,
+            container_pid: container_pid as i32,
+            container_start_time: v["container_start_time"].as_u64(),
+        })
+    }
+
+    /// Atomically writes the state into `dir`.
+    pub fn save(&self, dir: &Path) -> ConmonResult<()> {
+        let path = dir.join(STATE_FILE_NAME);
+        let tmp_path = dir.join(format!("{STATE_FILE_NAME}.tmp"));
+        fs::write(&tmp_path, self.to_json().to_string())?;
+        fs::rename(&tmp_path, &path).map_err(|e| {
+            ConmonError::new(
+                format!("Failed to write state file {}: {e}", path.display()),
+                1,
+            )
+        })?;
+        info!("Saved session state to {}", path.display());
+        Ok(())
+    }
+
+    /// Loads the state from `dir`.
+    pub fn load(dir: &Path) -> ConmonResult<Self> {
+        let path = dir.join(STATE_FILE_NAME);
+        let contents = fs::read_to_string(&path).map_err(|e| {
+            ConmonError::new(
+                format!("Failed to read state file {}: {e}", path.display()),
+                1,
+            )
+        })?;
+        let v: Value = serde_json::from_str(&contents)?;
+        Self::from_json(&v)
+    }
+
+    /// Removes the state file from `dir`.
+    pub fn remove(dir: &Path) {
+        let path = dir.join(STATE_FILE_NAME);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove state file {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn save_and_load_roundtrip() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let ours = SessionState::new("cid", 10, std::process::id() as i32);
+        assert!(ours.container_start_time.is_some());
+        ours.save(tmp.path())?;
+        assert_eq!(SessionState::load(tmp.path())?, ours);
+
+        let state = SessionState {
+            cid: "cid".to_string(),
+            conmon_pid: 10,
+            container_pid: 20,
+            container_start_time: None,
+        };
+        state.save(tmp.path())?;
+        let loaded = SessionState::load(tmp.path())?;
+        assert_eq!(loaded, state);
+
+        SessionState::remove(tmp.path());
+        assert!(SessionState::load(tmp.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn from_json_rejects_missing_pid() {
+        let v = json!({"cid": "abc", "conmon_pid": 1});
+        let err = SessionState::from_json(&v).unwrap_err();
+        assert!(err.to_string().contains("container_pid"));
+    }
+}
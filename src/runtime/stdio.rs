@@ -1,7 +1,10 @@
 use crate::{
+    attach::AttachOutput,
     error::{ConmonError, ConmonResult},
-    logging::plugin::LogPlugin,
-    unix_socket::{RemoteSocket, Socket, SocketType, UnixSocket},
+    heartbeat,
+    logging::plugin::{LogPlugin, reopen_if_requested},
+    metrics::metrics,
+    unix_socket::{AttachClient, RemoteSocket, Socket, SocketType, UnixSocket},
 };
 
 use nix::{
@@ -18,6 +21,7 @@ use std::{
     io::{self, IoSliceMut},
     os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
     path::PathBuf,
+    time::Instant,
 };
 
 use log::{debug, info};
@@ -168,6 +172,27 @@ pub fn receive_console_fd(console_socket: UnixSocket) -> ConmonResult<RemoteSock
     ))
 }
 
+/// Returns the number of attach clients still sending the container's stdin.
+fn stdin_clients(fds: &[PollFd], sockets: &[Socket]) -> usize {
+    fds.iter()
+        .zip(sockets)
+        .filter(|(pfd, s)| {
+            matches!(s, Socket::Remote(r) if r.socket_type == SocketType::Console)
+                && pfd.events().contains(PollFlags::POLLIN)
+        })
+        .count()
+}
+
+/// The sockets still open when the `handle_stdio` event-loop stopped.
+#[derive(Debug, Default)]
+pub struct StdioRemains {
+    /// Sockets the event-loop was polling.
+    pub sockets: Vec<Socket>,
+
+    /// The container's stdin.
+    pub workerfd_stdin: Option<OwnedFd>,
+}
+
 /// Handles incomming data on fds and forwards them to right destination.
 /// This function blocks until the container is running.
 /// # Arguments
@@ -181,12 +206,21 @@ pub fn receive_console_fd(console_socket: UnixSocket) -> ConmonResult<RemoteSock
 /// * `ctl_fifo` - Remote socket for `ctl` fifo.
 /// * `winsz_fifo` - Remote socket for `winsz` fifo.
 /// * `leave_stdin_open` - Whether to keep stdin open attach client disconnects.
+/// * `attach_output` - The recent output replayed to the new attach clients
+///   and the limits of the output queued for them.
+/// * `signal_fd` - signalfd of the handled signals, or -1.
+/// * `pidfd` - pidfd of the container process, or -1. The `idle_callback` is
+///   executed as soon as the container exits.
 /// * `idle_callback` - function executed periodically during the event-loop.
+///
+/// # Returns
+///
+/// * The sockets still open when the event-loop stopped.
 #[allow(clippy::too_many_arguments)]
 pub fn handle_stdio<F>(
     log_plugin: &mut dyn LogPlugin,
     mut mainfd_stdout: Option<OwnedFd>,
-    mainfd_stderr: OwnedFd,
+    mainfd_stderr: Option<OwnedFd>,
     mut workerfd_stdin: Option<OwnedFd>,
     attach_socket: Option<UnixSocket>,
     terminal_socket: Option<RemoteSocket>,
@@ -197,9 +231,11 @@ pub fn handle_stdio<F>(
     notify_host_path: Option<PathBuf>,
     stdin_attached: bool,
     leave_stdin_open: bool,
+    attach_output: &mut AttachOutput,
     signal_fd: i32,
+    pidfd: i32,
     mut idle_callback: F,
-) -> ConmonResult<()>
+) -> ConmonResult<StdioRemains>
 where
     F: FnMut(bool) -> ConmonResult<bool>,
 {
@@ -210,7 +246,7 @@ where
 
     // Helpers containing fds for console, terminal and stdout, so we can easily
     // forward data to them.
-    let mut console_fds = Vec::new();
+    let mut console_clients = Vec::new();
     let mut terminal_fds = Vec::new();
     let mut stdout_fd: i32 = -1;
 
@@ -238,12 +274,14 @@ where
     }
 
     // Container's stderr.
-    let borrowed = unsafe { BorrowedFd::borrow_raw(mainfd_stderr.as_raw_fd()) };
-    fds.push(PollFd::new(borrowed, PollFlags::POLLIN));
-    sockets.push(Socket::Remote(RemoteSocket::new(
-        SocketType::Stderr,
-        mainfd_stderr,
-    )));
+    if let Some(stderr) = mainfd_stderr {
+        let borrowed = unsafe { BorrowedFd::borrow_raw(stderr.as_raw_fd()) };
+        fds.push(PollFd::new(borrowed, PollFlags::POLLIN));
+        sockets.push(Socket::Remote(RemoteSocket::new(
+            SocketType::Stderr,
+            stderr,
+        )));
+    }
 
     // Optional terminal socket.
     if let Some(terminal) = terminal_socket {
@@ -290,6 +328,13 @@ where
         sockets.push(Socket::Invalid());
     }
 
+    // Pidfd becoming readable once the container exits.
+    if pidfd >= 0 {
+        let borrowed = unsafe { BorrowedFd::borrow_raw(pidfd) };
+        fds.push(PollFd::new(borrowed, PollFlags::POLLIN));
+        sockets.push(Socket::Invalid());
+    }
+
     // Main loop.
     // Iterates as long as we have some RemoteSocket to read from or
     // as long as `idle_callback` returns `true`.
@@ -305,17 +350,26 @@ where
             )
         })?;
 
+        metrics().record_wakeup(n == 0);
+        heartbeat::beat();
+        reopen_if_requested(log_plugin);
+        console_clients.retain_mut(AttachClient::flush);
+
         // We have no fd to read from, so execute the idle function.
         if n == 0 {
             let keep_running = idle_callback(false)?;
             if !keep_running {
                 info!("idle_callback stopped the event loop.");
-                return Ok(());
+                return Ok(StdioRemains {
+                    sockets,
+                    workerfd_stdin,
+                });
             }
             continue;
         }
 
         // We will mutate fds/remote_sockets, so iterate by index.
+        let loop_start = Instant::now();
         let mut i = 0;
         while i < fds.len() {
             // The poll fd.
@@ -330,7 +384,29 @@ where
                     // If the POLLIN comes from the signal fd, run the idle_callback to handle
                     // the received signal.
                     if pfd.as_fd().as_raw_fd() == signal_fd {
-                        idle_callback(true)?;
+                        if !idle_callback(true)? {
+                            info!("idle_callback stopped the event loop.");
+                            return Ok(StdioRemains {
+                                sockets,
+                                workerfd_stdin,
+                            });
+                        }
+                        i += 1;
+                        continue;
+                    }
+
+                    // The container exited, reap it right away.
+                    if pfd.as_fd().as_raw_fd() == pidfd {
+                        debug!("Container exit reported by pidfd");
+                        // The pidfd stays readable, do not poll it anymore.
+                        fds[i].set_events(PollFlags::empty());
+                        if !idle_callback(false)? {
+                            info!("idle_callback stopped the event loop.");
+                            return Ok(StdioRemains {
+                                sockets,
+                                workerfd_stdin,
+                            });
+                        }
                         i += 1;
                         continue;
                     }
@@ -339,11 +415,12 @@ where
                     continue_reading = sockets[i].handle_data(
                         log_plugin,
                         &mut new_sockets,
-                        workerfd_stdin.as_ref(),
-                        &console_fds,
+                        &mut workerfd_stdin,
+                        &mut console_clients,
                         &terminal_fds,
                         stdout_fd,
                         &notify_host_path,
+                        &mut attach_output.replay,
                     )?;
 
                     // Add new sockets to `sockets` and `fds`.
@@ -354,7 +431,15 @@ where
                             info!("Adding {:?} into poll fds", new_socket);
                             if let Some(n_s) = new_socket {
                                 if n_s.socket_type == SocketType::Console {
-                                    console_fds.push(n_s.fd.as_raw_fd());
+                                    // Send the recent output before the live one.
+                                    let mut client = AttachClient::new(&n_s, attach_output);
+                                    if attach_output
+                                        .replay
+                                        .packets()
+                                        .all(|packet| client.push(&[packet]))
+                                    {
+                                        console_clients.push(client);
+                                    }
                                 }
                                 let borrowed =
                                     unsafe { BorrowedFd::borrow_raw(n_s.fd.as_raw_fd()) };
@@ -381,11 +466,12 @@ where
 
                 if let Socket::Remote(r) = &sockets[i] {
                     if r.socket_type == SocketType::Console && stdin_attached {
-                        // We closed the Console socket attached to container's stdin.
-                        // This normally means we also close the container's stdin, unless
-                        // the called instructed us no to do it using the `--leave-stdin-open`.
-                        // console_fds.retain(|&x| x != r.fd.as_raw_fd());
-                        if !leave_stdin_open {
+                        // The attach client stopped sending the container's stdin.
+                        // It keeps receiving the output until it disconnects. Once
+                        // the last client stops sending, we close the container's
+                        // stdin, unless the caller instructed us not to do it using
+                        // the `--leave-stdin-open`.
+                        if !leave_stdin_open && stdin_clients(&fds, &sockets) == 0 {
                             // This closes the socket, since it moves out of scope.
                             workerfd_stdin.take();
                         }
@@ -401,14 +487,35 @@ where
             } else {
                 // Remove the fd completely.
                 let socket = sockets.swap_remove(i);
+                if let Socket::Remote(r) = &socket {
+                    if r.socket_type == SocketType::Console {
+                        // The fd number may be reused by a new connection.
+                        console_clients.retain(|c| c.fd != r.fd.as_raw_fd());
+                    }
+                }
                 info!("Removing socket {:?}", socket);
                 fds.swap_remove(i);
 
                 // Do NOT increment the `i`, since it now points to swapped fd.
             }
         }
+
+        let buffered = sockets
+            .iter()
+            .map(|s| match s {
+                Socket::Remote(r) => r.buffered_len(),
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0);
+        let mut m = metrics();
+        m.record_loop_latency(loop_start.elapsed());
+        m.record_queue_depth(fds.len(), buffered);
     }
-    Ok(())
+    Ok(StdioRemains {
+        sockets,
+        workerfd_stdin,
+    })
 }
 
 #[cfg(test)]
@@ -0,0 +1,323 @@
+use std::convert::Infallible;
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::os::fd::RawFd;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+
+use log::info;
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use nix::unistd::execve;
+use serde_json::{Map, Value};
+
+use crate::error::{ConmonError, ConmonResult};
+
+/// Environment variable with the serialized `Handover` passed to the
+/// re-executed conmon binary.
+pub const HANDOVER_ENV: &str = "_CONMON_HANDOVER";
+
+/// Session state handed over to the re-executed conmon binary on live upgrade.
+///
+/// The file descriptors stay open across `execve()`, so the new binary
+/// continues to supervise the container without restarting it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Handover {
+    /// PID of the runtime process.
+    pub runtime_pid: i32,
+
+    /// The pidfd of the runtime process.
+    pub runtime_pidfd: Option<RawFd>,
+
+    /// Exit code of the runtime process.
+    pub exit_code: i32,
+
+    /// PID of the container.
+    pub container_pid: i32,
+
+    /// The pidfd of the container, so the new binary does not look the
+    /// container up by its PID, which may have been reused.
+    pub container_pidfd: Option<RawFd>,
+
+    /// True if the container started.
+    pub container_started: bool,
+
+    /// Time (unix timestamp) after which the session should terminate.
+    pub timeout: u64,
+
+    /// The sync pipe to the parent.
+    pub sync_pipe_fd: Option<RawFd>,
+
+    /// The `_OCI_EXITFD` eventfd, if not signalled yet.
+    pub exit_fd: Option<RawFd>,
+
+    /// The `_OCI_SYNCSOCK` sync socket.
+    pub sync_socket_fd: Option<RawFd>,
+
+    /// Container's stdin.
+    pub stdin_fd: Option<RawFd>,
+
+    /// Container's stdout.
+    pub stdout_fd: Option<RawFd>,
+
+    /// Container's stderr.
+    pub stderr_fd: Option<RawFd>,
+
+    /// Terminal received from the runtime.
+    pub terminal_fd: Option<RawFd>,
+
+    /// The sd-notify socket.
+    pub notify_fd: Option<RawFd>,
+
+    /// The listening attach socket.
+    pub attach_fd: Option<RawFd>,
+
+    /// Path of the attach socket.
+    pub attach_path: Option<PathBuf>,
+}
+
+/// Sets or clears the FD_CLOEXEC flag of `fd`.
+fn set_cloexec(fd: RawFd, cloexec: bool) -> ConmonResult<()> {
+    let flags = if cloexec {
+        FdFlag::FD_CLOEXEC
+    } else {
+        FdFlag::empty()
+    };
+    let borrowed = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+    fcntl(borrowed, FcntlArg::F_SETFD(flags))
+        .map_err(|e| ConmonError::new(format!("Failed to set FD_CLOEXEC on {fd}: {e}"), 1))?;
+    Ok(())
+}
+
+/// Returns the path to the conmon binary.
+///
+/// When the binary has been replaced on disk, `/proc/self/exe` points to the
+/// deleted file, so the " (deleted)" suffix is removed to get the new binary.
+fn conmon_binary_path() -> ConmonResult<PathBuf> {
+    let exe = fs::read_link("/proc/self/exe")?;
+    let exe = exe.to_string_lossy();
+    Ok(PathBuf::from(
+        exe.strip_suffix(" (deleted)").unwrap_or(&exe).to_string(),
+    ))
+}
+
+/// Converts optional fd to JSON value.
+fn fd_to_json(fd: Option<RawFd>) -> Value {
+    fd.map(Value::from).unwrap_or(Value::Null)
+}
+
+/// Parses optional fd from JSON value.
+fn fd_from_json(v: &Value) -> Option<RawFd> {
+    v.as_i64().map(|fd| fd as RawFd)
+}
+
+impl Handover {
+    /// Returns all the file descriptors which have to survive the `execve()`.
+    pub fn fds(&self) -> Vec<RawFd> {
+        [
+            self.runtime_pidfd,
+            self.container_pidfd,
+            self.sync_pipe_fd,
+            self.exit_fd,
+            self.sync_socket_fd,
+            self.stdin_fd,
+            self.stdout_fd,
+            self.stderr_fd,
+            self.terminal_fd,
+            self.notify_fd,
+            self.attach_fd,
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Returns the handover as JSON object.
+    pub fn to_json(&self) -> Value {
+        let mut obj = Map::new();
+        obj.insert("runtime_pid".into(), Value::from(self.runtime_pid));
+        obj.insert("exit_code".into(), Value::from(self.exit_code));
+        obj.insert("container_pid".into(), Value::from(self.container_pid));
+        obj.insert(
+            "container_started".into(),
+            Value::from(self.container_started),
+        );
+        obj.insert("timeout".into(), Value::from(self.timeout));
+        obj.insert("runtime_pidfd".into(), fd_to_json(self.runtime_pidfd));
+        obj.insert("container_pidfd".into(), fd_to_json(self.container_pidfd));
+        obj.insert("sync_pipe_fd".into(), fd_to_json(self.sync_pipe_fd));
+        obj.insert("exit_fd".into(), fd_to_json(self.exit_fd));
+        obj.insert("sync_socket_fd".into(), fd_to_json(self.sync_socket_fd));
+        obj.insert("stdin_fd".into(), fd_to_json(self.stdin_fd));
+        obj.insert("stdout_fd".into(), fd_to_json(self.stdout_fd));
+        obj.insert("stderr_fd".into(), fd_to_json(self.stderr_fd));
+        obj.insert("terminal_fd".into(), fd_to_json(self.terminal_fd));
+        obj.insert("notify_fd".into(), fd_to_json(self.notify_fd));
+        obj.insert("attach_fd".into(), fd_to_json(self.attach_fd));
+        obj.insert(
+            "attach_path".into(),
+            match &self.attach_path {
+                Some(p) => Value::from(p.to_string_lossy().into_owned()),
+                None => Value::Null,
+            },
+        );
+        Value::Object(obj)
+    }
+
+    /// Parses the handover from JSON object.
+    pub fn from_json(v: &Value) -> ConmonResult<Self> {
+        let invalid = |field: &str| ConmonError::new(format!("Invalid handover: {field}"), 1);
+        Ok(Self {
+            runtime_pid: v["runtime_pid"]
+                .as_i64()
+                .ok_or_else(|| invalid("runtime_pid"))? as i32,
+            exit_code: v["exit_code"]
+                .as_i64()
+                .ok_or_else(|| invalid("exit_code"))? as i32,
+            container_pid: v["container_pid"]
+                .as_i64()
+                .ok_or_else(|| invalid("container_pid"))? as i32,
+            container_started: v["container_started"]
+                .as_bool()
+                .ok_or_else(|| invalid("container_started"))?,
+            timeout: v["timeout"].as_u64().unwrap_or(0),
+            runtime_pidfd: fd_from_json(&v["runtime_pidfd"]),
+            container_pidfd: fd_from_json(&v["container_pidfd"]),
+            sync_pipe_fd: fd_from_json(&v["sync_pipe_fd"]),
+            exit_fd: fd_from_json(&v["exit_fd"]),
+            sync_socket_fd: fd_from_json(&v["sync_socket_fd"]),
+            stdin_fd: fd_from_json(&v["stdin_fd"]),
+            stdout_fd: fd_from_json(&v["stdout_fd"]),
+            stderr_fd: fd_from_json(&v["stderr_fd"]),
+            terminal_fd: fd_from_json(&v["terminal_fd"]),
+            notify_fd: fd_from_json(&v["notify_fd"]),
+            attach_fd: fd_from_json(&v["attach_fd"]),
+            attach_path: v["attach_path"].as_str().map(PathBuf::from),
+        })
+    }
+
+    /// Returns the handover passed by the previous conmon binary, if any.
+    ///
+    /// The environment variable is removed, so it is not inherited by
+    /// the processes we execute later.
+    pub fn from_env() -> ConmonResult<Option<Self>> {
+        let Ok(data) = env::var(HANDOVER_ENV) else {
+            return Ok(None);
+        };
+        unsafe { env::remove_var(HANDOVER_ENV) };
+        let v: Value = serde_json::from_str(&data)?;
+        let handover = Self::from_json(&v)?;
+        // Do not leak the handed over fds to the runtime or exit command.
+        for fd in handover.fds() {
+            set_cloexec(fd, true)?;
+        }
+        Ok(Some(handover))
+    }
+
+    /// Returns the environment of the new binary: the environment of this
+    /// process with the serialized handover added.
+    fn envp(&self) -> ConmonResult<Vec<CString>> {
+        let mut envp = Vec::new();
+        for (key, value) in env::vars_os() {
+            if key == HANDOVER_ENV {
+                continue;
+            }
+            let mut var = key.into_vec();
+            var.push(b'=');
+            var.extend_from_slice(value.as_bytes());
+            envp.push(CString::new(var)?);
+        }
+        envp.push(CString::new(format!("{HANDOVER_ENV}={}", self.to_json()))?);
+        Ok(envp)
+    }
+
+    /// Re-executes the conmon binary with the same arguments and hands over
+    /// the session to it.
+    ///
+    /// The handover is passed in the environment built for the new binary,
+    /// the environment of this process is not modified, as other threads
+    /// may still read it. The caller stops the log threads before.
+    ///
+    /// This function returns only on failure, in which case the fds are
+    /// left untouched and the session can continue in this process.
+    pub fn exec(&self) -> ConmonResult<Infallible> {
+        let exe = conmon_binary_path()?;
+        let args = env::args_os()
+            .map(|a| CString::new(a.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let exe_c = CString::new(exe.as_os_str().as_bytes())?;
+        let envp = self.envp()?;
+
+        for fd in self.fds() {
+            set_cloexec(fd, false)?;
+        }
+
+        info!("Re-executing {} for live upgrade", exe.display());
+        let err = match execve(&exe_c, &args, &envp) {
+            Ok(never) => match never {},
+            Err(e) => e,
+        };
+
+        // The execve() failed, so revert everything and keep running.
+        for fd in self.fds() {
+            set_cloexec(fd, true)?;
+        }
+        Err(ConmonError::new(
+            format!("Failed to re-exec {}: {err}", exe.display()),
+            1,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_roundtrip() -> ConmonResult<()> {
+        let handover = Handover {
+            runtime_pid: 10,
+            exit_code: 0,
+            container_pid: 20,
+            container_started: true,
+            timeout: 1234,
+            container_pidfd: Some(8),
+            sync_pipe_fd: Some(3),
+            exit_fd: Some(4),
+            stdout_fd: Some(5),
+            stderr_fd: Some(6),
+            attach_fd: Some(7),
+            attach_path: Some(PathBuf::from("/run/attach")),
+            ..Default::default()
+        };
+        let parsed = Handover::from_json(&handover.to_json())?;
+        assert_eq!(parsed, handover);
+        assert_eq!(parsed.fds(), vec![8, 3, 4, 5, 6, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn envp_passes_handover() -> ConmonResult<()> {
+        let handover = Handover {
+            runtime_pid: 10,
+            ..Default::default()
+        };
+        let envp = handover.envp()?;
+        let prefix = format!("{HANDOVER_ENV}=");
+        let passed: Vec<_> = envp
+            .iter()
+            .filter_map(|v| v.to_str().ok()?.strip_prefix(prefix.as_str()))
+            .collect();
+        assert_eq!(passed.len(), 1);
+        let v: Value = serde_json::from_str(passed[0])?;
+        assert_eq!(Handover::from_json(&v)?, handover);
+        Ok(())
+    }
+
+    #[test]
+    fn from_json_rejects_missing_pid() {
+        let v = serde_json::json!({"exit_code": 0});
+        let err = Handover::from_json(&v).unwrap_err();
+        assert!(err.to_string().contains("runtime_pid"));
+    }
+}
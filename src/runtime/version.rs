@@ -0,0 +1,184 @@
+use std::fmt;
+use std::str::FromStr;
+
+use log::{debug, info, warn};
+
+use crate::cli::CommonCfg;
+use crate::error::{ConmonError, ConmonResult};
+use crate::runtime::command::run_runtime;
+
+/// What to do when the runtime is older than the version supporting the
+/// flags conmon passes to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VersionCheck {
+    /// Do not probe the runtime version at all.
+    Off,
+    /// Log a warning and continue.
+    #[default]
+    Warn,
+    /// Fail before executing the runtime.
+    Fail,
+}
+
+impl FromStr for VersionCheck {
+    type Err = ConmonError;
+
+    fn from_str(s: &str) -> ConmonResult<Self> {
+        match s {
+            "off" => Ok(VersionCheck::Off),
+            "warn" => Ok(VersionCheck::Warn),
+            "fail" => Ok(VersionCheck::Fail),
+            _ => Err(ConmonError::new(
+                format!("Invalid runtime version check '{s}', expected off, warn or fail"),
+                1,
+            )),
+        }
+    }
+}
+
+/// Runtime version, pre-release suffixes like "-rc92" are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+impl Version {
+    /// Parses "MAJOR[.MINOR[.PATCH]]" followed by optional suffix.
+    fn parse(s: &str) -> Option<Self> {
+        let mut nums = s.split('.').map(|part| {
+            let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+            digits.parse::<u32>().ok()
+        });
+        let major = nums.next()??;
+        let minor = nums.next().flatten().unwrap_or(0);
+        let patch = nums.next().flatten().unwrap_or(0);
+        Some(Version(major, minor, patch))
+    }
+}
+
+/// Minimum runtime versions supporting the flags conmon may pass.
+const REQUIREMENTS: &[(&str, &str, Version)] = &[
+    ("runc", "--console-socket", Version(1, 0, 0)),
+    ("runc", "--preserve-fds", Version(1, 0, 0)),
+    ("runc", "--no-new-keyring", Version(1, 0, 0)),
+    ("runc", "--image-path", Version(1, 0, 0)),
+    ("crun", "--image-path", Version(0, 16, 0)),
+    ("crun", "--work-path", Version(0, 16, 0)),
+];
+
+/// Parses the `runtime --version` output, like "runc version 1.1.12".
+///
+/// # Returns
+///
+/// * The runtime name and its version.
+pub fn parse_version_output(output: &str) -> Option<(String, Version)> {
+    output.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        let name = words.next()?;
+        if words.next()? != "version" {
+            return None;
+        }
+        let version = Version::parse(words.next()?.trim_start_matches('v'))?;
+        Some((name.to_string(), version))
+    })
+}
+
+/// Returns the problems of running runtime `name` in `version` with `argv`.
+fn unsupported_flags(name: &str, version: Version, argv: &[String]) -> Vec<String> {
+    REQUIREMENTS
+        .iter()
+        .filter(|(runtime, flag, min)| {
+            *runtime == name && version < *min && argv.iter().any(|a| a == flag)
+        })
+        .map(|(_, flag, min)| format!("{flag} requires {name} {min} or newer"))
+        .collect()
+}
+
+/// Probes the runtime version and checks it supports the runtime arguments.
+///
+/// Runtimes without recognizable `--version` output are not checked.
+///
+/// # Arguments
+///
+/// * `common` - The Conmon common configuration.
+/// * `argv` - The runtime arguments conmon is about to execute.
+///
+/// # Errors
+///
+/// * [`ConmonError`] if the runtime is too old and `--runtime-version-check`
+///   is `fail`.
+pub fn check_runtime_version(common: &CommonCfg, argv: &[String]) -> ConmonResult<()> {
+    if common.runtime_version_check == VersionCheck::Off {
+        return Ok(());
+    }
+    let output = match run_runtime(common, &["--version"]) {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Failed to probe the runtime version: {}", e.msg);
+            return Ok(());
+        }
+    };
+    let Some((name, version)) = parse_version_output(&output) else {
+        debug!("Unrecognized runtime version: {}", output.trim());
+        return Ok(());
+    };
+    info!("Runtime {name} version {version}");
+
+    let problems = unsupported_flags(&name, version, argv);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let msg = format!(
+        "Runtime {name} {version} is too old: {}",
+        problems.join(", ")
+    );
+    match common.runtime_version_check {
+        VersionCheck::Fail => Err(ConmonError::new(msg, 1)),
+        _ => {
+            warn!("{msg}");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_version_output() {
+        let runc = "runc version 1.0.0-rc92\ncommit: ff819c7e\nspec: 1.0.2-dev\n";
+        assert_eq!(
+            parse_version_output(runc),
+            Some(("runc".to_string(), Version(1, 0, 0)))
+        );
+        let crun = "crun version 1.14.4\ncommit: a220ca6\n+SYSTEMD +SELINUX";
+        assert_eq!(
+            parse_version_output(crun),
+            Some(("crun".to_string(), Version(1, 14, 4)))
+        );
+        assert_eq!(
+            parse_version_output("youki version v0.3"),
+            Some(("youki".to_string(), Version(0, 3, 0)))
+        );
+        assert_eq!(parse_version_output("usage: runtime"), None);
+    }
+
+    #[test]
+    fn reports_unsupported_flags() {
+        let argv: Vec<String> = ["crun", "restore", "--image-path", "/cp", "abc"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            unsupported_flags("crun", Version(0, 15, 1), &argv),
+            vec!["--image-path requires crun 0.16.0 or newer".to_string()]
+        );
+        assert!(unsupported_flags("crun", Version(1, 0, 0), &argv).is_empty());
+        assert!(unsupported_flags("youki", Version(0, 1, 0), &argv).is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+
+use log::{debug, info};
+use nix::errno::Errno;
+use serde_json::{Map, Value};
+
+use crate::error::{ConmonError, ConmonResult};
+use crate::exit::ExitSignal;
+use crate::parent_pipe::{get_pipe_fd_from_env, write_all_fd};
+use crate::runtime::stdio::read_pipe;
+
+/// Environment variable with the fd of the sync socket.
+pub const SYNC_SOCKET_ENV: &str = "_OCI_SYNCSOCK";
+
+/// The sync socket protocol version implemented by this conmon.
+pub const SYNC_PROTOCOL_VERSION: u64 = 1;
+
+/// Message exchanged over the sync socket.
+///
+/// Every message is sent as a single JSON frame terminated by newline. The
+/// frame contains the protocol `version`, the message `type` and the message
+/// specific fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncMessage {
+    /// Parent to conmon: execute the runtime. With `--attach`, conmon waits
+    /// for the second `start` once the parent is attached.
+    Start,
+
+    /// Conmon to parent: the attach socket is ready.
+    AttachReady,
+
+    /// Conmon to parent: the container has been created.
+    Pid { pid: i32 },
+
+    /// Conmon to parent: the runtime failed.
+    Error { exit_code: i32, message: String },
+
+    /// Conmon to parent: the container has exited.
+    Exit {
+        exit_code: i32,
+        signal: Option<String>,
+        core_dumped: bool,
+        oom: bool,
+        message: Option<String>,
+    },
+}
+
+impl SyncMessage {
+    /// Returns the `type` of the message.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            SyncMessage::Start => "start",
+            SyncMessage::AttachReady => "attach-ready",
+            SyncMessage::Pid { .. } => "pid",
+            SyncMessage::Error { .. } => "error",
+            SyncMessage::Exit { .. } => "exit",
+        }
+    }
+
+    /// Returns the exit message of the container which exited with
+    /// `exit_code`, possibly killed by `exit_signal`. `oom` is true if the
+    /// container ran out of memory.
+    pub fn exit(
+        exit_code: i32,
+        exit_signal: Option<&ExitSignal>,
+        oom: bool,
+        message: Option<String>,
+    ) -> Self {
+        SyncMessage::Exit {
+            exit_code,
+            signal: exit_signal.map(|s| s.signal.as_str().to_string()),
+            core_dumped: exit_signal.is_some_and(|s| s.core_dumped),
+            oom,
+            message,
+        }
+    }
+
+    /// Returns the message as newline terminated JSON frame.
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut obj = Map::new();
+        obj.insert("version".into(), Value::from(SYNC_PROTOCOL_VERSION));
+        obj.insert("type".into(), Value::from(self.type_name()));
+        match self {
+            SyncMessage::Start | SyncMessage::AttachReady => {}
+            SyncMessage::Pid { pid } => {
+                obj.insert("pid".into(), Value::from(*pid));
+            }
+            SyncMessage::Error { exit_code, message } => {
+                obj.insert("exit_code".into(), Value::from(*exit_code));
+                obj.insert("message".into(), Value::from(message.as_str()));
+            }
+            SyncMessage::Exit {
+                exit_code,
+                signal,
+                core_dumped,
+                oom,
+                message,
+            } => {
+                obj.insert("exit_code".into(), Value::from(*exit_code));
+                obj.insert("signal".into(), Value::from(signal.clone()));
+                obj.insert("core_dumped".into(), Value::from(*core_dumped));
+                obj.insert("oom".into(), Value::from(*oom));
+                obj.insert("message".into(), Value::from(message.clone()));
+            }
+        }
+        let mut frame = Value::Object(obj).to_string().into_bytes();
+        frame.push(b'\n');
+        frame
+    }
+
+    /// Parses the message from JSON frame.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the frame is malformed, uses newer protocol
+    ///   version or unknown message type.
+    pub fn from_frame(frame: &[u8]) -> ConmonResult<Self> {
+        let v: Value = serde_json::from_slice(frame)?;
+        let invalid = |field: &str| ConmonError::new(format!("Invalid sync frame: {field}"), 1);
+        let version = v["version"].as_u64().ok_or_else(|| invalid("version"))?;
+        if version > SYNC_PROTOCOL_VERSION {
+            return Err(ConmonError::new(
+                format!("Unsupported sync protocol version {version}"),
+                1,
+            ));
+        }
+        let exit_code = || {
+            v["exit_code"]
+                .as_i64()
+                .map(|c| c as i32)
+                .ok_or_else(|| invalid("exit_code"))
+        };
+        let msg = match v["type"].as_str().ok_or_else(|| invalid("type"))? {
+            "start" => SyncMessage::Start,
+            "attach-ready" => SyncMessage::AttachReady,
+            "pid" => SyncMessage::Pid {
+                pid: v["pid"].as_i64().ok_or_else(|| invalid("pid"))? as i32,
+            },
+            "error" => SyncMessage::Error {
+                exit_code: exit_code()?,
+                message: v["message"].as_str().unwrap_or_default().to_string(),
+            },
+            "exit" => SyncMessage::Exit {
+                exit_code: exit_code()?,
+                signal: v["signal"].as_str().map(String::from),
+                core_dumped: v["core_dumped"].as_bool().unwrap_or(false),
+                oom: v["oom"].as_bool().unwrap_or(false),
+                message: v["message"].as_str().map(String::from),
+            },
+            t => {
+                return Err(ConmonError::new(
+                    format!("Unknown sync message type '{t}'"),
+                    1,
+                ));
+            }
+        };
+        Ok(msg)
+    }
+}
+
+/// Structured sync channel with the parent.
+///
+/// It replaces the `_OCI_SYNCPIPE`, `_OCI_ATTACHPIPE` and `_OCI_STARTPIPE`
+/// one-shot pipes with a single unix socket passed in `_OCI_SYNCSOCK`.
+pub struct SyncSocket {
+    fd: OwnedFd,
+
+    /// Received data not forming a complete frame yet.
+    buf: Vec<u8>,
+
+    /// True once the parent closed its side, so the messages are dropped.
+    closed: bool,
+}
+
+impl SyncSocket {
+    pub fn new(fd: OwnedFd) -> Self {
+        Self {
+            fd,
+            buf: Vec::new(),
+            closed: false,
+        }
+    }
+
+    /// Returns the sync socket passed by the parent, if any.
+    pub fn from_env() -> ConmonResult<Option<Self>> {
+        Ok(get_pipe_fd_from_env(SYNC_SOCKET_ENV)?.map(Self::new))
+    }
+
+    /// Returns new SyncSocket sharing the socket with this one.
+    pub fn try_clone(&self) -> ConmonResult<Self> {
+        Ok(Self::new(self.fd.try_clone()?))
+    }
+
+    /// Returns the raw fd of the socket.
+    pub fn raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    /// Sends the message to the parent.
+    ///
+    /// The message is dropped if the parent does not listen anymore.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the message cannot be sent.
+    pub fn send(&mut self, msg: &SyncMessage) -> ConmonResult<()> {
+        if self.closed {
+            debug!("Sync socket closed, dropping {msg:?}");
+            return Ok(());
+        }
+        match write_all_fd(&self.fd, &msg.to_frame()) {
+            Ok(()) => Ok(()),
+            Err(Errno::EPIPE) | Err(Errno::ECONNRESET) => {
+                info!("Parent closed the sync socket");
+                self.closed = true;
+                Ok(())
+            }
+            Err(e) => Err(ConmonError::new(
+                format!("Unable to send {} message to parent: {e}", msg.type_name()),
+                1,
+            )),
+        }
+    }
+
+    /// Receives the next message from the parent.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the parent closes the socket or sends a malformed
+    ///   frame.
+    pub fn recv(&mut self) -> ConmonResult<SyncMessage> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+                let frame: Vec<u8> = self.buf.drain(..=pos).collect();
+                return SyncMessage::from_frame(&frame);
+            }
+            let mut chunk = [0u8; 8192];
+            let n = read_pipe(&self.fd, &mut chunk)?;
+            if n == 0 {
+                return Err(ConmonError::new("Parent closed the sync socket", 1));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Waits until the parent sends the `start` message.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the parent sends a different message.
+    pub fn wait_for_start(&mut self) -> ConmonResult<()> {
+        match self.recv()? {
+            SyncMessage::Start => Ok(()),
+            msg => Err(ConmonError::new(
+                format!("Expected start message, got {}", msg.type_name()),
+                1,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::{AddressFamily, SockFlag, SockType, socketpair};
+
+    fn pair() -> ConmonResult<(SyncSocket, SyncSocket)> {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?;
+        Ok((SyncSocket::new(a), SyncSocket::new(b)))
+    }
+
+    #[test]
+    fn messages_roundtrip() -> ConmonResult<()> {
+        let (mut conmon, mut parent) = pair()?;
+        let exit_signal = ExitSignal {
+            signal: nix::sys::signal::Signal::SIGKILL,
+            core_dumped: false,
+        };
+        let msgs = [
+            SyncMessage::AttachReady,
+            SyncMessage::Pid { pid: 42 },
+            SyncMessage::Error {
+                exit_code: 1,
+                message: "no such file".into(),
+            },
+            SyncMessage::exit(137, Some(&exit_signal), true, None),
+        ];
+        for msg in &msgs {
+            conmon.send(msg)?;
+        }
+        for msg in msgs {
+            assert_eq!(parent.recv()?, msg);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_start_checks_message() -> ConmonResult<()> {
+        let (mut conmon, parent) = pair()?;
+        // Two frames in one write are split correctly.
+        let mut data = SyncMessage::Start.to_frame();
+        data.extend(SyncMessage::Pid { pid: 1 }.to_frame());
+        write_all_fd(&parent.fd, &data)?;
+        conmon.wait_for_start()?;
+        assert!(conmon.wait_for_start().is_err());
+
+        drop(parent);
+        assert!(conmon.wait_for_start().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn from_frame_rejects_newer_version_and_unknown_type() {
+        let err = SyncMessage::from_frame(br#"{"version":2,"type":"start"}"#).unwrap_err();
+        assert!(err.msg.contains("version 2"), "{}", err.msg);
+        assert!(SyncMessage::from_frame(br#"{"version":1,"type":"resize"}"#).is_err());
+        assert!(SyncMessage::from_frame(br#"{"type":"start"}"#).is_err());
+    }
+
+    #[test]
+    fn send_drops_messages_after_parent_closed() -> ConmonResult<()> {
+        let (mut conmon, parent) = pair()?;
+        drop(parent);
+        conmon.send(&SyncMessage::Pid { pid: 1 })?;
+        conmon.send(&SyncMessage::Pid { pid: 1 })?;
+        assert!(conmon.closed);
+        Ok(())
+    }
+}
@@ -1,30 +1,41 @@
 use std::{
+    collections::VecDeque,
     fmt,
     os::fd::{AsFd, OwnedFd},
     path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
 use log::{debug, error, warn};
 use nix::{
     errno::Errno,
     fcntl::OFlag,
-    sys::{
-        socket::{MsgFlags, SockaddrStorage, recvfrom, sendto},
-        uio::writev,
+    sys::socket::{
+        MsgFlags, Shutdown, SockaddrStorage, UnixCredentials, getsockopt, recvfrom, sendmsg,
+        sockopt,
     },
     unistd::{read, write},
 };
 
 use crate::{
+    attach::{self, AttachAudit, AttachOutput, Negotiated, ReplayBuffer, SlowClientPolicy},
     error::{ConmonError, ConmonResult},
-    logging::plugin::LogPlugin,
+    logging::plugin::{LogPlugin, LogStream},
+    metrics::metrics,
+    recording,
+    runtime::control::{self, ControlRequest},
     runtime::ctl::{process_terminal_ctrl_line, process_winsz_ctrl_line},
+    runtime::notify,
 };
 use std::{
     ffi::OsStr,
     io,
     os::fd::{AsRawFd, FromRawFd},
     os::unix::ffi::OsStrExt,
+    os::unix::fs::{PermissionsExt, chown},
 };
 
 use nix::{
@@ -32,7 +43,8 @@ use nix::{
     fcntl::{AT_FDCWD, open},
     sys::{
         socket::{
-            AddressFamily, Backlog, SockFlag, SockType, UnixAddr, accept, bind, listen, socket,
+            AddressFamily, Backlog, SockFlag, SockType, UnixAddr, VsockAddr, accept, bind, listen,
+            socket,
         },
         stat::{Mode, fchmod},
     },
@@ -55,6 +67,8 @@ pub enum SocketType {
     ConsoleFifo,  // Fifo for `winsz`.
     Inotify,      // Inotify socket of OOM detection.
     SignalFd,     // Signal fd to receive UNIX signals
+    Timer,        // Timer fd of the pending container stop.
+    Stdin,        // Pipe to container's stdin.
 }
 
 type RemoteSocketHandler = Box<dyn FnMut(&[u8]) -> bool + Send + 'static>;
@@ -69,6 +83,12 @@ const SOCKET_BUFFER_SIZE: usize = 32768;
 // package and some data would be lost. See SOCKET_BUFFER_SIZE.
 const CONMON_CLIENT_BUFFER_SIZE: usize = 8192;
 
+// Size of the big-endian length prefixing the packets on the stream attach socket.
+const FRAME_HEADER_LEN: usize = 4;
+
+// The end-of-file character (Ctrl-D) of the terminal in canonical mode.
+const VEOF_CHAR: u8 = 0x04;
+
 /// Remote side (attach client or sd-notify FD inside container).
 pub struct RemoteSocket {
     /// Type of this socket.
@@ -88,6 +108,21 @@ pub struct RemoteSocket {
 
     /// Handler to call on new data.
     handler: Option<RemoteSocketHandler>,
+
+    /// True once the first packet has been received.
+    greeted: bool,
+
+    /// The attach protocol negotiated with the client, if it sent a hello.
+    pub attach_protocol: Option<Negotiated>,
+
+    /// True if the packets are length-prefixed, on the stream attach socket.
+    pub framed: bool,
+
+    /// The fds received with the last sd-notify message.
+    received_fds: Vec<OwnedFd>,
+
+    /// The audit record of the attach connection, written on drop.
+    pub audit: Option<AttachAudit>,
 }
 
 impl fmt::Debug for RemoteSocket {
@@ -111,7 +146,121 @@ impl RemoteSocket {
             buf_start: 0,
             buf_end: 0,
             handler: None,
+            greeted: false,
+            attach_protocol: None,
+            framed: false,
+            received_fds: Vec::new(),
+            audit: None,
+        }
+    }
+
+    /// Sends the packet made of `parts` to the attach client, with the
+    /// length prefix if the connection is framed.
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes written.
+    pub fn send(&self, parts: &[&[u8]]) -> nix::Result<usize> {
+        send_packet(self.fd.as_raw_fd(), self.framed, parts)
+    }
+
+    /// Returns the next complete length-prefixed packet in the buffer.
+    ///
+    /// # Returns
+    ///
+    /// * None if no complete packet is buffered yet.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the packet does not fit into the buffer.
+    fn next_frame(&mut self) -> ConmonResult<Option<Vec<u8>>> {
+        let avail = &self.buf[self.buf_start..self.buf_end];
+        let Some(header) = avail.first_chunk::<FRAME_HEADER_LEN>() else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(*header) as usize;
+        if len > SOCKET_BUFFER_SIZE - FRAME_HEADER_LEN {
+            return Err(ConmonError::new(
+                format!("attach packet of {len} bytes is too long"),
+                1,
+            ));
+        }
+        let Some(packet) = avail.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len) else {
+            return Ok(None);
+        };
+        let packet = packet.to_vec();
+        self.buf_start += FRAME_HEADER_LEN + len;
+        if self.buf_start == self.buf_end {
+            self.clear_buffer();
+        }
+        Ok(Some(packet))
+    }
+
+    /// Handles single packet received from the attach client.
+    ///
+    /// # Arguments
+    ///
+    /// * `packet` - The packet received.
+    /// * `log_plugin` - The log plugin to log the container's stdin to.
+    /// * `workerfd_stdin` - The container's stdin.
+    /// * `terminal_fds` - Terminal fds into which we forward data for container's stdin.
+    fn handle_console_packet(
+        &mut self,
+        packet: &[u8],
+        log_plugin: &mut dyn LogPlugin,
+        workerfd_stdin: &mut Option<OwnedFd>,
+        terminal_fds: &Vec<i32>,
+    ) -> ConmonResult<()> {
+        // The client may start the connection with a hello packet
+        // to negotiate the attach protocol.
+        let first_packet = !std::mem::replace(&mut self.greeted, true);
+        if first_packet {
+            if let Some(negotiated) = attach::negotiate(packet) {
+                let terminal = !terminal_fds.is_empty();
+                let n = self.send(&[&negotiated.ack_packet(terminal)])?;
+                metrics().record_write(SocketType::Console, self.fd.as_raw_fd(), n);
+                self.attach_protocol = Some(negotiated);
+                return Ok(());
+            }
+        }
+
+        // The client closes the container's stdin, but keeps
+        // receiving the output.
+        if let Some(negotiated) = &self.attach_protocol {
+            if negotiated.is_stdin_eof(packet) {
+                info!("Attach client closed the container's stdin");
+                workerfd_stdin.take();
+                // A terminal has no stdin of its own; send the EOF
+                // character to it instead.
+                for &fd in terminal_fds {
+                    let borrowed = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+                    write(borrowed, &[VEOF_CHAR])?;
+                }
+                return Ok(());
+            }
+        }
+
+        if let Some(audit) = self.audit.as_mut() {
+            audit.bytes_in += packet.len() as u64;
+        }
+
+        // Console socket: forward data to container's stdin.
+        // The log plugins drop it unless `--log-stdin` is used.
+        let _ = log_plugin.write(LogStream::Stdin, packet);
+        recording::record_input(packet);
+        if let Some(workerfd_stdin) = workerfd_stdin.as_ref() {
+            let bytes_written = write(workerfd_stdin, packet)?;
+            info!("bytes written: {}", bytes_written);
+            metrics().record_write(SocketType::Stdin, workerfd_stdin.as_raw_fd(), bytes_written);
         }
+        // Forward data to terminal.
+        for &fd in terminal_fds {
+            debug!("Forwarding to terminal {}", fd);
+            let borrowed = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+            let n = write(borrowed, packet)?;
+            metrics().record_write(SocketType::Terminal, fd, n);
+        }
+        Ok(())
     }
 
     /// Attach a handler to this socket.
@@ -148,6 +297,11 @@ impl RemoteSocket {
         self.buf_end = len;
     }
 
+    /// Returns the number of bytes buffered and not yet dispatched.
+    pub fn buffered_len(&self) -> usize {
+        self.buf_end - self.buf_start
+    }
+
     /// Removes all data from the buffer.
     pub fn clear_buffer(&mut self) {
         self.buf_start = 0;
@@ -178,6 +332,7 @@ impl RemoteSocket {
                 | SocketType::Terminal
                 | SocketType::TerminalFifo
                 | SocketType::Inotify
+                | SocketType::Timer
                 | SocketType::ConsoleFifo => match read(self.fd.as_fd(), dst) {
                     Ok(n) => break n,
                     Err(err) if err == Errno::EWOULDBLOCK || err == Errno::EAGAIN => {
@@ -190,6 +345,22 @@ impl RemoteSocket {
                         ));
                     }
                 },
+                // The sd-notify messages may pass fds, like the BARRIER=1 one.
+                SocketType::Notify => match notify::recv_message(self.fd.as_raw_fd(), dst) {
+                    Ok((n, fds)) => {
+                        self.received_fds = fds;
+                        break n;
+                    }
+                    Err(err) if err == Errno::EWOULDBLOCK || err == Errno::EAGAIN => {
+                        continue;
+                    }
+                    Err(err) => {
+                        return Err(ConmonError::new(
+                            format!("read failed: {}", io::Error::from_raw_os_error(err as i32)),
+                            1,
+                        ));
+                    }
+                },
                 _ => match recvfrom::<SockaddrStorage>(self.fd.as_fd().as_raw_fd(), dst) {
                     Ok((n, _addr)) => break n,
                     Err(err) if err == Errno::EWOULDBLOCK || err == Errno::EAGAIN => {
@@ -280,7 +451,10 @@ impl RemoteSocket {
 
 impl Drop for RemoteSocket {
     fn drop(&mut self) {
-        info!("Dropping RemoteSocket {:?}", self.fd)
+        info!("Dropping RemoteSocket {:?}", self.fd);
+        if let Some(audit) = &self.audit {
+            audit.finish();
+        }
     }
 }
 
@@ -293,7 +467,222 @@ impl From<UnixSocket> for RemoteSocket {
             buf_start: 0,
             buf_end: 0,
             handler: None,
+            greeted: false,
+            attach_protocol: None,
+            framed: false,
+            received_fds: Vec::new(),
+            audit: None,
+        }
+    }
+}
+
+/// Sends the packet made of `parts` to the attach client `fd` without
+/// blocking, with the length prefix if `framed`.
+///
+/// # Returns
+///
+/// * The number of bytes written.
+fn send_packet(fd: i32, framed: bool, parts: &[&[u8]]) -> nix::Result<usize> {
+    let len = parts.iter().map(|p| p.len()).sum::<usize>() as u32;
+    let header = len.to_be_bytes();
+    let mut iov = Vec::with_capacity(parts.len() + 1);
+    if framed {
+        iov.push(std::io::IoSlice::new(&header));
+    }
+    iov.extend(parts.iter().map(|p| std::io::IoSlice::new(p)));
+    sendmsg::<()>(
+        fd,
+        &iov,
+        &[],
+        MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
+        None,
+    )
+}
+
+/// Attach client receiving the container output.
+///
+/// The output is sent without blocking. What the client does not read in
+/// time is queued, up to `--attach-buffer-size` bytes, and sent once the
+/// client catches up; a full queue is handled by the `--attach-slow-policy`.
+#[derive(Debug, Default)]
+pub struct AttachClient {
+    pub fd: i32,
+
+    /// True if the packets are length-prefixed, on the stream attach socket.
+    pub framed: bool,
+
+    /// The packets not sent yet, with their length prefix.
+    queue: VecDeque<Vec<u8>>,
+
+    /// Bytes of the first queued packet already sent. Only stream sockets
+    /// send the packets partially.
+    queue_sent: usize,
+
+    /// Total size of `queue` in bytes.
+    queue_len: usize,
+
+    /// Maximum size of `queue` in bytes.
+    buffer_size: usize,
+
+    policy: SlowClientPolicy,
+
+    /// Bytes sent to the client, counted for its audit record.
+    bytes_out: Option<Arc<AtomicU64>>,
+}
+
+impl PartialEq for AttachClient {
+    fn eq(&self, other: &Self) -> bool {
+        self.fd == other.fd
+    }
+}
+
+impl AttachClient {
+    pub fn new(remote: &RemoteSocket, output: &AttachOutput) -> Self {
+        Self {
+            fd: remote.fd.as_raw_fd(),
+            framed: remote.framed,
+            buffer_size: output.buffer_size,
+            policy: output.policy,
+            bytes_out: remote.audit.as_ref().map(|a| a.bytes_out.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Counts `n` bytes sent to the client.
+    fn record_sent(&self, n: usize) {
+        metrics().record_write(SocketType::Console, self.fd, n);
+        if let Some(bytes_out) = &self.bytes_out {
+            bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Sends the packet made of `parts` right away, with the length prefix
+    /// if the client is framed.
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes written.
+    pub fn send(&self, parts: &[&[u8]]) -> nix::Result<usize> {
+        send_packet(self.fd, self.framed, parts)
+    }
+
+    /// Sends the packet made of `parts`, or queues it if the client is not
+    /// ready to receive it.
+    ///
+    /// # Returns
+    ///
+    /// * False if the client has been disconnected.
+    pub fn push(&mut self, parts: &[&[u8]]) -> bool {
+        let len: usize = parts.iter().map(|p| p.len()).sum();
+        let header_len = if self.framed { FRAME_HEADER_LEN } else { 0 };
+        let mut sent = 0;
+        if self.queue.is_empty() {
+            match self.send(parts) {
+                Ok(n) => {
+                    self.record_sent(n);
+                    sent = n;
+                }
+                Err(Errno::EAGAIN) => {}
+                Err(e) => {
+                    warn!("Detaching attach client {}: {e}", self.fd);
+                    return false;
+                }
+            }
+            if sent == header_len + len {
+                return true;
+            }
+        }
+
+        let mut packet = Vec::with_capacity(header_len + len);
+        if self.framed {
+            packet.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        for p in parts {
+            packet.extend_from_slice(p);
+        }
+        if self.queue.is_empty() {
+            self.queue_sent = sent;
+        }
+        self.queue_len += packet.len();
+        self.queue.push_back(packet);
+        self.limit_queue()
+    }
+
+    /// Applies the policy if the queue exceeds the buffer size.
+    ///
+    /// # Returns
+    ///
+    /// * False if the client has been disconnected.
+    fn limit_queue(&mut self) -> bool {
+        metrics().record_attach_queued(self.queue_len);
+        if self.queue_len <= self.buffer_size {
+            return true;
+        }
+        if self.policy == SlowClientPolicy::Disconnect {
+            warn!(
+                "Disconnecting attach client {}, it is not reading the output",
+                self.fd
+            );
+            metrics().record_attach_slow_disconnect();
+            let _ = nix::sys::socket::shutdown(self.fd, Shutdown::Both);
+            return false;
+        }
+        // The partially sent packet has to be completed to keep the framing.
+        let keep = usize::from(self.queue_sent > 0);
+        let mut dropped = 0;
+        while self.queue_len > self.buffer_size && self.queue.len() > keep {
+            if let Some(p) = self.queue.remove(keep) {
+                self.queue_len -= p.len();
+                dropped += 1;
+            }
         }
+        if dropped > 0 {
+            debug!(
+                "Dropped {dropped} packets for slow attach client {}",
+                self.fd
+            );
+            metrics().record_attach_drop(dropped);
+        }
+        true
+    }
+
+    /// Sends the queued packets the client is ready to receive.
+    ///
+    /// # Returns
+    ///
+    /// * False if the client has been disconnected.
+    pub fn flush(&mut self) -> bool {
+        while let Some(packet) = self.queue.front() {
+            let rest = &packet[self.queue_sent..];
+            let n = match sendmsg::<()>(
+                self.fd,
+                &[std::io::IoSlice::new(rest)],
+                &[],
+                MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
+                None,
+            ) {
+                Ok(n) => n,
+                Err(Errno::EAGAIN) => return true,
+                Err(e) => {
+                    warn!("Detaching attach client {}: {e}", self.fd);
+                    return false;
+                }
+            };
+            self.record_sent(n);
+            if n < rest.len() {
+                self.queue_sent += n;
+                return true;
+            }
+            self.queue_len -= packet.len();
+            self.queue_sent = 0;
+            self.queue.pop_front();
+        }
+        true
+    }
+
+    /// Returns the number of bytes queued for the client.
+    pub fn queued_len(&self) -> usize {
+        self.queue_len
     }
 }
 
@@ -307,6 +696,15 @@ pub struct UnixSocket {
     path: Option<PathBuf>,
     fd: Option<OwnedFd>,
     socket_type: SocketType,
+
+    /// The UIDs of the peers allowed to connect; empty allows everyone.
+    allowed_uids: Vec<u32>,
+
+    /// The (uid, gid) the socket and its directory are chowned to in `listen`.
+    owner: Option<(u32, Option<u32>)>,
+
+    /// The permissions the socket is chmoded to in `listen`.
+    mode: Option<u32>,
 }
 
 impl UnixSocket {
@@ -334,6 +732,94 @@ impl UnixSocket {
         self.path.as_ref()
     }
 
+    /// Sets the owner and permissions applied to the bound socket in `listen`.
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The uid and optional gid to chown the socket and its parent
+    ///   directory to.
+    /// * `mode` - The permissions to chmod the socket to.
+    pub fn set_access(&mut self, owner: Option<(u32, Option<u32>)>, mode: Option<u32>) {
+        self.owner = owner;
+        self.mode = mode;
+    }
+
+    /// Allows only the peers running as one of `uids` to connect, checked
+    /// using `SO_PEERCRED` in `accept`.
+    pub fn set_allowed_uids(&mut self, uids: Vec<u32>) {
+        self.allowed_uids = uids;
+    }
+
+    /// Returns true if the peer with the credentials `cred` may stay connected.
+    fn peer_allowed(&self, cred: &nix::Result<UnixCredentials>) -> bool {
+        if self.allowed_uids.is_empty() {
+            return true;
+        }
+        match cred {
+            Ok(cred) if self.allowed_uids.contains(&cred.uid()) => true,
+            Ok(cred) => {
+                warn!(
+                    "Rejecting connection on socket {:?} from pid {} uid {}",
+                    self.path,
+                    cred.pid(),
+                    cred.uid()
+                );
+                false
+            }
+            Err(e) => {
+                warn!(
+                    "Rejecting connection on socket {:?}, cannot get peer credentials: {e}",
+                    self.path
+                );
+                false
+            }
+        }
+    }
+
+    /// Creates UnixSocket from the already bound and listening socket `fd`.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_type` - Type of the socket.
+    /// * `fd` - The bound socket.
+    /// * `path` - Path the socket is bound to. It is removed on drop.
+    pub fn from_listening_fd(socket_type: SocketType, fd: OwnedFd, path: Option<PathBuf>) -> Self {
+        let mut s = Self::default();
+        s.socket_type = socket_type;
+        s.fd = Some(fd);
+        s.path = path;
+        s
+    }
+
+    /// Creates new AF_VSOCK socket listening on `cid`:`port`, used as the
+    /// attach socket of the VM-based runtimes (`--attach-vsock`).
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_type` - Type of the socket.
+    /// * `cid` - The context ID to listen on, `VMADDR_CID_ANY` for any.
+    /// * `port` - The vsock port to listen on.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the socket cannot be created or bound, for example
+    ///   when the kernel has no vsock support.
+    pub fn listen_vsock(socket_type: SocketType, cid: u32, port: u32) -> ConmonResult<Self> {
+        let fd = socket(
+            AddressFamily::Vsock,
+            SockType::SeqPacket,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            None,
+        )
+        .map_err(|e| ConmonError::new(format!("Failed to create vsock socket: {e}"), 1))?;
+        bind(fd.as_raw_fd(), &VsockAddr::new(cid, port)).map_err(|e| {
+            ConmonError::new(format!("Failed to bind vsock socket {cid}:{port}: {e}"), 1)
+        })?;
+        listen(&fd, Backlog::MAXCONN)?;
+        info!("Listening on vsock {cid}:{port}");
+        Ok(Self::from_listening_fd(socket_type, fd, None))
+    }
+
     /// Generates the socket path, creates new socket and binds to the path.
     ///
     /// # Arguments
@@ -360,8 +846,11 @@ impl UnixSocket {
             // prefix the path with it.
             full_path = path.to_owned();
             let mut fallback;
+            // The directory derived from socket_parent_dir() must stay in the bundle.
+            let mut in_bundle = true;
             let dir = if let Some(parent) = path.parent() {
                 if parent.is_absolute() {
+                    in_bundle = false;
                     parent
                 } else {
                     fallback = self.socket_parent_dir()?;
@@ -383,6 +872,10 @@ impl UnixSocket {
                 ConmonError::new(format!("Failed to open directory {dir:?}: {e:?}"), 1)
             })?;
 
+            if in_bundle {
+                self.verify_dir_in_bundle(&dfd, dir)?;
+            }
+
             // Store the dir_fd, because we will be creating the socket in this dir.
             dir_fd = Some(dfd);
         } else {
@@ -416,7 +909,16 @@ impl UnixSocket {
         Ok(())
     }
 
+    /// Starts listening on the bound socket, after applying the owner and
+    /// permissions set by `set_access`.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the socket cannot be chowned, chmoded or listened on.
     pub fn listen(&self) -> ConmonResult<()> {
+        if let Some(path) = &self.path {
+            self.apply_access(path)?;
+        }
         if let Some(fd) = &self.fd {
             listen(fd, Backlog::MAXCONN)?;
             info!("Listening on {:?}", self.path);
@@ -424,6 +926,25 @@ impl UnixSocket {
         Ok(())
     }
 
+    /// Applies the owner and permissions set by `set_access` to the socket
+    /// at `path`.
+    fn apply_access(&self, path: &Path) -> ConmonResult<()> {
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(|e| {
+                ConmonError::new(format!("Failed to chmod socket {path:?}: {e}"), 1)
+            })?;
+        }
+        if let Some((uid, gid)) = self.owner {
+            let dirs = path.parent().filter(|d| !d.as_os_str().is_empty());
+            for p in std::iter::once(path).chain(dirs) {
+                chown(p, Some(uid), gid).map_err(|e| {
+                    ConmonError::new(format!("Failed to chown {p:?} to {uid}: {e}"), 1)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
     /// Binds the socket to relative path.
     ///
     /// # Arguments
@@ -469,6 +990,42 @@ impl UnixSocket {
         Ok(())
     }
 
+    /// Verifies the opened socket directory resolves into the bundle.
+    ///
+    /// The socket directory is reached through the `--socket-dir-path` symlink
+    /// and paths inside the bundle, any of which may be a symlink planted
+    /// in a shared directory to make us create the socket elsewhere.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir_fd` - The opened socket directory.
+    /// * `dir` - The path `dir_fd` has been opened with, used in errors.
+    ///
+    /// # Errors
+    ///
+    /// * [`ConmonError`] if the directory resolves outside of the bundle.
+    fn verify_dir_in_bundle(&self, dir_fd: &OwnedFd, dir: &Path) -> ConmonResult<()> {
+        let bundle = std::fs::canonicalize(&self.bundle_path).map_err(|e| {
+            ConmonError::new(
+                format!("Failed to resolve bundle path {:?}: {e}", self.bundle_path),
+                1,
+            )
+        })?;
+        let resolved = std::fs::read_link(format!("/proc/self/fd/{}", dir_fd.as_raw_fd()))
+            .map_err(|e| {
+                ConmonError::new(format!("Failed to resolve directory {dir:?}: {e}"), 1)
+            })?;
+        if !resolved.starts_with(&bundle) {
+            return Err(ConmonError::new(
+                format!(
+                    "Socket directory {dir:?} resolves to {resolved:?} outside of the bundle {bundle:?}"
+                ),
+                1,
+            ));
+        }
+        Ok(())
+    }
+
     /// Returns the max socket path length.
     fn max_socket_path_len(&mut self) -> usize {
         let addr: nix::sys::socket::sockaddr_un = unsafe { std::mem::zeroed() };
@@ -552,9 +1109,12 @@ impl UnixSocket {
 
     /// Accepts new UnixSocket client (remote) connection.
     ///
+    /// The connections of the peers not in the allowed UIDs are closed.
+    ///
     /// # Returns
     /// * The RemoteSocket with new client connection. The type of the RemoteSocket
     ///   is the same as type of this UnixSocket.
+    /// * None if no connection is pending or it has been rejected.
     pub fn accept(&self) -> ConmonResult<Option<RemoteSocket>> {
         if self.fd.is_none() {
             return Ok(None);
@@ -562,17 +1122,39 @@ impl UnixSocket {
 
         match accept(self.fd.as_ref().unwrap().as_raw_fd()) {
             Ok(new_fd) => {
+                let new_fd = unsafe { OwnedFd::from_raw_fd(new_fd) };
+                let cred = getsockopt(&new_fd, sockopt::PeerCredentials);
+                if !self.peer_allowed(&cred) {
+                    return Ok(None);
+                }
                 info!(
-                    "Accepted new remote connection on socket {:?}: {}",
+                    "Accepted new remote connection on socket {:?}: {:?}",
                     self.path, new_fd
                 );
-                let remote =
-                    RemoteSocket::new(self.socket_type, unsafe { OwnedFd::from_raw_fd(new_fd) });
+                let mut remote = RemoteSocket::new(self.socket_type, new_fd);
+                // The stream sockets do not keep the packet boundaries.
+                remote.framed = self.socket_type == SocketType::Console
+                    && matches!(
+                        getsockopt(self.fd.as_ref().unwrap(), sockopt::SockType),
+                        Ok(SockType::Stream)
+                    );
+                if self.socket_type == SocketType::Console {
+                    // The vsock peers have no credentials.
+                    let peer = cred.ok().map(|c| (c.uid(), c.gid(), c.pid()));
+                    info!(
+                        "Attach client connected: {}",
+                        match peer {
+                            Some((uid, gid, pid)) => format!("uid {uid} gid {gid} pid {pid}"),
+                            None => "unknown peer".to_string(),
+                        }
+                    );
+                    remote.audit = Some(AttachAudit::new(peer));
+                }
                 Ok(Some(remote))
             }
             Err(Errno::EWOULDBLOCK) => Ok(None),
             Err(e) => {
-                eprintln!("warn: Failed to accept client connection on attach socket: {e}");
+                warn!("Failed to accept client connection on attach socket: {e}");
                 Ok(None)
             }
         }
@@ -587,30 +1169,6 @@ impl Drop for UnixSocket {
     }
 }
 
-/// Creates new socket for sd-notify.
-///
-/// This socket is later used to forward messages to systemd.
-///
-/// # Arguments
-///
-/// * `socket_path` - Path to `notify.sock`.
-///
-/// # Returns
-///
-/// * (created socket, addr)
-fn make_notify_socket_and_addr(socket_path: &Path) -> nix::Result<(OwnedFd, UnixAddr)> {
-    // socket(AF_UNIX, SOCK_DGRAM | SOCK_NONBLOCK | SOCK_CLOEXEC, 0)
-    let fd = socket(
-        AddressFamily::Unix,
-        SockType::Datagram,
-        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
-        None,
-    )?;
-    let addr = UnixAddr::new(socket_path)?;
-
-    Ok((fd, addr))
-}
-
 /// Enum representing UnixSocket, RemoteSocket or invalid socket.
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
@@ -627,23 +1185,27 @@ impl Socket {
     ///
     /// * `log_plugin` - The log plugin to forward container message to.
     /// * `new_sockets` - Vector into which newly created RemoteSocket can be added into.
-    /// * `workerfd_stdin` - The container's stdin.
-    /// * `console_fds` - The list of podman's fds using which the podman receives
-    ///   stdout/stderr data from container.
+    /// * `workerfd_stdin` - The container's stdin. It is closed when an attach
+    ///   client sends the stdin EOF packet.
+    /// * `console_clients` - The attach clients using which the podman receives
+    ///   stdout/stderr data from container. The clients which cannot be written
+    ///   to anymore are removed from it.
     /// * `terminal_fds` - Terminal fds into which we forward data for container's stdin.
     /// * `stdout_fd` - The fd of container's stdout. We use it to change the terminal
     ///   size.
     /// * `sdnotify_socket` - Path to systemd's "notify.sock".
+    /// * `replay` - The recent output replayed to the new attach clients.
     #[allow(clippy::too_many_arguments)]
     pub fn handle_data(
         &mut self,
         log_plugin: &mut dyn LogPlugin,
         new_sockets: &mut Vec<RemoteSocket>,
-        workerfd_stdin: Option<&OwnedFd>,
-        console_fds: &Vec<i32>,
+        workerfd_stdin: &mut Option<OwnedFd>,
+        console_clients: &mut Vec<AttachClient>,
         terminal_fds: &Vec<i32>,
         stdout_fd: i32,
         sdnotify_socket: &Option<PathBuf>,
+        replay: &mut ReplayBuffer,
     ) -> ConmonResult<bool> {
         match self {
             Socket::Unix(l) => {
@@ -666,17 +1228,26 @@ impl Socket {
                 if bytes_read == 0 {
                     return Ok(false);
                 }
+                metrics().record_read(r.socket_type, r.fd.as_raw_fd(), bytes_read);
 
                 // If the Socket has a handler, call the handler directly and return.
                 if let Some(handler) = r.handler.as_mut() {
-                    return Ok(handler(&r.buf[..bytes_read]));
+                    let keep = handler(&r.buf[r.buf_end - bytes_read..r.buf_end]);
+                    r.clear_buffer();
+                    return Ok(keep);
                 }
 
                 match r.socket_type {
                     SocketType::Stdout | SocketType::Stderr | SocketType::Terminal => {
                         // Forward data to logs.
                         let is_stderr = r.socket_type == SocketType::Stderr;
-                        let _ = log_plugin.write(!is_stderr, &r.buf[..bytes_read]);
+                        let stream = if is_stderr {
+                            LogStream::Stderr
+                        } else {
+                            LogStream::Stdout
+                        };
+                        let _ = log_plugin.write(stream, &r.buf[..bytes_read]);
+                        recording::record_output(&r.buf[..bytes_read]);
 
                         // Forward data to remote sockets attached to `attach` socket.
                         // The data is prefixed with single byte indicating whether
@@ -691,50 +1262,65 @@ impl Socket {
                         // buffer has 8192+1 bytes. It would be nice to unify that, but we need to
                         // keep the backwards compatibility for now. We also have to keep using
                         // SOCKET_SEQPACKET and therefore everything needs to be sent in a single packet.
+                        // A client failing to receive the data is detached, so it
+                        // does not affect the other clients.
                         let data = &r.buf[..bytes_read];
                         for chunk in data.chunks(CONMON_CLIENT_BUFFER_SIZE) {
-                            for &fd in console_fds {
-                                let borrowed = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
-                                let iov = [
-                                    std::io::IoSlice::new(prefix_buf),
-                                    std::io::IoSlice::new(chunk),
-                                ];
-                                writev(borrowed, &iov)?;
-                            }
+                            replay.push(prefix_buf, chunk);
+                            console_clients.retain_mut(|client| client.push(&[prefix_buf, chunk]));
                         }
                         r.clear_buffer();
                     }
                     SocketType::Console => {
-                        // Console socket: forward data to container's stdin.
-                        if let Some(workerfd_stdin) = workerfd_stdin.as_ref() {
-                            let bytes_written = write(workerfd_stdin, &r.buf[..bytes_read])?;
-                            info!("bytes written: {}", bytes_written);
-                        }
-                        // Forward data to terminal.
-                        for &fd in terminal_fds {
-                            debug!("Forwarding to terminal {}", fd);
-                            let borrowed = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
-                            write(borrowed, &r.buf[..bytes_read])?;
+                        // The packets of the stream attach socket are length-prefixed
+                        // and may arrive split or coalesced.
+                        let packets = if r.framed {
+                            let mut packets = Vec::new();
+                            loop {
+                                match r.next_frame() {
+                                    Ok(Some(packet)) => packets.push(packet),
+                                    Ok(None) => break,
+                                    Err(e) => {
+                                        warn!("Detaching attach client {:?}: {}", r.fd, e.msg);
+                                        return Ok(false);
+                                    }
+                                }
+                            }
+                            packets
+                        } else {
+                            let packet = r.buf[..bytes_read].to_vec();
+                            r.clear_buffer();
+                            vec![packet]
+                        };
+                        for packet in packets {
+                            r.handle_console_packet(
+                                &packet,
+                                log_plugin,
+                                workerfd_stdin,
+                                terminal_fds,
+                            )?;
                         }
-                        r.clear_buffer();
                     }
                     SocketType::Notify => {
                         // We received something from "notify.sock" from the container. We need
-                        // to forward it to host system's systemd.
-                        if let Some(notify_path) = &sdnotify_socket {
-                            let (notify_fd, notify_addr) =
-                                make_notify_socket_and_addr(notify_path)?;
-                            // Handle all complete lines.
-                            while let Some((ptr, len)) = r.next_line(true) {
-                                let line = unsafe { std::slice::from_raw_parts(ptr, len) };
-                                let line_str = String::from_utf8_lossy(line);
-                                info!("Received systemd notify line: {}", line_str);
-                                sendto(
-                                    notify_fd.as_raw_fd(),
-                                    line,
-                                    &notify_addr,
-                                    MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
-                                )?;
+                        // to forward it to host system's systemd. Every datagram is single
+                        // notification.
+                        let message = r.buf[r.buf_start..r.buf_end].to_vec();
+                        r.clear_buffer();
+                        info!(
+                            "Received systemd notify message: {}",
+                            String::from_utf8_lossy(&message)
+                        );
+                        // Only the barrier fd is passed on, the others are closed.
+                        let mut fds = std::mem::take(&mut r.received_fds);
+                        if !notify::is_barrier(&message) {
+                            fds.clear();
+                        }
+                        let relayed = notify::filter_message(&message, notify::main_pid());
+                        if let (Some(notify_path), Some(relayed)) = (sdnotify_socket, relayed) {
+                            // The host systemd may be gone; it must not stop the container.
+                            if let Err(e) = notify::relay(notify_path, &relayed, &fds) {
+                                warn!("Failed to relay systemd notify message: {e}");
                             }
                         }
                     }
@@ -745,7 +1331,16 @@ impl Socket {
                             let line = unsafe { std::slice::from_raw_parts(ptr, len) };
                             let line_str = String::from_utf8_lossy(line);
                             if r.socket_type == SocketType::TerminalFifo {
-                                if let Err(err) =
+                                // Container control requests like "kill" or "stop".
+                                if let Some(req) = ControlRequest::parse(&line_str) {
+                                    match req.and_then(control::handle_request) {
+                                        Ok(Some(timer)) => new_sockets.push(timer),
+                                        Ok(None) => {}
+                                        Err(err) => {
+                                            warn!("failed to process control request: {}", err)
+                                        }
+                                    }
+                                } else if let Err(err) =
                                     process_terminal_ctrl_line(log_plugin, stdout_fd, &line_str)
                                 {
                                     warn!("failed to process terminal ctrl line: {}", err);
@@ -755,7 +1350,11 @@ impl Socket {
                             }
                         }
                     }
-                    SocketType::Inotify | SocketType::SignalFd | SocketType::Attach => {}
+                    SocketType::Inotify
+                    | SocketType::SignalFd
+                    | SocketType::Attach
+                    | SocketType::Timer
+                    | SocketType::Stdin => {}
                 }
             }
             Socket::Invalid() => {
@@ -765,3 +1364,337 @@ impl Socket {
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::plugin::initialize_log_plugin;
+    use nix::sys::socket::socketpair;
+    use tempfile::tempdir;
+
+    fn bind_in(bundle: &Path, socket_dir: &Path, path: &str) -> ConmonResult<UnixSocket> {
+        let mut socket = UnixSocket::new(
+            SocketType::Console,
+            false,
+            bundle.to_path_buf(),
+            Some(socket_dir.to_path_buf()),
+            Some("cuuid".into()),
+        );
+        socket.bind(
+            Some(PathBuf::from(path)),
+            SockType::SeqPacket,
+            SockFlag::SOCK_CLOEXEC,
+            Mode::from_bits_truncate(0o700),
+        )?;
+        Ok(socket)
+    }
+
+    #[test]
+    fn output_is_sent_to_all_attach_clients() -> ConmonResult<()> {
+        let pair = || {
+            socketpair(
+                AddressFamily::Unix,
+                SockType::SeqPacket,
+                None,
+                SockFlag::SOCK_CLOEXEC,
+            )
+        };
+        let (first, first_peer) = pair()?;
+        let (gone, gone_peer) = pair()?;
+        let (stdout, stdout_peer) = pair()?;
+        drop(gone_peer);
+
+        let client = |fd: &OwnedFd| AttachClient {
+            fd: fd.as_raw_fd(),
+            ..Default::default()
+        };
+        let mut console_clients = vec![client(&first), client(&gone)];
+        let mut plugin = initialize_log_plugin("none", &Default::default())?;
+        let mut socket = Socket::Remote(RemoteSocket::new(SocketType::Stdout, stdout));
+        write(&stdout_peer, b"hi")?;
+        socket.handle_data(
+            plugin.as_mut(),
+            &mut Vec::new(),
+            &mut None,
+            &mut console_clients,
+            &Vec::new(),
+            -1,
+            &None,
+            &mut ReplayBuffer::default(),
+        )?;
+
+        // The disconnected client is detached, the other one gets the data.
+        assert_eq!(console_clients, vec![client(&first)]);
+        let mut buf = [0u8; 16];
+        let n = nix::unistd::read(&first_peer, &mut buf)?;
+        assert_eq!(&buf[..n], b"\x02hi");
+        Ok(())
+    }
+
+    #[test]
+    fn slow_attach_clients_are_handled_by_policy() -> ConmonResult<()> {
+        for policy in [SlowClientPolicy::Disconnect, SlowClientPolicy::DropOldest] {
+            let (conn, peer) = socketpair(
+                AddressFamily::Unix,
+                SockType::SeqPacket,
+                None,
+                SockFlag::SOCK_CLOEXEC,
+            )?;
+            let output = AttachOutput::new(0, 64 * 1024, policy);
+            let remote = RemoteSocket::new(SocketType::Console, conn);
+            let mut client = AttachClient::new(&remote, &output);
+            let chunk = [b'x'; 4096];
+
+            // The peer does not read, so the output is queued once the socket is full.
+            let mut connected = true;
+            for _ in 0..1024 {
+                connected = client.push(&[&[2], &chunk]);
+                if !connected {
+                    break;
+                }
+            }
+            assert_eq!(connected, policy == SlowClientPolicy::DropOldest);
+            if !connected {
+                continue;
+            }
+            assert!(client.queued_len() <= 64 * 1024);
+
+            // Once the peer reads, the queued output is sent.
+            let mut buf = [0u8; 8192];
+            while client.queued_len() > 0 {
+                nix::unistd::read(&peer, &mut buf)?;
+                assert!(client.flush());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn attach_client_closes_stdin_without_disconnecting() -> ConmonResult<()> {
+        let (client, client_peer) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?;
+        let (_stdin_peer, stdin) = nix::unistd::pipe()?;
+        let mut workerfd_stdin = Some(stdin);
+        let mut plugin = initialize_log_plugin("none", &Default::default())?;
+        let mut socket = Socket::Remote(RemoteSocket::new(SocketType::Console, client));
+        let mut hello = attach::HELLO_MAGIC.to_vec();
+        hello.extend_from_slice(br#"{"features":["stdin-eof"]}"#);
+
+        for packet in [&hello[..], attach::STDIN_EOF_MAGIC] {
+            write(&client_peer, packet)?;
+            let keep_reading = socket.handle_data(
+                plugin.as_mut(),
+                &mut Vec::new(),
+                &mut workerfd_stdin,
+                &mut Vec::new(),
+                &Vec::new(),
+                -1,
+                &None,
+                &mut ReplayBuffer::default(),
+            )?;
+            assert!(keep_reading);
+        }
+        assert!(workerfd_stdin.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn stream_attach_packets_are_length_prefixed() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let bundle = tmp.path().join("bundle");
+        let socket_dir = tmp.path().join("sockets");
+        std::fs::create_dir_all(&bundle)?;
+        std::fs::create_dir_all(&socket_dir)?;
+        let mut listener = UnixSocket::new(
+            SocketType::Console,
+            false,
+            bundle,
+            Some(socket_dir),
+            Some("cuuid".into()),
+        );
+        listener.bind(
+            Some(PathBuf::from("attach")),
+            SockType::Stream,
+            SockFlag::SOCK_CLOEXEC,
+            Mode::from_bits_truncate(0o700),
+        )?;
+        listener.listen()?;
+        let client = socket(
+            AddressFamily::Unix,
+            SockType::Stream,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        nix::sys::socket::connect(
+            client.as_raw_fd(),
+            &UnixAddr::new(listener.path().unwrap())?,
+        )?;
+        let remote = listener.accept()?.unwrap();
+        assert!(remote.framed);
+
+        // Two packets, the second one split across writes.
+        let (stdin_peer, stdin) = nix::unistd::pipe()?;
+        let mut workerfd_stdin = Some(stdin);
+        let mut plugin = initialize_log_plugin("none", &Default::default())?;
+        let mut socket = Socket::Remote(remote);
+        for data in [&b"\0\0\0\x03one\0\0\0\x03t"[..], b"wo"] {
+            write(&client, data)?;
+            socket.handle_data(
+                plugin.as_mut(),
+                &mut Vec::new(),
+                &mut workerfd_stdin,
+                &mut Vec::new(),
+                &Vec::new(),
+                -1,
+                &None,
+                &mut ReplayBuffer::default(),
+            )?;
+        }
+        let mut buf = [0u8; 16];
+        let n = nix::unistd::read(&stdin_peer, &mut buf)?;
+        assert_eq!(&buf[..n], b"onetwo");
+
+        // The output is length-prefixed too.
+        let Socket::Remote(remote) = &socket else {
+            unreachable!();
+        };
+        remote.send(&[&[2], b"out"])?;
+        let n = nix::unistd::read(&client, &mut buf)?;
+        assert_eq!(&buf[..n], b"\0\0\0\x04\x02out");
+        Ok(())
+    }
+
+    #[test]
+    fn accept_rejects_peers_not_allowed() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let bundle = tmp.path().join("bundle");
+        let socket_dir = tmp.path().join("sockets");
+        std::fs::create_dir_all(&bundle)?;
+        std::fs::create_dir_all(&socket_dir)?;
+        let mut listener = bind_in(&bundle, &socket_dir, "attach")?;
+        listener.listen()?;
+
+        let uid = nix::unistd::getuid().as_raw();
+        for (allowed, accepted) in [(vec![], true), (vec![uid], true), (vec![uid + 1], false)] {
+            listener.set_allowed_uids(allowed);
+            let client = socket(
+                AddressFamily::Unix,
+                SockType::SeqPacket,
+                SockFlag::SOCK_CLOEXEC,
+                None,
+            )?;
+            nix::sys::socket::connect(
+                client.as_raw_fd(),
+                &UnixAddr::new(listener.path().unwrap())?,
+            )?;
+            assert_eq!(listener.accept()?.is_some(), accepted);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn accepted_attach_connections_are_audited() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let bundle = tmp.path().join("bundle");
+        let socket_dir = tmp.path().join("sockets");
+        std::fs::create_dir_all(&bundle)?;
+        std::fs::create_dir_all(&socket_dir)?;
+        let listener = bind_in(&bundle, &socket_dir, "attach")?;
+        listener.listen()?;
+        let client = socket(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        nix::sys::socket::connect(
+            client.as_raw_fd(),
+            &UnixAddr::new(listener.path().unwrap())?,
+        )?;
+        let mut remote = listener.accept()?.unwrap();
+        let peer = remote.audit.as_ref().unwrap().peer;
+        assert_eq!(
+            peer,
+            Some((
+                nix::unistd::getuid().as_raw(),
+                nix::unistd::getgid().as_raw(),
+                std::process::id() as i32
+            ))
+        );
+
+        let mut plugin = initialize_log_plugin("none", &Default::default())?;
+        remote.handle_console_packet(b"input", plugin.as_mut(), &mut None, &Vec::new())?;
+        let output = AttachOutput::new(0, 1024, SlowClientPolicy::Disconnect);
+        let mut attach_client = AttachClient::new(&remote, &output);
+        assert!(attach_client.push(&[b"\x02", b"output"]));
+
+        let record = remote.audit.as_ref().unwrap().record();
+        assert_eq!(record["bytes_in"], 5);
+        assert_eq!(record["bytes_out"], 7);
+        assert_eq!(record["pid"], std::process::id());
+        Ok(())
+    }
+
+    #[test]
+    fn listen_applies_owner_and_mode() -> ConmonResult<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = tempdir()?;
+        let bundle = tmp.path().join("bundle");
+        let socket_dir = tmp.path().join("sockets");
+        std::fs::create_dir_all(&bundle)?;
+        std::fs::create_dir_all(&socket_dir)?;
+        let mut listener = bind_in(&bundle, &socket_dir, "attach")?;
+        let (uid, gid) = (
+            nix::unistd::getuid().as_raw(),
+            nix::unistd::getgid().as_raw(),
+        );
+        listener.set_access(Some((uid, Some(gid))), Some(0o660));
+        listener.listen()?;
+
+        let meta = std::fs::metadata(listener.path().unwrap())?;
+        assert_eq!(meta.mode() & 0o777, 0o660);
+        assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+        Ok(())
+    }
+
+    #[test]
+    fn bind_through_socket_dir_symlink() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let bundle = tmp.path().join("bundle");
+        let socket_dir = tmp.path().join("sockets");
+        std::fs::create_dir_all(&bundle)?;
+        std::fs::create_dir_all(&socket_dir)?;
+
+        let socket = bind_in(&bundle, &socket_dir, "attach")?;
+        assert_eq!(
+            socket.path(),
+            Some(&socket_dir.join("cuuid").join("attach"))
+        );
+        assert!(bundle.join("attach").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn bind_rejects_symlink_escaping_bundle() -> ConmonResult<()> {
+        let tmp = tempdir()?;
+        let bundle = tmp.path().join("bundle");
+        let socket_dir = tmp.path().join("sockets");
+        let outside = tmp.path().join("outside");
+        std::fs::create_dir_all(&bundle)?;
+        std::fs::create_dir_all(&socket_dir)?;
+        std::fs::create_dir_all(&outside)?;
+        std::os::unix::fs::symlink(&outside, bundle.join("notify"))?;
+
+        let Err(err) = bind_in(&bundle, &socket_dir, "notify/notify.sock") else {
+            panic!("bind must fail");
+        };
+        assert!(err.msg.contains("outside of the bundle"), "{}", err.msg);
+        assert!(!outside.join("notify.sock").exists());
+        Ok(())
+    }
+}